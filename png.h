@@ -1618,6 +1618,21 @@ PNG_EXPORT(85, void, png_set_write_user_transform_fn, (png_structrp png_ptr,
     png_user_transform_ptr write_user_transform_fn));
 #endif
 
+/* Add a transform written in Rust (a RowTransform of png_rust) after the
+ * transforms of libpng on read, or before them on write.  'transform' is a
+ * Box<Box<dyn RowTransform>> given with Box::into_raw, png_ptr takes
+ * ownership of it.
+ */
+#ifdef PNG_READ_USER_TRANSFORM_SUPPORTED
+PNG_EXPORT(250, void, png_set_read_rust_transform, (png_structrp png_ptr,
+    png_voidp transform));
+#endif
+
+#ifdef PNG_WRITE_USER_TRANSFORM_SUPPORTED
+PNG_EXPORT(251, void, png_set_write_rust_transform, (png_structrp png_ptr,
+    png_voidp transform));
+#endif
+
 #ifdef PNG_USER_TRANSFORM_PTR_SUPPORTED
 PNG_EXPORT(86, void, png_set_user_transform_info, (png_structrp png_ptr,
     png_voidp user_transform_ptr, int user_transform_depth,
@@ -1704,6 +1719,13 @@ PNG_EXPORT(219, size_t, png_process_data_pause, (png_structrp, int save));
  */
 PNG_EXPORT(220, png_uint_32, png_process_data_skip, (png_structrp));
 
+/* The Png of png_rust reading the datastream of png_ptr, for the Rust
+ * applications which take the progressive events (Png::take_progressive_events)
+ * instead of setting the callbacks of png_set_progressive_read_fn.
+ */
+PNG_EXPORT(252, png_voidp, png_get_progressive_rust_ptr,
+    (png_const_structrp png_ptr));
+
 /* Function that combines rows.  'new_row' is a flag that should come from
  * the callback and be non-NULL if anything needs to be done; the library
  * stores its own version of the new data internally and ignores the passed
@@ -3235,7 +3257,7 @@ PNG_EXPORT(244, int, png_set_option, (png_structrp png_ptr, int option,
  * one to use is one more than this.)
  */
 #ifdef PNG_EXPORT_LAST_ORDINAL
-  PNG_EXPORT_LAST_ORDINAL(249);
+  PNG_EXPORT_LAST_ORDINAL(252);
 #endif
 
 #ifdef __cplusplus
@@ -0,0 +1,94 @@
+/* Throughput of write_image with the IDAT stream deflated on one thread and
+ * in row bands on several threads.  Run with `cargo bench`.
+ */
+use png_rust::{ChunkWriter, Png, PngColor, PngInfo};
+use std::time::Instant;
+
+const WIDTH: u32 = 3840;
+const HEIGHT: u32 = 2160;
+const RUNS: u32 = 3;
+
+/* Something like a screenshot: flat areas, gradients and a bit of noise */
+fn screenshot() -> Vec<Vec<u8>>
+{
+    let mut seed = 12345u32;
+
+    (0..HEIGHT).map(|y| {
+        (0..WIDTH).flat_map(|x| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let noise = ((seed >> 16) & 0x7) as u8;
+
+            match (x / 480 + y / 270) % 3 {
+                0 => [0xf0, 0xf0, 0xf0, 0xff],
+                1 => [(x / 16) as u8, (y / 9) as u8, 0x80, 0xff],
+                _ => [noise.wrapping_add(x as u8), noise, (y as u8).wrapping_mul(3), 0xff],
+            }
+        }).collect()
+    }).collect()
+}
+
+fn encode(image: &[Vec<u8>], threads: usize) -> Vec<u8>
+{
+    let mut png = Png::new();
+    let mut info = PngInfo::new();
+    let mut writer = ChunkWriter::new(Vec::new());
+
+    info.width = WIDTH;
+    info.height = HEIGHT;
+    info.bit_depth = 8;
+    info.color_type = PngColor::TYPE_RGB_ALPHA;
+    png.set_compression_threads(threads);
+    png.set_compression_buffer_size(1 << 20).unwrap();
+
+    png.write_info(&mut writer, &mut info).unwrap();
+    png.write_image(&mut writer, image).unwrap();
+    png.write_end(&mut writer, None).unwrap();
+
+    writer.into_inner()
+}
+
+/* The image data of a PNG file, inflated */
+fn image_data(file: &[u8]) -> Vec<u8>
+{
+    let mut idat = Vec::new();
+    let mut pos = 8;
+
+    while pos + 12 <= file.len() {
+        let length = u32::from_be_bytes([file[pos], file[pos + 1], file[pos + 2], file[pos + 3]]) as usize;
+
+        if &file[pos + 4..pos + 8] == b"IDAT"
+        {
+            idat.extend_from_slice(&file[pos + 8..pos + 8 + length]);
+        }
+        pos += 12 + length;
+    }
+
+    miniz_oxide::inflate::decompress_to_vec_zlib(&idat).expect("invalid IDAT stream")
+}
+
+fn main()
+{
+    let image = screenshot();
+    let megabytes = (WIDTH * HEIGHT * 4) as f64 / (1024.0 * 1024.0);
+    let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    let mut thread_counts = vec![1, 2, 4, cpus];
+
+    thread_counts.sort_unstable();
+    thread_counts.dedup();
+
+    let reference = image_data(&encode(&image, 1));
+
+    for &threads in &thread_counts {
+        let start = Instant::now();
+        let mut file = Vec::new();
+
+        for _ in 0..RUNS {
+            file = encode(&image, threads);
+        }
+
+        let seconds = start.elapsed().as_secs_f64() / RUNS as f64;
+        assert!(image_data(&file) == reference, "image data differs with {} threads", threads);
+
+        println!("{:2} thread(s): {:7.1} MB/s, {:9} bytes", threads, megabytes / seconds, file.len());
+    }
+}
@@ -0,0 +1,42 @@
+/* With the c-tests feature the C part of libpng is compiled and linked to the
+ * tests, which compare the Rust code with the C API.  The default build
+ * leaves the C code to the Makefile, which links it with libpng_rust.a.
+ */
+fn main()
+{
+    #[cfg(feature = "c-tests")]
+    build_libpng();
+}
+
+#[cfg(feature = "c-tests")]
+const C_SOURCES: [&str; 15] = [
+    "png", "pngerror", "pngget", "pngmem", "pngpread", "pngread", "pngrio",
+    "pngrtran", "pngrutil", "pngset", "pngtrans", "pngwio", "pngwrite",
+    "pngwtran", "pngwutil",
+];
+
+#[cfg(feature = "c-tests")]
+fn build_libpng()
+{
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    /* The configuration of the Makefile without configure */
+    fs::copy("../scripts/pnglibconf.h.prebuilt", out_dir.join("pnglibconf.h")).unwrap();
+
+    let mut build = cc::Build::new();
+    build.include("..").include(&out_dir).warnings(false);
+    for source in C_SOURCES.iter() {
+        let path = format!("../{}.c", source);
+        println!("cargo:rerun-if-changed={}", path);
+        build.file(path);
+    }
+    build.compile("pngc");
+
+    println!("cargo:rerun-if-changed=../pngrust.h");
+    println!("cargo:rerun-if-changed=../scripts/pnglibconf.h.prebuilt");
+    println!("cargo:rustc-link-lib=z");
+}
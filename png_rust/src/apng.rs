@@ -0,0 +1,1258 @@
+use crate::{PngColor, PngFilter};
+use crate::png_info::{PngInfo, PngColor16, PngPaletteColor};
+use crate::deflate::{PngCompression, PngZlibStrategy, zlib_compress};
+use crate::filter::{PngFilterHeuristic, find_filter};
+use crate::wutil::{ChunkWriter, PNG_UINT_31_MAX, invalid_input};
+use std::io;
+use std::io::Write;
+
+/*******************************************************************************
+ *
+ *                               Png RUST
+ *
+ ******************************************************************************/
+
+/* Animated PNG (APNG): the acTL chunk gives the number of frames, each frame
+ * is described by an fcTL chunk and its image data is in the IDAT chunks (for
+ * the first frame, if the fcTL comes before them) or in the fdAT chunks which
+ * follow the fcTL.  The frames are rectangles of the image, composed on the
+ * previous ones, see ApngCompositor.
+ */
+
+/* The animation control (acTL) */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PngAnimationControl {
+    pub num_frames: u32,
+    pub num_plays: u32, /* 0 to loop forever */
+}
+
+/* What becomes of the frame area before the next frame is rendered */
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PngDisposeOp {
+    None       = 0, /* the area is left as it is */
+    Background = 1, /* the area is cleared to transparent black */
+    Previous   = 2, /* the area goes back to what it was before the frame */
+}
+
+/* How the frame is drawn on the area */
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PngBlendOp {
+    Source = 0, /* the frame replaces the area, alpha included */
+    Over   = 1, /* the frame is composed over the area with its alpha */
+}
+
+/* The frame control (fcTL) */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PngFrameControl {
+    pub sequence_number: u32,
+    pub width: u32,
+    pub height: u32,
+    pub x_offset: u32,
+    pub y_offset: u32,
+    pub delay_num: u16, /* the frame is shown delay_num/delay_den seconds */
+    pub delay_den: u16,
+    pub dispose_op: PngDisposeOp,
+    pub blend_op: PngBlendOp,
+}
+
+/* A frame of an animation: the rows of the frame rectangle, in the format of
+ * the image (as PngDecoder::read_image returns them).
+ */
+#[derive(Debug, Clone)]
+pub struct PngFrame {
+    pub control: PngFrameControl,
+    pub data: Vec<u8>,
+}
+
+impl PngFrameControl {
+    /* The fields of the 26 bytes of an fcTL chunk, None if the operations
+     * are unknown.  The frame is not checked against the image.
+     */
+    pub(crate) fn from_chunk(chunk: &[u8]) -> Option<PngFrameControl>
+    {
+        let get_u32 = |i: usize| u32::from_be_bytes([chunk[i], chunk[i + 1], chunk[i + 2], chunk[i + 3]]);
+        let get_u16 = |i: usize| u16::from_be_bytes([chunk[i], chunk[i + 1]]);
+
+        let dispose_op = match chunk[24] {
+            0 => PngDisposeOp::None,
+            1 => PngDisposeOp::Background,
+            2 => PngDisposeOp::Previous,
+            _ => return None,
+        };
+        let blend_op = match chunk[25] {
+            0 => PngBlendOp::Source,
+            1 => PngBlendOp::Over,
+            _ => return None,
+        };
+
+        Some(PngFrameControl {
+            sequence_number: get_u32(0),
+            width: get_u32(4),
+            height: get_u32(8),
+            x_offset: get_u32(12),
+            y_offset: get_u32(16),
+            delay_num: get_u16(20),
+            delay_den: get_u16(22),
+            dispose_op,
+            blend_op,
+        })
+    }
+
+    /* The 26 bytes of the fcTL chunk */
+    pub(crate) fn to_chunk(self) -> [u8; 26]
+    {
+        let mut chunk = [0u8; 26];
+
+        chunk[..4].copy_from_slice(&self.sequence_number.to_be_bytes());
+        chunk[4..8].copy_from_slice(&self.width.to_be_bytes());
+        chunk[8..12].copy_from_slice(&self.height.to_be_bytes());
+        chunk[12..16].copy_from_slice(&self.x_offset.to_be_bytes());
+        chunk[16..20].copy_from_slice(&self.y_offset.to_be_bytes());
+        chunk[20..22].copy_from_slice(&self.delay_num.to_be_bytes());
+        chunk[22..24].copy_from_slice(&self.delay_den.to_be_bytes());
+        chunk[24] = self.dispose_op as u8;
+        chunk[25] = self.blend_op as u8;
+
+        chunk
+    }
+
+    /* The delay in seconds, a denominator of 0 meaning 1/100 s */
+    pub fn delay(&self) -> f64
+    {
+        let den = match self.delay_den {
+            0 => 100,
+            den => den,
+        };
+
+        self.delay_num as f64 / den as f64
+    }
+}
+
+/* Renders the frames of an animation, in order, on an RGBA canvas of 8-bit
+ * channels (not premultiplied) of the size of the image.  The canvas starts
+ * transparent black; each frame is drawn according to its blend_op after the
+ * dispose_op of the previous frame is applied.
+ */
+pub struct ApngCompositor {
+    width: usize,
+    height: usize,
+    color_type: PngColor,
+    bit_depth: u8,
+    palette: Vec<PngPaletteColor>,
+    trans_alpha: Vec<u8>,
+    trans_color: Option<PngColor16>,
+
+    canvas: Vec<u8>,
+    /* The frame drawn last, and the area it covered before if it has to be
+     * restored.
+     */
+    last: Option<PngFrameControl>,
+    saved: Vec<u8>,
+}
+
+impl ApngCompositor {
+    /* A compositor for the frames of the image of 'info' */
+    pub fn new(info: &PngInfo) -> ApngCompositor
+    {
+        let (trans_alpha, trans_color) = match info.trans() {
+            Some((trans_alpha, trans_color)) => match info.color_type == PngColor::TYPE_PALETTE {
+                true => (trans_alpha.to_vec(), None),
+                false => (Vec::new(), Some(trans_color)),
+            },
+            None => (Vec::new(), None),
+        };
+
+        ApngCompositor {
+            width: info.width as usize,
+            height: info.height as usize,
+            color_type: info.color_type,
+            bit_depth: info.bit_depth,
+            palette: info.palette().to_vec(),
+            trans_alpha,
+            trans_color,
+            canvas: vec![0; info.width as usize * info.height as usize * 4],
+            last: None,
+            saved: Vec::new(),
+        }
+    }
+
+    /* The canvas as it is after the last frame: 'height' rows of 'width'
+     * RGBA pixels.
+     */
+    pub fn canvas(&self) -> &[u8]
+    {
+        &self.canvas
+    }
+
+    /* Draw the next frame, returning the canvas to display */
+    pub fn compose(&mut self, frame: &PngFrame) -> Result<&[u8], &'static str>
+    {
+        let control = &frame.control;
+        let (x0, y0) = (control.x_offset as usize, control.y_offset as usize);
+        let (width, height) = (control.width as usize, control.height as usize);
+
+        if width == 0 || height == 0 || x0 + width > self.width || y0 + height > self.height
+        {
+            return Err("APNG: frame outside the canvas");
+        }
+
+        let rowbytes = (width * self.pixel_depth()).div_ceil(8);
+        if frame.data.len() < rowbytes * height
+        {
+            return Err("APNG: not enough frame data");
+        }
+
+        /* A first frame to be restored is restored to the empty canvas */
+        let dispose_op = match (self.last, control.dispose_op) {
+            (None, PngDisposeOp::Previous) => PngDisposeOp::Background,
+            (_, dispose_op) => dispose_op,
+        };
+
+        self.dispose();
+
+        if dispose_op == PngDisposeOp::Previous
+        {
+            self.saved.clear();
+            for y in y0..y0 + height {
+                let start = (y * self.width + x0) * 4;
+                self.saved.extend_from_slice(&self.canvas[start..start + width * 4]);
+            }
+        }
+
+        let mut rgba = vec![0u8; width * 4];
+        for (y, row) in frame.data.chunks(rowbytes).take(height).enumerate() {
+            self.row_to_rgba(row, &mut rgba);
+
+            let start = ((y0 + y) * self.width + x0) * 4;
+            let canvas_row = &mut self.canvas[start..start + width * 4];
+
+            match control.blend_op {
+                PngBlendOp::Source => canvas_row.copy_from_slice(&rgba),
+                PngBlendOp::Over => {
+                    for (dst, src) in canvas_row.chunks_exact_mut(4).zip(rgba.chunks_exact(4)) {
+                        blend_over(dst, src);
+                    }
+                },
+            }
+        }
+
+        self.last = Some(PngFrameControl { dispose_op, ..*control });
+        Ok(&self.canvas)
+    }
+
+    /* Apply the dispose_op of the last frame */
+    fn dispose(&mut self)
+    {
+        let last = match self.last.take() {
+            Some(last) => last,
+            None => return,
+        };
+        if last.dispose_op == PngDisposeOp::None
+        {
+            return;
+        }
+
+        let (x0, y0) = (last.x_offset as usize, last.y_offset as usize);
+        let row_size = last.width as usize * 4;
+
+        for y in 0..last.height as usize {
+            let start = ((y0 + y) * self.width + x0) * 4;
+            let canvas_row = &mut self.canvas[start..start + row_size];
+
+            match last.dispose_op {
+                PngDisposeOp::Previous => canvas_row.copy_from_slice(&self.saved[y * row_size..(y + 1) * row_size]),
+                _ => canvas_row.iter_mut().for_each(|byte| *byte = 0),
+            }
+        }
+    }
+
+    fn pixel_depth(&self) -> usize
+    {
+        let channels = match self.color_type {
+            PngColor::TYPE_RGB => 3,
+            PngColor::TYPE_RGB_ALPHA => 4,
+            PngColor::TYPE_GRAY_ALPHA => 2,
+            _ => 1,
+        };
+
+        channels * self.bit_depth as usize
+    }
+
+    /* Convert a row in the format of the image to RGBA, 16-bit channels are
+     * reduced to their high byte.
+     */
+    fn row_to_rgba(&self, row: &[u8], rgba: &mut [u8])
+    {
+        let bit_depth = self.bit_depth as usize;
+
+        /* The sample 'i' of the row */
+        let sample = |i: usize| -> u16 {
+            match bit_depth {
+                16 => u16::from_be_bytes([row[2 * i], row[2 * i + 1]]),
+                8 => row[i] as u16,
+                _ => {
+                    let bit = i * bit_depth;
+                    let shift = 8 - bit_depth - bit % 8;
+
+                    ((row[bit / 8] >> shift) & ((1u8 << bit_depth) - 1)) as u16
+                },
+            }
+        };
+        let to_8bit = |value: u16| -> u8 {
+            match bit_depth {
+                16 => (value >> 8) as u8,
+                8 => value as u8,
+                _ => (value as u32 * 255 / ((1u32 << bit_depth) - 1)) as u8,
+            }
+        };
+
+        for (x, pixel) in rgba.chunks_exact_mut(4).enumerate() {
+            let rgba_pixel = match self.color_type {
+                PngColor::TYPE_PALETTE => {
+                    let index = sample(x) as usize;
+                    let color = self.palette.get(index).copied().unwrap_or_default();
+                    let alpha = self.trans_alpha.get(index).copied().unwrap_or(255);
+
+                    [color.red, color.green, color.blue, alpha]
+                },
+                PngColor::TYPE_GRAY => {
+                    let gray = sample(x);
+                    let alpha = match self.trans_color {
+                        Some(trans) if trans.gray == gray => 0,
+                        _ => 255,
+                    };
+                    let gray = to_8bit(gray);
+
+                    [gray, gray, gray, alpha]
+                },
+                PngColor::TYPE_GRAY_ALPHA => {
+                    let gray = to_8bit(sample(2 * x));
+
+                    [gray, gray, gray, to_8bit(sample(2 * x + 1))]
+                },
+                PngColor::TYPE_RGB => {
+                    let (r, g, b) = (sample(3 * x), sample(3 * x + 1), sample(3 * x + 2));
+                    let alpha = match self.trans_color {
+                        Some(trans) if (trans.red, trans.green, trans.blue) == (r, g, b) => 0,
+                        _ => 255,
+                    };
+
+                    [to_8bit(r), to_8bit(g), to_8bit(b), alpha]
+                },
+                _ => [to_8bit(sample(4 * x)), to_8bit(sample(4 * x + 1)),
+                      to_8bit(sample(4 * x + 2)), to_8bit(sample(4 * x + 3))],
+            };
+
+            pixel.copy_from_slice(&rgba_pixel);
+        }
+    }
+}
+
+/* Compose the RGBA pixel 'src' over 'dst' (APNG_BLEND_OP_OVER) */
+fn blend_over(dst: &mut [u8], src: &[u8])
+{
+    let src_alpha = src[3] as u32;
+
+    match src_alpha {
+        255 => dst.copy_from_slice(src),
+        0 => {},
+        _ => {
+            /* The alpha of dst seen through src, scaled by 255 */
+            let dst_alpha = dst[3] as u32 * (255 - src_alpha);
+            let alpha = src_alpha * 255 + dst_alpha;
+
+            for c in 0..3 {
+                let value = src[c] as u32 * src_alpha * 255 + dst[c] as u32 * dst_alpha;
+                dst[c] = ((value + alpha / 2) / alpha) as u8;
+            }
+            dst[3] = ((alpha + 127) / 255) as u8;
+        },
+    }
+}
+
+/* A frame given to ApngEncoder, kept until the next frame tells which
+ * dispose_op suits it best.
+ */
+struct PendingFrame {
+    control: PngFrameControl,
+    zdata: Vec<u8>,      /* the compressed frame data */
+    canvas: Vec<u8>,     /* the image once the frame is drawn */
+    base: Vec<u8>,       /* the image the frame is drawn on */
+    in_idat: bool,       /* the frame is the IDAT image */
+}
+
+/* The rectangle of a frame, and its data */
+struct FrameChoice {
+    control: PngFrameControl,
+    zdata: Vec<u8>,
+}
+
+/* Writes an animated PNG from full images of 8-bit RGBA pixels, in the
+ * order they are displayed.  Each frame is reduced to the rectangle which
+ * changes, and the dispose_op of a frame and the blend_op of the next are
+ * chosen to make the next frame data the smallest.  The IDAT image is either
+ * the first frame or, with write_default_image, an image shown only by the
+ * decoders which do not know APNG.
+ */
+pub struct ApngEncoder<W: Write> {
+    writer: ChunkWriter<W>,
+    width: u32,
+    height: u32,
+    animation: PngAnimationControl,
+    compression: PngCompression,
+    next_sequence: u32,
+    frames: u32,         /* frames given so far */
+    wrote_idat: bool,
+    pending: Option<PendingFrame>,
+}
+
+impl<W: Write> ApngEncoder<W> {
+    /* Write the signature, IHDR and acTL of an animation of 'width' by
+     * 'height' RGBA images.  Exactly animation.num_frames frames must be
+     * written.
+     */
+    pub fn new(writer: W, width: u32, height: u32, animation: PngAnimationControl) -> io::Result<ApngEncoder<W>>
+    {
+        if width == 0 || width > PNG_UINT_31_MAX || height == 0 || height > PNG_UINT_31_MAX
+        {
+            return Err(invalid_input("APNG: invalid image size"));
+        }
+
+        if animation.num_frames == 0 || animation.num_frames > PNG_UINT_31_MAX
+        {
+            return Err(invalid_input("APNG: invalid number of frames"));
+        }
+
+        let mut writer = ChunkWriter::new(writer);
+        writer.write_sig(0)?;
+
+        let mut ihdr = [0u8; 13];
+        ihdr[..4].copy_from_slice(&width.to_be_bytes());
+        ihdr[4..8].copy_from_slice(&height.to_be_bytes());
+        ihdr[8] = 8;
+        ihdr[9] = PngColor::TYPE_RGB_ALPHA.bits();
+        writer.write_chunk(*b"IHDR", &ihdr)?;
+
+        let mut actl = [0u8; 8];
+        actl[..4].copy_from_slice(&animation.num_frames.to_be_bytes());
+        actl[4..].copy_from_slice(&animation.num_plays.to_be_bytes());
+        writer.write_chunk(*b"acTL", &actl)?;
+
+        Ok(ApngEncoder {
+            writer,
+            width,
+            height,
+            animation,
+            compression: PngCompression {
+                strategy: PngZlibStrategy::Filtered,
+                ..PngCompression::default()
+            },
+            next_sequence: 0,
+            frames: 0,
+            wrote_idat: false,
+            pending: None,
+        })
+    }
+
+    /* Set the compression of the image data */
+    pub fn set_compression(&mut self, params: PngCompression)
+    {
+        self.compression = params;
+    }
+
+    fn image_size(&self) -> usize
+    {
+        self.width as usize * self.height as usize * 4
+    }
+
+    /* Write the IDAT image, not part of the animation.  This must come
+     * before the frames.
+     */
+    pub fn write_default_image(&mut self, image: &[u8]) -> io::Result<()>
+    {
+        if self.wrote_idat || self.frames > 0
+        {
+            return Err(invalid_input("APNG: the default image must be written first"));
+        }
+
+        if image.len() != self.image_size()
+        {
+            return Err(invalid_input("APNG: wrong image size"));
+        }
+
+        let zdata = self.compress(image, 0, 0, self.width as usize, self.height as usize);
+
+        self.write_data(*b"IDAT", &zdata)?;
+        self.wrote_idat = true;
+        Ok(())
+    }
+
+    /* Write the next frame, shown for delay_num/delay_den seconds.  The frame
+     * before is written now that its dispose_op can be chosen.
+     */
+    pub fn write_frame(&mut self, image: &[u8], delay_num: u16, delay_den: u16) -> io::Result<()>
+    {
+        if image.len() != self.image_size()
+        {
+            return Err(invalid_input("APNG: wrong image size"));
+        }
+
+        if self.frames == self.animation.num_frames
+        {
+            return Err(invalid_input("APNG: more frames than in acTL"));
+        }
+
+        let frame = match self.pending.take() {
+            /* The first frame covers the whole image */
+            None => {
+                let (width, height) = (self.width as usize, self.height as usize);
+
+                PendingFrame {
+                    control: frame_control(0, 0, width, height, PngBlendOp::Source),
+                    zdata: self.compress(image, 0, 0, width, height),
+                    canvas: image.to_vec(),
+                    base: vec![0; image.len()],
+                    in_idat: !self.wrote_idat,
+                }
+            },
+            Some(mut previous) => {
+                let mut best: Option<(PngDisposeOp, Vec<u8>, FrameChoice)> = None;
+
+                /* Restoring the first frame is clearing it */
+                let dispose_ops = match self.frames {
+                    1 => &[PngDisposeOp::None, PngDisposeOp::Background][..],
+                    _ => &[PngDisposeOp::None, PngDisposeOp::Background, PngDisposeOp::Previous][..],
+                };
+
+                for &dispose_op in dispose_ops {
+                    let base = self.disposed(&previous, dispose_op);
+                    let choice = self.best_frame(&base, image);
+
+                    if best.as_ref().is_none_or(|(_, _, best)| choice.zdata.len() < best.zdata.len())
+                    {
+                        best = Some((dispose_op, base, choice));
+                    }
+                }
+
+                let (dispose_op, base, choice) = best.unwrap();
+
+                previous.control.dispose_op = dispose_op;
+                self.write_frame_chunks(previous)?;
+
+                PendingFrame {
+                    control: choice.control,
+                    zdata: choice.zdata,
+                    canvas: image.to_vec(),
+                    base,
+                    in_idat: false,
+                }
+            },
+        };
+
+        self.pending = Some(PendingFrame {
+            control: PngFrameControl { delay_num, delay_den, ..frame.control },
+            ..frame
+        });
+        self.frames += 1;
+        Ok(())
+    }
+
+    /* Write the last frame and IEND, and give back the writer */
+    pub fn finish(mut self) -> io::Result<W>
+    {
+        if self.frames != self.animation.num_frames
+        {
+            return Err(invalid_input("APNG: fewer frames than in acTL"));
+        }
+
+        if let Some(last) = self.pending.take()
+        {
+            self.write_frame_chunks(last)?;
+        }
+
+        self.writer.write_chunk(*b"IEND", &[])?;
+        self.writer.flush()?;
+        Ok(self.writer.into_inner())
+    }
+
+    /* The image the next frame is drawn on after the 'dispose_op' of
+     * 'previous'.
+     */
+    fn disposed(&self, previous: &PendingFrame, dispose_op: PngDisposeOp) -> Vec<u8>
+    {
+        match dispose_op {
+            PngDisposeOp::None => previous.canvas.clone(),
+            PngDisposeOp::Previous => previous.base.clone(),
+            PngDisposeOp::Background => {
+                let mut base = previous.canvas.clone();
+                let control = &previous.control;
+                let row_size = control.width as usize * 4;
+
+                for y in 0..control.height as usize {
+                    let start = ((control.y_offset as usize + y) * self.width as usize + control.x_offset as usize) * 4;
+                    base[start..start + row_size].iter_mut().for_each(|byte| *byte = 0);
+                }
+
+                base
+            },
+        }
+    }
+
+    /* The smallest frame turning 'base' into 'image': the rectangle of the
+     * changed pixels, either replacing the base or, when the changed pixels
+     * are opaque, drawn over it with the unchanged pixels transparent.
+     */
+    fn best_frame(&self, base: &[u8], image: &[u8]) -> FrameChoice
+    {
+        let width = self.width as usize;
+        let changed = |x: usize, y: usize| {
+            let i = (y * width + x) * 4;
+            base[i..i + 4] != image[i..i + 4]
+        };
+
+        /* The bounding box of the changes, a single pixel if there are none */
+        let (mut x0, mut y0, mut x1, mut y1) = (width, self.height as usize, 0, 0);
+        for y in 0..self.height as usize {
+            for x in 0..width {
+                if changed(x, y)
+                {
+                    x0 = x0.min(x);
+                    y0 = y0.min(y);
+                    x1 = x1.max(x + 1);
+                    y1 = y1.max(y + 1);
+                }
+            }
+        }
+        if x0 >= x1
+        {
+            (x0, y0, x1, y1) = (0, 0, 1, 1);
+        }
+
+        let (frame_width, frame_height) = (x1 - x0, y1 - y0);
+        let mut choice = FrameChoice {
+            control: frame_control(x0, y0, frame_width, frame_height, PngBlendOp::Source),
+            zdata: self.compress(image, x0, y0, frame_width, frame_height),
+        };
+
+        let opaque = (y0..y1).all(|y| (x0..x1).all(|x| !changed(x, y) || image[(y * width + x) * 4 + 3] == 255));
+        if opaque
+        {
+            let mut over = image.to_vec();
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    if !changed(x, y)
+                    {
+                        let i = (y * width + x) * 4;
+                        over[i..i + 4].copy_from_slice(&[0; 4]);
+                    }
+                }
+            }
+
+            let zdata = self.compress(&over, x0, y0, frame_width, frame_height);
+            if zdata.len() < choice.zdata.len()
+            {
+                choice = FrameChoice {
+                    control: frame_control(x0, y0, frame_width, frame_height, PngBlendOp::Over),
+                    zdata,
+                };
+            }
+        }
+
+        choice
+    }
+
+    /* Filter and compress the rectangle of 'image' at x0, y0 */
+    fn compress(&self, image: &[u8], x0: usize, y0: usize, width: usize, height: usize) -> Vec<u8>
+    {
+        let row_size = width * 4;
+        let mut filtered = Vec::with_capacity((row_size + 1) * height);
+        let mut prev_row: &[u8] = &[];
+        let mut try_row = vec![0u8; row_size];
+        let mut tst_row = vec![0u8; row_size];
+
+        for y in y0..y0 + height {
+            let start = (y * self.width as usize + x0) * 4;
+            let row = &image[start..start + row_size];
+            let (filter, filtered_row) = find_filter(PngFilter::all(), PngFilterHeuristic::default(), 4,
+                                                     row, prev_row, &mut try_row, &mut tst_row);
+
+            filtered.push(filter as u8);
+            filtered.extend_from_slice(filtered_row);
+            prev_row = row;
+        }
+
+        zlib_compress(&self.compression, &filtered)
+    }
+
+    /* Write the fcTL of a frame and its data, in IDAT or fdAT chunks */
+    fn write_frame_chunks(&mut self, frame: PendingFrame) -> io::Result<()>
+    {
+        let control = PngFrameControl { sequence_number: self.next_sequence, ..frame.control };
+
+        self.writer.write_chunk(*b"fcTL", &control.to_chunk())?;
+        self.next_sequence += 1;
+
+        match frame.in_idat {
+            true => {
+                self.write_data(*b"IDAT", &frame.zdata)?;
+                self.wrote_idat = true;
+                Ok(())
+            },
+            false => self.write_data(*b"fdAT", &frame.zdata),
+        }
+    }
+
+    /* Write compressed image data, fdAT chunks starting with their sequence
+     * number.
+     */
+    fn write_data(&mut self, chunk_name: [u8; 4], zdata: &[u8]) -> io::Result<()>
+    {
+        for data in zdata.chunks(PNG_UINT_31_MAX as usize - 4) {
+            match &chunk_name {
+                b"fdAT" => {
+                    self.writer.write_chunk_header(chunk_name, data.len() as u32 + 4)?;
+                    self.writer.write_chunk_data(&self.next_sequence.to_be_bytes())?;
+                    self.writer.write_chunk_data(data)?;
+                    self.writer.write_chunk_end()?;
+                    self.next_sequence += 1;
+                },
+                _ => self.writer.write_chunk(chunk_name, data)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/* The control of a frame of the encoder, with the sequence number and delay
+ * filled in later.
+ */
+fn frame_control(x_offset: usize, y_offset: usize, width: usize, height: usize,
+                 blend_op: PngBlendOp) -> PngFrameControl
+{
+    PngFrameControl {
+        sequence_number: 0,
+        width: width as u32,
+        height: height as u32,
+        x_offset: x_offset as u32,
+        y_offset: y_offset as u32,
+        delay_num: 0,
+        delay_den: 0,
+        dispose_op: PngDisposeOp::None,
+        blend_op,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChunkReader, PngDecoder};
+
+    const A: [u8; 4] = [10, 20, 30, 255];
+    const B: [u8; 4] = [200, 0, 0, 255];
+    const C: [u8; 4] = [0, 0, 255, 128];
+    const T: [u8; 4] = [0, 0, 0, 0];
+
+    fn control(sequence_number: u32, x_offset: u32, y_offset: u32, width: u32, height: u32,
+               dispose_op: PngDisposeOp, blend_op: PngBlendOp) -> PngFrameControl
+    {
+        PngFrameControl {
+            sequence_number,
+            width,
+            height,
+            x_offset,
+            y_offset,
+            delay_num: 1,
+            delay_den: 10,
+            dispose_op,
+            blend_op,
+        }
+    }
+
+    /* The rows of 'width' RGBA pixels of 'pixels', with a None filter byte,
+     * compressed.
+     */
+    fn zdata(pixels: &[[u8; 4]], width: usize) -> Vec<u8>
+    {
+        let filtered: Vec<u8> = pixels.chunks(width).flat_map(|row| {
+            let mut filtered = vec![0u8];
+            filtered.extend(row.iter().flatten());
+            filtered
+        }).collect();
+
+        zlib_compress(&PngCompression::default(), &filtered)
+    }
+
+    /* A datastream of a 4 by 3 RGBA image with 'chunks' between IHDR and
+     * IEND.
+     */
+    fn datastream(chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8>
+    {
+        let mut writer = ChunkWriter::new(Vec::new());
+        writer.write_sig(0).unwrap();
+        writer.write_chunk(*b"IHDR", &[0, 0, 0, 4, 0, 0, 0, 3, 8, 6, 0, 0, 0]).unwrap();
+        for (name, data) in chunks {
+            writer.write_chunk(**name, data).unwrap();
+        }
+        writer.write_chunk(*b"IEND", &[]).unwrap();
+
+        writer.into_inner()
+    }
+
+    fn actl(num_frames: u32, num_plays: u32) -> Vec<u8>
+    {
+        [num_frames.to_be_bytes(), num_plays.to_be_bytes()].concat()
+    }
+
+    fn fdat(sequence_number: u32, zdata: &[u8]) -> Vec<u8>
+    {
+        [&sequence_number.to_be_bytes()[..], zdata].concat()
+    }
+
+    /* Three frames: the IDAT image, a 2 by 2 frame in the bottom right
+     * corner with its data split in two fdAT chunks, a single pixel in the
+     * other corner.
+     */
+    fn animation_chunks() -> Vec<(&'static [u8; 4], Vec<u8>)>
+    {
+        let corner = zdata(&[B, C, C, B], 2);
+        let (first_half, second_half) = corner.split_at(corner.len() / 2);
+
+        vec![
+            (b"acTL", actl(3, 2)),
+            (b"fcTL", control(0, 0, 0, 4, 3, PngDisposeOp::None, PngBlendOp::Source).to_chunk().to_vec()),
+            (b"IDAT", zdata(&[A; 12], 4)),
+            (b"fcTL", control(1, 2, 1, 2, 2, PngDisposeOp::Background, PngBlendOp::Over).to_chunk().to_vec()),
+            (b"fdAT", fdat(2, first_half)),
+            (b"fdAT", fdat(3, second_half)),
+            (b"fcTL", control(4, 0, 0, 1, 1, PngDisposeOp::Previous, PngBlendOp::Source).to_chunk().to_vec()),
+            (b"fdAT", fdat(5, &zdata(&[T], 1))),
+        ]
+    }
+
+    /* The frames of 'png', up to the first error */
+    fn frames(png: &[u8]) -> Result<Vec<PngFrame>, &'static str>
+    {
+        let mut decoder = PngDecoder::new(png);
+        let mut frames = Vec::new();
+
+        while let Some(frame) = decoder.next_frame()? {
+            frames.push(frame);
+        }
+
+        Ok(frames)
+    }
+
+    #[test]
+    fn frame_control_chunks()
+    {
+        let control = control(7, 1, 2, 3, 4, PngDisposeOp::Previous, PngBlendOp::Over);
+        let chunk = control.to_chunk();
+
+        assert_eq!(chunk, [0, 0, 0, 7, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 2, 0, 1, 0, 10, 2, 1]);
+        assert_eq!(PngFrameControl::from_chunk(&chunk), Some(control));
+        assert_eq!(control.delay(), 0.1);
+        assert_eq!(PngFrameControl { delay_den: 0, ..control }.delay(), 0.01);
+
+        /* Unknown operations */
+        let mut unknown = chunk;
+        unknown[24] = 3;
+        assert_eq!(PngFrameControl::from_chunk(&unknown), None);
+        unknown = chunk;
+        unknown[25] = 2;
+        assert_eq!(PngFrameControl::from_chunk(&unknown), None);
+    }
+
+    #[test]
+    fn frames_are_read_in_order()
+    {
+        let png = datastream(&animation_chunks());
+        let mut decoder = PngDecoder::new(&png);
+
+        assert_eq!(decoder.animation(), Ok(Some(PngAnimationControl { num_frames: 3, num_plays: 2 })));
+        assert_eq!(decoder.is_default_image_frame(), Ok(true));
+
+        let frames = frames(&png).unwrap();
+        let controls: Vec<_> = frames.iter().map(|frame| frame.control).collect();
+        assert_eq!(controls, [
+            control(0, 0, 0, 4, 3, PngDisposeOp::None, PngBlendOp::Source),
+            control(1, 2, 1, 2, 2, PngDisposeOp::Background, PngBlendOp::Over),
+            control(4, 0, 0, 1, 1, PngDisposeOp::Previous, PngBlendOp::Source),
+        ]);
+        assert_eq!(frames[0].data, [A; 12].concat());
+        assert_eq!(frames[1].data, [B, C, C, B].concat());
+        assert_eq!(frames[2].data, T);
+
+        /* The IDAT image is still read by read_image */
+        assert_eq!(PngDecoder::new(&png).read_image(), Ok([A; 12].concat()));
+    }
+
+    #[test]
+    fn default_image_is_not_a_frame()
+    {
+        let mut chunks = animation_chunks();
+        chunks.remove(1);
+        chunks[0] = (b"acTL", actl(2, 0));
+        for (_, data) in chunks.iter_mut().skip(2) {
+            /* The fcTL and fdAT chunks are numbered from 0 again */
+            let sequence_number = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) - 1;
+            data[..4].copy_from_slice(&sequence_number.to_be_bytes());
+        }
+        let png = datastream(&chunks);
+
+        assert_eq!(PngDecoder::new(&png).is_default_image_frame(), Ok(false));
+        let controls: Vec<_> = frames(&png).unwrap().iter().map(|frame| frame.control.sequence_number).collect();
+        assert_eq!(controls, [0, 3]);
+
+        /* Without acTL, fcTL and fdAT are unknown chunks: not an animation */
+        let png = datastream(&animation_chunks()[1..3]);
+        assert_eq!(PngDecoder::new(&png).animation(), Ok(None));
+        assert_eq!(frames(&png).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn sequence_numbers_follow_each_other()
+    {
+        let with_sequence = |index: usize, sequence_number: u32| {
+            let mut chunks = animation_chunks();
+            chunks[index].1[..4].copy_from_slice(&sequence_number.to_be_bytes());
+            frames(&datastream(&chunks))
+        };
+
+        /* The first fcTL, the second fdAT, the last fcTL */
+        assert_eq!(with_sequence(1, 1).err(), Some("APNG: out of order sequence number"));
+        assert_eq!(with_sequence(5, 2).err(), Some("APNG: out of order sequence number"));
+        assert_eq!(with_sequence(5, 4).err(), Some("APNG: out of order sequence number"));
+        assert_eq!(with_sequence(6, 5).err(), Some("APNG: out of order sequence number"));
+        assert_eq!(with_sequence(6, 4).map(|frames| frames.len()), Ok(3));
+
+        /* A missing fdAT */
+        let mut chunks = animation_chunks();
+        chunks.remove(4);
+        assert_eq!(frames(&datastream(&chunks)).err(), Some("APNG: out of order sequence number"));
+
+        /* A missing frame, an fdAT without its fcTL */
+        let mut chunks = animation_chunks();
+        chunks.truncate(6);
+        assert_eq!(frames(&datastream(&chunks)).err(), Some("APNG: fewer frames than in acTL"));
+        let mut chunks = animation_chunks();
+        chunks[6] = (b"tEXt", b"Comment\0fdAT next".to_vec());
+        chunks[7].1[..4].copy_from_slice(&4u32.to_be_bytes());
+        assert_eq!(frames(&datastream(&chunks)).err(), Some("fdAT: out of place"));
+    }
+
+    #[test]
+    fn frames_stay_in_the_image()
+    {
+        let with_control = |x_offset: u32, y_offset: u32, width: u32, height: u32| {
+            let mut chunks = animation_chunks();
+            chunks[3].1 = control(1, x_offset, y_offset, width, height, PngDisposeOp::None, PngBlendOp::Source)
+                .to_chunk().to_vec();
+            frames(&datastream(&chunks)).err()
+        };
+
+        assert_eq!(with_control(2, 1, 2, 2), None);
+        assert_eq!(with_control(3, 1, 2, 2), Some("fcTL: frame outside the image"));
+        assert_eq!(with_control(2, 2, 2, 2), Some("fcTL: frame outside the image"));
+        assert_eq!(with_control(4, 0, 1, 1), Some("fcTL: frame outside the image"));
+        assert_eq!(with_control(0, 0, 0, 1), Some("fcTL: frame outside the image"));
+        assert_eq!(with_control(u32::MAX, 1, 2, 2), Some("fcTL: frame outside the image"));
+
+        /* The first frame fills the image */
+        let mut chunks = animation_chunks();
+        chunks[1].1 = control(0, 0, 0, 4, 2, PngDisposeOp::None, PngBlendOp::Source).to_chunk().to_vec();
+        assert_eq!(PngDecoder::new(&datastream(&chunks)).animation().err(),
+                   Some("fcTL: the first frame must fill the image"));
+
+        chunks[0].1 = actl(0, 0);
+        assert_eq!(PngDecoder::new(&datastream(&chunks)).animation().err(), Some("acTL: invalid number of frames"));
+    }
+
+    /* A compositor of 3 by 2 RGBA pixels */
+    fn compositor() -> ApngCompositor
+    {
+        let mut info = PngInfo::new();
+        info.width = 3;
+        info.height = 2;
+        info.bit_depth = 8;
+        info.color_type = PngColor::TYPE_RGB_ALPHA;
+
+        ApngCompositor::new(&info)
+    }
+
+    fn frame(control: PngFrameControl, pixels: &[[u8; 4]]) -> PngFrame
+    {
+        PngFrame { control, data: pixels.concat() }
+    }
+
+    fn pixels(canvas: &[u8]) -> Vec<[u8; 4]>
+    {
+        canvas.chunks(4).map(|pixel| [pixel[0], pixel[1], pixel[2], pixel[3]]).collect()
+    }
+
+    /* The canvas after a frame of A with 'first_dispose', B and C drawn over
+     * the bottom right corner with 'dispose', and T in the top left corner.
+     */
+    fn compose(first_dispose: PngDisposeOp, dispose: PngDisposeOp) -> Vec<Vec<[u8; 4]>>
+    {
+        let mut compositor = compositor();
+
+        [
+            frame(control(0, 0, 0, 3, 2, first_dispose, PngBlendOp::Source), &[A; 6]),
+            frame(control(1, 1, 1, 2, 1, dispose, PngBlendOp::Over), &[B, C]),
+            frame(control(2, 0, 0, 1, 1, PngDisposeOp::None, PngBlendOp::Source), &[T]),
+        ].iter().map(|frame| pixels(compositor.compose(frame).unwrap())).collect()
+    }
+
+    #[test]
+    fn dispose_ops()
+    {
+        /* C over A */
+        let over = [5, 10, 143, 255];
+
+        assert_eq!(compose(PngDisposeOp::None, PngDisposeOp::None), [
+            vec![A, A, A, A, A, A],
+            vec![A, A, A, A, B, over],
+            vec![T, A, A, A, B, over],
+        ]);
+        assert_eq!(compose(PngDisposeOp::None, PngDisposeOp::Background)[2], [T, A, A, A, T, T]);
+        assert_eq!(compose(PngDisposeOp::None, PngDisposeOp::Previous)[2], [T, A, A, A, A, A]);
+
+        /* The first frame cleared, restoring it is clearing it too */
+        for &first_dispose in [PngDisposeOp::Background, PngDisposeOp::Previous].iter() {
+            assert_eq!(compose(first_dispose, PngDisposeOp::None), [
+                vec![A, A, A, A, A, A],
+                vec![T, T, T, T, B, C],
+                vec![T, T, T, T, B, C],
+            ]);
+        }
+    }
+
+    #[test]
+    fn blend_ops()
+    {
+        let mut compositor = compositor();
+        let half = [100, 50, 0, 128];
+
+        compositor.compose(&frame(control(0, 0, 0, 3, 2, PngDisposeOp::None, PngBlendOp::Source),
+                                  &[A, C, T, A, C, T])).unwrap();
+
+        /* Source replaces the pixels, alpha included */
+        let canvas = compositor.compose(&frame(control(1, 0, 0, 3, 1, PngDisposeOp::None, PngBlendOp::Source),
+                                               &[half, half, T])).unwrap();
+        assert_eq!(pixels(canvas), [half, half, T, A, C, T]);
+
+        /* Over an opaque pixel, over a translucent one, over nothing; a
+         * transparent pixel changes nothing and an opaque one replaces.
+         */
+        let canvas = compositor.compose(&frame(control(2, 0, 1, 3, 1, PngDisposeOp::None, PngBlendOp::Over),
+                                               &[half, half, half])).unwrap();
+        assert_eq!(pixels(canvas), [half, half, T, [55, 35, 15, 255], [67, 33, 85, 192], half]);
+
+        let canvas = compositor.compose(&frame(control(3, 0, 1, 3, 1, PngDisposeOp::None, PngBlendOp::Over),
+                                               &[T, B, T])).unwrap();
+        assert_eq!(pixels(canvas), [half, half, T, [55, 35, 15, 255], B, half]);
+    }
+
+    #[test]
+    fn frames_at_the_canvas_edge()
+    {
+        let mut compositor = compositor();
+
+        let canvas = compositor.compose(&frame(control(0, 2, 1, 1, 1, PngDisposeOp::None, PngBlendOp::Source),
+                                               &[B])).unwrap();
+        assert_eq!(pixels(canvas), [T, T, T, T, T, B]);
+        let canvas = compositor.compose(&frame(control(1, 0, 1, 3, 1, PngDisposeOp::None, PngBlendOp::Over),
+                                               &[A, T, T])).unwrap();
+        assert_eq!(pixels(canvas), [T, T, T, A, T, B]);
+
+        for &(x_offset, y_offset, width, height) in [(2, 1, 2, 1), (2, 1, 1, 2), (3, 0, 1, 1), (0, 0, 0, 1)].iter() {
+            let frame = frame(control(2, x_offset, y_offset, width, height, PngDisposeOp::None, PngBlendOp::Source),
+                              &[B; 2]);
+            assert_eq!(compositor.compose(&frame).err(), Some("APNG: frame outside the canvas"));
+        }
+
+        let short = frame(control(2, 0, 0, 3, 2, PngDisposeOp::None, PngBlendOp::Source), &[B; 5]);
+        assert_eq!(compositor.compose(&short).err(), Some("APNG: not enough frame data"));
+        assert_eq!(pixels(compositor.canvas()), [T, T, T, A, T, B]);
+    }
+
+    #[test]
+    fn frames_are_converted_to_rgba()
+    {
+        let mut info = PngInfo::new();
+        info.width = 3;
+        info.height = 1;
+        info.bit_depth = 2;
+        info.color_type = PngColor::TYPE_PALETTE;
+        info.set_palette(&[
+            PngPaletteColor { red: 1, green: 2, blue: 3 },
+            PngPaletteColor { red: 4, green: 5, blue: 6 },
+        ]);
+        info.set_trans(&[0], None);
+
+        /* Indexes 1, 0, and 3 outside the palette */
+        let mut compositor = ApngCompositor::new(&info);
+        let canvas = compositor.compose(&PngFrame {
+            control: control(0, 0, 0, 3, 1, PngDisposeOp::None, PngBlendOp::Source),
+            data: vec![0b0100_1100],
+        }).unwrap();
+        assert_eq!(pixels(canvas), [[4, 5, 6, 255], [1, 2, 3, 0], [0, 0, 0, 255]]);
+    }
+
+    /* An image of 16 by 12 pixels, 'pixel' giving each from x and y */
+    fn image(pixel: impl Fn(usize, usize) -> [u8; 4]) -> Vec<u8>
+    {
+        let pixel = &pixel;
+
+        (0..12).flat_map(|y| (0..16).flat_map(move |x| pixel(x, y))).collect()
+    }
+
+    fn background(x: usize, y: usize) -> [u8; 4]
+    {
+        [x as u8 * 16, y as u8 * 20, (x + y) as u8 * 8, 255]
+    }
+
+    /* Frames where a square appears and goes away, some pixels become
+     * translucent, nothing changes, and everything is cleared.
+     */
+    fn animation_frames() -> Vec<Vec<u8>>
+    {
+        let square = |x: usize, y: usize| (3..7).contains(&x) && (2..6).contains(&y);
+        let translucent = |x: usize, y: usize| [x as u8 * 16, y as u8 * 20, 0, 100];
+
+        vec![
+            image(background),
+            image(|x, y| match square(x, y) {
+                true => [255, 0, 0, 255],
+                false => background(x, y),
+            }),
+            image(background),
+            image(|x, y| match x > 12 {
+                true => translucent(x, y),
+                false => background(x, y),
+            }),
+            image(|x, y| match x > 12 {
+                true => translucent(x, y),
+                false => background(x, y),
+            }),
+            image(|_, _| [0; 4]),
+            image(|x, y| match square(x, y) {
+                true => [0, 255, 0, 255],
+                false => [0; 4],
+            }),
+        ]
+    }
+
+    /* Encode 'frames', after 'default_image' if any */
+    fn encode(default_image: Option<&[u8]>, frames: &[Vec<u8>]) -> Vec<u8>
+    {
+        let animation = PngAnimationControl { num_frames: frames.len() as u32, num_plays: 0 };
+        let mut encoder = ApngEncoder::new(Vec::new(), 16, 12, animation).unwrap();
+
+        if let Some(image) = default_image
+        {
+            encoder.write_default_image(image).unwrap();
+        }
+        for (i, frame) in frames.iter().enumerate() {
+            encoder.write_frame(frame, i as u16, 100).unwrap();
+        }
+
+        encoder.finish().unwrap()
+    }
+
+    /* Decode the frames of 'png' and check that the composed canvases are
+     * 'frames', returning the frame controls.
+     */
+    fn check_round_trip(png: &[u8], frames: &[Vec<u8>]) -> Vec<PngFrameControl>
+    {
+        let mut decoder = PngDecoder::new(png);
+        let mut compositor = ApngCompositor::new(decoder.read_info().unwrap());
+        let mut controls = Vec::new();
+
+        assert_eq!(decoder.animation(), Ok(Some(PngAnimationControl { num_frames: frames.len() as u32,
+                                                                      num_plays: 0 })));
+        for (i, expected) in frames.iter().enumerate() {
+            let frame = decoder.next_frame().unwrap().unwrap();
+
+            assert_eq!(compositor.compose(&frame).unwrap(), &expected[..], "frame {}", i);
+            assert_eq!((frame.control.delay_num, frame.control.delay_den), (i as u16, 100));
+            controls.push(frame.control);
+        }
+        assert!(decoder.next_frame().unwrap().is_none());
+
+        /* The fcTL and fdAT chunks are numbered from 0 in their order */
+        let sequence_numbers: Vec<u32> = ChunkReader::new(png)
+            .map(|chunk| chunk.unwrap())
+            .filter(|chunk| &chunk.name == b"fcTL" || &chunk.name == b"fdAT")
+            .map(|chunk| u32::from_be_bytes([chunk.data[0], chunk.data[1], chunk.data[2], chunk.data[3]]))
+            .collect();
+        assert_eq!(sequence_numbers, (0..sequence_numbers.len() as u32).collect::<Vec<_>>());
+
+        controls
+    }
+
+    #[test]
+    fn encoded_frames_compose_back()
+    {
+        let frames = animation_frames();
+        let png = encode(None, &frames);
+        let controls = check_round_trip(&png, &frames);
+
+        /* The first frame is the IDAT image */
+        let mut decoder = PngDecoder::new(&png);
+        assert_eq!(decoder.is_default_image_frame(), Ok(true));
+        assert_eq!(decoder.read_image().as_ref(), Ok(&frames[0]));
+
+        let rectangles: Vec<_> = controls.iter()
+            .map(|control| (control.x_offset, control.y_offset, control.width, control.height))
+            .collect();
+        assert_eq!(rectangles[..3], [(0, 0, 16, 12), (3, 2, 4, 4), (0, 0, 1, 1)]);
+
+        /* The square goes away with its frame: the frame after it is empty */
+        assert_eq!(controls[1].dispose_op, PngDisposeOp::Previous);
+
+        /* Translucent pixels replace the ones below, nothing changes after */
+        assert_eq!(rectangles[3], (13, 0, 3, 12));
+        assert_eq!(controls[3].blend_op, PngBlendOp::Source);
+        assert_eq!(rectangles[4], (0, 0, 1, 1));
+
+        /* The last square is drawn on the cleared image */
+        assert_eq!(rectangles[6], (3, 2, 4, 4));
+    }
+
+    #[test]
+    fn default_image_is_not_animated()
+    {
+        let frames = animation_frames();
+        let default_image = image(|x, y| [0, 0, 0, (x * y) as u8]);
+        let png = encode(Some(&default_image), &frames[1..]);
+
+        let mut decoder = PngDecoder::new(&png);
+        assert_eq!(decoder.is_default_image_frame(), Ok(false));
+        assert_eq!(decoder.read_image().as_ref(), Ok(&default_image));
+
+        let controls = check_round_trip(&png, &frames[1..]);
+        assert_eq!(controls[0].sequence_number, 0);
+        assert_eq!((controls[0].width, controls[0].height), (16, 12));
+
+        /* The IDAT chunks come before the first fcTL */
+        let names: Vec<[u8; 4]> = ChunkReader::new(&png[..]).map(|chunk| chunk.unwrap().name).collect();
+        assert_eq!(names[..4], [*b"IHDR", *b"acTL", *b"IDAT", *b"fcTL"]);
+        assert!(!names[4..].contains(b"IDAT"));
+
+        /* The default image goes first, the frames are all given */
+        let animation = PngAnimationControl { num_frames: 1, num_plays: 0 };
+        let mut encoder = ApngEncoder::new(Vec::new(), 16, 12, animation).unwrap();
+        encoder.write_frame(&frames[0], 1, 1).unwrap();
+        assert!(encoder.write_default_image(&default_image).is_err());
+        assert!(encoder.write_frame(&frames[0], 1, 1).is_err());
+
+        let animation = PngAnimationControl { num_frames: 2, num_plays: 0 };
+        let mut encoder = ApngEncoder::new(Vec::new(), 16, 12, animation).unwrap();
+        encoder.write_frame(&frames[0], 1, 1).unwrap();
+        assert!(encoder.finish().is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use crate::png_info::PngInfo;
+use crate::pread::PngProgressiveEvent;
+use crate::push::PngPushDecoder;
+use futures_core::Stream;
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/*******************************************************************************
+ *
+ *                               Png RUST
+ *
+ ******************************************************************************/
+
+/* A progressive decoder of an AsyncRead (feature "async"): a Stream of the
+ * events of PngPushDecoder, pushed the data as it arrives.  The stream ends
+ * after the End event, or after the first error.  Dropping the decoder, or
+ * taking the reader back with into_inner, cancels the decoding at any point,
+ * in the middle of the image data too: all its state is freed with it.
+ */
+pub struct AsyncDecoder<R> {
+    reader: R,
+    decoder: PngPushDecoder,
+    events: VecDeque<PngProgressiveEvent>,
+    buffer: Box<[u8]>,
+    finished: bool,     /* End or an error returned */
+}
+
+impl<R: AsyncRead + Unpin> AsyncDecoder<R> {
+    pub fn new(reader: R) -> AsyncDecoder<R>
+    {
+        AsyncDecoder::with_capacity(8192, reader)
+    }
+
+    /* A decoder reading at most 'capacity' bytes at a time */
+    pub fn with_capacity(capacity: usize, reader: R) -> AsyncDecoder<R>
+    {
+        AsyncDecoder {
+            reader,
+            decoder: PngPushDecoder::new(),
+            events: VecDeque::new(),
+            buffer: vec![0; capacity.max(1)].into_boxed_slice(),
+            finished: false,
+        }
+    }
+
+    /* The information of the chunks before the image data, after the
+     * Header event.
+     */
+    pub fn info(&self) -> Option<&PngInfo>
+    {
+        self.decoder.info()
+    }
+
+    pub fn get_ref(&self) -> &R
+    {
+        &self.reader
+    }
+
+    /* Stop decoding, the reader is returned where it was left */
+    pub fn into_inner(self) -> R
+    {
+        self.reader
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for AsyncDecoder<R> {
+    type Item = io::Result<PngProgressiveEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>
+    {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(event) = this.events.pop_front()
+            {
+                return Poll::Ready(Some(Ok(event)));
+            }
+
+            if this.finished || this.decoder.is_done()
+            {
+                return Poll::Ready(None);
+            }
+
+            let mut buf = ReadBuf::new(&mut this.buffer);
+            match Pin::new(&mut this.reader).poll_read(cx, &mut buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => {
+                    this.finished = true;
+                    return Poll::Ready(Some(Err(err)));
+                },
+                Poll::Ready(Ok(())) => {},
+            }
+
+            let result = match buf.filled() {
+                [] => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Not enough image data")),
+                data => this.decoder.push(data)
+                    .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, msg)),
+            };
+
+            match result {
+                Ok(events) => this.events.extend(events),
+                Err(err) => {
+                    this.finished = true;
+                    return Poll::Ready(Some(Err(err)));
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::push::PngPushDecoder;
+    use std::fs;
+    use std::path::Path;
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+
+    /* An AsyncRead giving 'data' in pieces of random sizes, returning Pending
+     * at random in between.
+     */
+    struct RandomReader {
+        data: Vec<u8>,
+        pos: usize,
+        seed: u32,
+    }
+
+    impl RandomReader {
+        fn random(&mut self) -> u32
+        {
+            /* xorshift32 */
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 17;
+            self.seed ^= self.seed << 5;
+            self.seed
+        }
+    }
+
+    impl AsyncRead for RandomReader {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>>
+        {
+            let this = self.get_mut();
+
+            if this.random() & 3 == 0
+            {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+
+            let size = (this.random() % 300) as usize + 1;
+            let end = this.data.len().min(this.pos + size.min(buf.remaining()));
+            buf.put_slice(&this.data[this.pos..end]);
+            this.pos = end;
+
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn noop_waker() -> Waker
+    {
+        fn clone(_: *const ()) -> RawWaker
+        {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ())
+        {
+        }
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+        unsafe { Waker::from_raw(clone(std::ptr::null())) }
+    }
+
+    /* Poll the decoder until the stream ends, counting the Pending */
+    fn collect<R: AsyncRead + Unpin>(mut decoder: AsyncDecoder<R>) -> (Vec<io::Result<PngProgressiveEvent>>, usize)
+    {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut events = Vec::new();
+        let mut pending = 0;
+
+        loop {
+            match Pin::new(&mut decoder).poll_next(&mut cx) {
+                Poll::Ready(Some(event)) => events.push(event),
+                Poll::Ready(None) => return (events, pending),
+                Poll::Pending => pending += 1,
+            }
+        }
+    }
+
+    /* The events of 'data' pushed a byte at a time, up to the error if any:
+     * the end of the input is one before IEND.
+     */
+    fn push_events(data: &[u8]) -> (Vec<PngProgressiveEvent>, Option<String>)
+    {
+        let mut decoder = PngPushDecoder::new();
+        let mut events = Vec::new();
+
+        for byte in data.chunks(1) {
+            match decoder.push(byte) {
+                Ok(mut pushed) => events.append(&mut pushed),
+                Err(msg) => return (events, Some(msg.to_string())),
+            }
+        }
+
+        match decoder.is_done() {
+            true => (events, None),
+            false => (events, Some("Not enough image data".to_string())),
+        }
+    }
+
+    #[test]
+    fn random_pending_gives_the_events_of_push()
+    {
+        let contrib = Path::new(env!("CARGO_MANIFEST_DIR")).join("../contrib");
+        let mut total_pending = 0;
+
+        for dir in ["pngsuite", "testpngs/crashers"].iter() {
+            let mut paths: Vec<_> = fs::read_dir(contrib.join(dir)).unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension() == Some("png".as_ref()))
+                .collect();
+            paths.sort();
+
+            for (i, path) in paths.iter().enumerate() {
+                let data = fs::read(path).unwrap();
+                let (expected, error) = push_events(&data);
+
+                for &capacity in [1, 7, 4096].iter() {
+                    let reader = RandomReader { data: data.clone(), pos: 0, seed: 0x9e37_79b9 ^ i as u32 };
+                    let (mut events, pending) = collect(AsyncDecoder::with_capacity(capacity, reader));
+                    let case = format!("{} read {} bytes at a time", path.display(), capacity);
+                    total_pending += pending;
+
+                    /* The stream ends after End or the first error */
+                    if let Some(error) = &error
+                    {
+                        let err = events.pop().unwrap().unwrap_err();
+                        assert_eq!(&err.to_string(), error, "{}", case);
+                    }
+
+                    let events: Vec<_> = events.into_iter().map(|event| event.unwrap()).collect();
+                    /* The events of the piece with an error are not returned */
+                    match error {
+                        Some(_) => assert!(expected.starts_with(&events), "{}", case),
+                        None => assert_eq!(events, expected, "{}", case),
+                    }
+                }
+            }
+        }
+
+        assert!(total_pending > 0);
+    }
+
+    #[test]
+    fn cut_datastream_is_an_error()
+    {
+        let data = fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("../contrib/pngsuite/basn2c08.png")).unwrap();
+
+        let reader = RandomReader { data: data[..data.len() / 2].to_vec(), pos: 0, seed: 1 };
+        let (events, _) = collect(AsyncDecoder::new(reader));
+        let err = events.last().unwrap().as_ref().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
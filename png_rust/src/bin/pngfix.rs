@@ -0,0 +1,738 @@
+/* pngfix-rs: check PNG files chunk by chunk and, with --out, write a repaired
+ * copy.  The chunks are read with ChunkReader and written with ChunkWriter, so
+ * every CRC of the output is computed again.  The problems are reported per
+ * chunk, as text or as JSON with --json:
+ *
+ *   crc       the CRC does not match the chunk: with --fix-crc the chunk is
+ *             kept, otherwise an ancillary chunk is dropped and a critical
+ *             chunk makes the file unrepairable.
+ *   window    the zlib stream declares a larger window than its data needs:
+ *             with --optimize the CMF byte is rewritten as libpng does when
+ *             writing (optimize_cmf).
+ *   zlib      the zlib stream is invalid, or inflates to more than the image
+ *             size of IHDR (IDAT) or PNG_USER_CHUNK_MALLOC_MAX (iCCP, zTXt and
+ *             iTXt): it is not changed.
+ *   truncated the datastream ends, or is unreadable, before IEND: the chunks
+ *             read are written followed by an IEND chunk.
+ *   trailing  data follows IEND: it is not written.
+ *
+ * The exit status is 0 when no problem is left in the output, 1 when some
+ * problems are not fixed and 2 when a file cannot be read or repaired.
+ */
+use png_rust::deflate::optimal_window_bits;
+use png_rust::interlace::{pass_cols, pass_rows, PNG_INTERLACE_ADAM7_PASSES};
+use png_rust::{ChunkReader, ChunkWriter, PngChunk, PNG_USER_CHUNK_MALLOC_MAX};
+use crc::crc32;
+use miniz_oxide::inflate::{decompress_to_vec_zlib_with_limit, TINFLStatus};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::process;
+
+const USAGE: &str = "\
+Usage: pngfix-rs [options] file...
+  --fix-crc     keep the chunks with a bad CRC, with the CRC computed again
+  --optimize    reduce the window of the zlib streams to what the data needs
+  --out=FILE    write the repaired datastream to FILE (a single input only)
+  --json        write the report as JSON
+";
+
+struct Options {
+    fix_crc: bool,
+    optimize: bool,
+    out: Option<String>,
+    json: bool,
+}
+
+/* A problem found, 'action' is what was done to the output to fix it */
+struct Problem {
+    kind: &'static str,
+    message: String,
+    action: Option<&'static str>,
+}
+
+struct ChunkReport {
+    name: [u8; 4],
+    offset: u64,
+    length: usize,
+    crc_ok: bool,
+    problems: Vec<Problem>,
+}
+
+struct FileReport {
+    path: String,
+    png_length: u64,        /* up to the end of IEND, or of the last chunk read */
+    trailing: u64,          /* bytes after IEND */
+    fatal: bool,            /* the file cannot be read or repaired */
+    written: bool,
+    problems: Vec<Problem>,
+    chunks: Vec<ChunkReport>,
+}
+
+impl FileReport {
+    fn all_problems(&self) -> impl Iterator<Item = &Problem>
+    {
+        self.problems.iter().chain(self.chunks.iter().flat_map(|c| c.problems.iter()))
+    }
+
+    fn status(&self) -> &'static str
+    {
+        if self.fatal
+        {
+            "error"
+        }
+        else if self.all_problems().next().is_none()
+        {
+            "ok"
+        }
+        else if self.all_problems().all(|p| p.action.is_some())
+        {
+            "fixed"
+        }
+        else
+        {
+            "problems"
+        }
+    }
+}
+
+fn main()
+{
+    let mut options = Options { fix_crc: false, optimize: false, out: None, json: false };
+    let mut paths = Vec::new();
+
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--fix-crc" => options.fix_crc = true,
+            "--optimize" => options.optimize = true,
+            "--json" => options.json = true,
+            "--help" | "-h" => {
+                print!("{}", USAGE);
+                return;
+            },
+            _ if arg.starts_with("--out=") => options.out = Some(arg["--out=".len()..].to_string()),
+            _ if arg.starts_with("--") => usage(),
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.is_empty() || (options.out.is_some() && paths.len() > 1)
+    {
+        usage();
+    }
+
+    let reports: Vec<FileReport> = paths.iter().map(|path| fix_file(path, &options)).collect();
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let result = match options.json {
+        true => write_json(&mut out, &reports),
+        false => write_text(&mut out, &reports),
+    };
+    if let Err(err) = result.and_then(|_| out.flush())
+    {
+        eprintln!("pngfix-rs: {}", err);
+        process::exit(2);
+    }
+
+    let status = reports.iter().map(|report| match report.status() {
+        "error" => 2,
+        "problems" => 1,
+        _ => 0,
+    }).max().unwrap_or(0);
+    process::exit(status);
+}
+
+fn usage() -> !
+{
+    eprint!("{}", USAGE);
+    process::exit(2);
+}
+
+fn fix_file(path: &str, options: &Options) -> FileReport
+{
+    let mut report = FileReport {
+        path: path.to_string(),
+        png_length: 0,
+        trailing: 0,
+        fatal: false,
+        written: false,
+        problems: Vec::new(),
+        chunks: Vec::new(),
+    };
+
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            report.fatal = true;
+            report.problems.push(Problem { kind: "read", message: err.to_string(), action: None });
+            return report;
+        },
+    };
+
+    let mut chunks = Vec::new();
+    let mut reader = ChunkReader::new(BufReader::new(file));
+    let mut end_error = None;
+
+    for chunk in &mut reader {
+        match chunk {
+            Ok(chunk) => chunks.push(chunk),
+            Err(err) => {
+                end_error = Some(err);
+                break;
+            },
+        }
+    }
+    report.png_length = reader.offset();
+
+    let has_iend = chunks.last().is_some_and(|c| &c.name == b"IEND");
+    match end_error {
+        /* Nothing to repair without the signature */
+        Some(err) if report.png_length == 0 => {
+            report.fatal = true;
+            report.problems.push(Problem { kind: "read", message: err.to_string(), action: None });
+            return report;
+        },
+        Some(ref err) if err.kind() != io::ErrorKind::InvalidData && err.kind() != io::ErrorKind::UnexpectedEof => {
+            report.fatal = true;
+            report.problems.push(Problem { kind: "read", message: err.to_string(), action: None });
+            return report;
+        },
+        _ => {},
+    }
+
+    report.chunks = chunks.iter().map(|chunk| ChunkReport {
+        name: chunk.name,
+        offset: chunk.offset,
+        length: chunk.data.len(),
+        crc_ok: chunk.crc_ok,
+        problems: Vec::new(),
+    }).collect();
+
+    /* A critical chunk must not be guessed at */
+    let bad_critical = chunks.iter().any(|c| !c.crc_ok && c.is_critical());
+    report.fatal = bad_critical && !options.fix_crc;
+    let write = options.out.is_some() && !report.fatal;
+
+    /* CRCs */
+    let mut keep = vec![true; chunks.len()];
+    for (i, chunk) in chunks.iter().enumerate() {
+        if !chunk.crc_ok
+        {
+            let action = match (write, options.fix_crc) {
+                (false, _) => None,
+                (true, true) => Some("CRC recomputed"),
+                (true, false) => {
+                    keep[i] = false;
+                    Some("chunk dropped")
+                },
+            };
+            report.chunks[i].problems.push(Problem {
+                kind: "crc",
+                message: format!("CRC {:08x}, expected {:08x}", chunk.crc, chunk_crc(chunk)),
+                action,
+            });
+        }
+    }
+
+    /* The zlib streams: the IDAT chunks together, the others one by one */
+    let idats: Vec<usize> = (0..chunks.len()).filter(|&i| &chunks[i].name == b"IDAT" && keep[i]).collect();
+    if !idats.is_empty()
+    {
+        let streams: Vec<(usize, usize)> = idats.iter().map(|&i| (i, 0)).collect();
+        let limit = image_data_size(&chunks).unwrap_or(PNG_USER_CHUNK_MALLOC_MAX);
+        check_stream(&mut chunks, &mut report, &streams, limit, write && options.optimize);
+    }
+
+    for i in 0..chunks.len() {
+        if keep[i]
+        {
+            if let Some(start) = zlib_start(&chunks[i])
+            {
+                check_stream(&mut chunks, &mut report, &[(i, start)], PNG_USER_CHUNK_MALLOC_MAX,
+                             write && options.optimize);
+            }
+        }
+    }
+
+    /* The end of the datastream */
+    if !has_iend
+    {
+        let message = match &end_error {
+            Some(err) => format!("{} at offset {}", err, report.png_length),
+            None => "no IEND chunk".to_string(),
+        };
+        report.problems.push(Problem {
+            kind: "truncated",
+            message,
+            action: match write {
+                true => Some("IEND added"),
+                false => None,
+            },
+        });
+    }
+    else
+    {
+        let mut rest = reader.into_inner();
+        match io::copy(&mut rest, &mut io::sink()) {
+            Ok(trailing) => report.trailing = trailing,
+            Err(err) => report.problems.push(Problem { kind: "read", message: err.to_string(), action: None }),
+        }
+
+        if report.trailing > 0
+        {
+            report.problems.push(Problem {
+                kind: "trailing",
+                message: format!("{} bytes after IEND", report.trailing),
+                action: match write {
+                    true => Some("data removed"),
+                    false => None,
+                },
+            });
+        }
+    }
+
+    if write
+    {
+        let path = options.out.as_ref().unwrap();
+        let chunks = chunks.iter().zip(&keep).filter(|(_, &keep)| keep).map(|(c, _)| c);
+
+        match write_png(path, chunks, !has_iend) {
+            Ok(()) => report.written = true,
+            Err(err) => {
+                report.fatal = true;
+                report.problems.push(Problem { kind: "write", message: err.to_string(), action: None });
+            },
+        }
+    }
+
+    report
+}
+
+fn chunk_crc(chunk: &PngChunk) -> u32
+{
+    let crc = crc32::update(0, &crc32::IEEE_TABLE, &chunk.name);
+    crc32::update(crc, &crc32::IEEE_TABLE, &chunk.data)
+}
+
+/* The start of the zlib stream in the data of an iCCP, zTXt or compressed
+ * iTXt chunk.
+ */
+fn zlib_start(chunk: &PngChunk) -> Option<usize>
+{
+    let data = &chunk.data;
+    let keyword_end = data.iter().position(|&b| b == 0)?;
+
+    match &chunk.name {
+        b"iCCP" | b"zTXt" => Some(keyword_end + 2).filter(|&start| start <= data.len()),
+        b"iTXt" => {
+            /* Compression flag and method, language tag, translated keyword */
+            if data.get(keyword_end + 1) != Some(&1)
+            {
+                return None;
+            }
+
+            let mut start = keyword_end + 3;
+            for _ in 0..2 {
+                start += data.get(start..)?.iter().position(|&b| b == 0)? + 1;
+            }
+            Some(start)
+        },
+        _ => None,
+    }
+}
+
+/* The size of the filtered image data IHDR describes, None without a valid
+ * IHDR.
+ */
+fn image_data_size(chunks: &[PngChunk]) -> Option<usize>
+{
+    let ihdr = chunks.first().filter(|chunk| &chunk.name == b"IHDR" && chunk.data.len() == 13)?;
+    let width = u32::from_be_bytes([ihdr.data[0], ihdr.data[1], ihdr.data[2], ihdr.data[3]]);
+    let height = u32::from_be_bytes([ihdr.data[4], ihdr.data[5], ihdr.data[6], ihdr.data[7]]);
+    let channels = match ihdr.data[9] {
+        0 | 3 => 1,
+        2 => 3,
+        4 => 2,
+        6 => 4,
+        _ => return None,
+    };
+    let pixel_bits = channels * ihdr.data[8] as usize;
+    let row_size = |width: u32| (width as usize * pixel_bits).div_ceil(8) + 1;
+
+    match ihdr.data[12] {
+        0 => Some(row_size(width) * height as usize),
+        _ => Some((0..PNG_INTERLACE_ADAM7_PASSES)
+            .filter(|&pass| pass_cols(width, pass) > 0)
+            .map(|pass| row_size(pass_cols(width, pass)) * pass_rows(height, pass) as usize)
+            .sum()),
+    }
+}
+
+/* Inflate the zlib stream made of the data of the chunks 'streams' from the
+ * offsets given, to at most 'limit' bytes, and reduce its window when
+ * 'optimize'.  The problems are reported on the first chunk.
+ */
+fn check_stream(chunks: &mut [PngChunk], report: &mut FileReport, streams: &[(usize, usize)], limit: usize,
+                optimize: bool)
+{
+    let first = streams[0].0;
+    let zdata: Vec<u8> = streams.iter().flat_map(|&(i, start)| chunks[i].data[start..].iter().copied()).collect();
+
+    let data_size = match decompress_to_vec_zlib_with_limit(&zdata, limit) {
+        Ok(data) => data.len(),
+        Err(err) if err.status == TINFLStatus::HasMoreOutput => {
+            report.chunks[first].problems.push(Problem {
+                kind: "zlib",
+                message: format!("zlib stream inflates to more than {} bytes", limit),
+                action: None,
+            });
+            return;
+        },
+        Err(err) => {
+            report.chunks[first].problems.push(Problem {
+                kind: "zlib",
+                message: format!("invalid zlib stream ({:?})", err.status),
+                action: None,
+            });
+            return;
+        },
+    };
+
+    let window_bits = (zdata[0] >> 4) + 8;
+    let optimal = optimal_window_bits(window_bits, data_size);
+    if optimal >= window_bits
+    {
+        return;
+    }
+
+    report.chunks[first].problems.push(Problem {
+        kind: "window",
+        message: format!("window of {} bytes for {} bytes of data, {} are enough",
+                         1u32 << window_bits, data_size, 1u32 << optimal),
+        action: match optimize {
+            true => Some("CMF rewritten"),
+            false => None,
+        },
+    });
+
+    if optimize
+    {
+        /* CMF and FLG, which may be in different IDAT chunks */
+        let mut positions = streams.iter()
+            .flat_map(|&(i, start)| (start..chunks[i].data.len()).map(move |pos| (i, pos)));
+        let (cmf_chunk, cmf_pos) = positions.next().unwrap();
+        let (flg_chunk, flg_pos) = positions.next().unwrap();
+
+        let cmf = ((optimal - 8) << 4) | (zdata[0] & 0x0f);
+        let mut flg = zdata[1] & 0xe0;
+        flg += 31 - ((cmf as u32 * 256 + flg as u32) % 31) as u8;
+
+        chunks[cmf_chunk].data[cmf_pos] = cmf;
+        chunks[flg_chunk].data[flg_pos] = flg;
+    }
+}
+
+fn write_png<'a, I>(path: &str, chunks: I, add_iend: bool) -> io::Result<()>
+    where I: Iterator<Item = &'a PngChunk>
+{
+    let mut writer = ChunkWriter::new(BufWriter::new(File::create(path)?));
+
+    writer.write_sig(0)?;
+    for chunk in chunks {
+        writer.write_chunk(chunk.name, &chunk.data)?;
+    }
+
+    if add_iend
+    {
+        writer.write_chunk(*b"IEND", &[])?;
+    }
+
+    writer.flush()
+}
+
+fn write_text<W: Write>(out: &mut W, reports: &[FileReport]) -> io::Result<()>
+{
+    for report in reports {
+        writeln!(out, "{}: {}", report.path, report.status())?;
+
+        for problem in &report.problems {
+            write_problem(out, "", problem)?;
+        }
+
+        for chunk in &report.chunks {
+            let prefix = format!("{} at {}: ", String::from_utf8_lossy(&chunk.name), chunk.offset);
+
+            for problem in &chunk.problems {
+                write_problem(out, &prefix, problem)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_problem<W: Write>(out: &mut W, prefix: &str, problem: &Problem) -> io::Result<()>
+{
+    match problem.action {
+        Some(action) => writeln!(out, "  {}{}: {} ({})", prefix, problem.kind, problem.message, action),
+        None => writeln!(out, "  {}{}: {}", prefix, problem.kind, problem.message),
+    }
+}
+
+/* An array of one object per file, on a line each */
+fn write_json<W: Write>(out: &mut W, reports: &[FileReport]) -> io::Result<()>
+{
+    writeln!(out, "[")?;
+
+    for (i, report) in reports.iter().enumerate() {
+        write!(out, "{{\"file\":{},\"status\":\"{}\",\"png_length\":{},\"trailing_bytes\":{},\"written\":{},",
+               json_string(&report.path), report.status(), report.png_length, report.trailing, report.written)?;
+        write!(out, "\"problems\":{},\"chunks\":[", json_problems(&report.problems))?;
+
+        for (j, chunk) in report.chunks.iter().enumerate() {
+            if j > 0
+            {
+                write!(out, ",")?;
+            }
+
+            write!(out, "{{\"name\":{},\"offset\":{},\"length\":{},\"crc_ok\":{},\"problems\":{}}}",
+                   json_string(&String::from_utf8_lossy(&chunk.name)), chunk.offset, chunk.length,
+                   chunk.crc_ok, json_problems(&chunk.problems))?;
+        }
+
+        match i + 1 < reports.len() {
+            true => writeln!(out, "]}},")?,
+            false => writeln!(out, "]}}")?,
+        }
+    }
+
+    writeln!(out, "]")
+}
+
+fn json_problems(problems: &[Problem]) -> String
+{
+    let problems: Vec<String> = problems.iter().map(|problem| {
+        let action = match problem.action {
+            Some(action) => json_string(action),
+            None => "null".to_string(),
+        };
+
+        format!("{{\"type\":\"{}\",\"message\":{},\"fixed\":{},\"action\":{}}}",
+                problem.kind, json_string(&problem.message), problem.action.is_some(), action)
+    }).collect();
+
+    format!("[{}]", problems.join(","))
+}
+
+fn json_string(s: &str) -> String
+{
+    let mut json = String::with_capacity(s.len() + 2);
+
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniz_oxide::deflate::compress_to_vec_zlib;
+    use miniz_oxide::inflate::decompress_to_vec_zlib;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn fixture(name: &str) -> String
+    {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../contrib").join(name).to_string_lossy().into_owned()
+    }
+
+    fn temp(name: &str) -> PathBuf
+    {
+        std::env::temp_dir().join(format!("pngfix-rs-{}-{}", process::id(), name))
+    }
+
+    fn options(fix_crc: bool, optimize: bool, out: &Path) -> Options
+    {
+        Options { fix_crc, optimize, out: Some(out.to_string_lossy().into_owned()), json: true }
+    }
+
+    fn json(report: FileReport) -> String
+    {
+        let mut out = Vec::new();
+
+        write_json(&mut out, &[report]).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn read_chunks(path: &Path) -> Vec<PngChunk>
+    {
+        ChunkReader::new(&fs::read(path).unwrap()[..]).map(|chunk| chunk.unwrap()).collect()
+    }
+
+    /* The output of pngfix-rs has nothing left to fix */
+    fn check_output(out: &Path)
+    {
+        let report = fix_file(&out.to_string_lossy(), &Options { fix_crc: false, optimize: false, out: None,
+                                                                 json: true });
+
+        assert_eq!(report.status(), "ok", "{}", json(report));
+    }
+
+    #[test]
+    fn crc()
+    {
+        let path = fixture("testpngs/crashers/badcrc.png");
+        let out = temp("crc.png");
+        let chunks = "\"chunks\":[\
+            {\"name\":\"IHDR\",\"offset\":8,\"length\":13,\"crc_ok\":true,\"problems\":[]},\
+            {\"name\":\"IDAT\",\"offset\":33,\"length\":10,\"crc_ok\":false,\"problems\":[\
+            {\"type\":\"crc\",\"message\":\"CRC abadcc32, expected e221bc33\",";
+
+        /* A critical chunk is not repaired without --fix-crc */
+        let report = fix_file(&path, &options(false, false, &out));
+        assert_eq!(json(report), format!("[\n{{\"file\":{},\"status\":\"error\",\"png_length\":67,\
+            \"trailing_bytes\":0,\"written\":false,\"problems\":[],{}\"fixed\":false,\"action\":null}}]}},\
+            {{\"name\":\"IEND\",\"offset\":55,\"length\":0,\"crc_ok\":true,\"problems\":[]}}]}}\n]\n",
+            json_string(&path), chunks));
+        assert!(!out.exists());
+
+        let report = fix_file(&path, &options(true, false, &out));
+        assert_eq!(json(report), format!("[\n{{\"file\":{},\"status\":\"fixed\",\"png_length\":67,\
+            \"trailing_bytes\":0,\"written\":true,\"problems\":[],{}\"fixed\":true,\"action\":\"CRC recomputed\"}}]}},\
+            {{\"name\":\"IEND\",\"offset\":55,\"length\":0,\"crc_ok\":true,\"problems\":[]}}]}}\n]\n",
+            json_string(&path), chunks));
+
+        check_output(&out);
+        let input: Vec<Vec<u8>> = ChunkReader::new(&fs::read(&path).unwrap()[..]).map(|c| c.unwrap().data).collect();
+        let output: Vec<Vec<u8>> = read_chunks(&out).into_iter().map(|c| c.data).collect();
+        assert_eq!(output, input);
+        fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn window()
+    {
+        let path = fixture("visupng/VisualPng.png");
+        let out = temp("window.png");
+
+        let report = fix_file(&path, &options(false, true, &out));
+        let json = json(report);
+        assert!(json.contains("\"status\":\"fixed\""), "{}", json);
+        assert!(json.contains("{\"type\":\"window\",\"message\":\"window of 512 bytes for 160 bytes of data, \
+                               256 are enough\",\"fixed\":true,\"action\":\"CMF rewritten\"}"), "{}", json);
+
+        check_output(&out);
+        let idat = |chunks: Vec<PngChunk>| -> Vec<u8> {
+            chunks.into_iter().filter(|c| &c.name == b"IDAT").flat_map(|c| c.data).collect()
+        };
+        let input = idat(ChunkReader::new(&fs::read(&path).unwrap()[..]).map(|c| c.unwrap()).collect());
+        let output = idat(read_chunks(&out));
+        assert_eq!(output[0] >> 4, (input[0] >> 4) - 1);
+        assert_eq!((output[0] as u32 * 256 + output[1] as u32) % 31, 0);
+        assert_eq!(output[2..], input[2..]);
+        assert_eq!(decompress_to_vec_zlib(&output).unwrap(), decompress_to_vec_zlib(&input).unwrap());
+        fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn truncated()
+    {
+        let path = fixture("testpngs/crashers/huge_IDAT.png");
+        let out = temp("truncated.png");
+
+        let report = fix_file(&path, &options(false, false, &out));
+        assert_eq!(json(report), format!("[\n{{\"file\":{},\"status\":\"fixed\",\"png_length\":33,\
+            \"trailing_bytes\":0,\"written\":true,\"problems\":[{{\"type\":\"truncated\",\
+            \"message\":\"chunk cut at offset 33\",\"fixed\":true,\"action\":\"IEND added\"}}],\"chunks\":[\
+            {{\"name\":\"IHDR\",\"offset\":8,\"length\":13,\"crc_ok\":true,\"problems\":[]}}]}}\n]\n",
+            json_string(&path)));
+
+        check_output(&out);
+        let names: Vec<[u8; 4]> = read_chunks(&out).iter().map(|c| c.name).collect();
+        assert_eq!(names, [*b"IHDR", *b"IEND"]);
+        fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn trailing()
+    {
+        let png = fs::read(fixture("../pngtest.png")).unwrap();
+        let path = temp("trailing-in.png");
+        let out = temp("trailing.png");
+
+        let mut data = png.clone();
+        data.extend_from_slice(b"trailing data");
+        fs::write(&path, &data).unwrap();
+
+        let report = fix_file(&path.to_string_lossy(), &options(false, false, &out));
+        let json = json(report);
+        assert!(json.contains(&format!("\"status\":\"fixed\",\"png_length\":{},\"trailing_bytes\":13,\
+                                        \"written\":true,\"problems\":[{{\"type\":\"trailing\",\
+                                        \"message\":\"13 bytes after IEND\",\"fixed\":true,\
+                                        \"action\":\"data removed\"}}]", png.len())), "{}", json);
+
+        check_output(&out);
+        assert_eq!(fs::read(&out).unwrap(), png);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&out).unwrap();
+    }
+
+    /* An IDAT stream larger than the image IHDR describes is not inflated */
+    #[test]
+    fn idat_larger_than_the_image()
+    {
+        let path = temp("limit-in.png");
+        let out = temp("limit.png");
+
+        let mut data = Vec::new();
+        let mut writer = ChunkWriter::new(&mut data);
+        writer.write_sig(0).unwrap();
+        writer.write_chunk(*b"IHDR", &[0, 0, 0, 3, 0, 0, 0, 2, 8, 0, 0, 0, 0]).unwrap();
+        writer.write_chunk(*b"IDAT", &compress_to_vec_zlib(&[0; 9], 9)).unwrap();
+        writer.write_chunk(*b"IEND", &[]).unwrap();
+        writer.flush().unwrap();
+        fs::write(&path, &data).unwrap();
+
+        let report = fix_file(&path.to_string_lossy(), &options(false, false, &out));
+        let json = json(report);
+        assert!(json.contains("{\"type\":\"zlib\",\"message\":\"zlib stream inflates to more than 8 bytes\",\
+                               \"fixed\":false,\"action\":null}"), "{}", json);
+        assert_eq!(fs::read(&out).unwrap(), data);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn image_data_sizes()
+    {
+        let ihdr = |width: u32, height: u32, depth: u8, color: u8, interlace: u8| {
+            let mut data = Vec::new();
+            data.extend_from_slice(&width.to_be_bytes());
+            data.extend_from_slice(&height.to_be_bytes());
+            data.extend_from_slice(&[depth, color, 0, 0, interlace]);
+            vec![PngChunk { name: *b"IHDR", data, crc: 0, crc_ok: true, offset: 8 }]
+        };
+
+        assert_eq!(image_data_size(&ihdr(3, 2, 8, 0, 0)), Some(8));
+        assert_eq!(image_data_size(&ihdr(3, 2, 16, 6, 0)), Some(2 * 25));
+        assert_eq!(image_data_size(&ihdr(9, 1, 1, 3, 0)), Some(3));
+        /* The first row of passes 1, 4 and 6 */
+        assert_eq!(image_data_size(&ihdr(3, 1, 8, 2, 1)), Some(4 + 4 + 4));
+        assert_eq!(image_data_size(&ihdr(1, 1, 8, 5, 0)), None);
+        assert_eq!(image_data_size(&[]), None);
+    }
+}
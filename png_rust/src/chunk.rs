@@ -0,0 +1,397 @@
+use crate::decoder::check_chunk_header;
+use crate::wutil::PNG_SIGNATURE;
+use crc::crc32;
+use std::io;
+use std::io::Read;
+
+/*******************************************************************************
+ *
+ *                               Png RUST
+ *
+ ******************************************************************************/
+
+/* A chunk as stored in the datastream: 'crc' is the CRC read, 'crc_ok' tells
+ * whether it matches the name and the data.
+ */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PngChunk {
+    pub name: [u8; 4],
+    pub data: Vec<u8>,
+    pub crc: u32,
+    pub crc_ok: bool,
+    pub offset: u64,    /* of the length, from the start of the datastream */
+}
+
+impl PngChunk {
+    /* Bit 5 of the first letter: a decoder must understand a critical chunk */
+    pub fn is_critical(&self) -> bool
+    {
+        self.name[0] & 0x20 == 0
+    }
+
+    /* Bit 5 of the last letter: the chunk stays valid when the critical
+     * chunks are modified.
+     */
+    pub fn is_safe_to_copy(&self) -> bool
+    {
+        self.name[3] & 0x20 != 0
+    }
+}
+
+/* Reads the chunks of a PNG datastream one at a time, from the signature to
+ * IEND, without decoding them.  The length and the name of each chunk are
+ * checked as PngDecoder does, a chunk with a bad CRC is returned with
+ * 'crc_ok' false.  The iteration ends after IEND, at the end of the input or
+ * after an error; what follows IEND is left in the reader.  With a
+ * ChunkWriter, which computes the CRCs again, the chunks are copied, dropped
+ * or replaced as they are read:
+ *
+ *     let mut writer = ChunkWriter::new(output);
+ *     writer.write_sig(0)?;
+ *     for chunk in ChunkReader::new(input) {
+ *         let chunk = chunk?;
+ *         if &chunk.name != b"tEXt"
+ *         {
+ *             writer.write_chunk(chunk.name, &chunk.data)?;
+ *         }
+ *     }
+ */
+pub struct ChunkReader<R: Read> {
+    reader: R,
+    offset: u64,        /* of the next chunk */
+    signature_read: bool,
+    done: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R) -> ChunkReader<R>
+    {
+        ChunkReader {
+            reader,
+            offset: 0,
+            signature_read: false,
+            done: false,
+        }
+    }
+
+    /* The position in the datastream: after IEND, the length of the PNG
+     * datastream.
+     */
+    pub fn offset(&self) -> u64
+    {
+        self.offset
+    }
+
+    pub fn get_ref(&self) -> &R
+    {
+        &self.reader
+    }
+
+    pub fn into_inner(self) -> R
+    {
+        self.reader
+    }
+
+    fn read_signature(&mut self) -> io::Result<()>
+    {
+        let mut signature = [0u8; 8];
+
+        match self.reader.read_exact(&mut signature) {
+            Ok(()) => {},
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Err(invalid_data("Not a PNG file")),
+            Err(err) => return Err(err),
+        }
+
+        if signature != PNG_SIGNATURE
+        {
+            return Err(invalid_data("Not a PNG file"));
+        }
+
+        self.offset = 8;
+        self.signature_read = true;
+        Ok(())
+    }
+
+    /* The next chunk, None at the end of the input */
+    fn read_chunk(&mut self) -> io::Result<Option<PngChunk>>
+    {
+        if !self.signature_read
+        {
+            self.read_signature()?;
+        }
+
+        let mut header = [0u8; 8];
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.reader.read(&mut header[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(err),
+            }
+        }
+
+        match filled {
+            0 => return Ok(None),
+            8 => {},
+            _ => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunk header cut")),
+        }
+
+        let mut name = [0u8; 4];
+        name.copy_from_slice(&header[4..]);
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let length = check_chunk_header(length, &name).map_err(invalid_data)?;
+
+        /* The data is not allocated from the length, which may be wrong */
+        let mut data = Vec::new();
+        self.reader.by_ref().take(length as u64).read_to_end(&mut data)?;
+
+        let mut crc = [0u8; 4];
+        if data.len() < length as usize || self.reader.read_exact(&mut crc).is_err()
+        {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunk cut"));
+        }
+        let crc = u32::from_be_bytes(crc);
+
+        let computed = crc32::update(0, &crc32::IEEE_TABLE, &name);
+        let computed = crc32::update(computed, &crc32::IEEE_TABLE, &data);
+
+        let chunk = PngChunk {
+            name,
+            data,
+            crc,
+            crc_ok: crc == computed,
+            offset: self.offset,
+        };
+
+        self.offset += 12 + length as u64;
+        Ok(Some(chunk))
+    }
+}
+
+impl<R: Read> Iterator for ChunkReader<R> {
+    type Item = io::Result<PngChunk>;
+
+    fn next(&mut self) -> Option<io::Result<PngChunk>>
+    {
+        if self.done
+        {
+            return None;
+        }
+
+        let result = self.read_chunk();
+        self.done = match &result {
+            Ok(Some(chunk)) => &chunk.name == b"IEND",
+            _ => true,
+        };
+
+        result.transpose()
+    }
+}
+
+impl<R: Read> std::iter::FusedIterator for ChunkReader<R> {}
+
+fn invalid_data(msg: &'static str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChunkWriter;
+    use std::fs;
+    use std::path::Path;
+
+    fn pngtest() -> Vec<u8>
+    {
+        fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("../pngtest.png")).unwrap()
+    }
+
+    fn chunks(png: &[u8]) -> Vec<io::Result<PngChunk>>
+    {
+        ChunkReader::new(png).collect()
+    }
+
+    /* A reader of 'data' which records the largest read asked for */
+    struct ReadSizes<'a> {
+        data: &'a [u8],
+        largest_read: usize,
+    }
+
+    impl Read for ReadSizes<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+        {
+            self.largest_read = self.largest_read.max(buf.len());
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn chunks_are_copied_byte_exact()
+    {
+        let contrib = Path::new(env!("CARGO_MANIFEST_DIR")).join("../contrib");
+        let mut paths = vec![Path::new(env!("CARGO_MANIFEST_DIR")).join("../pngtest.png")];
+        for dir in ["pngsuite", "testpngs"].iter() {
+            let mut dir_paths: Vec<_> = fs::read_dir(contrib.join(dir)).unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension() == Some("png".as_ref()))
+                .collect();
+            dir_paths.sort();
+            paths.append(&mut dir_paths);
+        }
+
+        for path in paths {
+            let png = fs::read(&path).unwrap();
+            let mut reader = ChunkReader::new(&png[..]);
+            let mut writer = ChunkWriter::new(Vec::new());
+            let mut pieces = ChunkWriter::new(Vec::new());
+            writer.write_sig(0).unwrap();
+            pieces.write_sig(3).unwrap();
+
+            for chunk in reader.by_ref() {
+                let chunk = chunk.unwrap();
+                assert!(chunk.crc_ok, "{}", path.display());
+                assert_eq!(chunk.offset as usize, writer.get_ref().len(), "{}", path.display());
+
+                writer.write_chunk(chunk.name, &chunk.data).unwrap();
+
+                /* The same in pieces of 7 bytes */
+                pieces.write_chunk_header(chunk.name, chunk.data.len() as u32).unwrap();
+                for piece in chunk.data.chunks(7) {
+                    pieces.write_chunk_data(piece).unwrap();
+                }
+                pieces.write_chunk_end().unwrap();
+            }
+
+            assert_eq!(reader.offset() as usize, png.len(), "{}", path.display());
+            assert_eq!(writer.into_inner(), png, "{}", path.display());
+            assert_eq!(pieces.into_inner(), &png[3..], "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn bad_crc()
+    {
+        let mut png = pngtest();
+        let good: Vec<PngChunk> = chunks(&png).into_iter().map(|chunk| chunk.unwrap()).collect();
+        let idat = good.iter().position(|chunk| &chunk.name == b"IDAT").unwrap();
+
+        /* A byte of the data, then of the CRC */
+        for &at in [8 + 5, 8 + good[idat].data.len() + 2].iter() {
+            png[good[idat].offset as usize + at] ^= 0x10;
+            let read: Vec<PngChunk> = chunks(&png).into_iter().map(|chunk| chunk.unwrap()).collect();
+            png[good[idat].offset as usize + at] ^= 0x10;
+
+            assert_eq!(read.len(), good.len());
+            for (i, (chunk, good)) in read.iter().zip(good.iter()).enumerate() {
+                assert_eq!(chunk.crc_ok, i != idat);
+                assert_eq!((chunk.name, chunk.offset), (good.name, good.offset));
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_datastream()
+    {
+        let png = pngtest();
+        let offsets: Vec<usize> = chunks(&png).into_iter().map(|chunk| chunk.unwrap().offset as usize).collect();
+
+        for end in 0..png.len() {
+            let read = chunks(&png[..end]);
+            let started = offsets.iter().filter(|&&offset| offset < end).count();
+
+            match end {
+                0..=7 => {
+                    assert_eq!(read.len(), 1);
+                    let err = read[0].as_ref().unwrap_err();
+                    assert_eq!((err.kind(), err.to_string()), (io::ErrorKind::InvalidData, "Not a PNG file".into()));
+                },
+                /* At the end of a chunk the iteration ends early */
+                _ if offsets.contains(&end) => {
+                    assert_eq!(read.len(), started, "cut at {}", end);
+                    assert!(read.iter().all(|chunk| chunk.is_ok()), "cut at {}", end);
+                },
+                /* Within the header or the data and CRC */
+                _ => {
+                    assert_eq!(read.len(), started, "cut at {}", end);
+                    assert!(read[..started - 1].iter().all(|chunk| chunk.is_ok()), "cut at {}", end);
+                    assert_eq!(read[started - 1].as_ref().unwrap_err().kind(), io::ErrorKind::UnexpectedEof,
+                               "cut at {}", end);
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn lengths_are_not_trusted()
+    {
+        let png = pngtest();
+        /* The length of the chunk after IHDR */
+        let with_length = |length: u32| {
+            let mut png = png.clone();
+            png[33..33 + 4].copy_from_slice(&length.to_be_bytes());
+            png
+        };
+
+        /* Out of range: rejected before any read of the data */
+        let png_too_long = with_length(0x8000_0000);
+        let mut reader = ChunkReader::new(ReadSizes { data: &png_too_long, largest_read: 0 });
+        assert!(reader.next().unwrap().is_ok());
+        let err = reader.next().unwrap().unwrap_err();
+        assert_eq!((err.kind(), err.to_string()), (io::ErrorKind::InvalidData, "PNG unsigned integer out of range".into()));
+        assert!(reader.next().is_none());
+        assert_eq!(reader.get_ref().data.len(), png.len() - 33 - 8);
+
+        /* The largest length: the data is read as it comes, the buffer is
+         * not allocated from the length.
+         */
+        let png_longest = with_length(0x7fff_ffff);
+        let mut reader = ChunkReader::new(ReadSizes { data: &png_longest, largest_read: 0 });
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(reader.next().unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(reader.get_ref().largest_read < 1 << 20);
+
+        /* A chunk name which is not letters */
+        let mut png_bad_name = png.clone();
+        png_bad_name[33 + 4] = b'1';
+        let err = chunks(&png_bad_name).pop().unwrap().unwrap_err();
+        assert_eq!((err.kind(), err.to_string()), (io::ErrorKind::InvalidData, "invalid chunk type".into()));
+    }
+
+    #[test]
+    fn iteration_ends_after_iend()
+    {
+        let png = pngtest();
+        let data = [&png[..], b"trailing data"].concat();
+        let mut reader = ChunkReader::new(&data[..]);
+
+        let names: Vec<[u8; 4]> = reader.by_ref().map(|chunk| chunk.unwrap().name).collect();
+        assert_eq!(names.last(), Some(b"IEND"));
+        assert!(reader.next().is_none());
+        assert_eq!(reader.offset() as usize, png.len());
+        assert_eq!(reader.into_inner(), b"trailing data");
+    }
+
+    #[test]
+    fn chunk_writer_checks_the_lengths()
+    {
+        let mut writer = ChunkWriter::new(Vec::new());
+
+        assert!(writer.write_chunk_data(b"data").is_err());
+        assert!(writer.write_chunk_end().is_err());
+        assert!(writer.write_chunk_header(*b"tEXt", 0x8000_0000).is_err());
+
+        writer.write_chunk_header(*b"tEXt", 4).unwrap();
+        assert!(writer.write_chunk_header(*b"tEXt", 4).is_err());
+        assert!(writer.write_chunk_data(b"too long").is_err());
+        writer.write_chunk_data(b"da").unwrap();
+        assert!(writer.write_chunk_end().is_err());
+        writer.write_chunk_data(b"ta").unwrap();
+        writer.write_chunk_end().unwrap();
+
+        let mut expected = ChunkWriter::new(Vec::new());
+        expected.write_chunk(*b"tEXt", b"data").unwrap();
+        assert_eq!(writer.into_inner(), expected.into_inner());
+    }
+}
@@ -0,0 +1,966 @@
+use crate::Png;
+use crate::PngMng;
+use crate::trans::PngRowInfo;
+use crate::{PngColor, PngCompressionType, PngFilterType, PngInterlace};
+use crate::{PNG_USER_CHUNK_MALLOC_MAX, PNG_USER_HEIGHT_MAX, PNG_USER_WIDTH_MAX};
+use crate::png_info::{PngInfo, PngColor8, PngColor16, PngPaletteColor, PngXy};
+use crate::png_info::{PngCicp, PngMasteringDisplay, PngContentLightLevel};
+use crate::filter::{PngFilterValue, unfilter_row};
+use crate::interlace::{pass_cols, pass_rows, row_from_pass_row, col_from_pass_col, move_pixel};
+use crate::interlace::PNG_INTERLACE_ADAM7_PASSES;
+use crate::wutil::{PNG_SIGNATURE, PNG_UINT_31_MAX};
+use crate::apng::{PngAnimationControl, PngFrame, PngFrameControl};
+use crc::crc32;
+use miniz_oxide::inflate::decompress_to_vec_zlib_with_limit;
+use miniz_oxide::inflate::TINFLStatus;
+
+/* A PNG decoder written in Rust only, reading a whole datastream held in
+ * memory.  read_info reads the chunks before the image data into a PngInfo,
+ * read_image the image data, as rows of the PNG format (no transformation),
+ * de-interlaced.
+ *
+ * The chunks used to display the image are read: IHDR, PLTE, tRNS, gAMA,
+ * sRGB, cHRM, iCCP, bKGD and sBIT, and the cICP, mDCv and cLLi chunks of
+ * HDR images; the other ancillary chunks are skipped.
+ * As in libpng a CRC error is an error in a critical chunk, an ancillary
+ * chunk with a wrong CRC is ignored.
+ *
+ * Animated PNG files (APNG) have an acTL chunk before the image data; their
+ * frames are read with next_frame, each described by an fcTL chunk and
+ * stored in the IDAT chunks or in the fdAT chunks following the fcTL.  The
+ * sequence numbers of fcTL and fdAT must follow each other from 0.
+ *
+ * The PNG datastreams embedded in a MNG file have no signature and may use
+ * the MNG features: intrapixel differencing (filter method 64), undone in
+ * read_image, and an empty PLTE standing for the global palette of the MNG.
+ */
+pub struct PngDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,         /* start of the next chunk */
+    info: PngInfo,
+    have_ihdr: bool,
+    idat_pos: usize,    /* start of the first IDAT */
+
+    animation: Option<PngAnimationControl>,
+    default_frame: Option<PngFrameControl>, /* fcTL of the IDAT image */
+    next_sequence: u32,
+    frames_read: u32,
+
+    embedded: bool,     /* in a MNG datastream, without signature */
+    mng_features: PngMng,
+    global_palette: Vec<PngPaletteColor>,
+}
+
+/* The sRGB endpoints, to tell whether a cHRM chunk describes sRGB */
+const PNG_SRGB_XY: PngXy = PngXy {
+    red_x: 64000, red_y: 33000,
+    green_x: 30000, green_y: 60000,
+    blue_x: 15000, blue_y: 6000,
+    white_x: 31270, white_y: 32900,
+};
+
+pub(crate) fn get_u32(buf: &[u8]) -> u32
+{
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+pub(crate) fn get_u16(buf: &[u8]) -> u16
+{
+    u16::from_be_bytes([buf[0], buf[1]])
+}
+
+/* Check the length and the name of a chunk header, return the length */
+pub(crate) fn check_chunk_header(length: u32, name: &[u8; 4]) -> Result<u32, &'static str>
+{
+    if length > PNG_UINT_31_MAX
+    {
+        return Err("PNG unsigned integer out of range");
+    }
+
+    if !name.iter().all(|c| c.is_ascii_alphabetic())
+    {
+        return Err("invalid chunk type");
+    }
+
+    Ok(length)
+}
+
+/* Read the chunk at 'pos' in 'data', its name and data, and move 'pos' past
+ * it.  The CRC is checked: a CRC error is an error in a critical chunk, the
+ * data of an ancillary chunk with a wrong CRC is returned empty.
+ */
+pub(crate) fn read_chunk<'a>(data: &'a [u8], pos: &mut usize) -> Result<([u8; 4], &'a [u8]), &'static str>
+{
+    if data.len() - *pos < 12
+    {
+        return Err("Read Error");
+    }
+
+    let mut name = [0u8; 4];
+    name.copy_from_slice(&data[*pos + 4..*pos + 8]);
+    let length = check_chunk_header(get_u32(&data[*pos..]), &name)? as usize;
+
+    let start = *pos + 8;
+    if data.len() - start < length + 4
+    {
+        return Err("Read Error");
+    }
+
+    let chunk = &data[start..start + length];
+    let crc = crc32::update(0, &crc32::IEEE_TABLE, &name);
+    let crc = crc32::update(crc, &crc32::IEEE_TABLE, chunk);
+    let crc_ok = crc == get_u32(&data[start + length..]);
+
+    *pos = start + length + 4;
+
+    /* Critical chunks have the bit 5 of the first letter clear */
+    match (crc_ok, name[0] & 0x20 == 0) {
+        (true, _) => Ok((name, chunk)),
+        (false, true) => Err("CRC error"),
+        (false, false) => Ok((name, &[])),
+    }
+}
+
+/* Whether the decoder reads the chunk 'name': the critical chunks and the
+ * ancillary chunks handled in read_info and next_frame.  The others can be
+ * skipped when the datastream is read, see PngReader.
+ */
+pub(crate) fn uses_chunk(name: &[u8; 4]) -> bool
+{
+    name[0] & 0x20 == 0 || matches!(name, b"tRNS" | b"gAMA" | b"sRGB" | b"cHRM" | b"iCCP" | b"bKGD" |
+                                          b"sBIT" | b"cICP" | b"mDCv" | b"cLLi" | b"acTL" | b"fcTL" | b"fdAT")
+}
+
+impl<'a> PngDecoder<'a> {
+    pub fn new(data: &'a [u8]) -> PngDecoder<'a>
+    {
+        PngDecoder {
+            data,
+            pos: 0,
+            info: PngInfo::new(),
+            have_ihdr: false,
+            idat_pos: 0,
+            animation: None,
+            default_frame: None,
+            next_sequence: 0,
+            frames_read: 0,
+            embedded: false,
+            mng_features: PngMng::empty(),
+            global_palette: Vec::new(),
+        }
+    }
+
+    /* A decoder of a PNG datastream embedded in a MNG one: 'data' starts with
+     * the IHDR chunk.  'global_palette' is used for an empty PLTE.
+     */
+    pub(crate) fn new_embedded(data: &'a [u8], mng_features: PngMng,
+                               global_palette: &[PngPaletteColor]) -> PngDecoder<'a>
+    {
+        PngDecoder {
+            embedded: true,
+            mng_features,
+            global_palette: global_palette.to_vec(),
+            ..PngDecoder::new(data)
+        }
+    }
+
+    pub fn info(&self) -> &PngInfo
+    {
+        &self.info
+    }
+
+    pub fn into_info(self) -> PngInfo
+    {
+        self.info
+    }
+
+    /* Whether the cHRM chunk, if any, gives the sRGB endpoints (within the
+     * tolerance of libpng).  A cICP chunk takes precedence: the primaries are
+     * those of sRGB if they are the BT.709 ones.
+     */
+    pub fn chromaticities_match_srgb(&self) -> bool
+    {
+        if let Some(cicp) = self.info.cicp()
+        {
+            return cicp.colour_primaries == 1;
+        }
+
+        match self.info.chromaticities() {
+            None => true,
+            Some(xy) => {
+                let close = |a: i32, b: i32| (a - b).abs() <= 100;
+
+                close(xy.red_x, PNG_SRGB_XY.red_x) && close(xy.red_y, PNG_SRGB_XY.red_y) &&
+                    close(xy.green_x, PNG_SRGB_XY.green_x) && close(xy.green_y, PNG_SRGB_XY.green_y) &&
+                    close(xy.blue_x, PNG_SRGB_XY.blue_x) && close(xy.blue_y, PNG_SRGB_XY.blue_y) &&
+                    close(xy.white_x, PNG_SRGB_XY.white_x) && close(xy.white_y, PNG_SRGB_XY.white_y)
+            },
+        }
+    }
+
+    /* The next chunk, its name and data.  The CRC is checked. */
+    fn next_chunk(&mut self) -> Result<([u8; 4], &'a [u8]), &'static str>
+    {
+        read_chunk(self.data, &mut self.pos)
+    }
+
+    /* Read the signature and the chunks up to the first IDAT */
+    pub fn read_info(&mut self) -> Result<&PngInfo, &'static str>
+    {
+        if self.have_ihdr
+        {
+            return Ok(&self.info);
+        }
+
+        if !self.embedded
+        {
+            self.check_signature(self.data)?;
+            self.pos = 8;
+        }
+
+        loop {
+            let chunk_start = self.pos;
+            let (name, chunk) = self.next_chunk()?;
+
+            if self.handle_info_chunk(&name, chunk)?
+            {
+                self.pos = chunk_start;
+                self.idat_pos = chunk_start;
+                return Ok(&self.info);
+            }
+        }
+    }
+
+    /* Check the PNG signature at the start of 'data' */
+    pub(crate) fn check_signature(&mut self, data: &[u8]) -> Result<(), &'static str>
+    {
+        if data.len() < 8 || data[..8] != PNG_SIGNATURE
+        {
+            return match data.len() >= 4 && data[..4] == PNG_SIGNATURE[..4] {
+                true => Err("PNG file corrupted by ASCII conversion"),
+                false => Err("Not a PNG file"),
+            };
+        }
+
+        self.info.signature.copy_from_slice(&data[..8]);
+        Ok(())
+    }
+
+    /* Handle a chunk before the image data, true for the first IDAT: the
+     * information is then complete.
+     */
+    pub(crate) fn handle_info_chunk(&mut self, name: &[u8; 4], chunk: &[u8]) -> Result<bool, &'static str>
+    {
+        if !self.have_ihdr && name != b"IHDR"
+        {
+            return Err("Missing IHDR before other chunks");
+        }
+
+        match name {
+            b"IHDR" => self.handle_ihdr(chunk)?,
+            b"PLTE" => self.handle_plte(chunk)?,
+            b"IDAT" => {
+                if self.info.color_type == PngColor::TYPE_PALETTE &&
+                    self.info.palette().is_empty()
+                {
+                    return Err("Missing PLTE before IDAT");
+                }
+                return Ok(true);
+            },
+            b"IEND" => return Err("Not enough image data"),
+            b"tRNS" => self.handle_trns(chunk),
+            b"gAMA" => self.handle_gama(chunk),
+            b"sRGB" => self.handle_srgb(chunk),
+            b"cHRM" => self.handle_chrm(chunk),
+            b"cICP" => self.handle_cicp(chunk),
+            b"mDCv" => self.handle_mdcv(chunk),
+            b"cLLi" => self.handle_clli(chunk),
+            b"iCCP" => self.handle_iccp(chunk),
+            b"bKGD" => self.handle_bkgd(chunk),
+            b"sBIT" => self.handle_sbit(chunk),
+            b"acTL" => self.handle_actl(chunk)?,
+            b"fcTL" => self.handle_default_fctl(chunk)?,
+            b"fdAT" => return Err("fdAT: out of place"),
+            _ => {
+                if name[0] & 0x20 == 0
+                {
+                    return Err("unknown critical chunk");
+                }
+            },
+        }
+
+        Ok(false)
+    }
+
+    fn handle_ihdr(&mut self, chunk: &[u8]) -> Result<(), &'static str>
+    {
+        if self.have_ihdr
+        {
+            return Err("IHDR: out of place");
+        }
+
+        if chunk.len() != 13
+        {
+            return Err("IHDR: invalid");
+        }
+
+        let width = get_u32(chunk);
+        let height = get_u32(&chunk[4..]);
+        let bit_depth = chunk[8];
+        let color_type = chunk[9];
+
+        if width == 0 || width > i32::MAX as u32 || width > PNG_USER_WIDTH_MAX
+        {
+            return Err("Invalid image width in IHDR");
+        }
+
+        if height == 0 || height > i32::MAX as u32 || height > PNG_USER_HEIGHT_MAX
+        {
+            return Err("Invalid image height in IHDR");
+        }
+
+        let depth_ok = match color_type {
+            0 => [1, 2, 4, 8, 16].contains(&bit_depth),
+            3 => [1, 2, 4, 8].contains(&bit_depth),
+            2 | 4 | 6 => bit_depth == 8 || bit_depth == 16,
+            _ => return Err("Invalid color type in IHDR"),
+        };
+        if !depth_ok
+        {
+            return Err("Invalid color type/bit depth combination in IHDR");
+        }
+
+        if chunk[10] != 0
+        {
+            return Err("Unknown compression method in IHDR");
+        }
+
+        /* Filter method 64 only in a MNG datastream, for RGB images */
+        let filter_type = match chunk[11] {
+            0 => PngFilterType::Base,
+            64 if self.embedded && self.mng_features.contains(PngMng::Filter64) &&
+                (color_type == 2 || color_type == 6) => PngFilterType::Differencing,
+            _ => return Err("Unknown filter method in IHDR"),
+        };
+
+        let interlace_type = match chunk[12] {
+            0 => PngInterlace::None,
+            1 => PngInterlace::ADAM7,
+            _ => return Err("Unknown interlace method in IHDR"),
+        };
+
+        let info = &mut self.info;
+        let color_type = PngColor::from_bits_truncate(color_type);
+
+        info.width = width;
+        info.height = height;
+        info.bit_depth = bit_depth;
+        info.color_type = color_type;
+        info.compression_type = PngCompressionType::Base;
+        info.filter_type = filter_type;
+        info.interlace_type = interlace_type;
+        info.channels = match color_type {
+            PngColor::TYPE_RGB => 3,
+            PngColor::TYPE_RGB_ALPHA => 4,
+            PngColor::TYPE_GRAY_ALPHA => 2,
+            _ => 1,
+        };
+        info.pixel_depth = info.channels * bit_depth;
+        info.rowbytes = Png::compute_rowbytes(info.pixel_depth, width as usize);
+
+        self.have_ihdr = true;
+        Ok(())
+    }
+
+    fn handle_plte(&mut self, chunk: &[u8]) -> Result<(), &'static str>
+    {
+        let color_type = self.info.color_type;
+
+        if !self.info.palette().is_empty()
+        {
+            return Err("PLTE: duplicate");
+        }
+
+        /* A palette is only a suggestion for the other images */
+        if !color_type.contains(PngColor::MASK_COLOR)
+        {
+            return Ok(());
+        }
+
+        let max_palette = match color_type == PngColor::TYPE_PALETTE {
+            true => 1usize << self.info.bit_depth,
+            false => 256,
+        };
+
+        if chunk.is_empty() && self.mng_features.contains(PngMng::EmptyPlte)
+        {
+            return match self.global_palette.is_empty() {
+                true => Err("PLTE: empty without a global palette"),
+                false => {
+                    let palette: Vec<PngPaletteColor> = self.global_palette.iter().copied().take(max_palette).collect();
+                    self.info.set_palette(&palette);
+                    Ok(())
+                },
+            };
+        }
+
+        if !chunk.len().is_multiple_of(3) || chunk.is_empty() || chunk.len() / 3 > 256
+        {
+            return match color_type == PngColor::TYPE_PALETTE {
+                true => Err("PLTE: invalid"),
+                false => Ok(()),
+            };
+        }
+
+        let palette: Vec<PngPaletteColor> = chunk.chunks(3)
+            .take(max_palette)
+            .map(|c| PngPaletteColor { red: c[0], green: c[1], blue: c[2] })
+            .collect();
+
+        self.info.set_palette(&palette);
+        Ok(())
+    }
+
+    fn handle_trns(&mut self, chunk: &[u8])
+    {
+        let info = &mut self.info;
+
+        if info.trans().is_some()
+        {
+            return;
+        }
+
+        match info.color_type {
+            PngColor::TYPE_GRAY if chunk.len() == 2 => {
+                let color = PngColor16 { gray: get_u16(chunk), ..Default::default() };
+                info.set_trans(&[], Some(color));
+            },
+            PngColor::TYPE_RGB if chunk.len() == 6 => {
+                let color = PngColor16 {
+                    red: get_u16(chunk),
+                    green: get_u16(&chunk[2..]),
+                    blue: get_u16(&chunk[4..]),
+                    ..Default::default()
+                };
+                info.set_trans(&[], Some(color));
+            },
+            PngColor::TYPE_PALETTE => {
+                let num_palette = info.palette().len();
+
+                if num_palette > 0 && !chunk.is_empty() && chunk.len() <= num_palette
+                {
+                    info.set_trans(chunk, None);
+                }
+            },
+            _ => {}, /* invalid, or the image has an alpha channel already */
+        }
+    }
+
+    fn handle_gama(&mut self, chunk: &[u8])
+    {
+        if chunk.len() == 4 && self.info.gamma().is_none()
+        {
+            let gamma = get_u32(chunk);
+
+            if gamma > 0 && gamma <= i32::MAX as u32
+            {
+                self.info.set_gamma(gamma);
+            }
+        }
+    }
+
+    fn handle_srgb(&mut self, chunk: &[u8])
+    {
+        if chunk.len() == 1 && chunk[0] <= 3 && self.info.srgb_intent().is_none()
+        {
+            self.info.set_srgb_intent(chunk[0]);
+        }
+    }
+
+    fn handle_chrm(&mut self, chunk: &[u8])
+    {
+        if chunk.len() == 32 && self.info.chromaticities().is_none()
+        {
+            let v = |i: usize| get_u32(&chunk[i * 4..]) as i32;
+
+            self.info.set_chromaticities(PngXy {
+                white_x: v(0), white_y: v(1),
+                red_x: v(2), red_y: v(3),
+                green_x: v(4), green_y: v(5),
+                blue_x: v(6), blue_y: v(7),
+            });
+        }
+    }
+
+    /* cICP must come before PLTE, invalid code points are ignored */
+    fn handle_cicp(&mut self, chunk: &[u8])
+    {
+        if chunk.len() != 4 || chunk[3] > 1 || self.info.cicp().is_some() || !self.info.palette().is_empty()
+        {
+            return;
+        }
+
+        let cicp = PngCicp {
+            colour_primaries: chunk[0],
+            transfer_function: chunk[1],
+            matrix_coefficients: chunk[2],
+            video_full_range: chunk[3] == 1,
+        };
+
+        if cicp.check().is_ok()
+        {
+            self.info.set_cicp(cicp);
+        }
+    }
+
+    fn handle_mdcv(&mut self, chunk: &[u8])
+    {
+        if chunk.len() != 24 || self.info.mastering_display().is_some()
+        {
+            return;
+        }
+
+        let v = |i: usize| get_u16(&chunk[i * 2..]);
+        let display = PngMasteringDisplay {
+            red_x: v(0), red_y: v(1),
+            green_x: v(2), green_y: v(3),
+            blue_x: v(4), blue_y: v(5),
+            white_x: v(6), white_y: v(7),
+            max_luminance: get_u32(&chunk[16..]),
+            min_luminance: get_u32(&chunk[20..]),
+        };
+
+        if display.check().is_ok()
+        {
+            self.info.set_mastering_display(display);
+        }
+    }
+
+    fn handle_clli(&mut self, chunk: &[u8])
+    {
+        if chunk.len() != 8 || self.info.content_light_level().is_some()
+        {
+            return;
+        }
+
+        let level = PngContentLightLevel {
+            max_cll: get_u32(chunk),
+            max_fall: get_u32(&chunk[4..]),
+        };
+
+        if level.check().is_ok()
+        {
+            self.info.set_content_light_level(level);
+        }
+    }
+
+    fn handle_iccp(&mut self, chunk: &[u8])
+    {
+        if self.info.iccp().is_some()
+        {
+            return;
+        }
+
+        let name_len = match chunk.iter().position(|&c| c == 0) {
+            Some(len) if (1..80).contains(&len) => len,
+            _ => return,
+        };
+
+        /* The profile is compressed with deflate (method 0) */
+        if chunk.len() < name_len + 2 || chunk[name_len + 1] != 0
+        {
+            return;
+        }
+
+        if let Ok(profile) = decompress_to_vec_zlib_with_limit(&chunk[name_len + 2..],
+                                                                PNG_USER_CHUNK_MALLOC_MAX)
+        {
+            if profile.len() >= 132
+            {
+                self.info.set_iccp(&chunk[..name_len], &profile);
+            }
+        }
+    }
+
+    fn handle_bkgd(&mut self, chunk: &[u8])
+    {
+        let info = &mut self.info;
+
+        let background = match (info.color_type, chunk.len()) {
+            (PngColor::TYPE_PALETTE, 1) => {
+                if chunk[0] as usize >= info.palette().len()
+                {
+                    return;
+                }
+
+                let entry = info.palette()[chunk[0] as usize];
+                PngColor16 {
+                    index: chunk[0],
+                    red: entry.red as u16,
+                    green: entry.green as u16,
+                    blue: entry.blue as u16,
+                    gray: 0,
+                }
+            },
+            (PngColor::TYPE_GRAY, 2) | (PngColor::TYPE_GRAY_ALPHA, 2) => {
+                let gray = get_u16(chunk);
+                PngColor16 { red: gray, green: gray, blue: gray, gray, index: 0 }
+            },
+            (PngColor::TYPE_RGB, 6) | (PngColor::TYPE_RGB_ALPHA, 6) => PngColor16 {
+                red: get_u16(chunk),
+                green: get_u16(&chunk[2..]),
+                blue: get_u16(&chunk[4..]),
+                ..Default::default()
+            },
+            _ => return,
+        };
+
+        info.set_background(background);
+    }
+
+    fn handle_sbit(&mut self, chunk: &[u8])
+    {
+        let info = &mut self.info;
+        let (channels, max) = match info.color_type {
+            PngColor::TYPE_PALETTE => (3, 8),
+            _ => (info.channels as usize, info.bit_depth),
+        };
+
+        if chunk.len() != channels || chunk.iter().any(|&b| b == 0 || b > max)
+        {
+            return;
+        }
+
+        let mut sig_bit = PngColor8::default();
+        match info.color_type.contains(PngColor::MASK_COLOR) {
+            true => {
+                sig_bit.red = chunk[0];
+                sig_bit.green = chunk[1];
+                sig_bit.blue = chunk[2];
+            },
+            false => sig_bit.gray = chunk[0],
+        }
+        if info.color_type.contains(PngColor::MASK_ALPHA)
+        {
+            sig_bit.alpha = chunk[channels - 1];
+        }
+
+        info.set_sig_bit(sig_bit);
+    }
+
+    fn handle_actl(&mut self, chunk: &[u8]) -> Result<(), &'static str>
+    {
+        if self.animation.is_some()
+        {
+            return Err("acTL: duplicate");
+        }
+
+        if chunk.len() != 8
+        {
+            return Err("acTL: invalid");
+        }
+
+        let num_frames = get_u32(chunk);
+        if num_frames == 0 || num_frames > i32::MAX as u32
+        {
+            return Err("acTL: invalid number of frames");
+        }
+
+        self.animation = Some(PngAnimationControl {
+            num_frames,
+            num_plays: get_u32(&chunk[4..]),
+        });
+        Ok(())
+    }
+
+    /* An fcTL before the image data: the IDAT image is the first frame */
+    fn handle_default_fctl(&mut self, chunk: &[u8]) -> Result<(), &'static str>
+    {
+        /* Without acTL the file is not animated, fcTL is just unknown */
+        if self.animation.is_none()
+        {
+            return Ok(());
+        }
+
+        if self.default_frame.is_some()
+        {
+            return Err("fcTL: duplicate");
+        }
+
+        let control = self.frame_control(chunk)?;
+        if control.x_offset != 0 || control.y_offset != 0 ||
+            control.width != self.info.width || control.height != self.info.height
+        {
+            return Err("fcTL: the first frame must fill the image");
+        }
+
+        self.default_frame = Some(control);
+        Ok(())
+    }
+
+    fn check_sequence(&mut self, chunk: &[u8]) -> Result<(), &'static str>
+    {
+        if chunk.len() < 4 || get_u32(chunk) != self.next_sequence
+        {
+            return Err("APNG: out of order sequence number");
+        }
+
+        self.next_sequence += 1;
+        Ok(())
+    }
+
+    /* Read an fcTL chunk, checking it against the image */
+    fn frame_control(&mut self, chunk: &[u8]) -> Result<PngFrameControl, &'static str>
+    {
+        if chunk.len() != 26
+        {
+            return Err("fcTL: invalid");
+        }
+
+        self.check_sequence(chunk)?;
+
+        let control = PngFrameControl::from_chunk(chunk).ok_or("fcTL: invalid")?;
+        let inside = |offset: u32, size: u32, image_size: u32| {
+            size > 0 && offset < image_size && size <= image_size - offset
+        };
+
+        if !inside(control.x_offset, control.width, self.info.width) ||
+            !inside(control.y_offset, control.height, self.info.height)
+        {
+            return Err("fcTL: frame outside the image");
+        }
+
+        Ok(control)
+    }
+
+    /* The animation of an APNG file, None for a still image */
+    pub fn animation(&mut self) -> Result<Option<PngAnimationControl>, &'static str>
+    {
+        self.read_info()?;
+
+        Ok(self.animation)
+    }
+
+    /* Whether the IDAT image is the first frame of the animation, and not
+     * only an image for the decoders which do not know APNG.
+     */
+    pub fn is_default_image_frame(&mut self) -> Result<bool, &'static str>
+    {
+        self.read_info()?;
+
+        Ok(self.default_frame.is_some())
+    }
+
+    /* Read the next frame of an APNG file, None after the last frame or for
+     * a still image.  This may be called before or after read_image: the
+     * first frame is then the IDAT image if it is part of the animation.
+     */
+    pub fn next_frame(&mut self) -> Result<Option<PngFrame>, &'static str>
+    {
+        self.read_info()?;
+
+        let animation = match self.animation {
+            Some(animation) => animation,
+            None => return Ok(None),
+        };
+
+        if self.frames_read == animation.num_frames
+        {
+            return Ok(None);
+        }
+
+        /* Move past the IDAT image, decoding it if it is the first frame */
+        if self.frames_read == 0
+        {
+            self.pos = self.idat_pos;
+            let zdata = self.read_data_chunks(b"IDAT")?;
+
+            if let Some(control) = self.default_frame
+            {
+                let data = self.unfilter_image(&zdata, control.width, control.height)?;
+
+                self.frames_read = 1;
+                return Ok(Some(PngFrame { control, data }));
+            }
+        }
+
+        loop {
+            let (name, chunk) = self.next_chunk()?;
+
+            match &name {
+                b"fcTL" => {
+                    let control = self.frame_control(chunk)?;
+                    let zdata = self.read_data_chunks(b"fdAT")?;
+
+                    if zdata.is_empty()
+                    {
+                        return Err("fdAT: missing frame data");
+                    }
+
+                    let data = self.unfilter_image(&zdata, control.width, control.height)?;
+
+                    self.frames_read += 1;
+                    return Ok(Some(PngFrame { control, data }));
+                },
+                b"fdAT" => return Err("fdAT: out of place"),
+                b"IEND" => return Err("APNG: fewer frames than in acTL"),
+                _ => {
+                    if name[0] & 0x20 == 0
+                    {
+                        return Err("unknown critical chunk");
+                    }
+                },
+            }
+        }
+    }
+
+    /* Gather the data of the consecutive IDAT, or fdAT, chunks at the
+     * current position; the sequence number of each fdAT is checked.
+     */
+    fn read_data_chunks(&mut self, chunk_name: &[u8; 4]) -> Result<Vec<u8>, &'static str>
+    {
+        let mut zdata = Vec::new();
+
+        loop {
+            let chunk_start = self.pos;
+
+            match self.next_chunk() {
+                Ok((name, chunk)) if &name == chunk_name => match &name {
+                    b"fdAT" => {
+                        self.check_sequence(chunk)?;
+                        zdata.extend_from_slice(&chunk[4..]);
+                    },
+                    _ => zdata.extend_from_slice(chunk),
+                },
+                Ok(_) => { self.pos = chunk_start; break; },
+                Err(msg) if zdata.is_empty() => return Err(msg),
+                Err(_) => break,
+            }
+        }
+
+        Ok(zdata)
+    }
+
+    /* The size of the filtered data of an image of 'width' by 'height' */
+    fn image_data_size(info: &PngInfo, width: u32, height: u32) -> usize
+    {
+        let row_size = |width: u32| Png::compute_rowbytes(info.pixel_depth, width as usize) + 1;
+
+        match info.interlace_type {
+            PngInterlace::None => row_size(width) * height as usize,
+            PngInterlace::ADAM7 => (0..PNG_INTERLACE_ADAM7_PASSES)
+                .filter(|&pass| pass_cols(width, pass) > 0)
+                .map(|pass| row_size(pass_cols(width, pass)) * pass_rows(height, pass) as usize)
+                .sum(),
+        }
+    }
+
+    /* Read the image data: the returned image has 'height' rows of
+     * 'rowbytes' bytes.  Compressed data past the end of the image is
+     * ignored, as are the chunks after the IDAT chunks.
+     */
+    pub fn read_image(&mut self) -> Result<Vec<u8>, &'static str>
+    {
+        self.read_info()?;
+
+        self.pos = self.idat_pos;
+        let zdata = self.read_data_chunks(b"IDAT")?;
+
+        self.unfilter_image(&zdata, self.info.width, self.info.height)
+    }
+
+    /* Inflate, unfilter and de-interlace the image data of an image, or a
+     * frame, of 'width' by 'height' pixels.
+     */
+    fn unfilter_image(&self, zdata: &[u8], width: u32, height: u32) -> Result<Vec<u8>, &'static str>
+    {
+        let info = &self.info;
+        let size = PngDecoder::image_data_size(info, width, height);
+        let filtered = match decompress_to_vec_zlib_with_limit(zdata, size) {
+            Ok(filtered) => filtered,
+            Err(err) if err.status == TINFLStatus::HasMoreOutput => err.output,
+            /* A truncated stream is fine if it holds all the rows */
+            Err(err) if err.status == TINFLStatus::FailedCannotMakeProgress => err.output,
+            Err(_) => return Err("IDAT: invalid data"),
+        };
+
+        if filtered.len() < size
+        {
+            return Err("Not enough image data");
+        }
+
+        let bpp = (info.pixel_depth as usize).div_ceil(8);
+        let rowbytes = Png::compute_rowbytes(info.pixel_depth, width as usize);
+        let mut image = vec![0u8; rowbytes * height as usize];
+
+        match info.interlace_type {
+            PngInterlace::None => {
+                let mut src = &filtered[..];
+
+                for y in 0..height as usize {
+                    let (above, row) = image.split_at_mut(y * rowbytes);
+                    let row = &mut row[..rowbytes];
+                    let prev_row = match y {
+                        0 => &[][..],
+                        _ => &above[(y - 1) * rowbytes..],
+                    };
+
+                    let filter = PngFilterValue::from_u8(src[0]).ok_or("bad adaptive filter value")?;
+                    row.copy_from_slice(&src[1..rowbytes + 1]);
+                    unfilter_row(filter, bpp, row, prev_row);
+                    src = &src[rowbytes + 1..];
+                }
+            },
+            PngInterlace::ADAM7 => {
+                let mut src = &filtered[..];
+
+                for pass in 0..PNG_INTERLACE_ADAM7_PASSES {
+                    let cols = pass_cols(width, pass);
+                    if cols == 0
+                    {
+                        continue;
+                    }
+
+                    let pass_bytes = Png::compute_rowbytes(info.pixel_depth, cols as usize);
+                    let mut prev_row = Vec::new();
+                    let mut row = vec![0u8; pass_bytes];
+
+                    for y in 0..pass_rows(height, pass) {
+                        let filter = PngFilterValue::from_u8(src[0]).ok_or("bad adaptive filter value")?;
+                        row.copy_from_slice(&src[1..pass_bytes + 1]);
+                        unfilter_row(filter, bpp, &mut row, &prev_row);
+                        src = &src[pass_bytes + 1..];
+
+                        let start = row_from_pass_row(y, pass) as usize * rowbytes;
+                        let dp = &mut image[start..start + rowbytes];
+                        for x in 0..cols {
+                            move_pixel(dp, col_from_pass_col(x, pass) as usize, &row, x as usize,
+                                       info.pixel_depth as usize, false);
+                        }
+
+                        std::mem::swap(&mut prev_row, &mut row);
+                        row.resize(pass_bytes, 0);
+                    }
+                }
+            },
+        }
+
+        if info.filter_type == PngFilterType::Differencing
+        {
+            let row_info = PngRowInfo {
+                width,
+                rowbytes,
+                color_type: info.color_type,
+                bit_depth: info.bit_depth,
+                channels: info.channels,
+                pixel_depth: info.pixel_depth,
+            };
+
+            for row in image.chunks_mut(rowbytes) {
+                row_info.do_read_intrapixel(row);
+            }
+        }
+
+        Ok(image)
+    }
+}
@@ -0,0 +1,775 @@
+use crate::Png;
+use crate::PngFlags;
+use crate::PngFilter;
+use miniz_oxide::deflate::core::{CompressorOxide, TDEFLFlush, TDEFLStatus};
+use miniz_oxide::deflate::core::{compress_to_output, create_comp_flags_from_zip_params};
+use std::thread;
+
+/* Default size of the IDAT chunks, as PNG_ZBUF_SIZE in C */
+pub const PNG_ZBUF_SIZE: usize = 8192;
+
+/* Compression level used when none is set, as Z_DEFAULT_COMPRESSION */
+pub const PNG_Z_DEFAULT_LEVEL: u8 = 6;
+
+/* Largest (and default) LZ77 window of a PNG datastream */
+pub const PNG_Z_MAX_WINDOW_BITS: u8 = 15;
+
+/* Smallest LZ77 window of a PNG datastream */
+pub const PNG_Z_MIN_WINDOW_BITS: u8 = 8;
+
+/* Compression strategies, with the values of the zlib constants */
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PngZlibStrategy {
+    Default     = 0, /* Z_DEFAULT_STRATEGY */
+    Filtered    = 1, /* Z_FILTERED: matches of at least 5 bytes */
+    HuffmanOnly = 2, /* Z_HUFFMAN_ONLY: no matches at all */
+    Rle         = 3, /* Z_RLE: matches of distance 1 only */
+    Fixed       = 4, /* Z_FIXED: no dynamic Huffman codes */
+}
+
+/* Flush modes of the deflate stream, as the zlib ones */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PngFlush {
+    None,   /* Z_NO_FLUSH: keep the data until it is worth compressing */
+    Sync,   /* Z_SYNC_FLUSH: all the input so far can be decompressed */
+    Full,   /* Z_FULL_FLUSH: as Sync, and the following data does not
+             * refer to the previous one */
+    Finish, /* Z_FINISH: end of the stream */
+}
+
+/* Parameters of a deflate stream */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PngCompression {
+    pub level: u8,                 /* 0 (none) to 9 (best) */
+    pub strategy: PngZlibStrategy,
+    pub window_bits: u8,           /* 8 (256 bytes) to 15 (32K) */
+}
+
+impl Default for PngCompression {
+    fn default() -> PngCompression
+    {
+        PngCompression {
+            level: PNG_Z_DEFAULT_LEVEL,
+            strategy: PngZlibStrategy::Default,
+            window_bits: PNG_Z_MAX_WINDOW_BITS,
+        }
+    }
+}
+
+impl From<PngFlush> for TDEFLFlush {
+    fn from(flush: PngFlush) -> TDEFLFlush
+    {
+        match flush {
+            PngFlush::None => TDEFLFlush::None,
+            PngFlush::Sync => TDEFLFlush::Sync,
+            PngFlush::Full => TDEFLFlush::Full,
+            PngFlush::Finish => TDEFLFlush::Finish,
+        }
+    }
+}
+
+/* Update a running Adler-32 checksum with 'data' */
+pub fn adler32(adler: u32, data: &[u8]) -> u32
+{
+    /* Largest number of bytes which can be summed before s2 overflows */
+    const NMAX: usize = 5552;
+    const BASE: u32 = 65521;
+
+    let mut s1 = adler & 0xffff;
+    let mut s2 = adler >> 16;
+
+    for block in data.chunks(NMAX) {
+        for &byte in block {
+            s1 += byte as u32;
+            s2 += s1;
+        }
+        s1 %= BASE;
+        s2 %= BASE;
+    }
+
+    (s2 << 16) | s1
+}
+
+/* The Adler-32 of the concatenation of two pieces of data, from the checksums
+ * of the pieces and the size of the second one, as adler32_combine in zlib.
+ */
+pub fn adler32_combine(adler1: u32, adler2: u32, len2: usize) -> u32
+{
+    const BASE: u32 = 65521;
+
+    let rem = (len2 % BASE as usize) as u32;
+    let mut sum1 = adler1 & 0xffff;
+    let mut sum2 = (rem * sum1) % BASE;
+
+    sum1 += (adler2 & 0xffff) + BASE - 1;
+    sum2 += (adler1 >> 16) + (adler2 >> 16) + BASE - rem;
+
+    if sum1 >= BASE
+    {
+        sum1 -= BASE;
+    }
+    if sum1 >= BASE
+    {
+        sum1 -= BASE;
+    }
+    if sum2 >= BASE << 1
+    {
+        sum2 -= BASE << 1;
+    }
+    if sum2 >= BASE
+    {
+        sum2 -= BASE;
+    }
+
+    (sum2 << 16) | sum1
+}
+
+/* The zlib header of a stream: compression method 8 with the window size in
+ * CINFO, and the compression level in FLEVEL as zlib writes it.
+ */
+fn zlib_header(window_bits: u8, level: u8, strategy: PngZlibStrategy) -> [u8; 2]
+{
+    let cmf = ((window_bits - 8) << 4) | 8;
+    let level_flags = if strategy as u8 >= PngZlibStrategy::HuffmanOnly as u8 || level < 2
+    {
+        0
+    }
+    else if level < 6
+    {
+        1
+    }
+    else if level == 6
+    {
+        2
+    }
+    else
+    {
+        3
+    };
+    let mut flg = level_flags << 6;
+    flg += 31 - ((cmf as u32 * 256 + flg as u32) % 31) as u8;
+
+    [cmf, flg]
+}
+
+/* The window of 'window_bits' reduced while 'data_size' bytes of data still
+ * fit in half of it, which is what libpng does to the header of the stream
+ * written by zlib (optimize_cmf).  No distance of the stream can exceed the
+ * window returned.
+ */
+pub fn optimal_window_bits(window_bits: u8, data_size: usize) -> u8
+{
+    let mut window_bits = window_bits.clamp(PNG_Z_MIN_WINDOW_BITS, PNG_Z_MAX_WINDOW_BITS);
+
+    if data_size <= 16384
+    {
+        while window_bits > PNG_Z_MIN_WINDOW_BITS && data_size <= 1 << (window_bits - 1) {
+            window_bits -= 1;
+        }
+    }
+
+    window_bits
+}
+
+/* The window to use for 'data_size' bytes of data with 'params' */
+fn window_bits_for(params: &PngCompression, data_size: Option<usize>) -> u8
+{
+    match data_size {
+        Some(data_size) => optimal_window_bits(params.window_bits, data_size),
+        None => params.window_bits.clamp(PNG_Z_MIN_WINDOW_BITS, PNG_Z_MAX_WINDOW_BITS),
+    }
+}
+
+/* A zlib stream written with a pure Rust deflate.  The deflate itself always
+ * searches a 32K window, so a smaller window is honoured by resetting the
+ * dictionary (a full flush) each time that much data has been compressed.
+ */
+pub struct ZlibEncoder {
+    compressor: Box<CompressorOxide>,
+    level: u8,
+    strategy: PngZlibStrategy,
+    window_bits: u8,     /* window written in the header */
+    window_used: usize,  /* data compressed since the dictionary was reset */
+    adler: u32,          /* Adler-32 of the uncompressed data */
+    started: bool,       /* the header has been written */
+    finished: bool,      /* the trailer has been written */
+    raw: bool,           /* no header nor trailer, only the deflate data */
+}
+
+impl ZlibEncoder {
+    /* A stream compressed with 'params'.  'data_size' is the amount of data
+     * which will be compressed if it is known, it allows a smaller window to
+     * be declared in the header.
+     */
+    pub fn new(params: &PngCompression, data_size: Option<usize>) -> ZlibEncoder
+    {
+        let level = params.level.min(9);
+        /* Negative window bits for a raw deflate stream, the zlib wrapper is
+         * written here.
+         */
+        let flags = create_comp_flags_from_zip_params(level as i32, -15, params.strategy as i32);
+
+        ZlibEncoder {
+            compressor: Box::new(CompressorOxide::new(flags)),
+            level,
+            strategy: params.strategy,
+            window_bits: window_bits_for(params, data_size),
+            window_used: 0,
+            adler: 1,
+            started: false,
+            finished: false,
+            raw: false,
+        }
+    }
+
+    /* As new, for a piece of deflate data without the zlib header and
+     * trailer, to be joined with others into one stream.
+     */
+    pub fn new_raw(params: &PngCompression, data_size: Option<usize>) -> ZlibEncoder
+    {
+        ZlibEncoder {
+            raw: true,
+            ..ZlibEncoder::new(params, data_size)
+        }
+    }
+
+    /* Compress 'input' and append the compressed data to 'out'.  Once
+     * PngFlush::Finish has been used the stream is complete and no more data
+     * may be compressed.
+     */
+    pub fn compress(&mut self, mut input: &[u8], flush: PngFlush, out: &mut Vec<u8>) -> Result<(), &'static str>
+    {
+        if self.finished
+        {
+            return Err("compressed data after the end of the zlib stream");
+        }
+
+        if !self.started
+        {
+            if !self.raw
+            {
+                out.extend_from_slice(&self.header());
+            }
+            self.started = true;
+        }
+
+        self.adler = adler32(self.adler, input);
+
+        /* Reset the dictionary each time a window of data has been compressed,
+         * so that no match can refer further back than the window.
+         */
+        if self.window_bits < PNG_Z_MAX_WINDOW_BITS
+        {
+            let window_size = 1 << self.window_bits;
+
+            while self.window_used + input.len() > window_size {
+                let (head, tail) = input.split_at(window_size - self.window_used);
+
+                self.deflate(head, PngFlush::Full, out)?;
+                self.window_used = 0;
+                input = tail;
+            }
+
+            self.window_used += input.len();
+        }
+
+        self.deflate(input, flush, out)?;
+
+        match flush {
+            PngFlush::Full => {
+                self.window_used = 0;
+            },
+            PngFlush::Finish => {
+                if !self.raw
+                {
+                    out.extend_from_slice(&self.adler.to_be_bytes());
+                }
+                self.finished = true;
+            },
+            _ => {},
+        }
+
+        Ok(())
+    }
+
+    fn deflate(&mut self, input: &[u8], flush: PngFlush, out: &mut Vec<u8>) -> Result<(), &'static str>
+    {
+        let (status, _) = compress_to_output(&mut self.compressor, input, flush.into(), |data| {
+            out.extend_from_slice(data);
+            true
+        });
+
+        match status {
+            TDEFLStatus::Okay | TDEFLStatus::Done => Ok(()),
+            _ => Err("deflate failed"),
+        }
+    }
+
+    /* The zlib header of the stream */
+    pub fn header(&self) -> [u8; 2]
+    {
+        zlib_header(self.window_bits, self.level, self.strategy)
+    }
+
+    /* Adler-32 of the data compressed so far */
+    pub fn adler32(&self) -> u32
+    {
+        self.adler
+    }
+
+    /* The window declared in the zlib header */
+    pub fn window_bits(&self) -> u8
+    {
+        self.window_bits
+    }
+
+    pub fn is_finished(&self) -> bool
+    {
+        self.finished
+    }
+}
+
+/* Compress 'data' into a complete zlib stream */
+pub fn zlib_compress(params: &PngCompression, data: &[u8]) -> Vec<u8>
+{
+    let mut out = Vec::with_capacity(data.len() / 2 + 64);
+    let mut encoder = ZlibEncoder::new(params, Some(data.len()));
+
+    /* Cannot fail on a new stream */
+    let _ = encoder.compress(data, PngFlush::Finish, &mut out);
+
+    out
+}
+
+impl Png {
+    /* The parameters of the IDAT stream.  Unless the application set the
+     * strategy, filtered images use PngZlibStrategy::Filtered and unfiltered
+     * ones the default strategy.
+     */
+    pub fn get_compression(&self) -> PngCompression
+    {
+        let mut params = self.zlib_compression;
+
+        if !self.flags.contains(PngFlags::ZLIB_CUSTOM_STRATEGY)
+        {
+            params.strategy = match self.do_filter == PngFilter::NONE {
+                true => PngZlibStrategy::Default,
+                false => PngZlibStrategy::Filtered,
+            };
+        }
+
+        params
+    }
+
+    pub fn get_text_compression(&self) -> PngCompression
+    {
+        self.zlib_text_compression
+    }
+
+    pub fn set_compression_level(&mut self, level: u8)
+    {
+        self.zlib_compression.level = level.min(9);
+    }
+
+    /* The flag setting here prevents the dynamic selection of strategy */
+    pub fn set_compression_strategy(&mut self, strategy: PngZlibStrategy)
+    {
+        self.flags.insert(PngFlags::ZLIB_CUSTOM_STRATEGY);
+        self.zlib_compression.strategy = strategy;
+    }
+
+    /* A smaller window may still be used when the image is small enough */
+    pub fn set_compression_window_bits(&mut self, window_bits: u8)
+    {
+        self.zlib_compression.window_bits = window_bits.clamp(PNG_Z_MIN_WINDOW_BITS, PNG_Z_MAX_WINDOW_BITS);
+    }
+
+    pub fn set_text_compression_level(&mut self, level: u8)
+    {
+        self.zlib_text_compression.level = level.min(9);
+    }
+
+    pub fn set_text_compression_strategy(&mut self, strategy: PngZlibStrategy)
+    {
+        self.zlib_text_compression.strategy = strategy;
+    }
+
+    pub fn set_text_compression_window_bits(&mut self, window_bits: u8)
+    {
+        self.zlib_text_compression.window_bits = window_bits.clamp(PNG_Z_MIN_WINDOW_BITS, PNG_Z_MAX_WINDOW_BITS);
+    }
+
+    /* Set the size of the IDAT chunks */
+    pub fn set_compression_buffer_size(&mut self, size: usize) -> Result<(), &'static str>
+    {
+        if size == 0 || size > crate::wutil::PNG_UINT_31_MAX as usize
+        {
+            return Err("invalid compression buffer size");
+        }
+
+        self.zbuffer_size = size;
+
+        Ok(())
+    }
+
+    pub fn get_compression_buffer_size(&self) -> usize
+    {
+        self.zbuffer_size
+    }
+
+    /* Flush the IDAT stream every 'nrows' rows, 0 to never flush */
+    pub fn set_flush(&mut self, nrows: u32)
+    {
+        self.flush_dist = nrows;
+    }
+
+    /* Deflate the image data of write_image on 'threads' threads, 0 for as
+     * many as there are CPUs, 1 (the default) to deflate it as it is written.
+     */
+    pub fn set_compression_threads(&mut self, threads: usize)
+    {
+        self.compression_threads = threads;
+    }
+
+    /* The number of threads deflating the image data of write_image */
+    pub fn get_compression_threads(&self) -> usize
+    {
+        match self.compression_threads {
+            0 => thread::available_parallelism().map_or(1, |threads| threads.get()),
+            threads => threads,
+        }
+    }
+
+    /* Compress the data of a zTXt, iTXt or iCCP chunk */
+    pub fn compress_text(&self, data: &[u8]) -> Vec<u8>
+    {
+        zlib_compress(&self.zlib_text_compression, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::ChunkReader;
+    use crate::decoder::PngDecoder;
+    use crate::png_info::PngInfo;
+    use crate::wutil::ChunkWriter;
+    use crate::PngColor;
+    use miniz_oxide::inflate::core::inflate_flags::{TINFL_FLAG_HAS_MORE_INPUT, TINFL_FLAG_PARSE_ZLIB_HEADER,
+                                                    TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF};
+    use miniz_oxide::inflate::core::{decompress, DecompressorOxide};
+    use miniz_oxide::inflate::{decompress_to_vec, decompress_to_vec_zlib, TINFLStatus};
+
+    const STRATEGIES: [PngZlibStrategy; 5] = [PngZlibStrategy::Default, PngZlibStrategy::Filtered,
+                                              PngZlibStrategy::HuffmanOnly, PngZlibStrategy::Rle,
+                                              PngZlibStrategy::Fixed];
+
+    fn random_bytes(seed: &mut u32, length: usize) -> Vec<u8>
+    {
+        (0..length).map(|_| {
+            *seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (*seed >> 16) as u8
+        }).collect()
+    }
+
+    /* The data of a zlib stream which is not finished yet, all of which must
+     * be decompressed.
+     */
+    fn inflate_unfinished(zdata: &[u8], max_size: usize) -> Vec<u8>
+    {
+        let mut decompressor = DecompressorOxide::new();
+        let mut out = vec![0u8; max_size];
+        let flags = TINFL_FLAG_PARSE_ZLIB_HEADER | TINFL_FLAG_HAS_MORE_INPUT | TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+
+        let (status, consumed, written) = decompress(&mut decompressor, zdata, &mut out, 0, flags);
+        assert_eq!((status, consumed), (TINFLStatus::NeedsMoreInput, zdata.len()));
+
+        out.truncate(written);
+        out
+    }
+
+    /* The FLEVEL zlib writes for 'level' and 'strategy' */
+    fn flevel(level: u8, strategy: PngZlibStrategy) -> u8
+    {
+        match (level, strategy) {
+            (_, PngZlibStrategy::HuffmanOnly) | (_, PngZlibStrategy::Rle) | (_, PngZlibStrategy::Fixed) => 0,
+            (0..=1, _) => 0,
+            (2..=5, _) => 1,
+            (6, _) => 2,
+            _ => 3,
+        }
+    }
+
+    /* BTYPE of the first deflate block of a zlib stream */
+    fn first_block_type(zdata: &[u8]) -> u8
+    {
+        (zdata[2] >> 1) & 3
+    }
+
+    #[test]
+    fn zlib_header_declares_the_window_and_the_level()
+    {
+        let data = random_bytes(&mut 1, 1000);
+
+        for window_bits in PNG_Z_MIN_WINDOW_BITS..=PNG_Z_MAX_WINDOW_BITS {
+            for level in 0..=9 {
+                for &strategy in STRATEGIES.iter() {
+                    let params = PngCompression { level, strategy, window_bits };
+                    let [cmf, flg] = ZlibEncoder::new(&params, None).header();
+
+                    assert_eq!((cmf >> 4, cmf & 15), (window_bits - 8, 8), "{:?}", params);
+                    assert_eq!((flg >> 6, flg & 0x20), (flevel(level, strategy), 0), "{:?}", params);
+                    assert_eq!((cmf as u32 * 256 + flg as u32) % 31, 0, "{:?}", params);
+
+                    let mut zdata = Vec::new();
+                    let mut encoder = ZlibEncoder::new(&params, None);
+                    encoder.compress(&data, PngFlush::Finish, &mut zdata).unwrap();
+                    assert_eq!(zdata[..2], [cmf, flg]);
+                    assert_eq!(zdata[zdata.len() - 4..], adler32(1, &data).to_be_bytes());
+                    assert_eq!(decompress_to_vec_zlib(&zdata).unwrap(), data, "{:?}", params);
+                }
+            }
+        }
+
+        /* A smaller window is declared for the data of a known small size */
+        for &(size, window_bits) in [(0, 8), (256, 8), (257, 9), (1000, 10), (16384, 14), (16385, 15)].iter() {
+            let zdata = zlib_compress(&PngCompression::default(), &vec![7u8; size]);
+            assert_eq!(zdata[0] >> 4, window_bits - 8, "{} bytes", size);
+            assert_eq!(decompress_to_vec_zlib(&zdata).unwrap(), vec![7u8; size]);
+        }
+        assert_eq!(optimal_window_bits(9, 100), 8);
+        assert_eq!(optimal_window_bits(12, 16384), 12);
+    }
+
+    #[test]
+    fn matches_stay_in_the_window()
+    {
+        /* A block repeated 2048 bytes apart only compresses when the window
+         * reaches that far, and only within each window as the dictionary is
+         * reset after a window of data.
+         */
+        let block = random_bytes(&mut 2, 2048);
+        let data = block.repeat(8);
+
+        for window_bits in PNG_Z_MIN_WINDOW_BITS..=PNG_Z_MAX_WINDOW_BITS {
+            let params = PngCompression { window_bits, ..Default::default() };
+            let mut zdata = Vec::new();
+            let mut encoder = ZlibEncoder::new(&params, None);
+            encoder.compress(&data, PngFlush::Finish, &mut zdata).unwrap();
+
+            assert_eq!(zdata[0] >> 4, window_bits - 8);
+            assert_eq!(decompress_to_vec_zlib(&zdata).unwrap(), data);
+
+            /* The copies of the block which cannot be matched are stored whole */
+            let copies = match window_bits <= 11 {
+                true => data.len() / block.len(),
+                false => (data.len() >> window_bits).max(1),
+            };
+            assert!(zdata.len() > copies * block.len() && zdata.len() < copies * block.len() + data.len() / 16,
+                    "{} bits: {} bytes", window_bits, zdata.len());
+        }
+    }
+
+    #[test]
+    fn level_and_strategy_are_used()
+    {
+        let text: Vec<u8> = (0..20000).map(|i| b"the quick brown fox jumps over the lazy dog "[i % 44]).collect();
+        let compress = |level: u8, strategy: PngZlibStrategy, data: &[u8]| {
+            let zdata = zlib_compress(&PngCompression { level, strategy, window_bits: 15 }, data);
+            assert_eq!(decompress_to_vec_zlib(&zdata).unwrap(), data);
+            zdata
+        };
+
+        /* Level 0 stores the data */
+        let stored = compress(0, PngZlibStrategy::Default, &text);
+        assert_eq!(first_block_type(&stored), 0);
+        assert!(stored.len() > text.len());
+        assert_eq!(first_block_type(&compress(6, PngZlibStrategy::Default, &text)), 2);
+        assert!(compress(9, PngZlibStrategy::Default, &text).len() <= compress(1, PngZlibStrategy::Default, &text).len());
+
+        /* Fixed Huffman codes only */
+        assert_eq!(first_block_type(&compress(6, PngZlibStrategy::Fixed, &text)), 1);
+
+        /* No matches at all: the text is only Huffman coded */
+        let default = compress(6, PngZlibStrategy::Default, &text).len();
+        let huffman = compress(6, PngZlibStrategy::HuffmanOnly, &text).len();
+        assert!(default < text.len() / 20 && huffman > text.len() / 2, "{} {}", default, huffman);
+
+        /* Runs only: a period of 3 bytes is not a run, zeros are */
+        let period: Vec<u8> = (0..20000).map(|i| (i % 3) as u8).collect();
+        assert!(compress(6, PngZlibStrategy::Rle, &period).len() > 10 * compress(6, PngZlibStrategy::Default, &period).len());
+        assert!(compress(6, PngZlibStrategy::Rle, &[0u8; 20000]).len() < 100);
+    }
+
+    #[test]
+    fn flush_points()
+    {
+        let first = random_bytes(&mut 3, 3000);
+        let second = first.clone();
+
+        for &flush in [PngFlush::Sync, PngFlush::Full].iter() {
+            let mut zdata = Vec::new();
+            let mut encoder = ZlibEncoder::new(&PngCompression::default(), None);
+
+            /* All the data given so far can be decompressed */
+            encoder.compress(&first, flush, &mut zdata).unwrap();
+            assert_eq!(zdata[zdata.len() - 4..], [0, 0, 0xff, 0xff]);
+            assert_eq!(inflate_unfinished(&zdata, 8192), first);
+
+            let flushed = zdata.len();
+            encoder.compress(&second, PngFlush::Finish, &mut zdata).unwrap();
+            assert!(encoder.is_finished());
+            assert_eq!(decompress_to_vec_zlib(&zdata).unwrap(), [&first[..], &second[..]].concat());
+            assert_eq!(encoder.compress(&[1], PngFlush::None, &mut Vec::new()),
+                       Err("compressed data after the end of the zlib stream"));
+
+            /* After a full flush the data does not refer to the previous data,
+             * so it decompresses on its own.
+             */
+            let rest = decompress_to_vec(&zdata[flushed..zdata.len() - 4]);
+            match flush {
+                PngFlush::Full => assert_eq!(rest.unwrap(), second),
+                _ => assert!(rest.map(|rest| rest != second).unwrap_or(true)),
+            }
+        }
+    }
+
+    /* An RGB image of 'width' by 'height' pixels written row by row by a Png
+     * set up by 'setup'.
+     */
+    fn write_image<F>(width: u32, height: u32, image: &[Vec<u8>], setup: F) -> Vec<u8>
+        where F: Fn(&mut Png)
+    {
+        let mut info = PngInfo::new();
+        info.width = width;
+        info.height = height;
+        info.bit_depth = 8;
+        info.color_type = PngColor::TYPE_RGB;
+
+        let mut png = Png::new();
+        let mut writer = ChunkWriter::new(Vec::new());
+        setup(&mut png);
+        png.write_info(&mut writer, &mut info).unwrap();
+        for row in image {
+            png.write_row(&mut writer, row).unwrap();
+        }
+        png.write_end(&mut writer, Some(&mut info)).unwrap();
+
+        writer.into_inner()
+    }
+
+    fn idat_chunks(png: &[u8]) -> Vec<Vec<u8>>
+    {
+        ChunkReader::new(png).map(|chunk| chunk.unwrap())
+            .filter(|chunk| &chunk.name == b"IDAT")
+            .map(|chunk| chunk.data)
+            .collect()
+    }
+
+    fn noisy_image(seed: &mut u32, width: u32, height: u32) -> Vec<Vec<u8>>
+    {
+        (0..height).map(|y| {
+            random_bytes(seed, 3 * width as usize).iter().enumerate()
+                .map(|(x, &noise)| (x as u32 + y).wrapping_add(noise as u32 & 15) as u8)
+                .collect()
+        }).collect()
+    }
+
+    #[test]
+    fn idat_chunks_have_the_buffer_size()
+    {
+        let image = noisy_image(&mut 4, 64, 48);
+
+        for &size in [1, 7, 100, 1000, 4096, PNG_ZBUF_SIZE, 1 << 20].iter() {
+            let png = write_image(64, 48, &image, |png| png.set_compression_buffer_size(size).unwrap());
+            let chunks = idat_chunks(&png);
+            let (last, full) = chunks.split_last().unwrap();
+
+            assert!(full.iter().all(|chunk| chunk.len() == size), "{} bytes", size);
+            assert!(!last.is_empty() && last.len() <= size, "{} bytes", size);
+
+            let mut decoder = PngDecoder::new(&png);
+            decoder.read_info().unwrap();
+            assert_eq!(decoder.read_image().unwrap(), image.concat(), "{} bytes", size);
+        }
+
+        let mut png = Png::new();
+        assert_eq!(png.get_compression_buffer_size(), PNG_ZBUF_SIZE);
+        assert!(png.set_compression_buffer_size(0).is_err());
+        assert!(png.set_compression_buffer_size(1 << 31).is_err());
+        assert_eq!(png.get_compression_buffer_size(), PNG_ZBUF_SIZE);
+    }
+
+    #[test]
+    fn rows_are_flushed_every_flush_distance()
+    {
+        let (width, height) = (20u32, 10u32);
+        let row_size = 3 * width as usize + 1;
+        let image = noisy_image(&mut 5, width, height);
+
+        /* Without flushes the IDAT stream fits in one chunk */
+        let png = write_image(width, height, &image, |png| png.set_compression_buffer_size(1 << 20).unwrap());
+        assert_eq!(idat_chunks(&png).len(), 1);
+
+        let png = write_image(width, height, &image, |png| {
+            png.set_compression_buffer_size(1 << 20).unwrap();
+            png.set_flush(3);
+        });
+        let chunks = idat_chunks(&png);
+        assert_eq!(chunks.len(), 4);
+
+        /* Each flush writes an IDAT chunk with all the rows given so far */
+        let mut zdata = Vec::new();
+        for (i, chunk) in chunks[..3].iter().enumerate() {
+            zdata.extend_from_slice(chunk);
+            assert_eq!(chunk[chunk.len() - 4..], [0, 0, 0xff, 0xff]);
+            assert_eq!(inflate_unfinished(&zdata, 1 << 16).len(), 3 * (i + 1) * row_size);
+        }
+        zdata.extend_from_slice(&chunks[3]);
+        assert_eq!(decompress_to_vec_zlib(&zdata).unwrap().len(), height as usize * row_size);
+
+        let mut decoder = PngDecoder::new(&png);
+        decoder.read_info().unwrap();
+        assert_eq!(decoder.read_image().unwrap(), image.concat());
+    }
+
+    #[test]
+    fn compression_settings_reach_the_idat_stream()
+    {
+        /* Large enough for the window not to be reduced */
+        let image = noisy_image(&mut 6, 100, 60);
+
+        for &(level, strategy, window_bits) in [(0, PngZlibStrategy::Default, 15), (1, PngZlibStrategy::Filtered, 9),
+                                                (6, PngZlibStrategy::HuffmanOnly, 12), (9, PngZlibStrategy::Rle, 8),
+                                                (4, PngZlibStrategy::Fixed, 14)].iter() {
+            let png = write_image(100, 60, &image, |png| {
+                png.set_compression_level(level);
+                png.set_compression_strategy(strategy);
+                png.set_compression_window_bits(window_bits);
+            });
+            let zdata = idat_chunks(&png).concat();
+
+            assert_eq!(zdata[0], ((window_bits - 8) << 4) | 8);
+            assert_eq!(zdata[1] >> 6, flevel(level, strategy));
+            match (level, strategy) {
+                (0, _) => assert_eq!(first_block_type(&zdata), 0),
+                (_, PngZlibStrategy::Fixed) => assert_eq!(first_block_type(&zdata), 1),
+                _ => assert_eq!(first_block_type(&zdata), 2),
+            }
+
+            let mut decoder = PngDecoder::new(&png);
+            decoder.read_info().unwrap();
+            assert_eq!(decoder.read_image().unwrap(), image.concat());
+        }
+
+        /* The filtered rows use the Filtered strategy unless one is set, and
+         * a small image a smaller window.
+         */
+        let small = noisy_image(&mut 7, 8, 8);
+        let zdata = idat_chunks(&write_image(8, 8, &small, |_| {})).concat();
+        assert_eq!(zdata[..2], zlib_header(optimal_window_bits(15, 8 * 25), 6, PngZlibStrategy::Filtered));
+        let zdata = idat_chunks(&write_image(8, 8, &small, |png| png.set_filter(PngFilter::NONE))).concat();
+        assert_eq!(zdata[..2], zlib_header(optimal_window_bits(15, 8 * 25), 6, PngZlibStrategy::Default));
+    }
+}
@@ -0,0 +1,324 @@
+use crate::Png;
+use crate::PngMode;
+use crate::png_info::PngInfo;
+use crate::wutil::{ChunkWriter, invalid_input};
+use std::io;
+use std::io::Write;
+
+/* A PNG encoder taking the image a few rows at a time, for images too large
+ * to be held in memory.  The header is written when the encoder is created,
+ * then each write_rows compresses the rows it is given and writes an IDAT
+ * chunk each time the compression buffer (see
+ * Png::set_compression_buffer_size) is full.  Besides the rows the encoder
+ * keeps a few rows for the filters, at most one buffer and a piece of
+ * compressed data, and the state of the deflater.
+ *
+ * The rows are given as the application has them, before the write
+ * transformations set on the Png.  For an interlaced image they are the rows
+ * of each pass in turn, or, if set_interlace_handling was called (after the
+ * encoder was created), the rows of the whole image once for each pass.
+ */
+pub struct StreamEncoder<W: Write> {
+    png: Png,
+    info: PngInfo,
+    writer: ChunkWriter<W>,
+    /* The start of a row given by the last write_rows */
+    pending: Vec<u8>,
+}
+
+impl<W: Write> StreamEncoder<W> {
+    /* Write the signature and the chunks before the image data of 'info',
+     * with the default compression.
+     */
+    pub fn new(writer: W, info: PngInfo) -> io::Result<StreamEncoder<W>>
+    {
+        StreamEncoder::with_png(writer, Png::new(), info)
+    }
+
+    /* As new, with a Png already set up by the application (compression,
+     * filters, buffer size, transformations).
+     */
+    pub fn with_png(writer: W, mut png: Png, mut info: PngInfo) -> io::Result<StreamEncoder<W>>
+    {
+        let mut writer = ChunkWriter::new(writer);
+
+        png.write_info(&mut writer, &mut info)?;
+
+        Ok(StreamEncoder {
+            png,
+            info,
+            writer,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn png(&self) -> &Png
+    {
+        &self.png
+    }
+
+    /* The Png may be changed until the first rows are written, the
+     * compression buffer size at any time.
+     */
+    pub fn png_mut(&mut self) -> &mut Png
+    {
+        &mut self.png
+    }
+
+    /* The info written at the end, with the texts and chunks added since */
+    pub fn info_mut(&mut self) -> &mut PngInfo
+    {
+        &mut self.info
+    }
+
+    pub fn get_ref(&self) -> &W
+    {
+        self.writer.get_ref()
+    }
+
+    /* Set the most compressed data kept before an IDAT chunk is written */
+    pub fn set_buffer_size(&mut self, size: usize) -> io::Result<()>
+    {
+        self.png.set_compression_buffer_size(size).map_err(invalid_input)
+    }
+
+    /* The size of the next row expected */
+    pub fn row_bytes(&mut self) -> io::Result<usize>
+    {
+        self.png.write_row_bytes()
+    }
+
+    /* Write the rows in 'data', one after the other.  The data need not end
+     * at the end of a row: the rest is kept until the next call completes it.
+     */
+    pub fn write_rows(&mut self, data: &[u8]) -> io::Result<()>
+    {
+        let mut data = data;
+
+        if !self.pending.is_empty()
+        {
+            let row_bytes = self.png.write_row_bytes()?;
+            let needed = (row_bytes - self.pending.len()).min(data.len());
+
+            self.pending.extend_from_slice(&data[..needed]);
+            data = &data[needed..];
+
+            if self.pending.len() < row_bytes
+            {
+                return Ok(());
+            }
+
+            self.png.write_row(&mut self.writer, &self.pending)?;
+            self.pending.clear();
+        }
+
+        while !data.is_empty() {
+            let row_bytes = self.png.write_row_bytes()?;
+
+            if data.len() < row_bytes
+            {
+                self.pending.extend_from_slice(data);
+                break;
+            }
+
+            self.png.write_row(&mut self.writer, &data[..row_bytes])?;
+            data = &data[row_bytes..];
+        }
+
+        Ok(())
+    }
+
+    /* Compress and write out everything given so far, so that a reader gets
+     * all the complete rows.  Flushing often makes the compression worse.
+     */
+    pub fn flush(&mut self) -> io::Result<()>
+    {
+        self.png.write_flush(&mut self.writer)
+    }
+
+    /* Write the end of the file after the last row, and give back the writer */
+    pub fn finish(mut self) -> io::Result<W>
+    {
+        if !self.pending.is_empty()
+        {
+            return Err(invalid_input("incomplete row at the end of the image"));
+        }
+
+        if !self.png.mode.contains(PngMode::AFTER_IDAT)
+        {
+            return Err(invalid_input("not enough rows written"));
+        }
+
+        self.png.write_end(&mut self.writer, Some(&mut self.info))?;
+
+        Ok(self.writer.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::ChunkReader;
+    use crate::decoder::PngDecoder;
+    use crate::PngColor;
+    use crate::PngInterlace;
+
+    /* A writer keeping what it is given and counting the writes */
+    #[derive(Default)]
+    struct CountingWriter {
+        data: Vec<u8>,
+        writes: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+        {
+            self.data.extend_from_slice(buf);
+            self.writes += 1;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()>
+        {
+            Ok(())
+        }
+    }
+
+    /* The lengths of the complete IDAT chunks written so far */
+    fn idat_lengths(data: &[u8]) -> Vec<usize>
+    {
+        ChunkReader::new(data)
+            .take_while(|chunk| chunk.is_ok())
+            .map(|chunk| chunk.unwrap())
+            .filter(|chunk| &chunk.name == b"IDAT")
+            .map(|chunk| chunk.data.len())
+            .collect()
+    }
+
+    fn rgb_info(width: u32, height: u32, interlace_type: PngInterlace) -> PngInfo
+    {
+        let mut info = PngInfo::new();
+        info.width = width;
+        info.height = height;
+        info.bit_depth = 8;
+        info.color_type = PngColor::TYPE_RGB;
+        info.interlace_type = interlace_type;
+
+        info
+    }
+
+    /* Rows of noise, which compress badly */
+    fn noisy_image(width: u32, height: u32) -> Vec<u8>
+    {
+        let mut seed = 1u32;
+
+        (0..3 * width as usize * height as usize).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        }).collect()
+    }
+
+    fn decode(png: &[u8]) -> Vec<u8>
+    {
+        let mut decoder = PngDecoder::new(png);
+        decoder.read_info().unwrap();
+        decoder.read_image().unwrap()
+    }
+
+    #[test]
+    fn idat_chunks_are_written_as_the_rows_come()
+    {
+        let (width, height) = (400, 200);
+        let image = noisy_image(width, height);
+        let size = 4096;
+
+        let mut encoder = StreamEncoder::new(CountingWriter::default(), rgb_info(width, height, PngInterlace::None)).unwrap();
+        encoder.set_buffer_size(size).unwrap();
+        let header_writes = encoder.get_ref().writes;
+
+        /* Pieces which do not end at the end of the rows */
+        let mut given = 0;
+        for piece in image.chunks(1000) {
+            encoder.write_rows(piece).unwrap();
+            given += piece.len();
+
+            /* The complete buffers are written out, less than a buffer of
+             * compressed data and a row are kept.  The last row ends the
+             * stream, with a shorter IDAT.
+             */
+            let written = idat_lengths(&encoder.get_ref().data);
+            let full = match given < image.len() {
+                true => &written[..],
+                false => &written[..written.len() - 1],
+            };
+            assert!(full.iter().all(|&length| length == size));
+            assert!(encoder.png().idat_buffer.len() < size, "{} bytes kept", encoder.png().idat_buffer.len());
+            assert!(encoder.pending.len() < 3 * width as usize);
+
+            /* Noise does not compress, so the data given and not written yet
+             * is in the deflater, which holds less than its 64K buffer.
+             */
+            assert!(given < written.len() * size + 65536, "{} IDAT for {} bytes", written.len(), given);
+        }
+
+        /* All the IDAT chunks are out before finish, which only adds IEND */
+        assert!(encoder.get_ref().writes > header_writes);
+        let before_finish = encoder.get_ref().data.clone();
+
+        let out = encoder.finish().unwrap();
+        assert_eq!(out.data[..before_finish.len()], before_finish[..]);
+        assert_eq!(out.data[before_finish.len()..], [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]);
+        assert_eq!(decode(&out.data), image);
+    }
+
+    #[test]
+    fn rows_read_back()
+    {
+        let (width, height) = (13, 11);
+        let image = noisy_image(width, height);
+
+        /* Whole rows, all at once, a byte at a time */
+        for &piece_size in [3 * width as usize, image.len(), 1, 7].iter() {
+            let mut encoder = StreamEncoder::new(Vec::new(), rgb_info(width, height, PngInterlace::None)).unwrap();
+            assert_eq!(encoder.row_bytes().unwrap(), 3 * width as usize);
+
+            for piece in image.chunks(piece_size) {
+                encoder.write_rows(piece).unwrap();
+            }
+            assert_eq!(decode(&encoder.finish().unwrap()), image, "pieces of {} bytes", piece_size);
+        }
+
+        /* The whole image once per pass with the interlace handling */
+        let mut encoder = StreamEncoder::new(Vec::new(), rgb_info(width, height, PngInterlace::ADAM7)).unwrap();
+        assert_eq!(encoder.png_mut().set_interlace_handling(), 7);
+        for _ in 0..7 {
+            for piece in image.chunks(100) {
+                encoder.write_rows(piece).unwrap();
+            }
+        }
+        let png = encoder.finish().unwrap();
+
+        let mut decoder = PngDecoder::new(&png);
+        assert_eq!(decoder.read_info().unwrap().interlace_type, PngInterlace::ADAM7);
+        assert_eq!(decoder.read_image().unwrap(), image);
+    }
+
+    #[test]
+    fn incomplete_images()
+    {
+        let image = noisy_image(4, 3);
+
+        let mut encoder = StreamEncoder::new(Vec::new(), rgb_info(4, 3, PngInterlace::None)).unwrap();
+        encoder.write_rows(&image[..image.len() - 1]).unwrap();
+        assert!(encoder.finish().is_err());
+
+        let mut encoder = StreamEncoder::new(Vec::new(), rgb_info(4, 3, PngInterlace::None)).unwrap();
+        encoder.write_rows(&image[..24]).unwrap();
+        assert!(encoder.finish().is_err());
+
+        let mut encoder = StreamEncoder::new(Vec::new(), rgb_info(4, 3, PngInterlace::None)).unwrap();
+        assert!(encoder.set_buffer_size(0).is_err());
+        encoder.write_rows(&image).unwrap();
+        assert_eq!(decode(&encoder.finish().unwrap()), image);
+    }
+}
@@ -0,0 +1,792 @@
+use crate::Png;
+use crate::PngFilter;
+use crate::trans::PngRowInfo;
+use crate::CPtr;
+use std::slice;
+
+/* Filter method of a row, stored in the first byte of each row of the image
+ * data.
+ */
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PngFilterValue {
+    None  = 0,
+    Sub   = 1,
+    Up    = 2,
+    Avg   = 3,
+    Paeth = 4,
+}
+
+/* How the filter of a row is chosen when more than one filter is allowed */
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum PngFilterHeuristic {
+    #[default]
+    SumAbsDiff, /* minimum sum of absolute differences, as libpng always did */
+    Entropy,    /* minimum Shannon entropy of the bytes of the filtered row */
+    BruteForce, /* smallest row once compressed, very slow */
+}
+
+/* Compression level used to compare the rows in BruteForce mode */
+const PNG_BRUTE_FORCE_LEVEL: u8 = 6;
+
+/* The filters which are tried, in order, after the 'none' filter */
+static PNG_TRIED_FILTERS: &[PngFilterValue] = &[PngFilterValue::Sub, PngFilterValue::Up,
+                                                 PngFilterValue::Avg, PngFilterValue::Paeth];
+
+impl PngFilterValue {
+    /* The PngFilter flag which allows this filter */
+    pub fn mask(self) -> PngFilter
+    {
+        match self {
+            PngFilterValue::None => PngFilter::NONE,
+            PngFilterValue::Sub => PngFilter::SUB,
+            PngFilterValue::Up => PngFilter::UP,
+            PngFilterValue::Avg => PngFilter::AVG,
+            PngFilterValue::Paeth => PngFilter::PAETH,
+        }
+    }
+
+    /* The single filter allowed by 'filters', None if there are several */
+    fn from_mask(filters: PngFilter) -> Option<PngFilterValue>
+    {
+        [PngFilterValue::None, PngFilterValue::Sub, PngFilterValue::Up,
+         PngFilterValue::Avg, PngFilterValue::Paeth].iter()
+            .find(|value| value.mask() == filters)
+            .copied()
+    }
+
+    /* The filter of a filter byte read from the image data */
+    pub fn from_u8(value: u8) -> Option<PngFilterValue>
+    {
+        match value {
+            0 => Some(PngFilterValue::None),
+            1 => Some(PngFilterValue::Sub),
+            2 => Some(PngFilterValue::Up),
+            3 => Some(PngFilterValue::Avg),
+            4 => Some(PngFilterValue::Paeth),
+            _ => None,
+        }
+    }
+}
+
+/*******************************************************************************
+ *
+ *                               Png C API
+ *
+ ******************************************************************************/
+
+/* Filter the row in png_ptr->row_buf and return the buffer holding the row to
+ * write, filter byte included: row_buf itself, try_row or tst_row.
+ */
+#[no_mangle]
+pub unsafe extern fn png_rust_find_filter(this: *const Png, row_info: *const PngRowInfo) -> CPtr
+{
+    match (this.as_ref(), row_info.as_ref()) {
+        (Some(png_ptr), Some(row_info)) => {
+            let row_bytes = row_info.rowbytes;
+            let bpp = (row_info.pixel_depth as usize + 7) >> 3;
+
+            let row = slice::from_raw_parts((png_ptr.row_buf + 1) as *const u8, row_bytes);
+            let prev_row: &[u8] = match png_ptr.prev_row {
+                0 => &[],
+                prev_row => slice::from_raw_parts((prev_row + 1) as *const u8, row_bytes),
+            };
+            let try_row: &mut [u8] = match png_ptr.try_row {
+                0 => &mut [],
+                try_row => slice::from_raw_parts_mut((try_row + 1) as *mut u8, row_bytes),
+            };
+            let tst_row: &mut [u8] = match png_ptr.tst_row {
+                0 => &mut [],
+                tst_row => slice::from_raw_parts_mut((tst_row + 1) as *mut u8, row_bytes),
+            };
+
+            let (filter, best_row) = find_filter(png_ptr.do_filter, png_ptr.filter_heuristic, bpp,
+                                                 row, prev_row, try_row, tst_row);
+
+            /* The filter byte precedes the filtered data in all the buffers */
+            let best_row = best_row.as_ptr() as CPtr - 1;
+            *(best_row as *mut u8) = filter as u8;
+
+            best_row
+        },
+        _ => 0,
+    }
+}
+
+/*******************************************************************************
+ *
+ *                               Png RUST
+ *
+ ******************************************************************************/
+
+/* Filter 'row' with 'filter' into 'out', which must be as long as the row.
+ * 'bpp' is the number of bytes per pixel, rounded up, and 'prev_row' is the
+ * previous unfiltered row of the pass, or empty for the first row.
+ */
+pub fn filter_row(filter: PngFilterValue, bpp: usize, row: &[u8], prev_row: &[u8], out: &mut [u8])
+{
+    let row_bytes = row.len();
+    let bpp = bpp.min(row_bytes);
+    let out = &mut out[..row_bytes];
+    let zeros;
+    let prev_row = match prev_row.is_empty() {
+        true => { zeros = vec![0u8; row_bytes]; &zeros[..] },
+        false => &prev_row[..row_bytes],
+    };
+
+    match filter {
+        PngFilterValue::None => {
+            out.copy_from_slice(row);
+        },
+        PngFilterValue::Sub => {
+            out[..bpp].copy_from_slice(&row[..bpp]);
+            for i in bpp..row_bytes {
+                out[i] = row[i].wrapping_sub(row[i - bpp]);
+            }
+        },
+        PngFilterValue::Up => {
+            for i in 0..row_bytes {
+                out[i] = row[i].wrapping_sub(prev_row[i]);
+            }
+        },
+        PngFilterValue::Avg => {
+            for i in 0..bpp {
+                out[i] = row[i].wrapping_sub(prev_row[i] / 2);
+            }
+            for i in bpp..row_bytes {
+                let avg = (prev_row[i] as u32 + row[i - bpp] as u32) / 2;
+                out[i] = row[i].wrapping_sub(avg as u8);
+            }
+        },
+        PngFilterValue::Paeth => {
+            for i in 0..bpp {
+                out[i] = row[i].wrapping_sub(prev_row[i]);
+            }
+            for i in bpp..row_bytes {
+                let predictor = paeth_predictor(row[i - bpp], prev_row[i], prev_row[i - bpp]);
+                out[i] = row[i].wrapping_sub(predictor);
+            }
+        },
+    }
+}
+
+/* Undo the filter 'filter' of 'row' in place, the reverse of filter_row.
+ * 'prev_row' is the previous row of the pass once unfiltered, or empty for the
+ * first row.
+ */
+pub fn unfilter_row(filter: PngFilterValue, bpp: usize, row: &mut [u8], prev_row: &[u8])
+{
+    let row_bytes = row.len();
+    let bpp = bpp.min(row_bytes);
+    let zeros;
+    let prev_row = match prev_row.is_empty() {
+        true => { zeros = vec![0u8; row_bytes]; &zeros[..] },
+        false => &prev_row[..row_bytes],
+    };
+
+    match filter {
+        PngFilterValue::None => {},
+        PngFilterValue::Sub => {
+            for i in bpp..row_bytes {
+                row[i] = row[i].wrapping_add(row[i - bpp]);
+            }
+        },
+        PngFilterValue::Up => {
+            for i in 0..row_bytes {
+                row[i] = row[i].wrapping_add(prev_row[i]);
+            }
+        },
+        PngFilterValue::Avg => {
+            for i in 0..bpp {
+                row[i] = row[i].wrapping_add(prev_row[i] / 2);
+            }
+            for i in bpp..row_bytes {
+                let avg = (prev_row[i] as u32 + row[i - bpp] as u32) / 2;
+                row[i] = row[i].wrapping_add(avg as u8);
+            }
+        },
+        PngFilterValue::Paeth => {
+            for i in 0..bpp {
+                row[i] = row[i].wrapping_add(prev_row[i]);
+            }
+            for i in bpp..row_bytes {
+                let predictor = paeth_predictor(row[i - bpp], prev_row[i], prev_row[i - bpp]);
+                row[i] = row[i].wrapping_add(predictor);
+            }
+        },
+    }
+}
+
+/* Choose the filter of 'row' among 'filters' and filter it.  The returned
+ * slice is the filtered row, without the filter byte: 'row' itself for the
+ * 'none' filter, otherwise 'try_row' or 'tst_row'.  'tst_row' may be empty
+ * when at most one filter other than 'none' is allowed, though the row is
+ * then filtered twice when more are.
+ */
+pub fn find_filter<'a>(filters: PngFilter, heuristic: PngFilterHeuristic, bpp: usize,
+                       row: &'a [u8], prev_row: &[u8],
+                       try_row: &'a mut [u8], tst_row: &'a mut [u8]) -> (PngFilterValue, &'a [u8])
+{
+    let row_bytes = row.len();
+    let mut filters = filters & PngFilter::all();
+
+    if filters.is_empty()
+    {
+        return (PngFilterValue::None, row);
+    }
+
+    if usize::MAX / 128 <= row_bytes
+    {
+        /* Overflow can occur in the calculation, just select the lowest set
+         * filter.
+         */
+        filters = PngFilter::from_bits_truncate(filters.bits() & filters.bits().wrapping_neg());
+    }
+
+    /* We don't need to test a filter if it is the only one chosen */
+    if let Some(filter) = PngFilterValue::from_mask(filters)
+    {
+        if filter == PngFilterValue::None
+        {
+            return (filter, row);
+        }
+
+        filter_row(filter, bpp, row, prev_row, try_row);
+        return (filter, &try_row[..row_bytes]);
+    }
+
+    let mut try_row = &mut try_row[..row_bytes];
+    let mut tst_row = match tst_row.len() >= row_bytes {
+        true => &mut tst_row[..row_bytes],
+        false => &mut tst_row[..0],
+    };
+    let have_tst = !tst_row.is_empty() || row_bytes == 0;
+
+    /* Any tried filter is better than 'none' unless it is in the list, so
+     * 'mins' starts at a value no row can reach.
+     */
+    let mut best = PngFilterValue::None;
+    let mut mins = usize::MAX - 256;
+    let mut last = PngFilterValue::None;
+
+    if filters.contains(PngFilter::NONE)
+    {
+        mins = row_cost(heuristic, row, mins);
+    }
+
+    for &filter in PNG_TRIED_FILTERS {
+        if !filters.contains(filter.mask())
+        {
+            continue;
+        }
+
+        filter_row(filter, bpp, row, prev_row, try_row);
+        last = filter;
+
+        let sum = row_cost(heuristic, try_row, mins);
+        if sum < mins
+        {
+            mins = sum;
+            best = filter;
+
+            /* Keep the best row in tst_row, try_row is then free for the
+             * next filter.
+             */
+            if have_tst
+            {
+                std::mem::swap(&mut try_row, &mut tst_row);
+            }
+        }
+    }
+
+    if best == PngFilterValue::None
+    {
+        return (best, row);
+    }
+
+    if have_tst
+    {
+        return (best, tst_row);
+    }
+
+    if last != best
+    {
+        filter_row(best, bpp, row, prev_row, try_row);
+    }
+
+    (best, try_row)
+}
+
+/* The Paeth predictor of the pixel from its left 'a', above 'b' and upper left
+ * 'c' neighbours.
+ */
+fn paeth_predictor(a: u8, b: u8, c: u8) -> u8
+{
+    let p = b as i32 - c as i32;
+    let pc = a as i32 - c as i32;
+
+    let pa = p.abs();
+    let pb = pc.abs();
+    let pc = (p + pc).abs();
+
+    match pa <= pb && pa <= pc {
+        true => a,
+        false => match pb <= pc {
+            true => b,
+            false => c,
+        },
+    }
+}
+
+/* The cost of writing 'row' according to 'heuristic'.  The computation may
+ * stop early once the cost is above 'limit'.
+ */
+fn row_cost(heuristic: PngFilterHeuristic, row: &[u8], limit: usize) -> usize
+{
+    match heuristic {
+        PngFilterHeuristic::SumAbsDiff => sum_abs_diff(row, limit),
+        PngFilterHeuristic::Entropy => entropy_bits(row),
+        PngFilterHeuristic::BruteForce => {
+            miniz_oxide::deflate::compress_to_vec(row, PNG_BRUTE_FORCE_LEVEL).len()
+        },
+    }
+}
+
+/* Sum of the distances from zero of the bytes, using anything >= 128 as
+ * negative numbers.
+ */
+fn sum_abs_diff(row: &[u8], limit: usize) -> usize
+{
+    let mut sum = 0;
+
+    for &v in row {
+        sum += match v < 128 {
+            true => v as usize,
+            false => 256 - v as usize,
+        };
+
+        if sum > limit  /* We are already worse, don't continue. */
+        {
+            break;
+        }
+    }
+
+    sum
+}
+
+/* Number of bits needed to code the bytes of the row with an order-0 entropy
+ * coder, rounded up.
+ */
+fn entropy_bits(row: &[u8]) -> usize
+{
+    let mut counts = [0usize; 256];
+
+    for &v in row {
+        counts[v as usize] += 1;
+    }
+
+    let n = row.len() as f64;
+    let bits: f64 = counts.iter()
+        .filter(|&&count| count != 0)
+        .map(|&count| {
+            let count = count as f64;
+            -count * (count / n).log2()
+        })
+        .sum();
+
+    bits.ceil() as usize
+}
+
+impl Png {
+    /* Set the filters which may be used for the rows of the image */
+    pub fn set_filter(&mut self, filters: PngFilter)
+    {
+        self.do_filter = filters;
+    }
+
+    /* Set how the filter of each row is chosen among the allowed ones */
+    pub fn set_filter_heuristic(&mut self, heuristic: PngFilterHeuristic)
+    {
+        self.filter_heuristic = heuristic;
+    }
+
+    pub fn get_filter_heuristic(&self) -> PngFilterHeuristic
+    {
+        self.filter_heuristic
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [PngFilterValue; 5] = [PngFilterValue::None, PngFilterValue::Sub, PngFilterValue::Up,
+                                          PngFilterValue::Avg, PngFilterValue::Paeth];
+
+    /* A small deterministic generator for the test rows */
+    struct Lcg(u32);
+
+    impl Lcg {
+        fn next(&mut self) -> u8
+        {
+            self.0 = self.0.wrapping_mul(1103515245).wrapping_add(12345);
+            (self.0 >> 16) as u8
+        }
+
+        /* A ramp with noise of 'amplitude', so that each filter wins some
+         * rows.
+         */
+        fn row(&mut self, len: usize, amplitude: u8) -> Vec<u8>
+        {
+            let base = self.next();
+            let slope = self.next() & 7;
+
+            (0..len).map(|i| {
+                let noise = match amplitude {
+                    0 => 0,
+                    _ => self.next() % amplitude,
+                };
+                base.wrapping_add((i as u8).wrapping_mul(slope)).wrapping_add(noise)
+            }).collect()
+        }
+    }
+
+    /* The masks of all the non empty sets of filters */
+    fn all_masks() -> impl Iterator<Item = PngFilter>
+    {
+        (1u8..32).map(|bits| PngFilter::from_bits_truncate(bits << 3))
+    }
+
+    /* The filter and the filtered row png_write_find_filter of libpng 1.6
+     * chose in minimum sum mode, before the choice moved to Rust: the filters
+     * are tried in the order of pngwutil.c, a filter replacing the best one
+     * when its sum is strictly lower, and the sum stops once above the best
+     * one.  An empty 'prev_row' is the zeroed first prev_row.
+     */
+    fn libpng_find_filter(filters: PngFilter, bpp: usize, row: &[u8], prev_row: &[u8]) -> (u8, Vec<u8>)
+    {
+        let row_bytes = row.len();
+        let zeros = vec![0u8; row_bytes];
+        let pp = match prev_row.is_empty() {
+            true => &zeros[..],
+            false => prev_row,
+        };
+        let distance = |v: u8| -> usize {
+            match v < 128 {
+                true => v as usize,
+                false => 256 - v as usize,
+            }
+        };
+
+        let filter_to_do = filters.bits();
+        let mut mins = usize::MAX - 256;
+        let mut best: (u8, Vec<u8>) = (0, row.to_vec());
+
+        if filter_to_do & 0x08 != 0 && filter_to_do != 0x08
+        {
+            mins = row.iter().map(|&v| distance(v)).sum();
+        }
+
+        for (mask, value) in [(0x10u8, 1u8), (0x20, 2), (0x40, 3), (0x80, 4)].iter() {
+            if filter_to_do & mask == 0
+            {
+                continue;
+            }
+
+            let mut dp = vec![0u8; row_bytes];
+            for i in 0..row_bytes {
+                let a = match i < bpp { true => 0, false => row[i - bpp] as i32 };
+                let c = match i < bpp { true => 0, false => pp[i - bpp] as i32 };
+                let b = pp[i] as i32;
+                let predictor = match value {
+                    1 => a,
+                    2 => b,
+                    3 => (a + b) / 2,
+                    _ => {
+                        let p = b - c;
+                        let pc = a - c;
+                        let (pa, pb, pc) = (p.abs(), pc.abs(), (p + pc).abs());
+                        match (pa <= pb && pa <= pc, pb <= pc) {
+                            (true, _) => a,
+                            (false, true) => b,
+                            (false, false) => c,
+                        }
+                    },
+                };
+                dp[i] = (row[i] as i32 - predictor) as u8;
+            }
+
+            if filter_to_do == *mask
+            {
+                return (*value, dp);
+            }
+
+            let mut sum = 0;
+            for (i, &v) in dp.iter().enumerate() {
+                sum += distance(v);
+                if i >= bpp && sum > mins
+                {
+                    break;
+                }
+            }
+
+            if sum < mins
+            {
+                mins = sum;
+                best = (*value, dp);
+            }
+        }
+
+        best
+    }
+
+    /* find_filter with buffers of the row length, 'tst_row' empty or not */
+    fn find(filters: PngFilter, heuristic: PngFilterHeuristic, bpp: usize, row: &[u8], prev_row: &[u8],
+            with_tst_row: bool) -> (PngFilterValue, Vec<u8>)
+    {
+        let mut try_row = vec![0xee; row.len()];
+        let mut tst_row = match with_tst_row {
+            true => vec![0xdd; row.len()],
+            false => Vec::new(),
+        };
+
+        let (filter, best_row) = find_filter(filters, heuristic, bpp, row, prev_row, &mut try_row, &mut tst_row);
+        (filter, best_row.to_vec())
+    }
+
+    #[test]
+    fn unfilter_undoes_filter()
+    {
+        let mut lcg = Lcg(1);
+
+        for &bpp in [1usize, 2, 3, 4, 6, 8].iter() {
+            for len in [bpp, 2 * bpp, 7 * bpp, 33 * bpp].iter() {
+                let row = lcg.row(*len, 255);
+                for prev_row in [Vec::new(), lcg.row(*len, 255)].iter() {
+                    for &filter in FILTERS.iter() {
+                        let mut filtered = vec![0u8; *len];
+                        filter_row(filter, bpp, &row, prev_row, &mut filtered);
+
+                        unfilter_row(filter, bpp, &mut filtered, prev_row);
+                        assert_eq!(filtered, row, "{:?} bpp {} length {}", filter, bpp, len);
+                    }
+                }
+            }
+        }
+    }
+
+    /* A row, its previous row and the rows of each filter, computed by hand,
+     * with the filter of the minimum sum of absolute differences.
+     */
+    struct Case {
+        bpp: usize,
+        row: &'static [u8],
+        prev_row: &'static [u8],
+        filtered: [&'static [u8]; 5],
+        sums: [usize; 5],
+        best: PngFilterValue,
+    }
+
+    const CASES: [Case; 3] = [
+        Case { bpp: 1, row: &[10, 20, 15, 200], prev_row: &[5, 30, 30, 100],
+               filtered: [&[10, 20, 15, 200], &[10, 10, 251, 185], &[5, 246, 241, 100],
+                          &[8, 0, 246, 143], &[5, 246, 251, 100]],
+               sums: [101, 96, 130, 131, 120], best: PngFilterValue::Sub },
+        /* The first row: Sub and Paeth tie, the first one tried is kept */
+        Case { bpp: 2, row: &[1, 2, 3, 4, 5, 6], prev_row: &[],
+               filtered: [&[1, 2, 3, 4, 5, 6], &[1, 2, 2, 2, 2, 2], &[1, 2, 3, 4, 5, 6],
+                          &[1, 2, 3, 3, 4, 4], &[1, 2, 2, 2, 2, 2]],
+               sums: [21, 11, 21, 17, 11], best: PngFilterValue::Sub },
+        /* A copy of the previous row */
+        Case { bpp: 3, row: &[200, 100, 50, 201, 99, 52], prev_row: &[200, 100, 50, 201, 99, 52],
+               filtered: [&[200, 100, 50, 201, 99, 52], &[200, 100, 50, 1, 255, 2], &[0, 0, 0, 0, 0, 0],
+                          &[100, 50, 25, 1, 0, 1], &[0, 0, 0, 0, 0, 0]],
+               sums: [412, 210, 0, 177, 0], best: PngFilterValue::Up },
+    ];
+
+    #[test]
+    fn filters_of_the_cases()
+    {
+        for (i, case) in CASES.iter().enumerate() {
+            for (&filter, &filtered) in FILTERS.iter().zip(case.filtered.iter()) {
+                let mut out = vec![0u8; case.row.len()];
+                filter_row(filter, case.bpp, case.row, case.prev_row, &mut out);
+                assert_eq!(out, filtered, "case {} {:?}", i, filter);
+
+                /* A single filter is used without computing its sum */
+                let expected = (filter, filtered.to_vec());
+                assert_eq!(find(filter.mask(), PngFilterHeuristic::SumAbsDiff, case.bpp, case.row,
+                                case.prev_row, false), expected, "case {}", i);
+            }
+
+            let sums: Vec<usize> = case.filtered.iter().map(|row| sum_abs_diff(row, usize::MAX)).collect();
+            assert_eq!(sums, case.sums, "case {}", i);
+
+            let best = (case.best, case.filtered[case.best as usize].to_vec());
+            for &with_tst_row in [false, true].iter() {
+                assert_eq!(find(PngFilter::all(), PngFilterHeuristic::SumAbsDiff, case.bpp, case.row,
+                                case.prev_row, with_tst_row), best, "case {}", i);
+            }
+        }
+    }
+
+    #[test]
+    fn filters_outside_the_mask_are_not_chosen()
+    {
+        let case = &CASES[0];
+        let masks = [
+            (PngFilter::UP | PngFilter::AVG, PngFilterValue::Up),
+            (PngFilter::AVG | PngFilter::PAETH, PngFilterValue::Paeth),
+            (PngFilter::NONE | PngFilter::UP | PngFilter::AVG | PngFilter::PAETH, PngFilterValue::None),
+            (PngFilter::FAST_FILTERS, PngFilterValue::Sub),
+            (PngFilter::empty(), PngFilterValue::None),
+        ];
+
+        for &(filters, filter) in masks.iter() {
+            let expected = (filter, case.filtered[filter as usize].to_vec());
+            assert_eq!(find(filters, PngFilterHeuristic::SumAbsDiff, case.bpp, case.row, case.prev_row, true),
+                       expected, "{:?}", filters);
+        }
+
+        /* Paeth beats Up on the first row when Sub is not allowed */
+        let case = &CASES[1];
+        assert_eq!(find(PngFilter::UP | PngFilter::PAETH, PngFilterHeuristic::SumAbsDiff, case.bpp, case.row,
+                        case.prev_row, true), (PngFilterValue::Paeth, case.filtered[4].to_vec()));
+    }
+
+    #[test]
+    fn minimum_sum_is_the_choice_of_libpng()
+    {
+        let mut lcg = Lcg(2);
+
+        for &bpp in [1usize, 2, 3, 4, 6, 8].iter() {
+            for &amplitude in [0u8, 3, 20, 255].iter() {
+                let len = bpp * (1 + (lcg.next() % 24) as usize);
+                let row = lcg.row(len, amplitude);
+
+                for prev_row in [Vec::new(), lcg.row(len, amplitude), row.clone()].iter() {
+                    for filters in all_masks() {
+                        let (value, filtered) = libpng_find_filter(filters, bpp, &row, prev_row);
+
+                        for &with_tst_row in [false, true].iter() {
+                            let (filter, best_row) = find(filters, PngFilterHeuristic::SumAbsDiff, bpp, &row,
+                                                          prev_row, with_tst_row);
+                            assert_eq!((filter as u8, &best_row), (value, &filtered),
+                                       "{:?} bpp {} row {:?} prev_row {:?}", filters, bpp, row, prev_row);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn entropy_and_brute_force_choose_their_minimum()
+    {
+        let mut lcg = Lcg(3);
+
+        for &heuristic in [PngFilterHeuristic::Entropy, PngFilterHeuristic::BruteForce].iter() {
+            for &(bpp, amplitude) in [(1usize, 0u8), (3, 5), (4, 40), (2, 255)].iter() {
+                let row = lcg.row(16 * bpp, amplitude);
+                let prev_row = lcg.row(16 * bpp, amplitude);
+
+                for filters in all_masks() {
+                    /* The first filter of the lowest cost among the allowed
+                     * ones, None first.
+                     */
+                    let mut expected: Option<(usize, PngFilterValue, Vec<u8>)> = None;
+                    for &filter in FILTERS.iter().filter(|filter| filters.contains(filter.mask())) {
+                        let mut out = vec![0u8; row.len()];
+                        filter_row(filter, bpp, &row, &prev_row, &mut out);
+
+                        let cost = row_cost(heuristic, &out, usize::MAX);
+                        if expected.as_ref().map(|best| cost < best.0).unwrap_or(true)
+                        {
+                            expected = Some((cost, filter, out));
+                        }
+                    }
+                    let (_, filter, out) = expected.unwrap();
+
+                    for &with_tst_row in [false, true].iter() {
+                        assert_eq!(find(filters, heuristic, bpp, &row, &prev_row, with_tst_row),
+                                   (filter, out.clone()), "{:?} {:?}", heuristic, filters);
+                    }
+                }
+            }
+        }
+
+        /* A constant row costs nothing to code */
+        assert_eq!(entropy_bits(&[7; 64]), 0);
+        assert_eq!(entropy_bits(&(0..=255).collect::<Vec<u8>>()), 8 * 256);
+    }
+
+    #[test]
+    fn c_entry_writes_the_filter_byte()
+    {
+        let case = &CASES[0];
+        let row_info = PngRowInfo::new(4, crate::PngColor::TYPE_GRAY, 8);
+        let mut row_buf = [&[0xff][..], case.row].concat();
+        let mut prev_row = [&[0xff][..], case.prev_row].concat();
+        let mut try_row = vec![0xee; 5];
+        let mut tst_row = vec![0xee; 5];
+
+        let mut png_ptr = Png::new();
+        png_ptr.set_filter(PngFilter::all());
+        png_ptr.row_buf = row_buf.as_mut_ptr() as CPtr;
+        png_ptr.prev_row = prev_row.as_mut_ptr() as CPtr;
+        png_ptr.try_row = try_row.as_mut_ptr() as CPtr;
+        png_ptr.tst_row = tst_row.as_mut_ptr() as CPtr;
+
+        let best_row = unsafe { png_rust_find_filter(&png_ptr, &row_info) };
+        assert!(best_row == png_ptr.try_row || best_row == png_ptr.tst_row);
+        assert_eq!(unsafe { slice::from_raw_parts(best_row as *const u8, 5) }, &[1, 10, 10, 251, 185]);
+
+        /* Only 'none': the row buffer, its filter byte set */
+        png_ptr.set_filter(PngFilter::NONE);
+        let best_row = unsafe { png_rust_find_filter(&png_ptr, &row_info) };
+        assert_eq!(best_row, png_ptr.row_buf);
+        assert_eq!(row_buf, [0, 10, 20, 15, 200]);
+    }
+
+    /* The C writer gives each row to png_write_find_filter, which must
+     * choose as libpng did.
+     */
+    #[cfg(feature = "c-tests")]
+    #[test]
+    fn png_write_find_filter_chooses_as_libpng()
+    {
+        use crate::chunk::ChunkReader;
+        use crate::PngColor;
+        use crate::PngInterlace;
+        use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+        let mut lcg = Lcg(4);
+        let formats = [(PngColor::TYPE_GRAY, 8u8, 1usize), (PngColor::TYPE_GRAY_ALPHA, 8, 2),
+                       (PngColor::TYPE_RGB, 8, 3), (PngColor::TYPE_RGB_ALPHA, 8, 4),
+                       (PngColor::TYPE_GRAY, 16, 2), (PngColor::TYPE_RGB, 16, 6),
+                       (PngColor::TYPE_RGB_ALPHA, 16, 8)];
+
+        for &(color_type, bit_depth, bpp) in formats.iter() {
+            let (width, height) = (13, 24);
+            let rows: Vec<Vec<u8>> = (0..height).map(|y| lcg.row(width * bpp, [0, 3, 20, 255][y % 4])).collect();
+
+            let png = crate::libpng::write_png(width as u32, height as u32, bit_depth, color_type,
+                                               PngInterlace::None, &[], &rows);
+            let zdata: Vec<u8> = ChunkReader::new(&png[..]).map(|chunk| chunk.unwrap())
+                .filter(|chunk| &chunk.name == b"IDAT")
+                .flat_map(|chunk| chunk.data)
+                .collect();
+            let filtered = decompress_to_vec_zlib(&zdata).unwrap();
+
+            let mut prev_row: &[u8] = &[];
+            for (y, row) in rows.iter().enumerate() {
+                let written = &filtered[y * (width * bpp + 1)..(y + 1) * (width * bpp + 1)];
+                let (value, expected) = libpng_find_filter(PngFilter::all(), bpp, row, prev_row);
+
+                assert_eq!((written[0], &written[1..]), (value, &expected[..]),
+                           "{:?} {} bits row {}", color_type, bit_depth, y);
+                prev_row = row;
+            }
+        }
+    }
+}
@@ -0,0 +1,201 @@
+/* The gamma arithmetic of the simplified API, the same as the one of png.c
+ * so that the results match those of libpng.
+ */
+
+pub(crate) const PNG_FP_1: u32 = 100000;
+pub(crate) const PNG_GAMMA_SRGB_INVERSE: u32 = 45455;
+const PNG_GAMMA_THRESHOLD_FIXED: u32 = 5000;
+
+/* The linear 16-bit value of each 8-bit sRGB encoded value */
+pub(crate) static PNG_SRGB_TABLE: [u16; 256] = [
+    0,20,40,60,80,99,119,139,
+    159,179,199,219,241,264,288,313,
+    340,367,396,427,458,491,526,562,
+    599,637,677,718,761,805,851,898,
+    947,997,1048,1101,1156,1212,1270,1330,
+    1391,1453,1517,1583,1651,1720,1790,1863,
+    1937,2013,2090,2170,2250,2333,2418,2504,
+    2592,2681,2773,2866,2961,3058,3157,3258,
+    3360,3464,3570,3678,3788,3900,4014,4129,
+    4247,4366,4488,4611,4736,4864,4993,5124,
+    5257,5392,5530,5669,5810,5953,6099,6246,
+    6395,6547,6700,6856,7014,7174,7335,7500,
+    7666,7834,8004,8177,8352,8528,8708,8889,
+    9072,9258,9445,9635,9828,10022,10219,10417,
+    10619,10822,11028,11235,11446,11658,11873,12090,
+    12309,12530,12754,12980,13209,13440,13673,13909,
+    14146,14387,14629,14874,15122,15371,15623,15878,
+    16135,16394,16656,16920,17187,17456,17727,18001,
+    18277,18556,18837,19121,19407,19696,19987,20281,
+    20577,20876,21177,21481,21787,22096,22407,22721,
+    23038,23357,23678,24002,24329,24658,24990,25325,
+    25662,26001,26344,26688,27036,27386,27739,28094,
+    28452,28813,29176,29542,29911,30282,30656,31033,
+    31412,31794,32179,32567,32957,33350,33745,34143,
+    34544,34948,35355,35764,36176,36591,37008,37429,
+    37852,38278,38706,39138,39572,40009,40449,40891,
+    41337,41785,42236,42690,43147,43606,44069,44534,
+    45002,45473,45947,46423,46903,47385,47871,48359,
+    48850,49344,49841,50341,50844,51349,51858,52369,
+    52884,53401,53921,54445,54971,55500,56032,56567,
+    57105,57646,58190,58737,59287,59840,60396,60955,
+    61517,62082,62650,63221,63795,64372,64952,65535,
+];
+
+/* The base and delta tables of srgb_from_linear */
+static PNG_SRGB_BASE: [u16; 512] = [
+    128,1782,3383,4644,5675,6564,7357,8074,
+    8732,9346,9921,10463,10977,11466,11935,12384,
+    12816,13233,13634,14024,14402,14769,15125,15473,
+    15812,16142,16466,16781,17090,17393,17690,17981,
+    18266,18546,18822,19093,19359,19621,19879,20133,
+    20383,20630,20873,21113,21349,21583,21813,22041,
+    22265,22487,22707,22923,23138,23350,23559,23767,
+    23972,24175,24376,24575,24772,24967,25160,25352,
+    25542,25730,25916,26101,26284,26465,26645,26823,
+    27000,27176,27350,27523,27695,27865,28034,28201,
+    28368,28533,28697,28860,29021,29182,29341,29500,
+    29657,29813,29969,30123,30276,30429,30580,30730,
+    30880,31028,31176,31323,31469,31614,31758,31902,
+    32045,32186,32327,32468,32607,32746,32884,33021,
+    33158,33294,33429,33564,33697,33831,33963,34095,
+    34226,34357,34486,34616,34744,34873,35000,35127,
+    35253,35379,35504,35629,35753,35876,35999,36122,
+    36244,36365,36486,36606,36726,36845,36964,37083,
+    37201,37318,37435,37551,37668,37783,37898,38013,
+    38127,38241,38354,38467,38580,38692,38803,38915,
+    39026,39136,39246,39356,39465,39574,39682,39790,
+    39898,40005,40112,40219,40325,40431,40537,40642,
+    40747,40851,40955,41059,41163,41266,41369,41471,
+    41573,41675,41777,41878,41979,42079,42179,42279,
+    42379,42478,42577,42676,42775,42873,42971,43068,
+    43165,43262,43359,43456,43552,43648,43743,43839,
+    43934,44028,44123,44217,44311,44405,44499,44592,
+    44685,44778,44870,44962,45054,45146,45238,45329,
+    45420,45511,45601,45692,45782,45872,45961,46051,
+    46140,46229,46318,46406,46494,46583,46670,46758,
+    46846,46933,47020,47107,47193,47280,47366,47452,
+    47538,47623,47709,47794,47879,47964,48048,48133,
+    48217,48301,48385,48468,48552,48635,48718,48801,
+    48884,48966,49048,49131,49213,49294,49376,49458,
+    49539,49620,49701,49782,49862,49943,50023,50103,
+    50183,50263,50342,50422,50501,50580,50659,50738,
+    50816,50895,50973,51051,51129,51207,51285,51362,
+    51439,51517,51594,51671,51747,51824,51900,51977,
+    52053,52129,52205,52280,52356,52432,52507,52582,
+    52657,52732,52807,52881,52956,53030,53104,53178,
+    53252,53326,53400,53473,53546,53620,53693,53766,
+    53839,53911,53984,54056,54129,54201,54273,54345,
+    54417,54489,54560,54632,54703,54774,54845,54916,
+    54987,55058,55129,55199,55269,55340,55410,55480,
+    55550,55620,55689,55759,55828,55898,55967,56036,
+    56105,56174,56243,56311,56380,56448,56517,56585,
+    56653,56721,56789,56857,56924,56992,57059,57127,
+    57194,57261,57328,57395,57462,57529,57595,57662,
+    57728,57795,57861,57927,57993,58059,58125,58191,
+    58256,58322,58387,58453,58518,58583,58648,58713,
+    58778,58843,58908,58972,59037,59101,59165,59230,
+    59294,59358,59422,59486,59549,59613,59677,59740,
+    59804,59867,59930,59993,60056,60119,60182,60245,
+    60308,60370,60433,60495,60558,60620,60682,60744,
+    60806,60868,60930,60992,61054,61115,61177,61238,
+    61300,61361,61422,61483,61544,61605,61666,61727,
+    61788,61848,61909,61969,62030,62090,62150,62211,
+    62271,62331,62391,62450,62510,62570,62630,62689,
+    62749,62808,62867,62927,62986,63045,63104,63163,
+    63222,63281,63340,63398,63457,63515,63574,63632,
+    63691,63749,63807,63865,63923,63981,64039,64097,
+    64155,64212,64270,64328,64385,64443,64500,64557,
+    64614,64672,64729,64786,64843,64900,64956,65013,
+    65070,65126,65183,65239,65296,65352,65409,65465,
+];
+
+static PNG_SRGB_DELTA: [u8; 512] = [
+    207,201,158,129,113,100,90,82,77,72,68,64,61,59,56,54,
+    52,50,49,47,46,45,43,42,41,40,39,39,38,37,36,36,
+    35,34,34,33,33,32,32,31,31,30,30,30,29,29,28,28,
+    28,27,27,27,27,26,26,26,25,25,25,25,24,24,24,24,
+    23,23,23,23,23,22,22,22,22,22,22,21,21,21,21,21,
+    21,20,20,20,20,20,20,20,20,19,19,19,19,19,19,19,
+    19,18,18,18,18,18,18,18,18,18,18,17,17,17,17,17,
+    17,17,17,17,17,17,16,16,16,16,16,16,16,16,16,16,
+    16,16,16,16,15,15,15,15,15,15,15,15,15,15,15,15,
+    15,15,15,15,14,14,14,14,14,14,14,14,14,14,14,14,
+    14,14,14,14,14,14,14,13,13,13,13,13,13,13,13,13,
+    13,13,13,13,13,13,13,13,13,13,13,13,13,13,12,12,
+    12,12,12,12,12,12,12,12,12,12,12,12,12,12,12,12,
+    12,12,12,12,12,12,12,12,12,12,12,12,11,11,11,11,
+    11,11,11,11,11,11,11,11,11,11,11,11,11,11,11,11,
+    11,11,11,11,11,11,11,11,11,11,11,11,11,11,11,11,
+    11,10,10,10,10,10,10,10,10,10,10,10,10,10,10,10,
+    10,10,10,10,10,10,10,10,10,10,10,10,10,10,10,10,
+    10,10,10,10,10,10,10,10,10,10,10,10,10,10,10,10,
+    10,9,9,9,9,9,9,9,9,9,9,9,9,9,9,9,
+    9,9,9,9,9,9,9,9,9,9,9,9,9,9,9,9,
+    9,9,9,9,9,9,9,9,9,9,9,9,9,9,9,9,
+    9,9,9,9,9,9,9,9,9,9,9,9,9,9,9,9,
+    9,8,8,8,8,8,8,8,8,8,8,8,8,8,8,8,
+    8,8,8,8,8,8,8,8,8,8,8,8,8,8,8,8,
+    8,8,8,8,8,8,8,8,8,8,8,8,8,8,8,8,
+    8,8,8,8,8,8,8,8,8,8,8,8,8,8,8,8,
+    8,8,8,8,8,8,8,8,8,8,8,8,8,8,8,8,
+    8,8,8,8,8,8,8,8,8,7,7,7,7,7,7,7,
+    7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,
+    7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,
+    7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,
+];
+
+/* The sRGB encoded 8-bit value of 'linear', a 16-bit linear value scaled by
+ * 255 (PNG_sRGB_FROM_LINEAR).
+ */
+pub(crate) fn srgb_from_linear(linear: u32) -> u8
+{
+    let i = (linear >> 15) as usize;
+    let base = PNG_SRGB_BASE[i] as u32;
+    let delta = PNG_SRGB_DELTA[i] as u32;
+
+    ((base + (((linear & 0x7fff) * delta) >> 12)) >> 8) as u8
+}
+
+/* Divide a 16-bit value by 257, rounded (PNG_DIV257) */
+pub(crate) fn div257(v16: u32) -> u32
+{
+    (v16 * 255 + 32895) >> 16
+}
+
+/* 1/a in fixed point, 0 on overflow */
+pub(crate) fn reciprocal(a: u32) -> u32
+{
+    let r = (1E10 / a as f64 + 0.5).floor();
+
+    match r <= i32::MAX as f64 {
+        true => r as u32,
+        false => 0,
+    }
+}
+
+/* Whether correcting with 'gamma' would change anything */
+pub(crate) fn gamma_significant(gamma: u32) -> bool
+{
+    !(PNG_FP_1 - PNG_GAMMA_THRESHOLD_FIXED..=PNG_FP_1 + PNG_GAMMA_THRESHOLD_FIXED).contains(&gamma)
+}
+
+/* Whether the file gamma 'gamma' is significantly different from sRGB, an
+ * unknown (zero) gamma is taken as sRGB.
+ */
+pub(crate) fn gamma_not_srgb(gamma: u32) -> bool
+{
+    match gamma < PNG_FP_1 {
+        true => gamma != 0 && gamma_significant((gamma * 11 + 2) / 5),
+        false => true,
+    }
+}
+
+/* Gamma correct the 16-bit 'value' with the exponent 'gamma' */
+pub(crate) fn gamma_16bit_correct(value: u32, gamma: u32) -> u32
+{
+    match value > 0 && value < 65535 {
+        true => (65535.0 * (value as f64 / 65535.0).powf(gamma as f64 * 0.00001) + 0.5).floor() as u32,
+        false => value,
+    }
+}
@@ -0,0 +1,2175 @@
+use crate::{Png, PngColor, PngFilter};
+use crate::png_info::{PngInfo, PngPaletteColor, PngXy};
+use crate::wutil::ChunkWriter;
+use crate::decoder::PngDecoder;
+use crate::source::PngReader;
+use crate::gamma::{PNG_FP_1, PNG_GAMMA_SRGB_INVERSE, PNG_SRGB_TABLE};
+use crate::gamma::{div257, gamma_16bit_correct, gamma_not_srgb, gamma_significant, reciprocal, srgb_from_linear};
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+/*******************************************************************************
+ *
+ *                               Png RUST
+ *
+ ******************************************************************************/
+
+/* The simplified API of png.h (png_image), reading a PNG file into a buffer in
+ * one of a few formats regardless of the format of the file, and writing such
+ * a buffer as a PNG file.
+ *
+ * The 8-bit formats are sRGB encoded, with an alpha channel which is not
+ * premultiplied, unless FLAG_ASSOCIATED_ALPHA is set.  The LINEAR formats have
+ * 16-bit components (in the byte order of the machine) with a linear encoding,
+ * premultiplied by the alpha channel.  The COLORMAP formats have one byte per
+ * pixel, an index in a color-map returned alongside, whose entries have the
+ * format without the COLORMAP flag.
+ */
+bitflags! {
+    pub struct PngFormat: u32 {
+        const FLAG_ALPHA            = 0x01; /* format with an alpha channel */
+        const FLAG_COLOR            = 0x02; /* color format: otherwise grayscale */
+        const FLAG_LINEAR           = 0x04; /* 2-byte channels else 1-byte */
+        const FLAG_COLORMAP         = 0x08; /* image data is color-mapped */
+        const FLAG_BGR              = 0x10; /* BGR colors, else order is RGB */
+        const FLAG_AFIRST           = 0x20; /* alpha channel comes first */
+        const FLAG_ASSOCIATED_ALPHA = 0x40; /* alpha channel is associated */
+
+        const GRAY = 0;
+        const GA   = 0x01;
+        const AG   = 0x01 | 0x20;
+        const RGB  = 0x02;
+        const BGR  = 0x02 | 0x10;
+        const RGBA = 0x02 | 0x01;
+        const ARGB = 0x02 | 0x01 | 0x20;
+        const BGRA = 0x02 | 0x10 | 0x01;
+        const ABGR = 0x02 | 0x10 | 0x01 | 0x20;
+
+        const LINEAR_Y         = 0x04;
+        const LINEAR_Y_ALPHA   = 0x04 | 0x01;
+        const LINEAR_RGB       = 0x04 | 0x02;
+        const LINEAR_RGB_ALPHA = 0x04 | 0x02 | 0x01;
+
+        const RGB_COLORMAP  = 0x02 | 0x08;
+        const BGR_COLORMAP  = 0x02 | 0x10 | 0x08;
+        const RGBA_COLORMAP = 0x02 | 0x01 | 0x08;
+        const ARGB_COLORMAP = 0x02 | 0x01 | 0x20 | 0x08;
+        const BGRA_COLORMAP = 0x02 | 0x10 | 0x01 | 0x08;
+        const ABGR_COLORMAP = 0x02 | 0x10 | 0x01 | 0x20 | 0x08;
+    }
+}
+
+bitflags! {
+    pub struct PngImageFlags: u32 {
+        /* The color endpoints of the file are not those of sRGB, set by
+         * begin_read.
+         */
+        const COLORSPACE_NOT_SRGB = 0x01;
+
+        /* Optimize for speed rather than size when writing */
+        const FAST = 0x02;
+
+        /* 16-bit files without gamma information are taken as sRGB encoded,
+         * not linear.
+         */
+        const SRGB_16BIT = 0x04;
+    }
+}
+
+impl PngFormat {
+    /* Number of channels of a sample: a pixel, or a color-map entry */
+    pub fn sample_channels(self) -> usize
+    {
+        ((self.bits & (PngFormat::FLAG_COLOR.bits | PngFormat::FLAG_ALPHA.bits)) + 1) as usize
+    }
+
+    /* Size in bytes of a component of a sample */
+    pub fn sample_component_size(self) -> usize
+    {
+        (((self.bits & PngFormat::FLAG_LINEAR.bits) >> 2) + 1) as usize
+    }
+
+    pub fn sample_size(self) -> usize
+    {
+        self.sample_channels() * self.sample_component_size()
+    }
+
+    /* Number of channels of a pixel in the image buffer */
+    pub fn pixel_channels(self) -> usize
+    {
+        match self.contains(PngFormat::FLAG_COLORMAP) {
+            true => 1,
+            false => self.sample_channels(),
+        }
+    }
+
+    pub fn pixel_component_size(self) -> usize
+    {
+        match self.contains(PngFormat::FLAG_COLORMAP) {
+            true => 1,
+            false => self.sample_component_size(),
+        }
+    }
+
+    pub fn pixel_size(self) -> usize
+    {
+        self.pixel_channels() * self.pixel_component_size()
+    }
+}
+
+/* An image read or written with the simplified API.  begin_read_* reads the
+ * header and sets the fields from the file, the application may then change
+ * format (and flags) before calling finish_read.  To write, the application
+ * creates the image with new (setting flags and colormap_entries as needed)
+ * and calls one of the write_to_* functions.
+ */
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub format: PngFormat,
+    pub flags: PngImageFlags,
+    /* The number of entries of the color-map: the most needed by the file on
+     * begin_read, the number used after finish_read.
+     */
+    pub colormap_entries: u32,
+    source: Option<Vec<u8>>, /* the PNG datastream until finish_read */
+}
+
+fn invalid_data(msg: &'static str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn invalid_input(msg: &'static str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+impl Image {
+    /* Read the header of the PNG file 'path' */
+    pub fn begin_read_from_file<P: AsRef<Path>>(path: P) -> io::Result<Image>
+    {
+        Image::begin_read_from_vec(PngReader::from_seek(fs::File::open(path)?)?.into_data())
+    }
+
+    /* Read the header of the PNG datastream in 'memory' */
+    pub fn begin_read_from_memory(memory: &[u8]) -> io::Result<Image>
+    {
+        Image::begin_read_from_vec(memory.to_vec())
+    }
+
+    /* Read the header of the PNG datastream read from 'reader', up to IEND */
+    pub fn begin_read<R: Read>(reader: R) -> io::Result<Image>
+    {
+        Image::begin_read_from_vec(PngReader::from_buf_read(io::BufReader::new(reader))?.into_data())
+    }
+
+    fn begin_read_from_vec(data: Vec<u8>) -> io::Result<Image>
+    {
+        let mut decoder = PngDecoder::new(&data);
+        let info = decoder.read_info().map_err(invalid_data)?;
+
+        let format = Image::png_format(info);
+        let colormap_entries = match info.color_type {
+            PngColor::TYPE_GRAY => 1u32 << info.bit_depth,
+            PngColor::TYPE_PALETTE => info.palette().len() as u32,
+            _ => 256,
+        };
+        let (width, height) = (info.width, info.height);
+
+        let mut flags = PngImageFlags::empty();
+        if format.contains(PngFormat::FLAG_COLOR) && !decoder.chromaticities_match_srgb()
+        {
+            flags.insert(PngImageFlags::COLORSPACE_NOT_SRGB);
+        }
+
+        Ok(Image {
+            width,
+            height,
+            format,
+            flags,
+            colormap_entries: colormap_entries.min(256),
+            source: Some(data),
+        })
+    }
+
+    /* The format closest to the one of the file */
+    fn png_format(info: &PngInfo) -> PngFormat
+    {
+        let mut format = PngFormat::GRAY;
+
+        if info.color_type.contains(PngColor::MASK_COLOR)
+        {
+            format |= PngFormat::FLAG_COLOR;
+        }
+
+        if info.color_type.contains(PngColor::MASK_ALPHA) || info.trans().is_some()
+        {
+            format |= PngFormat::FLAG_ALPHA;
+        }
+
+        if info.bit_depth == 16
+        {
+            format |= PngFormat::FLAG_LINEAR;
+        }
+
+        if info.color_type.contains(PngColor::MASK_PALETTE)
+        {
+            format |= PngFormat::FLAG_COLORMAP;
+        }
+
+        format
+    }
+
+    /* The minimum row stride, in components */
+    pub fn row_stride(&self) -> usize
+    {
+        self.width as usize * self.format.pixel_channels()
+    }
+
+    /* The size in bytes of the buffer for rows of 'row_stride' components */
+    pub fn buffer_size(&self, row_stride: usize) -> usize
+    {
+        self.format.pixel_component_size() * self.height as usize * row_stride
+    }
+
+    /* The size in bytes of the buffer with the minimum row stride */
+    pub fn size(&self) -> usize
+    {
+        self.buffer_size(self.row_stride())
+    }
+
+    /* The size in bytes of the color-map of colormap_entries entries */
+    pub fn colormap_size(&self) -> usize
+    {
+        self.format.sample_size() * self.colormap_entries as usize
+    }
+
+    /* Read the image into 'buffer' in 'format'.  'row_stride' is the distance
+     * between rows in components, 0 for row_stride(), negative for an image
+     * stored bottom-up.  'background' is the color the alpha channel of the
+     * file is composed on when 'format' has no alpha channel; if it is None
+     * the image is composed on the contents of 'buffer'.  For the COLORMAP
+     * formats, 'colormap' receives the color-map (colormap_size() bytes) and
+     * a background is then required to remove an alpha channel.
+     *
+     * The file is released, finish_read can only be called once.
+     */
+    pub fn finish_read(&mut self, background: Option<PngPaletteColor>, buffer: &mut [u8],
+                       row_stride: isize, colormap: Option<&mut [u8]>) -> io::Result<()>
+    {
+        let channels = self.format.pixel_channels();
+
+        if self.width as usize > i32::MAX as usize / channels
+        {
+            return Err(invalid_input("png_image_finish_read: row_stride too large"));
+        }
+
+        let min_stride = self.row_stride();
+        let row_stride = match row_stride {
+            0 => min_stride as isize,
+            _ => row_stride,
+        };
+        let check = row_stride.unsigned_abs();
+
+        if self.source.is_none() || check < min_stride
+        {
+            return Err(invalid_input("png_image_finish_read: invalid argument"));
+        }
+
+        if buffer.len() < self.buffer_size(check)
+        {
+            return Err(invalid_input("png_image_finish_read: image too large"));
+        }
+
+        let colormap = match self.format.contains(PngFormat::FLAG_COLORMAP) {
+            true => match colormap {
+                Some(colormap) if self.colormap_entries > 0 => Some(colormap),
+                _ => return Err(invalid_input("png_image_finish_read[color-map]: no color-map")),
+            },
+            false => None,
+        };
+
+        let source = self.source.take().unwrap();
+        let mut decoder = PngDecoder::new(&source);
+        let rows = decoder.read_image().map_err(invalid_data)?;
+        let info = decoder.into_info();
+        let read = ImageRead::new(self, &info, rows);
+
+        let row_bytes = check * self.format.pixel_component_size();
+        let out = OutRows {
+            buffer,
+            row_bytes,
+            bottom_up: row_stride < 0,
+            height: self.height as usize,
+        };
+
+        match colormap {
+            Some(colormap) => read.read_colormapped(self, background, out, colormap),
+            None => {
+                read.read_direct(self.format, background, out);
+                Ok(())
+            },
+        }
+    }
+}
+
+/* The chromaticities of sRGB, written with linear 16-bit files */
+const PNG_SRGB_XY: PngXy = PngXy {
+    red_x: 64000,
+    red_y: 33000,
+    green_x: 30000,
+    green_y: 60000,
+    blue_x: 15000,
+    blue_y: 6000,
+    white_x: 31270,
+    white_y: 32900,
+};
+
+impl Image {
+    /* An image of 'width' by 'height' pixels in 'format', to be written */
+    pub fn new(width: u32, height: u32, format: PngFormat) -> Image
+    {
+        Image {
+            width,
+            height,
+            format,
+            flags: PngImageFlags::empty(),
+            colormap_entries: 0,
+            source: None,
+        }
+    }
+
+    /* Write the image in 'buffer' to the file 'path', see write_to.  Nothing
+     * is left at 'path' if the image cannot be written.
+     */
+    pub fn write_to_file<P: AsRef<Path>>(&mut self, path: P, convert_to_8bit: bool, buffer: &[u8],
+                                         row_stride: isize, colormap: Option<&[u8]>) -> io::Result<()>
+    {
+        let data = self.write_to_vec(convert_to_8bit, buffer, row_stride, colormap)?;
+
+        fs::write(path, data)
+    }
+
+    /* Write the image in 'buffer' to memory, see write_to */
+    pub fn write_to_vec(&mut self, convert_to_8bit: bool, buffer: &[u8], row_stride: isize,
+                        colormap: Option<&[u8]>) -> io::Result<Vec<u8>>
+    {
+        let mut data = Vec::new();
+
+        self.write_to(&mut data, convert_to_8bit, buffer, row_stride, colormap)?;
+        Ok(data)
+    }
+
+    /* Write the image in 'buffer', in 'format', as a PNG datastream.
+     * 'row_stride' is as for finish_read.  The LINEAR formats are written as
+     * 16-bit files, without the premultiplication, unless 'convert_to_8bit' is
+     * set: they are then written as 8-bit sRGB.  The COLORMAP formats are
+     * written as palette images of the first colormap_entries entries (at most
+     * 256) of 'colormap', in the format without the COLORMAP flag.
+     *
+     * The chunks other than IDAT are those png_image_write_to_memory writes
+     * with the same arguments, and the IDAT chunks hold the same filtered
+     * rows, but the bytes of the IDAT chunks differ: the rows are compressed
+     * by miniz_oxide, not by zlib, and split in other chunks.  Unlike C,
+     * convert_to_8bit has no effect on the 8-bit formats.
+     */
+    pub fn write_to<W: Write>(&mut self, writer: &mut W, convert_to_8bit: bool, buffer: &[u8],
+                              row_stride: isize, colormap: Option<&[u8]>) -> io::Result<()>
+    {
+        let format = self.format;
+        let colormapped = format.contains(PngFormat::FLAG_COLORMAP);
+        let linear = !colormapped && format.contains(PngFormat::FLAG_LINEAR);
+        let alpha = !colormapped && format.contains(PngFormat::FLAG_ALPHA);
+        let write_16bit = linear && !convert_to_8bit;
+
+        /* Check the row stride and the size of the image */
+        let channels = format.pixel_channels();
+
+        if self.width as usize > 0x7fffffff / channels
+        {
+            return Err(invalid_input("png_image_write: image row stride too large"));
+        }
+
+        let min_stride = self.row_stride();
+        let row_stride = match row_stride {
+            0 => min_stride as isize,
+            _ => row_stride,
+        };
+        let check = row_stride.unsigned_abs();
+
+        if check < min_stride
+        {
+            return Err(invalid_input("png_image_write: supplied row stride too small"));
+        }
+
+        if self.height as u64 * min_stride as u64 > 0xffffffff
+        {
+            return Err(invalid_input("png_image_write: memory image too large"));
+        }
+
+        if buffer.len() < self.buffer_size(check)
+        {
+            return Err(invalid_input("png_image_write: invalid argument"));
+        }
+
+        let unsupported = PngFormat::FLAG_COLOR | PngFormat::FLAG_LINEAR | PngFormat::FLAG_ALPHA |
+            PngFormat::FLAG_COLORMAP | PngFormat::FLAG_BGR | PngFormat::FLAG_AFIRST;
+
+        if !(format - unsupported).is_empty()
+        {
+            return Err(invalid_input("png_write_image: unsupported transformation"));
+        }
+
+        let mut info = PngInfo::new();
+        info.width = self.width;
+        info.height = self.height;
+
+        match colormapped {
+            true => {
+                let colormap = match colormap {
+                    Some(colormap) if self.colormap_entries > 0 => colormap,
+                    _ => return Err(invalid_input("png_image_write: no color-map for color-mapped image")),
+                };
+                let entries = self.colormap_entries.min(256);
+
+                if colormap.len() < format.sample_size() * entries as usize
+                {
+                    return Err(invalid_input("png_image_write: no color-map for color-mapped image"));
+                }
+
+                info.bit_depth = match entries {
+                    17..=256 => 8,
+                    5..=16 => 4,
+                    3..=4 => 2,
+                    _ => 1,
+                };
+                info.color_type = PngColor::TYPE_PALETTE;
+                self.colormap_entries = entries;
+                self.set_palette(&mut info, colormap);
+            },
+            false => {
+                info.bit_depth = match write_16bit {
+                    true => 16,
+                    false => 8,
+                };
+
+                if format.contains(PngFormat::FLAG_COLOR)
+                {
+                    info.color_type |= PngColor::MASK_COLOR;
+                }
+
+                if format.contains(PngFormat::FLAG_ALPHA)
+                {
+                    info.color_type |= PngColor::MASK_ALPHA;
+                }
+            },
+        }
+
+        /* The color space: linear with the sRGB end points for 16-bit files,
+         * otherwise sRGB, or just the sRGB gamma if the colors are not sRGB.
+         */
+        let srgb = !self.flags.contains(PngImageFlags::COLORSPACE_NOT_SRGB);
+
+        if write_16bit
+        {
+            info.set_gamma(PNG_FP_1);
+
+            if srgb
+            {
+                info.set_chromaticities(PNG_SRGB_XY);
+            }
+        }
+        else if srgb
+        {
+            info.set_srgb_intent(0 /* perceptual */);
+        }
+        else
+        {
+            info.set_gamma(PNG_GAMMA_SRGB_INVERSE);
+        }
+
+        let mut png = Png::new();
+        let mut writer = ChunkWriter::new(writer);
+
+        png.write_info(&mut writer, &mut info)?;
+
+        /* The transformations are set after the header is written, as in C */
+        if write_16bit && cfg!(target_endian = "little")
+        {
+            png.set_swap();
+        }
+
+        if !colormapped && format.contains(PngFormat::FLAG_COLOR | PngFormat::FLAG_BGR)
+        {
+            png.set_bgr();
+        }
+
+        if alpha && format.contains(PngFormat::FLAG_AFIRST)
+        {
+            png.set_swap_alpha();
+        }
+
+        if colormapped && self.colormap_entries <= 16
+        {
+            png.set_packing();
+        }
+
+        if self.flags.contains(PngImageFlags::FAST)
+        {
+            png.set_filter(PngFilter::NONE);
+            png.set_compression_level(3);
+        }
+
+        let row_bytes = check * format.pixel_component_size();
+        let used_bytes = min_stride * format.pixel_component_size();
+        let height = self.height as usize;
+        let input_row = |y: usize| {
+            let y = match row_stride < 0 {
+                true => height - 1 - y,
+                false => y,
+            };
+
+            &buffer[y * row_bytes..y * row_bytes + used_bytes]
+        };
+
+        /* 16-bit input with an alpha channel or written as 8-bit is converted
+         * first, the rest is in a format the writer takes as it is.
+         */
+        match linear && (alpha || !write_16bit) {
+            true => {
+                let mut local_row = vec![0; png.write_row_bytes()?];
+
+                for y in 0..height {
+                    match write_16bit {
+                        true => self.unpremultiply_row_16bit(input_row(y), &mut local_row),
+                        false => self.unpremultiply_row_8bit(input_row(y), &mut local_row),
+                    }
+
+                    png.write_row(&mut writer, &local_row)?;
+                }
+            },
+            false => {
+                for y in 0..height {
+                    png.write_row(&mut writer, input_row(y))?;
+                }
+            },
+        }
+
+        png.write_end(&mut writer, Some(&mut info))?;
+        writer.flush()
+    }
+
+    /* The position of the alpha channel in a linear pixel, and the first
+     * color (or gray) channel.
+     */
+    fn linear_layout(&self) -> (usize, usize, usize)
+    {
+        let channels = match self.format.contains(PngFormat::FLAG_COLOR) {
+            true => 3,
+            false => 1,
+        };
+
+        match self.format.contains(PngFormat::FLAG_AFIRST) {
+            true => (channels, 0, 1),
+            false => (channels, channels, 0),
+        }
+    }
+
+    /* Remove the premultiplication of a row with an alpha channel, keeping
+     * 16-bit linear components (png_write_image_16bit).
+     */
+    fn unpremultiply_row_16bit(&self, input: &[u8], output: &mut [u8])
+    {
+        let (channels, aindex, first) = self.linear_layout();
+        let pixels = input.chunks_exact(2 * (channels + 1)).zip(output.chunks_exact_mut(2 * (channels + 1)));
+
+        for (in_pixel, out_pixel) in pixels {
+            let alpha = linear_component(in_pixel, aindex);
+            let reciprocal = match alpha > 0 && alpha < 65535 {
+                true => ((0xffff << 15) + (alpha >> 1)) / alpha,
+                false => 0,
+            };
+
+            set_linear_component(out_pixel, aindex, alpha);
+
+            for c in first..first + channels {
+                let component = linear_component(in_pixel, c);
+
+                /* 0/0 is written as 1, like any component above alpha */
+                let component = match component {
+                    _ if component >= alpha => 65535,
+                    1..=65535 if alpha < 65535 => (component * reciprocal + 16384) >> 15,
+                    _ => component,
+                };
+
+                set_linear_component(out_pixel, c, component);
+            }
+        }
+    }
+
+    /* Convert a row of 16-bit linear components to 8-bit sRGB, removing the
+     * premultiplication if there is an alpha channel (png_write_image_8bit).
+     */
+    fn unpremultiply_row_8bit(&self, input: &[u8], output: &mut [u8])
+    {
+        if !self.format.contains(PngFormat::FLAG_ALPHA)
+        {
+            for (component, out) in input.chunks_exact(2).zip(output.iter_mut()) {
+                *out = srgb_from_linear(linear_component(component, 0) * 255);
+            }
+
+            return;
+        }
+
+        let (channels, aindex, first) = self.linear_layout();
+        let pixels = input.chunks_exact(2 * (channels + 1)).zip(output.chunks_exact_mut(channels + 1));
+
+        for (in_pixel, out_pixel) in pixels {
+            let alpha = linear_component(in_pixel, aindex);
+            let alphabyte = div257(alpha);
+            let reciprocal = match alphabyte > 0 && alphabyte < 255 {
+                true => unpremultiply_reciprocal(alpha),
+                false => 0,
+            };
+
+            out_pixel[aindex] = alphabyte as u8;
+
+            for (c, out) in out_pixel.iter_mut().enumerate().skip(first).take(channels) {
+                *out = unpremultiply(linear_component(in_pixel, c), alpha, reciprocal);
+            }
+        }
+    }
+
+    /* Set the PLTE and tRNS of 'info' from the color-map of colormap_entries
+     * entries (png_image_set_PLTE): linear entries are converted to sRGB.
+     */
+    fn set_palette(&self, info: &mut PngInfo, colormap: &[u8])
+    {
+        let format = self.format;
+        let entries = self.colormap_entries as usize;
+        let channels = format.sample_channels();
+        let afirst = match format.contains(PngFormat::FLAG_AFIRST | PngFormat::FLAG_ALPHA) {
+            true => 1,
+            false => 0,
+        };
+        let bgr = match format.contains(PngFormat::FLAG_BGR) {
+            true => 2,
+            false => 0,
+        };
+
+        let mut palette = vec![PngPaletteColor::default(); entries];
+        let mut trans = [255u8; 256];
+        let mut num_trans = 0;
+
+        for (i, color) in palette.iter_mut().enumerate() {
+            match format.contains(PngFormat::FLAG_LINEAR) {
+                true => {
+                    let entry = &colormap[2 * i * channels..2 * (i + 1) * channels];
+
+                    match channels {
+                        1 | 3 => {
+                            let encode = |c: usize| srgb_from_linear(255 * linear_component(entry, c));
+
+                            *color = match channels {
+                                3 => PngPaletteColor { red: encode(bgr), green: encode(1), blue: encode(2 ^ bgr) },
+                                _ => {
+                                    let gray = encode(0);
+                                    PngPaletteColor { red: gray, green: gray, blue: gray }
+                                },
+                            };
+                        },
+                        _ => {
+                            let alpha = linear_component(entry, match afirst {
+                                1 => 0,
+                                _ => channels - 1,
+                            });
+                            let alphabyte = div257(alpha);
+                            let reciprocal = match alphabyte > 0 && alphabyte < 255 {
+                                true => unpremultiply_reciprocal(alpha),
+                                false => 0,
+                            };
+                            let encode = |c: usize| unpremultiply(linear_component(entry, afirst + c), alpha, reciprocal);
+
+                            trans[i] = alphabyte as u8;
+                            if alphabyte < 255
+                            {
+                                num_trans = i + 1;
+                            }
+
+                            *color = match channels {
+                                4 => PngPaletteColor { red: encode(bgr), green: encode(1), blue: encode(2 ^ bgr) },
+                                _ => {
+                                    let gray = encode(0);
+                                    PngPaletteColor { red: gray, green: gray, blue: gray }
+                                },
+                            };
+                        },
+                    }
+                },
+                false => {
+                    let entry = &colormap[i * channels..(i + 1) * channels];
+
+                    match channels {
+                        4 => trans[i] = entry[match afirst {
+                            1 => 0,
+                            _ => 3,
+                        }],
+                        2 => trans[i] = entry[1 ^ afirst],
+                        _ => (),
+                    }
+
+                    if trans[i] < 255
+                    {
+                        num_trans = i + 1;
+                    }
+
+                    *color = match channels {
+                        3 | 4 => PngPaletteColor {
+                            red: entry[afirst + bgr],
+                            green: entry[afirst + 1],
+                            blue: entry[afirst + (2 ^ bgr)],
+                        },
+                        _ => PngPaletteColor { red: entry[afirst], green: entry[afirst], blue: entry[afirst] },
+                    };
+                },
+            }
+        }
+
+        info.set_palette(&palette);
+
+        if num_trans > 0
+        {
+            info.set_trans(&trans[..num_trans], None);
+        }
+    }
+}
+
+/* The 16-bit component 'c' of a pixel of a linear buffer */
+fn linear_component(pixel: &[u8], c: usize) -> u32
+{
+    u16::from_ne_bytes([pixel[2 * c], pixel[2 * c + 1]]) as u32
+}
+
+fn set_linear_component(pixel: &mut [u8], c: usize, value: u32)
+{
+    pixel[2 * c..2 * c + 2].copy_from_slice(&(value as u16).to_ne_bytes());
+}
+
+/* 255*65535/alpha with 7 bits of fraction (UNP_RECIPROCAL) */
+fn unpremultiply_reciprocal(alpha: u32) -> u32
+{
+    (((0xffff * 0xff) << 7) + (alpha >> 1)) / alpha
+}
+
+/* The 8-bit sRGB value of a linear 'component' premultiplied by 'alpha'
+ * (png_unpremultiply).  Components above alpha, and the components of pixels
+ * with an alpha which is 0 in 8 bits, are written as 255 rather than
+ * introducing spurious colors.
+ */
+fn unpremultiply(component: u32, alpha: u32, reciprocal: u32) -> u8
+{
+    if component >= alpha || alpha < 128
+    {
+        return 255;
+    }
+
+    match component {
+        0 => 0,
+        /* alpha/257 rounds to 255 from 65407 */
+        _ if alpha < 65407 => srgb_from_linear((component * reciprocal + 64) >> 7),
+        _ => srgb_from_linear(component * 255),
+    }
+}
+
+/* The rows of the application buffer */
+struct OutRows<'a> {
+    buffer: &'a mut [u8],
+    row_bytes: usize,
+    bottom_up: bool,
+    height: usize,
+}
+
+impl OutRows<'_> {
+    fn row(&mut self, y: usize) -> &mut [u8]
+    {
+        let y = match self.bottom_up {
+            true => self.height - 1 - y,
+            false => y,
+        };
+
+        &mut self.buffer[y * self.row_bytes..(y + 1) * self.row_bytes]
+    }
+}
+
+/* How color-map entries and values are encoded (P_* in pngread.c) */
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Encoding {
+    File,    /* 8-bit values with the file gamma */
+    Srgb,    /* 8-bit sRGB values */
+    Linear,  /* 16-bit linear values */
+    Linear8, /* 8-bit linear values, scaled by 257 to make 16-bit ones */
+}
+
+/* How the rows are turned into color-map indexes (PNG_CMAP_* in pngread.c) */
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum CmapProcessing {
+    None,     /* the data are the indexes */
+    Ga,       /* 8-bit gray and alpha, see make_ga_colormap */
+    Trans,    /* 8-bit gray and alpha 0 or 255, entry 254 is transparent */
+    Rgb,      /* 8-bit RGB, in the 6x6x6 cube */
+    RgbAlpha, /* 8-bit RGBA, the cube, a background and 27 half-alpha entries */
+}
+
+/* The data a color-mapped read gets from the file */
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum CmapData {
+    Index,          /* the file samples, or palette indexes */
+    FileGray(u32),  /* gray with the file encoding, composed on a P_FILE gray */
+    Srgb { color: bool, alpha: bool, background: Option<[u32; 3]> },
+}
+
+const PNG_CMAP_TRANS_BACKGROUND: u8 = 254;
+const PNG_CMAP_GA_BACKGROUND: u8 = 231;
+const PNG_CMAP_RGB_ALPHA_BACKGROUND: u8 = 216;
+
+/* Exact PNG_DIV51: 0..5 for the values 0..255 */
+fn div51(v8: u32) -> u32
+{
+    (v8 * 5 + 130) >> 8
+}
+
+/* The index of an 8-bit sRGB color in the 6x6x6 cube */
+fn rgb_index(r: u32, g: u32, b: u32) -> u8
+{
+    (6 * (6 * div51(r) + div51(g)) + div51(b)) as u8
+}
+
+/* Premultiply the 16-bit 'value' by the 16-bit 'alpha' */
+fn premultiply(value: u32, alpha: u32) -> u32
+{
+    match alpha {
+        65535 => value,
+        0 => 0,
+        _ => (value * alpha + 32767) / 65535,
+    }
+}
+
+/* A pixel of the file: its components, the same when gray, and alpha as
+ * 16-bit file values.
+ */
+#[derive(Clone, Copy)]
+struct Pixel {
+    c: [u32; 3],
+    alpha: u32,
+}
+
+impl Pixel {
+    fn is_gray(&self) -> bool
+    {
+        self.c[0] == self.c[1] && self.c[1] == self.c[2]
+    }
+}
+
+/* The decoded file and its conversion to the formats of the simplified API */
+struct ImageRead<'a> {
+    info: &'a PngInfo,
+    rows: Vec<u8>,
+    gamma: u32,                 /* file gamma, defaulted as libpng does here */
+    file_encoding: Encoding,    /* of the 8-bit file values */
+    gamma_to_linear: u32,
+    linear_table: Vec<u16>,     /* linear value of each (scaled) file value */
+    encode_table: Vec<u8>,      /* 8-bit sRGB value of each file value */
+    format: PngFormat,
+}
+
+impl<'a> ImageRead<'a> {
+    fn new(image: &Image, info: &'a PngInfo, rows: Vec<u8>) -> ImageRead<'a>
+    {
+        /* The gamma of cICP, sRGB or gAMA, by their precedence.  Without any
+         * the 16-bit files are linear unless the image flags say otherwise.
+         */
+        let gamma = match info.file_gamma() {
+            Some(gamma) => gamma,
+            None => match info.bit_depth == 16 && !image.flags.contains(PngImageFlags::SRGB_16BIT) {
+                true => PNG_FP_1,
+                false => PNG_GAMMA_SRGB_INVERSE,
+            },
+        };
+        let gamma_to_linear = reciprocal(gamma);
+        let file_encoding = match (gamma_significant(gamma), gamma_not_srgb(gamma)) {
+            (false, _) => Encoding::Linear8,
+            (true, true) => Encoding::File,
+            (true, false) => Encoding::Srgb,
+        };
+
+        /* The gamma corrections, for each possible file value: 256 of them
+         * (the value divided by 257) for the files of at most 8 bits.
+         */
+        let depth16 = info.bit_depth == 16;
+        let values: Box<dyn Iterator<Item = u32>> = match depth16 {
+            true => Box::new(0..65536u32),
+            false => Box::new((0..256u32).map(|v| v * 257)),
+        };
+        let encode_gamma = (1E15 / gamma as f64 / 220000.0 + 0.5).floor();
+        let mut linear_table = Vec::new();
+        let mut encode_table = Vec::new();
+
+        for v in values {
+            linear_table.push(match gamma_significant(gamma) {
+                true => gamma_16bit_correct(v, gamma_to_linear) as u16,
+                false => v as u16,
+            });
+            encode_table.push(match gamma_not_srgb(gamma) {
+                true => (255.0 * (v as f64 / 65535.0).powf(encode_gamma * 0.00001) + 0.5).floor() as u8,
+                false => div257(v) as u8,
+            });
+        }
+
+        ImageRead {
+            info,
+            rows,
+            gamma,
+            file_encoding,
+            gamma_to_linear,
+            linear_table,
+            encode_table,
+            format: image.format,
+        }
+    }
+
+    fn table_index(&self, v16: u32) -> usize
+    {
+        match self.info.bit_depth == 16 {
+            true => v16 as usize,
+            false => (v16 >> 8) as usize,
+        }
+    }
+
+    /* The linear 16-bit value of a file value */
+    fn linear(&self, v16: u32) -> u32
+    {
+        self.linear_table[self.table_index(v16)] as u32
+    }
+
+    /* The 8-bit sRGB value of a file value */
+    fn encode8(&self, v16: u32) -> u32
+    {
+        self.encode_table[self.table_index(v16)] as u32
+    }
+
+    /* The 8-bit sRGB value of a 16-bit linear value, as libpng encodes for
+     * the screen.
+     */
+    fn linear_to_8(linear: u32) -> u32
+    {
+        (255.0 * (linear as f64 / 65535.0).powf(PNG_GAMMA_SRGB_INVERSE as f64 * 0.00001) + 0.5).floor() as u32
+    }
+
+    /* The linear gray of linear red, green and blue values */
+    fn gray_linear(r: u32, g: u32, b: u32) -> u32
+    {
+        (6968 * r + 23434 * g + 2366 * b + 16384) >> 15
+    }
+
+    /* The sample 'c' of pixel 'x' of row 'y', as in the file */
+    fn sample(&self, y: usize, x: usize, c: usize) -> u32
+    {
+        let info = self.info;
+        let row = &self.rows[y * info.rowbytes..(y + 1) * info.rowbytes];
+        let channels = info.channels as usize;
+
+        match info.bit_depth {
+            16 => {
+                let i = (x * channels + c) * 2;
+                ((row[i] as u32) << 8) | row[i + 1] as u32
+            },
+            8 => row[x * channels + c] as u32,
+            depth => {
+                let depth = depth as usize;
+                let shift = 8 - depth - ((x * depth) & 7);
+                ((row[(x * depth) >> 3] >> shift) as u32) & ((1 << depth) - 1)
+            },
+        }
+    }
+
+    /* Pixel 'x' of row 'y', with 16-bit values */
+    fn pixel(&self, y: usize, x: usize) -> Pixel
+    {
+        let info = self.info;
+        let scale = match info.bit_depth {
+            1 => 65535,
+            2 => 0x5555,
+            4 => 0x1111,
+            8 => 257,
+            _ => 1,
+        };
+
+        match info.color_type {
+            PngColor::TYPE_PALETTE => {
+                let index = self.sample(y, x, 0) as usize;
+                let palette = info.palette();
+                let (trans, _) = info.trans().unwrap_or((&[], Default::default()));
+                let entry = palette.get(index).copied().unwrap_or_default();
+                let alpha = trans.get(index).copied().unwrap_or(255) as u32;
+
+                Pixel {
+                    c: [entry.red as u32 * 257, entry.green as u32 * 257, entry.blue as u32 * 257],
+                    alpha: alpha * 257,
+                }
+            },
+            PngColor::TYPE_GRAY | PngColor::TYPE_GRAY_ALPHA => {
+                let gray = self.sample(y, x, 0);
+                let alpha = match (info.color_type == PngColor::TYPE_GRAY_ALPHA, info.trans()) {
+                    (true, _) => self.sample(y, x, 1) * scale,
+                    (false, Some((_, color))) if color.gray as u32 == gray => 0,
+                    (false, _) => 65535,
+                };
+
+                Pixel { c: [gray * scale; 3], alpha }
+            },
+            _ => {
+                let c = [self.sample(y, x, 0), self.sample(y, x, 1), self.sample(y, x, 2)];
+                let alpha = match (info.color_type == PngColor::TYPE_RGB_ALPHA, info.trans()) {
+                    (true, _) => self.sample(y, x, 3) * scale,
+                    (false, Some((_, color))) if color.red as u32 == c[0] &&
+                        color.green as u32 == c[1] && color.blue as u32 == c[2] => 0,
+                    (false, _) => 65535,
+                };
+
+                Pixel { c: [c[0] * scale, c[1] * scale, c[2] * scale], alpha }
+            },
+        }
+    }
+
+    /* The linear components of 'pixel', the gray in the first one when
+     * 'color' is false.
+     */
+    fn linear_pixel(&self, pixel: &Pixel, color: bool) -> [u32; 3]
+    {
+        let linear = [self.linear(pixel.c[0]), self.linear(pixel.c[1]), self.linear(pixel.c[2])];
+
+        match color || pixel.is_gray() {
+            true => linear,
+            false => [ImageRead::gray_linear(linear[0], linear[1], linear[2]); 3],
+        }
+    }
+
+    /* The 8-bit sRGB components of 'pixel', not premultiplied */
+    fn srgb_pixel(&self, pixel: &Pixel, color: bool) -> [u32; 3]
+    {
+        match color || pixel.is_gray() {
+            true => [self.encode8(pixel.c[0]), self.encode8(pixel.c[1]), self.encode8(pixel.c[2])],
+            false => [ImageRead::linear_to_8(self.linear_pixel(pixel, false)[0]); 3],
+        }
+    }
+
+    /* Compose component 'c' of 'pixel' on the 8-bit sRGB 'background' */
+    fn compose(&self, pixel: &Pixel, color: bool, c: usize, background: u32) -> u32
+    {
+        let alpha = div257(pixel.alpha);
+
+        match alpha {
+            0 => background,
+            255 => self.srgb_pixel(pixel, color)[c],
+            _ => {
+                let linear = self.linear_pixel(pixel, color)[c];
+                let component = linear * alpha + PNG_SRGB_TABLE[background as usize] as u32 * (255 - alpha);
+
+                srgb_from_linear(component) as u32
+            },
+        }
+    }
+
+    /* Read the image in a format without color-map (png_image_read_direct) */
+    fn read_direct(&self, format: PngFormat, background: Option<PngPaletteColor>, mut out: OutRows)
+    {
+        let color = format.contains(PngFormat::FLAG_COLOR);
+        let alpha = format.contains(PngFormat::FLAG_ALPHA);
+        let linear = format.contains(PngFormat::FLAG_LINEAR);
+        let associated = format.contains(PngFormat::FLAG_ASSOCIATED_ALPHA);
+        let channels = format.sample_channels();
+        let ncolor = match color {
+            true => 3,
+            false => 1,
+        };
+
+        /* The position of each channel in the output pixel */
+        let afirst = (alpha && format.contains(PngFormat::FLAG_AFIRST)) as usize;
+        let mut position = [0usize; 4];
+        for (c, pos) in position.iter_mut().enumerate().take(ncolor) {
+            *pos = match color && format.contains(PngFormat::FLAG_BGR) {
+                true => afirst + 2 - c,
+                false => afirst + c,
+            };
+        }
+        position[ncolor] = match afirst {
+            1 => 0,
+            _ => ncolor,
+        };
+
+        let file_alpha = self.info.color_type.contains(PngColor::MASK_ALPHA) || self.info.trans().is_some();
+        let background = background.map(|bg| match color {
+            true => [bg.red as u32, bg.green as u32, bg.blue as u32],
+            false => [bg.green as u32; 3],
+        });
+
+        for y in 0..self.info.height as usize {
+            let row = out.row(y);
+
+            for x in 0..self.info.width as usize {
+                let pixel = self.pixel(y, x);
+                let mut values = [0u32; 4];
+
+                if linear
+                {
+                    /* Premultiplied, which composes on black without alpha */
+                    let components = self.linear_pixel(&pixel, color);
+                    for c in 0..ncolor {
+                        values[c] = premultiply(components[c], pixel.alpha);
+                    }
+                    values[ncolor] = pixel.alpha;
+
+                    for c in 0..channels {
+                        let i = (x * channels + position[c]) * 2;
+                        row[i..i + 2].copy_from_slice(&(values[c] as u16).to_ne_bytes());
+                    }
+                    continue;
+                }
+
+                let alpha8 = div257(pixel.alpha);
+                match (alpha, file_alpha) {
+                    (true, _) => {
+                        let components = match associated && alpha8 < 255 {
+                            true => {
+                                let linear = self.linear_pixel(&pixel, color);
+                                [0, 1, 2].map(|c| div257(premultiply(linear[c], pixel.alpha)))
+                            },
+                            false => self.srgb_pixel(&pixel, color),
+                        };
+
+                        values[..ncolor].copy_from_slice(&components[..ncolor]);
+                        values[ncolor] = alpha8;
+                    },
+                    (false, true) => {
+                        for c in 0..ncolor {
+                            /* Without a background the buffer is the background */
+                            let back = match background {
+                                Some(background) => background[c],
+                                None => row[x * channels + position[c]] as u32,
+                            };
+                            values[c] = self.compose(&pixel, color, c, back);
+                        }
+                    },
+                    (false, false) => {
+                        let components = self.srgb_pixel(&pixel, color);
+                        values[..ncolor].copy_from_slice(&components[..ncolor]);
+                    },
+                }
+
+                for c in 0..channels {
+                    row[x * channels + position[c]] = values[c] as u8;
+                }
+            }
+        }
+    }
+
+    /* The linear 16-bit value of an 8-bit value in 'encoding' (decode_gamma) */
+    fn decode_gamma(&self, value: u32, encoding: Encoding) -> u32
+    {
+        let encoding = match encoding {
+            Encoding::File => self.file_encoding,
+            encoding => encoding,
+        };
+
+        match encoding {
+            Encoding::File => gamma_16bit_correct(value * 257, self.gamma_to_linear),
+            Encoding::Srgb => PNG_SRGB_TABLE[value as usize] as u32,
+            Encoding::Linear => value,
+            Encoding::Linear8 => value * 257,
+        }
+    }
+
+    /* Compose the 8-bit 'foreground' on 'background', with the output
+     * encoding 'encoding' (png_colormap_compose).
+     */
+    fn colormap_compose(&self, foreground: u32, foreground_encoding: Encoding, alpha: u32,
+                        background: u32, encoding: Encoding) -> u32
+    {
+        let f = self.decode_gamma(foreground, foreground_encoding);
+        let b = self.decode_gamma(background, encoding);
+        let f = f * alpha + b * (255 - alpha);
+
+        match encoding {
+            Encoding::Linear => {
+                let f = f * 257;
+                let f = f + (f >> 16);
+                (f + 32768) >> 16
+            },
+            _ => srgb_from_linear(f) as u32,
+        }
+    }
+
+    /* Set entry 'ip' of the color-map (png_create_colormap_entry) */
+    #[allow(clippy::too_many_arguments)]
+    fn colormap_entry(&self, colormap: &mut [u8], ip: u32, red: u32, green: u32, blue: u32,
+                      alpha: u32, encoding: Encoding)
+    {
+        let format = self.format;
+        let output_encoding = match format.contains(PngFormat::FLAG_LINEAR) {
+            true => Encoding::Linear,
+            false => Encoding::Srgb,
+        };
+        let convert_to_y = !format.contains(PngFormat::FLAG_COLOR) && (red != green || green != blue);
+        let (mut red, mut green, mut blue, mut alpha, mut encoding) = (red, green, blue, alpha, encoding);
+
+        if encoding == Encoding::File
+        {
+            encoding = self.file_encoding;
+        }
+
+        if encoding == Encoding::File
+        {
+            let g = self.gamma_to_linear;
+
+            red = gamma_16bit_correct(red * 257, g);
+            green = gamma_16bit_correct(green * 257, g);
+            blue = gamma_16bit_correct(blue * 257, g);
+
+            match convert_to_y || output_encoding == Encoding::Linear {
+                true => {
+                    alpha *= 257;
+                    encoding = Encoding::Linear;
+                },
+                false => {
+                    red = srgb_from_linear(red * 255) as u32;
+                    green = srgb_from_linear(green * 255) as u32;
+                    blue = srgb_from_linear(blue * 255) as u32;
+                    encoding = Encoding::Srgb;
+                },
+            }
+        }
+        else if encoding == Encoding::Linear8
+        {
+            red *= 257;
+            green *= 257;
+            blue *= 257;
+            alpha *= 257;
+            encoding = Encoding::Linear;
+        }
+        else if encoding == Encoding::Srgb && (convert_to_y || output_encoding == Encoding::Linear)
+        {
+            red = PNG_SRGB_TABLE[red as usize] as u32;
+            green = PNG_SRGB_TABLE[green as usize] as u32;
+            blue = PNG_SRGB_TABLE[blue as usize] as u32;
+            alpha *= 257;
+            encoding = Encoding::Linear;
+        }
+
+        if encoding == Encoding::Linear
+        {
+            if convert_to_y
+            {
+                let mut y = 6968 * red + 23434 * green + 2366 * blue;
+
+                match output_encoding == Encoding::Linear {
+                    true => y = (y + 16384) >> 15,
+                    false => {
+                        /* y is scaled by 32768, it is needed scaled by 255 */
+                        y = ((y + 128) >> 8) * 255;
+                        y = srgb_from_linear((y + 64) >> 7) as u32;
+                        alpha = div257(alpha);
+                    },
+                }
+
+                red = y;
+                green = y;
+                blue = y;
+            }
+            else if output_encoding == Encoding::Srgb
+            {
+                red = srgb_from_linear(red * 255) as u32;
+                green = srgb_from_linear(green * 255) as u32;
+                blue = srgb_from_linear(blue * 255) as u32;
+                alpha = div257(alpha);
+            }
+        }
+
+        /* Store the entry */
+        let channels = format.sample_channels();
+        let afirst = (format.contains(PngFormat::FLAG_AFIRST) && format.contains(PngFormat::FLAG_ALPHA)) as usize;
+        let bgr = match format.contains(PngFormat::FLAG_BGR) {
+            true => 2,
+            false => 0,
+        };
+        let mut entry = [0u32; 4];
+
+        if output_encoding == Encoding::Linear && alpha < 65535
+        {
+            /* Linear entries are premultiplied */
+            red = premultiply(red, alpha);
+            green = premultiply(green, alpha);
+            blue = premultiply(blue, alpha);
+        }
+
+        match channels {
+            4 | 3 => {
+                if channels == 4
+                {
+                    entry[match afirst { 1 => 0, _ => 3 }] = alpha;
+                }
+                entry[afirst + (2 ^ bgr)] = blue;
+                entry[afirst + 1] = green;
+                entry[afirst + bgr] = red;
+            },
+            _ => {
+                if channels == 2
+                {
+                    entry[1 ^ afirst] = alpha;
+                }
+                entry[afirst] = green;
+            },
+        }
+
+        let start = ip as usize * channels;
+        match output_encoding {
+            Encoding::Linear => {
+                for (c, value) in entry.iter().take(channels).enumerate() {
+                    let i = (start + c) * 2;
+                    colormap[i..i + 2].copy_from_slice(&(*value as u16).to_ne_bytes());
+                }
+            },
+            _ => {
+                for (c, value) in entry.iter().take(channels).enumerate() {
+                    colormap[start + c] = *value as u8;
+                }
+            },
+        }
+    }
+
+    fn make_gray_file_colormap(&self, colormap: &mut [u8]) -> u32
+    {
+        for i in 0..256 {
+            self.colormap_entry(colormap, i, i, i, i, 255, Encoding::File);
+        }
+
+        256
+    }
+
+    fn make_gray_colormap(&self, colormap: &mut [u8]) -> u32
+    {
+        for i in 0..256 {
+            self.colormap_entry(colormap, i, i, i, i, 255, Encoding::Srgb);
+        }
+
+        256
+    }
+
+    /* Gray and alpha entries: the 231 opaque grays, a transparent entry and
+     * the six grays [0..5]*51 for the intermediate alpha values, see the pixel
+     * mapping in map_rows.
+     */
+    fn make_ga_colormap(&self, colormap: &mut [u8]) -> u32
+    {
+        let mut i = 0;
+
+        while i < 231 {
+            let gray = (i * 256 + 115) / 231;
+            self.colormap_entry(colormap, i, gray, gray, gray, 255, Encoding::Srgb);
+            i += 1;
+        }
+
+        /* 255 for consistency with the writer removing premultiplication */
+        self.colormap_entry(colormap, i, 255, 255, 255, 0, Encoding::Srgb);
+        i += 1;
+
+        for a in 1..5 {
+            for g in 0..6 {
+                self.colormap_entry(colormap, i, g * 51, g * 51, g * 51, a * 51, Encoding::Srgb);
+                i += 1;
+            }
+        }
+
+        i
+    }
+
+    /* The 6x6x6 opaque RGB cube */
+    fn make_rgb_colormap(&self, colormap: &mut [u8]) -> u32
+    {
+        let mut i = 0;
+
+        for r in 0..6 {
+            for g in 0..6 {
+                for b in 0..6 {
+                    self.colormap_entry(colormap, i, r * 51, g * 51, b * 51, 255, Encoding::Srgb);
+                    i += 1;
+                }
+            }
+        }
+
+        i
+    }
+
+    /* The components 0, 127 and 255 of the half-alpha entries */
+    fn half_levels() -> [u32; 3]
+    {
+        [0, 127, 255]
+    }
+
+    /* Build the color-map, return the number of entries, how the pixels are
+     * mapped and the data they are mapped from (png_image_read_colormap).
+     */
+    fn make_colormap(&self, image: &Image, background: Option<PngPaletteColor>,
+                     colormap: &mut [u8]) -> io::Result<(u32, CmapProcessing, CmapData)>
+    {
+        let info = self.info;
+        let output_format = self.format;
+        let output_encoding = match output_format.contains(PngFormat::FLAG_LINEAR) {
+            true => Encoding::Linear,
+            false => Encoding::Srgb,
+        };
+        let linear = output_encoding == Encoding::Linear;
+        let file_alpha = info.color_type.contains(PngColor::MASK_ALPHA) || info.trans().is_some();
+        let output_alpha = output_format.contains(PngFormat::FLAG_ALPHA);
+        let output_color = output_format.contains(PngFormat::FLAG_COLOR);
+        let too_few = |needed: u32, msg: &'static str| match needed > image.colormap_entries {
+            true => Err(invalid_input(msg)),
+            false => Ok(()),
+        };
+
+        let (back_r, back_g, back_b) = match (file_alpha && !output_alpha, linear, background) {
+            (true, true, _) => (0, 0, 0), /* compose on black */
+            (true, false, None) => {
+                return Err(invalid_input("background color must be supplied to remove alpha/transparency"));
+            },
+            (true, false, Some(bg)) => match output_color {
+                true => (bg.red as u32, bg.green as u32, bg.blue as u32),
+                false => (bg.green as u32, bg.green as u32, bg.green as u32),
+            },
+            (false, true, _) => (65535, 65535, 65535),
+            (false, false, _) => (255, 255, 255),
+        };
+        let back_alpha_opaque = match linear {
+            true => 65535,
+            false => 255,
+        };
+        /* The sRGB 8-bit gray of the background, the value in the data */
+        let gray_data = |gray: u32| match linear {
+            true => srgb_from_linear(gray * 255) as u32,
+            false => gray,
+        };
+
+        let result = match info.color_type {
+            PngColor::TYPE_GRAY if info.bit_depth <= 8 => {
+                let cmap_entries = 1u32 << info.bit_depth;
+                too_few(cmap_entries, "gray[8] color-map: too few entries")?;
+
+                let step = 255 / (cmap_entries - 1);
+                let (trans, back_alpha) = match info.trans() {
+                    Some((_, color)) => (color.gray as u32, match output_alpha {
+                        true => 0,
+                        false => back_alpha_opaque,
+                    }),
+                    None => (256, 0),
+                };
+
+                for i in 0..cmap_entries {
+                    match i != trans {
+                        true => self.colormap_entry(colormap, i, i * step, i * step, i * step, 255, Encoding::File),
+                        false => self.colormap_entry(colormap, i, back_r, back_g, back_b, back_alpha,
+                                                     output_encoding),
+                    }
+                }
+
+                (cmap_entries, CmapProcessing::None, CmapData::Index)
+            },
+            PngColor::TYPE_GRAY => {
+                too_few(256, "gray[16] color-map: too few entries")?;
+                let cmap_entries = self.make_gray_colormap(colormap);
+
+                match (info.trans().is_some(), output_alpha) {
+                    (false, _) => (cmap_entries, CmapProcessing::None,
+                                   CmapData::Srgb { color: false, alpha: false, background: None }),
+                    (true, false) if back_r == back_g && back_g == back_b => {
+                        let gray = gray_data(back_g);
+                        if linear
+                        {
+                            self.colormap_entry(colormap, gray, back_g, back_g, back_g, 65535, Encoding::Linear);
+                        }
+
+                        (cmap_entries, CmapProcessing::None,
+                         CmapData::Srgb { color: false, alpha: false, background: Some([gray; 3]) })
+                    },
+                    (true, _) => {
+                        let back_alpha = match output_alpha {
+                            true => 0,
+                            false => back_alpha_opaque,
+                        };
+
+                        /* Entry 254 is the transparent or background entry */
+                        self.colormap_entry(colormap, PNG_CMAP_TRANS_BACKGROUND as u32, back_r, back_g, back_b,
+                                            back_alpha, output_encoding);
+                        (cmap_entries, CmapProcessing::Trans,
+                         CmapData::Srgb { color: false, alpha: true, background: None })
+                    },
+                }
+            },
+            PngColor::TYPE_GRAY_ALPHA => {
+                if output_alpha
+                {
+                    too_few(256, "gray+alpha color-map: too few entries")?;
+
+                    (self.make_ga_colormap(colormap), CmapProcessing::Ga,
+                     CmapData::Srgb { color: false, alpha: true, background: None })
+                }
+                else if !output_color || (back_r == back_g && back_g == back_b)
+                {
+                    too_few(256, "gray-alpha color-map: too few entries")?;
+                    let cmap_entries = self.make_gray_colormap(colormap);
+
+                    let gray = gray_data(back_g);
+                    if linear
+                    {
+                        self.colormap_entry(colormap, gray, back_g, back_g, back_g, 65535, Encoding::Linear);
+                    }
+
+                    (cmap_entries, CmapProcessing::None,
+                     CmapData::Srgb { color: false, alpha: false, background: Some([gray; 3]) })
+                }
+                else
+                {
+                    too_few(256, "ga-alpha color-map: too few entries")?;
+
+                    /* make_ga_colormap with opaque entries, composed on the
+                     * background.
+                     */
+                    let mut i = 0;
+                    while i < 231 {
+                        let gray = (i * 256 + 115) / 231;
+                        self.colormap_entry(colormap, i, gray, gray, gray, 255, Encoding::Srgb);
+                        i += 1;
+                    }
+
+                    self.colormap_entry(colormap, i, back_r, back_g, back_b, back_alpha_opaque, output_encoding);
+                    i += 1;
+
+                    let (back_r, back_g, back_b) = match linear {
+                        true => (back_r, back_g, back_b),
+                        false => (PNG_SRGB_TABLE[back_r as usize] as u32, PNG_SRGB_TABLE[back_g as usize] as u32,
+                                  PNG_SRGB_TABLE[back_b as usize] as u32),
+                    };
+
+                    for a in 1..5 {
+                        let alpha = 51 * a;
+
+                        for g in 0..6 {
+                            let gray = PNG_SRGB_TABLE[g * 51] as u32 * alpha;
+
+                            self.colormap_entry(colormap, i,
+                                                srgb_from_linear(gray + (255 - alpha) * back_r) as u32,
+                                                srgb_from_linear(gray + (255 - alpha) * back_g) as u32,
+                                                srgb_from_linear(gray + (255 - alpha) * back_b) as u32,
+                                                255, Encoding::Srgb);
+                            i += 1;
+                        }
+                    }
+
+                    (i, CmapProcessing::Ga, CmapData::Srgb { color: false, alpha: true, background: None })
+                }
+            },
+            PngColor::TYPE_PALETTE => {
+                let palette = info.palette();
+                let (trans, _) = info.trans().unwrap_or((&[], Default::default()));
+                let do_background = !trans.is_empty() && !output_alpha;
+                let cmap_entries = (palette.len() as u32).min(256);
+
+                too_few(cmap_entries, "palette color-map: too few entries")?;
+
+                for (i, entry) in palette.iter().enumerate().take(cmap_entries as usize) {
+                    let ip = i as u32;
+                    let (red, green, blue) = (entry.red as u32, entry.green as u32, entry.blue as u32);
+
+                    match (do_background, trans.get(i).copied()) {
+                        (true, Some(0)) => self.colormap_entry(colormap, ip, back_r, back_g, back_b, 0,
+                                                               output_encoding),
+                        (true, Some(alpha)) if alpha < 255 => {
+                            let alpha = alpha as u32;
+
+                            self.colormap_entry(colormap, ip,
+                                                self.colormap_compose(red, Encoding::File, alpha, back_r,
+                                                                      output_encoding),
+                                                self.colormap_compose(green, Encoding::File, alpha, back_g,
+                                                                      output_encoding),
+                                                self.colormap_compose(blue, Encoding::File, alpha, back_b,
+                                                                      output_encoding),
+                                                match linear {
+                                                    true => alpha * 257,
+                                                    false => alpha,
+                                                },
+                                                output_encoding);
+                        },
+                        (_, alpha) => self.colormap_entry(colormap, ip, red, green, blue,
+                                                          alpha.unwrap_or(255) as u32, Encoding::File),
+                    }
+                }
+
+                (cmap_entries, CmapProcessing::None, CmapData::Index)
+            },
+            _ if !output_color => {
+                /* RGB to gray, handled as the gray cases */
+                if file_alpha && output_alpha
+                {
+                    too_few(256, "rgb[ga] color-map: too few entries")?;
+
+                    (self.make_ga_colormap(colormap), CmapProcessing::Ga,
+                     CmapData::Srgb { color: false, alpha: true, background: None })
+                }
+                else
+                {
+                    too_few(256, "rgb[gray] color-map: too few entries")?;
+
+                    /* Composing with a gamma other than sRGB is done in the
+                     * file encoding, the color-map does the correction.
+                     */
+                    let file = file_alpha && gamma_not_srgb(self.gamma);
+                    let cmap_entries = match file {
+                        true => self.make_gray_file_colormap(colormap),
+                        false => self.make_gray_colormap(colormap),
+                    };
+
+                    match (file_alpha, file) {
+                        (false, _) => (cmap_entries, CmapProcessing::None,
+                                       CmapData::Srgb { color: false, alpha: false, background: None }),
+                        (true, true) => {
+                            let gray = match linear {
+                                true => back_g,
+                                false => PNG_SRGB_TABLE[back_g as usize] as u32,
+                            };
+                            let gray = div257(gamma_16bit_correct(gray, self.gamma));
+
+                            self.colormap_entry(colormap, gray, back_g, back_g, back_g, 0, output_encoding);
+                            (cmap_entries, CmapProcessing::None, CmapData::FileGray(gray))
+                        },
+                        (true, false) => {
+                            let gray = gray_data(back_g);
+                            if linear
+                            {
+                                self.colormap_entry(colormap, gray, back_g, back_g, back_g, 0, Encoding::Linear);
+                            }
+
+                            (cmap_entries, CmapProcessing::None,
+                             CmapData::Srgb { color: false, alpha: false, background: Some([gray; 3]) })
+                        },
+                    }
+                }
+            },
+            _ => {
+                if !file_alpha
+                {
+                    too_few(216, "rgb color-map: too few entries")?;
+
+                    (self.make_rgb_colormap(colormap), CmapProcessing::Rgb,
+                     CmapData::Srgb { color: true, alpha: false, background: None })
+                }
+                else if output_alpha
+                {
+                    too_few(216 + 1 + 27, "rgb+alpha color-map: too few entries")?;
+                    let mut cmap_entries = self.make_rgb_colormap(colormap);
+
+                    /* A transparent entry, then 27 with alpha 0.5 */
+                    self.colormap_entry(colormap, cmap_entries, 255, 255, 255, 0, Encoding::Srgb);
+                    cmap_entries += 1;
+
+                    for r in ImageRead::half_levels() {
+                        for g in ImageRead::half_levels() {
+                            for b in ImageRead::half_levels() {
+                                self.colormap_entry(colormap, cmap_entries, r, g, b, 128, Encoding::Srgb);
+                                cmap_entries += 1;
+                            }
+                        }
+                    }
+
+                    (cmap_entries, CmapProcessing::RgbAlpha,
+                     CmapData::Srgb { color: true, alpha: true, background: None })
+                }
+                else
+                {
+                    too_few(216 + 1 + 27, "rgb-alpha color-map: too few entries")?;
+                    let mut cmap_entries = self.make_rgb_colormap(colormap);
+                    let sample_size = output_format.sample_size();
+
+                    self.colormap_entry(colormap, cmap_entries, back_r, back_g, back_b, back_alpha_opaque,
+                                        output_encoding);
+
+                    let (r, g, b) = match linear {
+                        true => (srgb_from_linear(back_r * 255) as u32, srgb_from_linear(back_g * 255) as u32,
+                                 srgb_from_linear(back_b * 255) as u32),
+                        false => (back_r, back_g, back_b),
+                    };
+
+                    /* Is the background in the cube already? */
+                    let added = cmap_entries as usize * sample_size;
+                    let cube = rgb_index(r, g, b) as usize * sample_size;
+                    match colormap[added..added + sample_size] == colormap[cube..cube + sample_size] {
+                        true => (cmap_entries, CmapProcessing::Rgb,
+                                 CmapData::Srgb { color: true, alpha: false, background: Some([r, g, b]) }),
+                        false => {
+                            /* Add the background, then 27 colors composed on
+                             * it with alpha 0.5.
+                             */
+                            cmap_entries += 1;
+
+                            for r in ImageRead::half_levels() {
+                                for g in ImageRead::half_levels() {
+                                    for b in ImageRead::half_levels() {
+                                        self.colormap_entry(colormap, cmap_entries,
+                                                            self.colormap_compose(r, Encoding::Srgb, 128, back_r,
+                                                                                  output_encoding),
+                                                            self.colormap_compose(g, Encoding::Srgb, 128, back_g,
+                                                                                  output_encoding),
+                                                            self.colormap_compose(b, Encoding::Srgb, 128, back_b,
+                                                                                  output_encoding),
+                                                            back_alpha_opaque, output_encoding);
+                                        cmap_entries += 1;
+                                    }
+                                }
+                            }
+
+                            (cmap_entries, CmapProcessing::RgbAlpha,
+                             CmapData::Srgb { color: true, alpha: true, background: None })
+                        },
+                    }
+                }
+            },
+        };
+
+        Ok(result)
+    }
+
+    /* The data of pixel 'x' of row 'y' for a color-mapped read: the index or
+     * the 8-bit channels, in PNG order.
+     */
+    fn cmap_data(&self, data: CmapData, y: usize, x: usize) -> [u32; 4]
+    {
+        match data {
+            CmapData::Index => [self.sample(y, x, 0), 0, 0, 0],
+            CmapData::FileGray(background) => {
+                let pixel = self.pixel(y, x);
+                let gray = match pixel.is_gray() && pixel.alpha == 65535 {
+                    true => div257(pixel.c[0]),
+                    false => {
+                        let linear = self.linear_pixel(&pixel, false)[0];
+                        let back = self.decode_gamma(background, Encoding::File);
+                        let linear = (linear * pixel.alpha + back * (65535 - pixel.alpha) + 32767) / 65535;
+
+                        div257(gamma_16bit_correct(linear, self.gamma))
+                    },
+                };
+
+                [gray, 0, 0, 0]
+            },
+            CmapData::Srgb { color, alpha, background } => {
+                let pixel = self.pixel(y, x);
+                let ncolor = match color {
+                    true => 3,
+                    false => 1,
+                };
+                let mut values = [0u32; 4];
+
+                match (alpha, background) {
+                    (false, Some(background)) => {
+                        for (c, value) in values.iter_mut().enumerate().take(ncolor) {
+                            *value = self.compose(&pixel, color, c, background[c]);
+                        }
+                    },
+                    _ => {
+                        values[..3].copy_from_slice(&self.srgb_pixel(&pixel, color));
+                        values[ncolor] = div257(pixel.alpha);
+                    },
+                }
+
+                values
+            },
+        }
+    }
+
+    /* Read the image as indexes in the color-map (png_image_read_colormapped) */
+    fn read_colormapped(&self, image: &mut Image, background: Option<PngPaletteColor>, mut out: OutRows,
+                        colormap: &mut [u8]) -> io::Result<()>
+    {
+        if colormap.len() < image.colormap_size()
+        {
+            return Err(invalid_input("png_image_finish_read[color-map]: no color-map"));
+        }
+
+        let (cmap_entries, processing, data) = self.make_colormap(image, background, colormap)?;
+        image.colormap_entries = cmap_entries;
+
+        for y in 0..self.info.height as usize {
+            let row = out.row(y);
+
+            for (x, index) in row.iter_mut().enumerate().take(self.info.width as usize) {
+                let v = self.cmap_data(data, y, x);
+
+                *index = match processing {
+                    CmapProcessing::None => v[0] as u8,
+                    CmapProcessing::Ga => {
+                        let (gray, alpha) = (v[0], v[1]);
+
+                        match alpha {
+                            230..=255 => ((231 * gray + 128) >> 8) as u8,
+                            0..=25 => PNG_CMAP_GA_BACKGROUND,
+                            _ => (226 + 6 * div51(alpha) + div51(gray)) as u8,
+                        }
+                    },
+                    CmapProcessing::Trans => match (v[0] as u8, v[1]) {
+                        (_, 0) => PNG_CMAP_TRANS_BACKGROUND,
+                        (PNG_CMAP_TRANS_BACKGROUND, _) => PNG_CMAP_TRANS_BACKGROUND + 1,
+                        (gray, _) => gray,
+                    },
+                    CmapProcessing::Rgb => rgb_index(v[0], v[1], v[2]),
+                    CmapProcessing::RgbAlpha => match v[3] {
+                        196..=255 => rgb_index(v[0], v[1], v[2]),
+                        0..=63 => PNG_CMAP_RGB_ALPHA_BACKGROUND,
+                        _ => {
+                            /* One of the three levels of each component */
+                            let level = |c: u32| match c {
+                                0..=63 => 0,
+                                64..=191 => 1,
+                                _ => 2,
+                            };
+
+                            PNG_CMAP_RGB_ALPHA_BACKGROUND + 1 + 9 * level(v[0]) + 3 * level(v[1]) + level(v[2])
+                        },
+                    },
+                };
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "c-tests"))]
+mod tests {
+    use super::*;
+    use crate::chunk::ChunkReader;
+    use crate::libpng::PngImage;
+    use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+    type Chunks = Vec<([u8; 4], Vec<u8>)>;
+
+    /* The chunks other than IDAT, and the filtered rows of the IDAT chunks */
+    fn chunks_and_rows(png: &[u8]) -> (Chunks, Vec<u8>)
+    {
+        let mut chunks = Vec::new();
+        let mut idat = Vec::new();
+
+        for chunk in ChunkReader::new(png) {
+            let chunk = chunk.unwrap();
+            assert!(chunk.crc_ok);
+
+            match &chunk.name {
+                b"IDAT" => idat.extend_from_slice(&chunk.data),
+                _ => chunks.push((chunk.name, chunk.data)),
+            }
+        }
+
+        (chunks, decompress_to_vec_zlib(&idat).unwrap())
+    }
+
+    fn random_bytes(seed: &mut u32, length: usize) -> Vec<u8>
+    {
+        (0..length).map(|_| {
+            *seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (*seed >> 16) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn write_to_matches_png_image_write_to_memory()
+    {
+        let formats = [
+            PngFormat::GRAY, PngFormat::GA, PngFormat::AG, PngFormat::RGB, PngFormat::BGR,
+            PngFormat::RGBA, PngFormat::ARGB, PngFormat::BGRA, PngFormat::ABGR,
+            PngFormat::LINEAR_Y, PngFormat::LINEAR_Y_ALPHA, PngFormat::LINEAR_RGB, PngFormat::LINEAR_RGB_ALPHA,
+            PngFormat::RGB_COLORMAP, PngFormat::BGR_COLORMAP, PngFormat::RGBA_COLORMAP,
+            PngFormat::ARGB_COLORMAP, PngFormat::BGRA_COLORMAP, PngFormat::ABGR_COLORMAP,
+        ];
+        let flags = [PngImageFlags::empty(), PngImageFlags::COLORSPACE_NOT_SRGB, PngImageFlags::FAST];
+        let mut seed = 1;
+
+        for &format in formats.iter() {
+            for &flag in flags.iter() {
+                /* C reads 8-bit formats as 16-bit ones with convert_to_8bit */
+                let linear = format.contains(PngFormat::FLAG_LINEAR);
+                for &convert_to_8bit in [false, true].iter().filter(|&&convert| !convert || linear) {
+                    /* The C code converting linear rows casts the row stride to
+                     * png_uint_16, it cannot read them bottom-up.
+                     */
+                    let converted = linear && (format.contains(PngFormat::FLAG_ALPHA) || convert_to_8bit);
+                    for &(entries, extra_stride, bottom_up) in [(2, 0, false), (16, 5, true), (200, 0, true)].iter() {
+                        let (width, height) = (19, 11);
+                        let mut image = Image::new(width, height, format);
+                        image.flags = flag;
+
+                        let stride = image.row_stride() + extra_stride;
+                        let mut buffer = random_bytes(&mut seed, image.buffer_size(stride));
+                        let colormap = match format.contains(PngFormat::FLAG_COLORMAP) {
+                            true => {
+                                image.colormap_entries = entries;
+                                for index in buffer.iter_mut() {
+                                    *index %= entries as u8;
+                                }
+                                Some(random_bytes(&mut seed, image.colormap_size()))
+                            },
+                            false => None,
+                        };
+                        let row_stride = match bottom_up && !converted {
+                            true => -(stride as isize),
+                            false => stride as isize,
+                        };
+
+                        let mut c_image = PngImage::new(width, height, format.bits());
+                        c_image.flags = flag.bits();
+                        c_image.colormap_entries = image.colormap_entries;
+                        let c_png = c_image.write_to_memory(convert_to_8bit, &buffer, row_stride as i32,
+                                                            colormap.as_deref()).unwrap();
+
+                        let png = image.write_to_vec(convert_to_8bit, &buffer, row_stride, colormap.as_deref()).unwrap();
+
+                        assert_eq!(chunks_and_rows(&png), chunks_and_rows(&c_png),
+                                   "{:?} {:?}, convert_to_8bit {}, {} entries, stride {}",
+                                   format, flag, convert_to_8bit, entries, row_stride);
+                    }
+                }
+            }
+        }
+    }
+
+    /* The PNG files of contrib/pngsuite and contrib/testpngs */
+    fn test_pngs() -> Vec<(String, Vec<u8>)>
+    {
+        let contrib = Path::new(env!("CARGO_MANIFEST_DIR")).join("../contrib");
+        let mut pngs = Vec::new();
+
+        for dir in ["pngsuite", "testpngs"].iter() {
+            let mut paths: Vec<_> = fs::read_dir(contrib.join(dir)).unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension() == Some("png".as_ref()))
+                .collect();
+            paths.sort();
+
+            for path in paths {
+                pngs.push((path.display().to_string(), fs::read(&path).unwrap()));
+            }
+        }
+
+        pngs
+    }
+
+    /* The image in 'format', the color-map and its entries, or the error.  The
+     * buffer starts with a pattern, on which an image with alpha is composed
+     * when there is no background.
+     */
+    type Read = Result<(Vec<u8>, Vec<u8>, u32), ()>;
+
+    fn c_finish_read(png: &[u8], format: PngFormat, background: Option<PngPaletteColor>,
+                     bottom_up: bool) -> Read
+    {
+        let mut image = PngImage::begin_read_from_memory(png).ok_or(())?;
+        image.format = format.bits();
+
+        let stride = (image.width as usize * format.pixel_channels()) as i32;
+        let size = format.pixel_component_size() * image.height as usize * stride as usize;
+        let mut buffer: Vec<u8> = (0..size).map(|i| (i * 7) as u8).collect();
+        let mut colormap = vec![0u8; format.sample_size() * 256];
+        let row_stride = if bottom_up { -stride } else { stride };
+
+        image.finish_read(background, &mut buffer, row_stride, Some(&mut colormap)).map_err(|_| ())?;
+        colormap.truncate(format.sample_size() * image.colormap_entries as usize);
+
+        Ok((buffer, colormap, image.colormap_entries))
+    }
+
+    fn rust_finish_read(png: &[u8], format: PngFormat, background: Option<PngPaletteColor>,
+                        bottom_up: bool) -> Read
+    {
+        let mut image = Image::begin_read_from_memory(png).map_err(|_| ())?;
+        image.format = format;
+
+        let stride = image.row_stride() as isize;
+        let mut buffer: Vec<u8> = (0..image.size()).map(|i| (i * 7) as u8).collect();
+        let mut colormap = vec![0u8; format.sample_size() * 256];
+        let row_stride = if bottom_up { -stride } else { stride };
+
+        image.finish_read(background, &mut buffer, row_stride, Some(&mut colormap)).map_err(|_| ())?;
+        colormap.truncate(image.colormap_size());
+
+        Ok((buffer, colormap, image.colormap_entries))
+    }
+
+    /* The pixels of a buffer in 'format', as red, green, blue and alpha */
+    fn pixels(format: PngFormat, buffer: &[u8], colormap: &[u8]) -> Vec<[u32; 4]>
+    {
+        let component_size = format.sample_component_size();
+        let channels = format.sample_channels();
+        let component = |data: &[u8], i: usize| match component_size {
+            2 => u16::from_ne_bytes([data[2 * i], data[2 * i + 1]]) as u32,
+            _ => data[i] as u32,
+        };
+        let opaque = match component_size {
+            2 => 65535,
+            _ => 255,
+        };
+        let afirst = format.contains(PngFormat::FLAG_ALPHA | PngFormat::FLAG_AFIRST) as usize;
+        let bgr = format.contains(PngFormat::FLAG_COLOR | PngFormat::FLAG_BGR);
+        let sample = |data: &[u8], start: usize| {
+            let alpha = match format.contains(PngFormat::FLAG_ALPHA) {
+                true => component(data, start + if afirst == 1 { 0 } else { channels - 1 }),
+                false => opaque,
+            };
+            match format.contains(PngFormat::FLAG_COLOR) {
+                true => {
+                    let (r, b) = if bgr { (2, 0) } else { (0, 2) };
+                    [component(data, start + afirst + r), component(data, start + afirst + 1),
+                     component(data, start + afirst + b), alpha]
+                },
+                false => {
+                    let gray = component(data, start + afirst);
+                    [gray, gray, gray, alpha]
+                },
+            }
+        };
+
+        match format.contains(PngFormat::FLAG_COLORMAP) {
+            true => buffer.iter().map(|&index| sample(colormap, index as usize * channels)).collect(),
+            false => (0..buffer.len() / component_size / channels).map(|i| sample(buffer, i * channels)).collect(),
+        }
+    }
+
+    /* The largest difference of a color component with png_image_finish_read:
+     * both read through their own gamma tables and round differently.  The
+     * limits are those of contrib/libtests/pngstest-errors.h for an 8-bit and
+     * a 16-bit output, and a step of the 6x6x6 color cube for a color-map.
+     */
+    fn color_error(format: PngFormat) -> u32
+    {
+        match (format.contains(PngFormat::FLAG_COLORMAP), format.contains(PngFormat::FLAG_LINEAR)) {
+            (true, _) => 51,
+            (false, true) => 900,
+            (false, false) => 20,
+        }
+    }
+
+    /* Two defects of png_image_finish_read which the Rust code does not have:
+     *
+     * 1) Without a background, the composition on the buffer of a palette
+     *    with tRNS or of a 16-bit image with alpha is wrong: the palette is
+     *    multiplied by alpha twice and the opaque 16-bit pixels are stored
+     *    linear.  These images are compared with a background only.
+     *
+     * 2) PNG_CMAP_RGB_ALPHA takes the green and blue of the half transparent
+     *    color-map entries from the red component, so the pixels of a color
+     *    image with an alpha from 64 to 195 are not compared in a color-map.
+     */
+    fn composes_on_buffer_wrongly(original: PngFormat, format: PngFormat, background: Option<PngPaletteColor>) -> bool
+    {
+        background.is_none() && original.contains(PngFormat::FLAG_ALPHA) &&
+            (original.contains(PngFormat::FLAG_COLORMAP) || original.contains(PngFormat::FLAG_LINEAR)) &&
+            !format.intersects(PngFormat::FLAG_ALPHA | PngFormat::FLAG_LINEAR | PngFormat::FLAG_COLORMAP)
+    }
+
+    fn maps_half_alpha_wrongly(original: PngFormat, format: PngFormat, alpha: u8) -> bool
+    {
+        format.contains(PngFormat::FLAG_COLORMAP) && !original.contains(PngFormat::FLAG_COLORMAP) &&
+            original.contains(PngFormat::FLAG_COLOR | PngFormat::FLAG_ALPHA) && (64..196).contains(&alpha)
+    }
+
+    #[test]
+    fn finish_read_matches_png_image_finish_read()
+    {
+        let formats = [
+            PngFormat::GRAY, PngFormat::GA, PngFormat::AG, PngFormat::RGB, PngFormat::BGR,
+            PngFormat::RGBA, PngFormat::ARGB, PngFormat::BGRA, PngFormat::ABGR,
+            PngFormat::LINEAR_Y, PngFormat::LINEAR_Y_ALPHA, PngFormat::LINEAR_RGB, PngFormat::LINEAR_RGB_ALPHA,
+            PngFormat::RGB_COLORMAP, PngFormat::BGR_COLORMAP, PngFormat::RGBA_COLORMAP,
+            PngFormat::ARGB_COLORMAP, PngFormat::BGRA_COLORMAP, PngFormat::ABGR_COLORMAP,
+        ];
+        let background = PngPaletteColor { red: 30, green: 140, blue: 220 };
+        let mut failures = Vec::new();
+
+        for (path, png) in test_pngs() {
+            let original = match Image::begin_read_from_memory(&png) {
+                Ok(image) => image.format,
+                Err(_) => {
+                    /* Both must reject the image */
+                    if PngImage::begin_read_from_memory(&png).is_some()
+                    {
+                        failures.push(format!("{}: not read", path));
+                    }
+                    continue;
+                },
+            };
+
+            for &(background, bottom_up) in [(None, false), (Some(background), false), (None, true)].iter() {
+                /* The alpha of each pixel in the order of the buffer */
+                let alpha: Vec<u8> = match c_finish_read(&png, PngFormat::RGBA, None, bottom_up) {
+                    Ok((rgba, _, _)) => rgba.chunks(4).map(|pixel| pixel[3]).collect(),
+                    Err(()) => {
+                        failures.push(format!("{}: not read by png_image_finish_read", path));
+                        continue;
+                    },
+                };
+
+                for &format in formats.iter() {
+                    if composes_on_buffer_wrongly(original, format, background)
+                    {
+                        continue;
+                    }
+
+                    let case = format!("{} {:?} background {} bottom-up {}", path, format, background.is_some(), bottom_up);
+                    let (got, expected) = match (rust_finish_read(&png, format, background, bottom_up),
+                                                 c_finish_read(&png, format, background, bottom_up)) {
+                        (Ok(got), Ok(expected)) => (got, expected),
+                        (Err(()), Err(())) => continue,
+                        (got, _) => {
+                            failures.push(format!("{}: read {}", case, got.is_ok()));
+                            continue;
+                        },
+                    };
+
+                    if got.2 != expected.2
+                    {
+                        failures.push(format!("{}: {} color-map entries, expected {}", case, got.2, expected.2));
+                        continue;
+                    }
+
+                    let error = color_error(format);
+                    let pixels = pixels(format, &got.0, &got.1).into_iter()
+                        .zip(pixels(format, &expected.0, &expected.1))
+                        .zip(alpha.iter());
+                    for (i, ((got, expected), &alpha)) in pixels.enumerate() {
+                        if maps_half_alpha_wrongly(original, format, alpha)
+                        {
+                            continue;
+                        }
+
+                        /* The color of a transparent pixel does not matter */
+                        let color_differs = got[3] != 0 &&
+                            (0..3).any(|c| (got[c] as i32 - expected[c] as i32).unsigned_abs() > error);
+                        if got[3] != expected[3] || color_differs
+                        {
+                            failures.push(format!("{}: pixel {} is {:?}, expected {:?}", case, i, got, expected));
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        assert!(failures.is_empty(), "{} differences:\n{}", failures.len(), failures.join("\n"));
+    }
+}
@@ -0,0 +1,732 @@
+use crate::Png;
+use crate::PngInterlace;
+use crate::PngTransformations;
+use crate::trans::PngRowInfo;
+use crate::CPtr;
+use std::slice;
+
+/* Arrays to facilitate easy interlacing - use pass (0 - 6) as index */
+
+/* Start of interlace block */
+pub static PNG_PASS_START: &[u8] = &[0, 4, 0, 2, 0, 1, 0];
+
+/* Offset to next interlace block */
+pub static PNG_PASS_INC: &[u8] = &[8, 8, 4, 4, 2, 2, 1];
+
+/* Start of interlace block in the y direction */
+pub static PNG_PASS_YSTART: &[u8] = &[0, 0, 4, 0, 2, 0, 1];
+
+/* Offset to next interlace block in the y direction */
+pub static PNG_PASS_YINC: &[u8] = &[8, 8, 8, 4, 4, 2, 2];
+
+/* Number of passes of the Adam7 interlace method */
+pub const PNG_INTERLACE_ADAM7_PASSES: u8 = 7;
+
+/* How png_combine_row fills a row of the application with an interlaced
+ * pass.
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PngDisplay {
+    Sparkle,   /* only the pixels of the pass are written */
+    Rectangle, /* pixels are replicated over the rest of their block */
+}
+
+/*******************************************************************************
+ *
+ *                               Png C API
+ *
+ ******************************************************************************/
+
+#[no_mangle]
+pub unsafe extern fn png_do_read_interlace(this: *mut PngRowInfo, row: CPtr, pass: i32, transformations: u32) {
+    match this.as_mut() {
+        None => { return; },
+        Some(row_info) => {
+            if row == 0 || !(0..6).contains(&pass)
+            {
+                return;
+            }
+
+            let final_width = row_info.width as usize * PNG_PASS_INC[pass as usize] as usize;
+            let slice = slice::from_raw_parts_mut(row as *mut u8,
+                                                  Png::compute_rowbytes(row_info.pixel_depth, final_width));
+            let transformations = PngTransformations::from_bits_truncate(transformations);
+            row_info.do_read_interlace(slice, pass as u8,
+                                       transformations.contains(PngTransformations::PACKSWAP));
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_do_write_interlace(this: *mut PngRowInfo, row: CPtr, pass: i32, transformations: u32) {
+    match this.as_mut() {
+        None => { return; },
+        Some(row_info) => {
+            if row == 0 || !(0..6).contains(&pass)
+            {
+                return;
+            }
+
+            let slice = slice::from_raw_parts_mut(row as *mut u8, row_info.rowbytes);
+            let transformations = PngTransformations::from_bits_truncate(transformations);
+            row_info.do_write_interlace(slice, pass as u8,
+                                        transformations.contains(PngTransformations::PACKSWAP));
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_rust_combine_row(this: *const Png, dp: CPtr, display: i32) {
+    match this.as_ref() {
+        None => { return; },
+        Some(png_ptr) => {
+            let pixel_depth = png_ptr.transformed_pixel_depth;
+            let rowbytes = Png::compute_rowbytes(pixel_depth, png_ptr.width as usize);
+            let sp = slice::from_raw_parts((png_ptr.row_buf + 1) as *const u8, rowbytes);
+            let dp = slice::from_raw_parts_mut(dp as *mut u8, rowbytes);
+
+            /* Other values than 0 and 1 copy the whole row */
+            png_ptr.combine_row(dp, sp, match display {
+                0 => Some(PngDisplay::Sparkle),
+                1 => Some(PngDisplay::Rectangle),
+                _ => None,
+            });
+        },
+    }
+}
+
+
+
+/*******************************************************************************
+ *
+ *                               Png RUST
+ *
+ ******************************************************************************/
+
+/* First row of the image in the pass */
+pub fn pass_start_row(pass: u8) -> u32
+{
+    PNG_PASS_YSTART[pass as usize] as u32
+}
+
+/* First column of the image in the pass */
+pub fn pass_start_col(pass: u8) -> u32
+{
+    PNG_PASS_START[pass as usize] as u32
+}
+
+/* Distance between two rows of the pass in the image */
+pub fn pass_row_offset(pass: u8) -> u32
+{
+    PNG_PASS_YINC[pass as usize] as u32
+}
+
+/* Distance between two columns of the pass in the image */
+pub fn pass_col_offset(pass: u8) -> u32
+{
+    PNG_PASS_INC[pass as usize] as u32
+}
+
+/* Number of rows of an image of the given height in the pass */
+pub fn pass_rows(height: u32, pass: u8) -> u32
+{
+    let start = pass_start_row(pass);
+
+    match height > start {
+        true => (height - start).div_ceil(pass_row_offset(pass)),
+        false => 0,
+    }
+}
+
+/* Number of columns of an image of the given width in the pass */
+pub fn pass_cols(width: u32, pass: u8) -> u32
+{
+    let start = pass_start_col(pass);
+
+    match width > start {
+        true => (width - start).div_ceil(pass_col_offset(pass)),
+        false => 0,
+    }
+}
+
+/* Row in the image of the row 'y' of the pass */
+pub fn row_from_pass_row(y: u32, pass: u8) -> u32
+{
+    y * pass_row_offset(pass) + pass_start_row(pass)
+}
+
+/* Column in the image of the column 'x' of the pass */
+pub fn col_from_pass_col(x: u32, pass: u8) -> u32
+{
+    x * pass_col_offset(pass) + pass_start_col(pass)
+}
+
+/* Whether the row 'y' of the image has pixels in the pass */
+pub fn row_in_interlace_pass(y: u32, pass: u8) -> bool
+{
+    (y & 7) >= pass_start_row(pass) && ((y & 7) - pass_start_row(pass)).is_multiple_of(pass_row_offset(pass))
+}
+
+/* Whether the column 'x' of the image has pixels in the pass */
+pub fn col_in_interlace_pass(x: u32, pass: u8) -> bool
+{
+    (x & 7) >= pass_start_col(pass) && ((x & 7) - pass_start_col(pass)).is_multiple_of(pass_col_offset(pass))
+}
+
+/* Width of the block a pixel of the pass stands for until the later passes
+ * fill it in (the 'rectangle' display)
+ */
+pub fn pass_block_width(pass: u8) -> u32
+{
+    match pass {
+        0 => 8,
+        _ => pass_col_offset(pass) >> (pass & 1),
+    }
+}
+
+/* Height of the block a pixel of the pass stands for */
+pub fn pass_block_height(pass: u8) -> u32
+{
+    match pass {
+        0 => 8,
+        _ => pass_row_offset(pass) >> (1 - (pass & 1)),
+    }
+}
+
+/* Whether the 'rectangle' display of the pass writes the column 'x': each
+ * pixel of the pass is replicated to the right over the columns the later
+ * passes will fill.
+ */
+fn col_in_display_block(x: u32, pass: u8) -> bool
+{
+    (x & 7) >= pass_start_col(pass) &&
+        ((x & 7) - pass_start_col(pass)) % pass_col_offset(pass) < pass_block_width(pass)
+}
+
+/* Shift of the pixel 'x' within its byte for sub-byte pixel depths.  PNG
+ * stores the leftmost pixel in the most significant bits, PACKSWAP in the
+ * least significant ones.
+ */
+fn pixel_shift(x: usize, pixel_depth: usize, packswap: bool) -> usize
+{
+    let bit = (x * pixel_depth) & 0x07;
+
+    match packswap {
+        true => bit,
+        false => 8 - pixel_depth - bit,
+    }
+}
+
+/* Copy the pixel 'sx' of sp to the pixel 'dx' of dp */
+pub(crate) fn move_pixel(dp: &mut [u8], dx: usize, sp: &[u8], sx: usize, pixel_depth: usize, packswap: bool)
+{
+    if pixel_depth >= 8
+    {
+        let bytes = pixel_depth >> 3;
+        dp[dx * bytes..(dx + 1) * bytes].copy_from_slice(&sp[sx * bytes..(sx + 1) * bytes]);
+    }
+    else
+    {
+        let mask = ((1u16 << pixel_depth) - 1) as u8;
+        let v = (sp[(sx * pixel_depth) >> 3] >> pixel_shift(sx, pixel_depth, packswap)) & mask;
+        let shift = pixel_shift(dx, pixel_depth, packswap);
+        let byte = &mut dp[(dx * pixel_depth) >> 3];
+        *byte = (*byte & !(mask << shift)) | (v << shift);
+    }
+}
+
+/* Copy the pixel 'x' from sp to dp, both rows having the same layout */
+fn copy_pixel(dp: &mut [u8], sp: &[u8], x: usize, pixel_depth: usize, packswap: bool)
+{
+    move_pixel(dp, x, sp, x, pixel_depth, packswap);
+}
+
+/* Extract the pixels of the pass from a row of the image (or a row expanded
+ * by do_read_interlace), packed as the pass row was in the file.
+ */
+pub fn pass_pixels(row: &[u8], width: u32, pixel_depth: u8, pass: u8, packswap: bool) -> Vec<u8>
+{
+    let cols = pass_cols(width, pass);
+    let mut dp = vec![0; Png::compute_rowbytes(pixel_depth, cols as usize)];
+
+    for i in 0..cols {
+        move_pixel(&mut dp, i as usize, row, col_from_pass_col(i, pass) as usize,
+                   pixel_depth as usize, packswap);
+    }
+
+    dp
+}
+
+impl PngRowInfo {
+    /* Expand a row of the interlace pass 'pass' (0 - 5) to the full width by
+     * replicating each pixel over its block.  This works in place from the
+     * right; the row must have room for width * PNG_PASS_INC[pass] pixels.
+     */
+    pub fn do_read_interlace(&mut self, row: &mut [u8], pass: u8, packswap: bool)
+    {
+        if pass >= 6
+        {
+            return;
+        }
+
+        let pixel_depth = self.pixel_depth as usize;
+        let inc = PNG_PASS_INC[pass as usize] as usize;
+        let width = self.width as usize;
+        let final_width = width * inc;
+        assert!(Png::compute_rowbytes(self.pixel_depth, final_width) <= row.len());
+
+        if pixel_depth >= 8
+        {
+            let bytes = pixel_depth >> 3;
+
+            for i in (0..width).rev() {
+                let mut v = [0; 8]; /* SAFE; pixel_depth does not exceed 64 */
+                v[..bytes].copy_from_slice(&row[i * bytes..(i + 1) * bytes]);
+
+                for j in (0..inc).rev() {
+                    let dp = (i * inc + j) * bytes;
+                    row[dp..dp + bytes].copy_from_slice(&v[..bytes]);
+                }
+            }
+        }
+        else if pixel_depth > 0
+        {
+            let mask = ((1u16 << pixel_depth) - 1) as u8;
+
+            for i in (0..width).rev() {
+                let v = (row[(i * pixel_depth) >> 3] >> pixel_shift(i, pixel_depth, packswap)) & mask;
+
+                for j in (0..inc).rev() {
+                    let x = i * inc + j;
+                    let shift = pixel_shift(x, pixel_depth, packswap);
+                    let byte = &mut row[(x * pixel_depth) >> 3];
+                    *byte = (*byte & !(mask << shift)) | (v << shift);
+                }
+            }
+        }
+
+        self.width = final_width as u32;
+        self.rowbytes = Png::compute_rowbytes(self.pixel_depth, final_width);
+    }
+
+    /* Pick out the pixels of the interlace pass 'pass' (0 - 5) from a full
+     * row.  The row is compacted in place from the left, the source pixel
+     * is never before the destination one so nothing is overwritten before
+     * it is read.  Pixels below 8 bits are packed in whole bytes, with the
+     * padding bits of the last byte cleared.
+     */
+    pub fn do_write_interlace(&mut self, row: &mut [u8], pass: u8, packswap: bool)
+    {
+        /* We don't have to do anything on the last pass (6) */
+        if pass >= 6
+        {
+            return;
+        }
+
+        let pixel_depth = self.pixel_depth as usize;
+        let cols = pass_cols(self.width, pass) as usize;
+
+        if pixel_depth >= 8
+        {
+            let bytes = pixel_depth >> 3;
+
+            for i in 0..cols {
+                let sx = col_from_pass_col(i as u32, pass) as usize;
+                row.copy_within(sx * bytes..(sx + 1) * bytes, i * bytes);
+            }
+        }
+        else if pixel_depth > 0
+        {
+            let mask = ((1u16 << pixel_depth) - 1) as u8;
+            let mut d = 0u8;
+
+            /* A byte is only written once all its pixels are known, the
+             * pixels still to read are all in the following bytes.
+             */
+            for i in 0..cols {
+                let sx = col_from_pass_col(i as u32, pass) as usize;
+                let v = (row[(sx * pixel_depth) >> 3] >> pixel_shift(sx, pixel_depth, packswap)) & mask;
+                d |= v << pixel_shift(i, pixel_depth, packswap);
+
+                if ((i + 1) * pixel_depth) & 0x07 == 0
+                {
+                    row[(i * pixel_depth) >> 3] = d;
+                    d = 0;
+                }
+            }
+
+            if (cols * pixel_depth) & 0x07 != 0
+            {
+                row[(cols * pixel_depth) >> 3] = d;
+            }
+        }
+
+        self.width = cols as u32;
+        self.rowbytes = Png::compute_rowbytes(self.pixel_depth, cols);
+    }
+}
+
+/* Combines a row of 'width' pixels (sp) with the existing pixels in the row
+ * of the application (dp).  With 'pass' set to an interlace pass (0 - 6) of
+ * an expanded row, only the pixels of the pass, or of their block in the
+ * 'rectangle' display, are written; otherwise the whole row is.  The padding
+ * bits of the last byte of dp are never modified.
+ */
+pub fn combine_row(dp: &mut [u8], sp: &[u8], width: u32, pixel_depth: u8,
+                   pass: Option<u8>, display: PngDisplay, packswap: bool)
+{
+    let rowbytes = Png::compute_rowbytes(pixel_depth, width as usize);
+    assert!(rowbytes <= dp.len() && rowbytes <= sp.len());
+
+    /* The whole row is written for non-interlaced rows, for the last pass
+     * and for the passes of the 'rectangle' display which fill whole rows.
+     */
+    let pass = match pass {
+        Some(pass) if pass < 6 && (display == PngDisplay::Sparkle || (pass & 1) != 0) => pass,
+        _ => {
+            let end_bits = (pixel_depth as usize * width as usize) & 0x07;
+
+            match end_bits {
+                0 => {
+                    dp[..rowbytes].copy_from_slice(&sp[..rowbytes]);
+                },
+                _ => {
+                    dp[..rowbytes - 1].copy_from_slice(&sp[..rowbytes - 1]);
+
+                    /* Only the bits of the pixels of the last byte */
+                    let mask = match packswap {
+                        true => 0xff >> (8 - end_bits),
+                        false => 0xff << (8 - end_bits),
+                    };
+                    dp[rowbytes - 1] = (dp[rowbytes - 1] & !mask) | (sp[rowbytes - 1] & mask);
+                },
+            }
+            return;
+        },
+    };
+
+    for x in 0..width {
+        let copy = match display {
+            PngDisplay::Sparkle => col_in_interlace_pass(x, pass),
+            PngDisplay::Rectangle => col_in_display_block(x, pass),
+        };
+
+        if copy
+        {
+            copy_pixel(dp, sp, x as usize, pixel_depth as usize, packswap);
+        }
+    }
+}
+
+impl Png {
+    /* Combines the row recently read in (row_buf) with the existing pixels in
+     * the row of the application; without a display mode the whole row is
+     * copied.  The errors on the row sizes are checked by the C caller.
+     */
+    pub fn combine_row(&self, dp: &mut [u8], sp: &[u8], display: Option<PngDisplay>)
+    {
+        let (pass, display) = match display {
+            Some(display) if self.interlaced != PngInterlace::None &&
+                self.transformations.contains(PngTransformations::INTERLACE) => (Some(self.pass), display),
+            _ => (None, PngDisplay::Sparkle),
+        };
+
+        combine_row(dp, sp, self.width, self.transformed_pixel_depth, pass, display,
+                    self.transformations.contains(PngTransformations::PACKSWAP));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PngColor;
+
+    const PIXEL_DEPTHS: [u8; 9] = [1, 2, 4, 8, 16, 24, 32, 48, 64];
+
+    /* A small deterministic generator for the test rows */
+    struct Lcg(u32);
+
+    impl Lcg {
+        fn row(&mut self, len: usize) -> Vec<u8>
+        {
+            (0..len).map(|_| {
+                self.0 = self.0.wrapping_mul(1103515245).wrapping_add(12345);
+                (self.0 >> 16) as u8
+            }).collect()
+        }
+    }
+
+    fn row_info(width: u32, pixel_depth: u8) -> PngRowInfo
+    {
+        let mut row_info = PngRowInfo::new(width, PngColor::TYPE_GRAY, 8);
+        row_info.pixel_depth = pixel_depth;
+        row_info.rowbytes = Png::compute_rowbytes(pixel_depth, width as usize);
+        row_info
+    }
+
+    /* The PACKSWAP layout of a row is the PNG layout with the order of the
+     * pixels reversed within each byte.
+     */
+    fn swap_pixels(row: &[u8], pixel_depth: u8) -> Vec<u8>
+    {
+        let depth = pixel_depth as u32;
+        let mask = ((1u16 << depth) - 1) as u8;
+
+        row.iter().map(|&b| {
+            (0..8 / depth).fold(0, |d, k| d | (((b >> (k * depth)) & mask) << (8 - depth - k * depth)))
+        }).collect()
+    }
+
+    /* png_do_read_interlace of libpng 1.6, the sub-byte cases only differ
+     * in the pixel depth.
+     */
+    fn libpng_read_interlace(width: u32, pixel_depth: u8, row: &mut [u8], pass: usize, packswap: bool) -> u32
+    {
+        let final_width = width * PNG_PASS_INC[pass] as u32;
+        let jstop = PNG_PASS_INC[pass] as usize;
+
+        match pixel_depth {
+            1 | 2 | 4 => {
+                let depth = pixel_depth as i32;
+                let ppb = 8 / pixel_depth as u32;
+                let mut sp = (((width - 1) * pixel_depth as u32) >> 3) as usize;
+                let mut dp = (((final_width - 1) * pixel_depth as u32) >> 3) as usize;
+                let (mut sshift, mut dshift, s_start, s_end, s_inc) = match packswap {
+                    true => ((((width + ppb - 1) & (ppb - 1)) * depth as u32) as i32,
+                             (((final_width + ppb - 1) & (ppb - 1)) * depth as u32) as i32,
+                             8 - depth, 0, -depth),
+                    false => (((ppb - 1 - ((width + ppb - 1) & (ppb - 1))) * depth as u32) as i32,
+                              ((ppb - 1 - ((final_width + ppb - 1) & (ppb - 1))) * depth as u32) as i32,
+                              0, 8 - depth, depth),
+                };
+
+                for _ in 0..width {
+                    let v = (row[sp] >> sshift) & ((1 << depth) - 1);
+
+                    for _ in 0..jstop {
+                        let keep = match pixel_depth {
+                            1 => 0x7f7f >> (7 - dshift),
+                            2 => 0x3f3f >> (6 - dshift),
+                            _ => 0xf0f >> (4 - dshift),
+                        };
+                        let tmp = (row[dp] as u32 & keep) | ((v as u32) << dshift);
+                        row[dp] = (tmp & 0xff) as u8;
+
+                        match dshift == s_end {
+                            true => {
+                                dshift = s_start;
+                                dp = dp.wrapping_sub(1);
+                            },
+                            false => dshift += s_inc,
+                        }
+                    }
+
+                    match sshift == s_end {
+                        true => {
+                            sshift = s_start;
+                            sp = sp.wrapping_sub(1);
+                        },
+                        false => sshift += s_inc,
+                    }
+                }
+            },
+            _ => {
+                let pixel_bytes = (pixel_depth >> 3) as usize;
+                let mut sp = (width as usize - 1) * pixel_bytes;
+                let mut dp = (final_width as usize - 1) * pixel_bytes;
+
+                for _ in 0..width {
+                    let mut v = [0; 8];
+                    v[..pixel_bytes].copy_from_slice(&row[sp..sp + pixel_bytes]);
+
+                    for _ in 0..jstop {
+                        row[dp..dp + pixel_bytes].copy_from_slice(&v[..pixel_bytes]);
+                        dp = dp.wrapping_sub(pixel_bytes);
+                    }
+
+                    sp = sp.wrapping_sub(pixel_bytes);
+                }
+            },
+        }
+
+        final_width
+    }
+
+    /* png_do_write_interlace of libpng 1.6, which knows nothing of
+     * PACKSWAP: libpng writes the interlace before the transformations.
+     */
+    fn libpng_write_interlace(width: u32, pixel_depth: u8, row: &mut [u8], pass: usize) -> u32
+    {
+        if pass >= 6
+        {
+            return width;
+        }
+
+        let start = PNG_PASS_START[pass] as u32;
+        let inc = PNG_PASS_INC[pass] as u32;
+
+        match pixel_depth {
+            1 | 2 | 4 => {
+                let depth = pixel_depth as u32;
+                let ppb = 8 / depth;
+                let mut dp = 0;
+                let mut d = 0u32;
+                let mut shift = 8 - depth;
+
+                for i in (start..width).step_by(inc as usize) {
+                    let sp = (i / ppb) as usize;
+                    let value = (row[sp] as u32 >> ((ppb - 1 - (i & (ppb - 1))) * depth)) & ((1 << depth) - 1);
+                    d |= value << shift;
+
+                    match shift {
+                        0 => {
+                            shift = 8 - depth;
+                            row[dp] = d as u8;
+                            dp += 1;
+                            d = 0;
+                        },
+                        _ => shift -= depth,
+                    }
+                }
+
+                if shift != 8 - depth
+                {
+                    row[dp] = d as u8;
+                }
+            },
+            _ => {
+                let pixel_bytes = (pixel_depth >> 3) as usize;
+                let mut dp = 0;
+
+                for i in (start..width).step_by(inc as usize) {
+                    let sp = i as usize * pixel_bytes;
+                    row.copy_within(sp..sp + pixel_bytes, dp);
+                    dp += pixel_bytes;
+                }
+            },
+        }
+
+        (width + inc - 1 - start) / inc
+    }
+
+    #[test]
+    fn read_interlace_sweep()
+    {
+        let mut lcg = Lcg(31);
+
+        for &pixel_depth in PIXEL_DEPTHS.iter() {
+            for (pass, &inc) in PNG_PASS_INC.iter().enumerate() {
+                for width in 1..=17 {
+                    for &packswap in [false, true].iter() {
+                        let final_width = width * inc as u32;
+                        let row = lcg.row(Png::compute_rowbytes(pixel_depth, final_width as usize));
+
+                        let mut expected = row.clone();
+                        let expected_width = libpng_read_interlace(width, pixel_depth, &mut expected, pass, packswap);
+                        assert_eq!(expected_width, final_width);
+
+                        let mut rust = row.clone();
+                        let mut info = row_info(width, pixel_depth);
+                        info.do_read_interlace(&mut rust, pass as u8, packswap);
+                        assert_eq!(rust, expected, "depth {} pass {} width {} packswap {}",
+                                   pixel_depth, pass, width, packswap);
+                        assert_eq!(info.width, final_width);
+                        assert_eq!(info.rowbytes, Png::compute_rowbytes(pixel_depth, final_width as usize));
+
+                        let mut c = row.clone();
+                        let mut info = row_info(width, pixel_depth);
+                        let transformations = match packswap {
+                            true => PngTransformations::PACKSWAP.bits(),
+                            false => 0,
+                        };
+                        unsafe {
+                            png_do_read_interlace(&mut info, c.as_mut_ptr() as CPtr, pass as i32, transformations);
+                        }
+                        assert_eq!(c, expected);
+                        assert_eq!(info.width, final_width);
+
+                        /* The pixels of the pass are the ones given */
+                        if pass < 6
+                        {
+                            let given = Png::compute_rowbytes(pixel_depth, width as usize);
+                            let mut pass_row = pass_pixels(&rust, final_width, pixel_depth, pass as u8, packswap);
+                            let last = pass_row.len() - 1;
+                            let end_bits = (width as usize * pixel_depth as usize) & 0x07;
+                            if end_bits != 0
+                            {
+                                let mask = match packswap {
+                                    true => 0xff >> (8 - end_bits),
+                                    false => 0xff << (8 - end_bits),
+                                };
+                                pass_row[last] |= row[given - 1] & !mask;
+                            }
+                            assert_eq!(pass_row.len(), given);
+                            assert_eq!(&pass_row[..], &row[..given]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn write_interlace_sweep()
+    {
+        let mut lcg = Lcg(2095);
+
+        for &pixel_depth in PIXEL_DEPTHS.iter() {
+            for pass in 0..7 {
+                for width in 1..=33 {
+                    for &packswap in [false, true].iter() {
+                        let row = lcg.row(Png::compute_rowbytes(pixel_depth, width as usize));
+
+                        /* With PACKSWAP the pixels are extracted as libpng
+                         * does from the row in the PNG layout.
+                         */
+                        let swapped = packswap && pixel_depth < 8;
+                        let mut expected = match swapped {
+                            true => swap_pixels(&row, pixel_depth),
+                            false => row.clone(),
+                        };
+                        let cols = libpng_write_interlace(width, pixel_depth, &mut expected, pass);
+                        if swapped
+                        {
+                            expected = swap_pixels(&expected, pixel_depth);
+                        }
+                        assert_eq!(cols, match pass {
+                            6 => width,
+                            _ => pass_cols(width, pass as u8),
+                        });
+
+                        let mut rust = row.clone();
+                        let mut info = row_info(width, pixel_depth);
+                        info.do_write_interlace(&mut rust, pass as u8, packswap);
+                        assert_eq!(rust, expected, "depth {} pass {} width {} packswap {}",
+                                   pixel_depth, pass, width, packswap);
+                        assert_eq!(info.width, cols);
+                        assert_eq!(info.rowbytes, Png::compute_rowbytes(pixel_depth, cols as usize));
+
+                        let mut c = row.clone();
+                        let mut info = row_info(width, pixel_depth);
+                        let transformations = match packswap {
+                            true => PngTransformations::PACKSWAP.bits(),
+                            false => 0,
+                        };
+                        unsafe {
+                            png_do_write_interlace(&mut info, c.as_mut_ptr() as CPtr, pass as i32, transformations);
+                        }
+                        assert_eq!(c, expected);
+                        assert_eq!(info.width, cols);
+
+                        if pass < 6
+                        {
+                            let rowbytes = Png::compute_rowbytes(pixel_depth, cols as usize);
+                            assert_eq!(pass_pixels(&row, width, pixel_depth, pass as u8, packswap),
+                                       &rust[..rowbytes]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
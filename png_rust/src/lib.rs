@@ -10,14 +10,55 @@ use std::collections::VecDeque;
 mod read;
 mod rutil;
 mod trans;
+mod rtran;
+mod wtran;
 mod set;
 mod get;
 mod pread;
 mod png;
 mod png_info;
+pub mod quantize;
+pub mod interlace;
+pub mod filter;
+pub mod deflate;
+mod wutil;
+mod write;
+mod encoder;
+mod gamma;
+mod decoder;
+mod image;
+mod apng;
+mod mng;
+mod source;
+mod push;
+mod chunk;
+#[cfg(feature = "async")]
+mod async_decoder;
+#[cfg(all(test, feature = "c-tests"))]
+mod libpng;
+
+pub use trans::{PngRowInfo, PngPaletteIndexes, RowTransform, RowTransformChain};
+pub use png_info::{PngInfo, PngColor8, PngColor16, PngPaletteColor, PngXy, PngTime};
+pub use png_info::{PngText, PngTextCompression, PngSplt, PngSpltEntry, PngPcal, PngUnknownChunk};
+pub use png_info::{PngCicp, PngMasteringDisplay, PngContentLightLevel};
+pub use wutil::{ChunkWriter, PNG_SIGNATURE};
+pub use encoder::StreamEncoder;
+pub use decoder::PngDecoder;
+pub use source::PngReader;
+pub use push::PngPushDecoder;
+pub use chunk::{ChunkReader, PngChunk};
+#[cfg(feature = "async")]
+pub use async_decoder::AsyncDecoder;
+pub use image::{Image, PngFormat, PngImageFlags};
+pub use apng::{PngAnimationControl, PngFrameControl, PngFrame, PngDisposeOp, PngBlendOp, ApngCompositor};
+pub use apng::ApngEncoder;
+pub use mng::{MngDecoder, MngHeader, MngImage, MNG_SIGNATURE, JNG_SIGNATURE};
+pub use pread::{PngProgressiveEvent, PngPreview};
+pub use filter::{PngFilterValue, PngFilterHeuristic};
+pub use deflate::{PngCompression, PngZlibStrategy, PngFlush};
 
 const PNG_USER_CHUNK_CACHE_MAX: u32 = 1000 as u32;
-const PNG_USER_CHUNK_MALLOC_MAX: usize = 8000000 as usize;
+pub const PNG_USER_CHUNK_MALLOC_MAX: usize = 8000000 as usize;
 const PNG_USER_HEIGHT_MAX: u32 = 1000000 as u32;
 const PNG_USER_WIDTH_MAX: u32 = 1000000 as u32;
 
@@ -229,6 +270,9 @@ bitflags! {
         const sCAL = 0x4000;  /* ESR, 1.0.6 */
         const IDAT = 0x8000;  /* ESR, 1.0.6 */
         const eXIf = 0x10000; /* GR-P, 1.6.31 */
+        const cICP = 0x20000; /* PNG third edition */
+        const mDCv = 0x40000;
+        const cLLi = 0x80000;
     }
 }
 
@@ -262,9 +306,14 @@ enum PngChunkType {
     IEND = 0x49_45_4E_44,
     IHDR = 0x49_48_44_52,
     PLTE = 0x50_4C_54_45,
+    acTL = 0x61_63_54_4C, /* APNG */
     bKGD = 0x62_4B_47_44,
     cHRM = 0x63_48_52_4D,
+    cICP = 0x63_49_43_50, /* PNG third edition */
+    cLLi = 0x63_4C_4C_69, /* PNG third edition */
     eXIf = 0x65_58_49_66, /* registered July 2017 */
+    fcTL = 0x66_63_54_4C, /* APNG */
+    fdAT = 0x66_64_41_54, /* APNG */
     fRAc = 0x66_52_41_63, /* registered, not defined */
     gAMA = 0x67_41_4D_41,
     gIFg = 0x67_49_46_67,
@@ -273,6 +322,7 @@ enum PngChunkType {
     hIST = 0x68_49_53_54,
     iCCP = 0x69_43_43_50,
     iTXt = 0x69_54_58_74,
+    mDCv = 0x6D_44_43_76, /* PNG third edition */
     oFFs = 0x6F_46_46_73,
     pCAL = 0x70_43_41_4C,
     pHYs = 0x70_48_59_73,
@@ -330,6 +380,8 @@ pub struct Png {
 
     try_row: CPtr,        /* buffer to save trial row when filtering */
     tst_row: CPtr,        /* buffer to save best trial row when filtering */
+    filter_heuristic: filter::PngFilterHeuristic, /* how the filter of a row
+                                                   * is chosen */
 
     info_rowbytes: usize, /* Added in 1.5.4: cache of updated row bytes */
 
@@ -339,6 +391,8 @@ pub struct Png {
     num_palette: u16,   /* number of color entries in palette */
 
     num_palette_max: i32, /* maximum palette index found in IDAT */
+    first_invalid_index: Option<(u32, u32)>, /* row and column of the first
+                                              * index beyond num_palette */
 
     usr_channels: u8,     /* channels at start of write: write only */
 
@@ -351,6 +405,8 @@ pub struct Png {
     info_fn: CPtr,              /* called after header data fully read */
     row_fn: CPtr,               /* called after a prog. row is decoded */
     end_fn: CPtr,               /* called after image is complete */
+    progressive_events: Option<VecDeque<pread::PngProgressiveEvent>>, /* events
+                                 * of the progressive reader, when enabled */
 
     save_buffer: VecDeque<u8>,    /* buffer for previously read data */
     current_buffer: VecDeque<u8>, /* buffer for recently used data */
@@ -367,6 +423,19 @@ pub struct Png {
     process_mode: PngPushMode,  /* what push library is currently doing */
     cur_palette: i32,           /* current push library palette index */
     zowner: u32,                /* ID (chunk type) of zstream owner, 0 if none */
+
+    /* Compression of the Rust writer */
+    zlib_compression: deflate::PngCompression,      /* IDAT parameters */
+    zlib_text_compression: deflate::PngCompression, /* zTXt, iTXt and iCCP
+                                                     * parameters */
+    zbuffer_size: usize,        /* size of the IDAT chunks */
+    idat_encoder: Option<deflate::ZlibEncoder>, /* IDAT stream being written */
+    idat_buffer: Vec<u8>,       /* compressed data not yet in an IDAT */
+    row_buffers: Option<write::PngRowBuffers>, /* rows of the Rust writer */
+    flush_dist: u32,            /* how many rows apart to flush, 0 - no flush */
+    flush_rows: u32,            /* number of rows written since last flush */
+    compression_threads: usize, /* threads deflating the image of write_image */
+
     io_ptr: CPtr,               /* ptr to application struct for I/O functions */
 
     mng_features_permitted: PngMng,
@@ -384,7 +453,15 @@ pub struct Png {
      * can occupy when decompressed.  0 means unlimited.
      */
     user_chunk_malloc_max: usize,
+    unknown_default: PngHandleChunk, /* handling of the chunks not in chunk_list */
+    chunk_list: Vec<([u8; 4], PngHandleChunk)>, /* handling of each chunk set by
+                                                 * set_keep_unknown_chunks */
     filler: u16,                /* filler bytes for pixel expansion */
+    shift: PngColor8,           /* shift for significant bit transformation */
+    quantizer: Option<quantize::Quantizer>, /* QUANTIZE palette and lookup tables */
+    quantize_dither: bool,      /* Floyd-Steinberg dithering of QUANTIZE */
+    read_user_transform: trans::RowTransformChain,  /* USER_TRANSFORM on read */
+    write_user_transform: trans::RowTransformChain, /* USER_TRANSFORM on write */
 }
 
 impl Drop for Png {
@@ -398,69 +475,101 @@ impl Drop for Png {
 #[no_mangle]
 pub extern fn png_rust_new() -> *mut Png
 {
-    let obj = Box::new(Png {
-        png_ptr: 0,
-        mode: PngMode::empty(),
-        flags: PngFlags::empty(),
-        transformations: PngTransformations::empty(),
-        pass : 0,
-        //compression : 0,
-        interlaced: PngInterlace::None,
-        //filter : 0,
-        num_trans: 0,
-        do_filter: PngFilter::empty(),
-        color_type: PngColor::MASK_PALETTE,
-        bit_depth: 0,
-        usr_bit_depth: 0,
-        pixel_depth: 0,
-        channels: 0,
-        width: 0,
-        height: 0,
-        num_rows: 0,
-        usr_width: 0,
-        rowbytes: 0,
-        iwidth: 0,
-        row_number: 0,
-        chunk_name: PngChunkType::NULL,
-        prev_row: 0,
-        row_buf: 0,
-        try_row: 0,
-        tst_row: 0,
-        info_rowbytes: 0,
-        idat_size: 0,
-        crc: 0,
-        palette: 0,
-        num_palette: 0,
-        num_palette_max: 0,
-        usr_channels: 0,
-        sig_bytes: 0,
-        maximum_pixel_depth: 0,
-        transformed_pixel_depth: 0,
-        info_fn: 0,
-        row_fn: 0,
-        end_fn: 0,
-        save_buffer: VecDeque::new(),
-        current_buffer: VecDeque::new(),
-        read_buffer: 0,
-        read_buffer_size: 0,
-        push_length: 0,
-        skip_length: 0,
-        buffer_size: 0,
-        process_mode: PngPushMode::ReadSig,
-        cur_palette: 0,
-        zowner: 0,
-        io_ptr: 0,
-        mng_features_permitted: PngMng::empty(),
-        filter_type: PngFilterType::Base,
-        user_width_max: PNG_USER_WIDTH_MAX,
-        user_height_max: PNG_USER_HEIGHT_MAX,
-        user_chunk_cache_max: PNG_USER_CHUNK_CACHE_MAX,
-        user_chunk_malloc_max: PNG_USER_CHUNK_MALLOC_MAX,
-        filler: 0,
-    });
+    let obj = Box::new(Png::new());
     Box::into_raw(obj)
 }
 
+impl Png {
+    pub fn new() -> Png
+    {
+        Png {
+            png_ptr: 0,
+            mode: PngMode::empty(),
+            flags: PngFlags::empty(),
+            transformations: PngTransformations::empty(),
+            pass : 0,
+            //compression : 0,
+            interlaced: PngInterlace::None,
+            //filter : 0,
+            num_trans: 0,
+            do_filter: PngFilter::empty(),
+            color_type: PngColor::MASK_PALETTE,
+            bit_depth: 0,
+            usr_bit_depth: 0,
+            pixel_depth: 0,
+            channels: 0,
+            width: 0,
+            height: 0,
+            num_rows: 0,
+            usr_width: 0,
+            rowbytes: 0,
+            iwidth: 0,
+            row_number: 0,
+            chunk_name: PngChunkType::NULL,
+            prev_row: 0,
+            row_buf: 0,
+            try_row: 0,
+            tst_row: 0,
+            filter_heuristic: filter::PngFilterHeuristic::default(),
+            info_rowbytes: 0,
+            idat_size: 0,
+            crc: 0,
+            palette: 0,
+            num_palette: 0,
+            num_palette_max: 0,
+            first_invalid_index: None,
+            usr_channels: 0,
+            sig_bytes: 0,
+            maximum_pixel_depth: 0,
+            transformed_pixel_depth: 0,
+            info_fn: 0,
+            row_fn: 0,
+            end_fn: 0,
+            progressive_events: None,
+            save_buffer: VecDeque::new(),
+            current_buffer: VecDeque::new(),
+            read_buffer: 0,
+            read_buffer_size: 0,
+            push_length: 0,
+            skip_length: 0,
+            buffer_size: 0,
+            process_mode: PngPushMode::ReadSig,
+            cur_palette: 0,
+            zowner: 0,
+            zlib_compression: deflate::PngCompression::default(),
+            zlib_text_compression: deflate::PngCompression::default(),
+            zbuffer_size: deflate::PNG_ZBUF_SIZE,
+            idat_encoder: None,
+            idat_buffer: Vec::new(),
+            row_buffers: None,
+            flush_dist: 0,
+            flush_rows: 0,
+            compression_threads: 1,
+            io_ptr: 0,
+            mng_features_permitted: PngMng::empty(),
+            filter_type: PngFilterType::Base,
+            user_width_max: PNG_USER_WIDTH_MAX,
+            user_height_max: PNG_USER_HEIGHT_MAX,
+            user_chunk_cache_max: PNG_USER_CHUNK_CACHE_MAX,
+            user_chunk_malloc_max: PNG_USER_CHUNK_MALLOC_MAX,
+            unknown_default: PngHandleChunk::AsDefault,
+            chunk_list: Vec::new(),
+            filler: 0,
+            shift: PngColor8 {
+                red: 0,
+                green: 0,
+                blue: 0,
+                gray: 0,
+                alpha: 0,
+            },
+            quantizer: None,
+            quantize_dither: false,
+            read_user_transform: trans::RowTransformChain::new(),
+            write_user_transform: trans::RowTransformChain::new(),
+        }
+    }
+}
+
 #[no_mangle]
 pub unsafe extern fn png_rust_free(this: *mut Png)
 {
@@ -0,0 +1,514 @@
+use crate::png_info::{PngColor8, PngInfo, PngPaletteColor};
+use crate::pread::PngProgressiveEvent;
+use crate::trans::RowTransform;
+use crate::Png;
+use crate::CPtr;
+use crate::PngColor;
+use crate::PngInterlace;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_ulong, c_void};
+use std::ptr;
+use std::slice;
+
+/*******************************************************************************
+ *
+ *                               Png C API
+ *
+ ******************************************************************************/
+
+/* The C API of libpng, built with the c-tests feature, for the tests which
+ * compare the Rust code with it.  An error of the C code aborts the test.
+ */
+
+pub type PngStructp = *mut c_void;
+type PngInfop = *mut c_void;
+type PngRwPtr = unsafe extern "C" fn(PngStructp, *mut u8, usize);
+type PngFlushPtr = unsafe extern "C" fn(PngStructp);
+
+pub const PNG_LIBPNG_VER_STRING: &[u8] = b"1.6.38.git\0";
+
+extern "C" {
+    fn png_create_write_struct(user_png_ver: *const c_char, error_ptr: *mut c_void,
+                               error_fn: *mut c_void, warn_fn: *mut c_void) -> PngStructp;
+    fn png_create_info_struct(png_ptr: PngStructp) -> PngInfop;
+    fn png_destroy_write_struct(png_ptr_ptr: *mut PngStructp, info_ptr_ptr: *mut PngInfop);
+    fn png_set_write_fn(png_ptr: PngStructp, io_ptr: *mut c_void,
+                        write_data_fn: PngRwPtr, output_flush_fn: PngFlushPtr);
+    fn png_get_io_ptr(png_ptr: PngStructp) -> *mut c_void;
+    fn png_set_IHDR(png_ptr: PngStructp, info_ptr: PngInfop, width: u32, height: u32,
+                    bit_depth: c_int, color_type: c_int, interlace_method: c_int,
+                    compression_method: c_int, filter_method: c_int);
+    fn png_set_PLTE(png_ptr: PngStructp, info_ptr: PngInfop, palette: *const PngPaletteColor,
+                    num_palette: c_int);
+    fn png_write_info(png_ptr: PngStructp, info_ptr: PngInfop);
+    fn png_write_image(png_ptr: PngStructp, image: *const *const u8);
+    fn png_write_end(png_ptr: PngStructp, info_ptr: PngInfop);
+}
+
+unsafe extern "C" fn write_data(png_ptr: PngStructp, data: *mut u8, length: usize)
+{
+    let output = &mut *(png_get_io_ptr(png_ptr) as *mut Vec<u8>);
+    output.extend_from_slice(slice::from_raw_parts(data, length));
+}
+
+unsafe extern "C" fn flush_data(_png_ptr: PngStructp)
+{
+}
+
+/* The C layout of png_text, png_time, png_sPLT_entry and png_sPLT_t */
+#[repr(C)]
+struct CText {
+    compression: c_int,
+    key: *const c_char,
+    text: *const c_char,
+    text_length: usize,
+    itxt_length: usize,
+    lang: *const c_char,
+    lang_key: *const c_char,
+}
+
+#[repr(C)]
+struct CTime {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+}
+
+#[repr(C)]
+struct CSpltEntry {
+    red: u16,
+    green: u16,
+    blue: u16,
+    alpha: u16,
+    frequency: u16,
+}
+
+#[repr(C)]
+struct CSplt {
+    name: *const c_char,
+    depth: u8,
+    entries: *const CSpltEntry,
+    nentries: i32,
+}
+
+extern "C" {
+    fn png_set_text(png_ptr: PngStructp, info_ptr: PngInfop, text_ptr: *const CText, num_text: c_int);
+    fn png_set_tIME(png_ptr: PngStructp, info_ptr: PngInfop, mod_time: *const CTime);
+    fn png_set_sPLT(png_ptr: PngStructp, info_ptr: PngInfop, entries: *const CSplt, nentries: c_int);
+    fn png_set_pCAL(png_ptr: PngStructp, info_ptr: PngInfop, purpose: *const c_char, x0: i32, x1: i32,
+                    equation_type: c_int, nparams: c_int, units: *const c_char, params: *const *const c_char);
+    fn png_set_sCAL_s(png_ptr: PngStructp, info_ptr: PngInfop, unit: c_int,
+                      swidth: *const c_char, sheight: *const c_char);
+    fn png_set_oFFs(png_ptr: PngStructp, info_ptr: PngInfop, offset_x: i32, offset_y: i32, unit_type: c_int);
+    fn png_set_pHYs(png_ptr: PngStructp, info_ptr: PngInfop, res_x: u32, res_y: u32, unit_type: c_int);
+    fn png_set_hIST(png_ptr: PngStructp, info_ptr: PngInfop, hist: *const u16);
+    fn png_set_eXIf_1(png_ptr: PngStructp, info_ptr: PngInfop, num_exif: u32, exif: *const u8);
+}
+
+/* A NUL terminated copy of 'bytes' */
+fn c_string(bytes: &[u8]) -> CString
+{
+    CString::new(bytes).unwrap()
+}
+
+/* The PNG datastream written by png_write_info, png_write_image and
+ * png_write_end: 'rows' are the rows of the image, 'palette' is written when
+ * it is not empty.
+ */
+pub fn write_png(width: u32, height: u32, bit_depth: u8, color_type: PngColor,
+                 interlace_type: PngInterlace, palette: &[PngPaletteColor], rows: &[Vec<u8>]) -> Vec<u8>
+{
+    let mut info = PngInfo::new();
+    info.width = width;
+    info.height = height;
+    info.bit_depth = bit_depth;
+    info.color_type = color_type;
+    info.interlace_type = interlace_type;
+    if !palette.is_empty()
+    {
+        info.set_palette(palette);
+    }
+
+    write_png_info(&info, rows)
+}
+
+/* write_png with the header, the palette, the texts and the chunks of
+ * png_write_info from 'info', set with the png_set functions.
+ */
+pub fn write_png_info(info: &PngInfo, rows: &[Vec<u8>]) -> Vec<u8>
+{
+    write_png_transformed(info, rows, |_| {})
+}
+
+/* The transformations of the write struct, set by the tests after
+ * png_write_info.
+ */
+extern "C" {
+    pub fn png_set_filler(png_ptr: PngStructp, filler: u32, filler_loc: c_int);
+    pub fn png_set_packing(png_ptr: PngStructp);
+    pub fn png_set_packswap(png_ptr: PngStructp);
+    pub fn png_set_swap(png_ptr: PngStructp);
+    pub fn png_set_shift(png_ptr: PngStructp, true_bits: *const PngColor8);
+    pub fn png_set_swap_alpha(png_ptr: PngStructp);
+    pub fn png_set_invert_alpha(png_ptr: PngStructp);
+    pub fn png_set_bgr(png_ptr: PngStructp);
+    pub fn png_set_invert_mono(png_ptr: PngStructp);
+}
+
+/* write_png_info with the transformations set by 'set_transforms' once the
+ * info is written: 'rows' are then the rows of the application.
+ */
+pub fn write_png_transformed<F>(info: &PngInfo, rows: &[Vec<u8>], set_transforms: F) -> Vec<u8>
+    where F: FnOnce(PngStructp)
+{
+    let mut output: Vec<u8> = Vec::new();
+    let row_pointers: Vec<*const u8> = rows.iter().map(|row| row.as_ptr()).collect();
+
+    unsafe {
+        let mut png_ptr = png_create_write_struct(PNG_LIBPNG_VER_STRING.as_ptr() as *const c_char,
+                                                  ptr::null_mut(), ptr::null_mut(), ptr::null_mut());
+        assert!(!png_ptr.is_null());
+        let mut info_ptr = png_create_info_struct(png_ptr);
+
+        png_set_write_fn(png_ptr, &mut output as *mut Vec<u8> as *mut c_void, write_data, flush_data);
+        png_set_IHDR(png_ptr, info_ptr, info.width, info.height, info.bit_depth as c_int,
+                     info.color_type.bits() as c_int, info.interlace_type as c_int, 0, 0);
+        if !info.palette().is_empty()
+        {
+            png_set_PLTE(png_ptr, info_ptr, info.palette().as_ptr(), info.palette().len() as c_int);
+        }
+
+        if let Some(exif) = info.exif()
+        {
+            png_set_eXIf_1(png_ptr, info_ptr, exif.len() as u32, exif.as_ptr());
+        }
+
+        if let Some(hist) = info.hist()
+        {
+            png_set_hIST(png_ptr, info_ptr, hist.as_ptr());
+        }
+
+        if let Some((x_offset, y_offset, unit_type)) = info.offsets()
+        {
+            png_set_oFFs(png_ptr, info_ptr, x_offset, y_offset, unit_type as c_int);
+        }
+
+        if let Some(pcal) = info.pcal()
+        {
+            let purpose = c_string(&pcal.purpose);
+            let units = c_string(&pcal.units);
+            let params: Vec<CString> = pcal.params.iter().map(|param| c_string(param)).collect();
+            let param_pointers: Vec<*const c_char> = params.iter().map(|param| param.as_ptr()).collect();
+
+            png_set_pCAL(png_ptr, info_ptr, purpose.as_ptr(), pcal.x0, pcal.x1, pcal.equation_type as c_int,
+                         params.len() as c_int, units.as_ptr(), param_pointers.as_ptr());
+        }
+
+        if let Some((unit, width, height)) = info.scal()
+        {
+            let (width, height) = (c_string(width), c_string(height));
+
+            png_set_sCAL_s(png_ptr, info_ptr, unit as c_int, width.as_ptr(), height.as_ptr());
+        }
+
+        if let Some((x_pixels_per_unit, y_pixels_per_unit, unit_type)) = info.phys()
+        {
+            png_set_pHYs(png_ptr, info_ptr, x_pixels_per_unit, y_pixels_per_unit, unit_type as c_int);
+        }
+
+        if let Some(mod_time) = info.mod_time()
+        {
+            let mod_time = CTime {
+                year: mod_time.year,
+                month: mod_time.month,
+                day: mod_time.day,
+                hour: mod_time.hour,
+                minute: mod_time.minute,
+                second: mod_time.second,
+            };
+
+            png_set_tIME(png_ptr, info_ptr, &mod_time);
+        }
+
+        /* The strings and entries are copied by the png_set functions */
+        let names: Vec<CString> = info.splt().iter().map(|spalette| c_string(&spalette.name)).collect();
+        let entries: Vec<Vec<CSpltEntry>> = info.splt().iter().map(|spalette| {
+            spalette.entries.iter().map(|entry| CSpltEntry {
+                red: entry.red,
+                green: entry.green,
+                blue: entry.blue,
+                alpha: entry.alpha,
+                frequency: entry.frequency,
+            }).collect()
+        }).collect();
+        let splt: Vec<CSplt> = info.splt().iter().enumerate().map(|(i, spalette)| CSplt {
+            name: names[i].as_ptr(),
+            depth: spalette.depth,
+            entries: entries[i].as_ptr(),
+            nentries: entries[i].len() as i32,
+        }).collect();
+        if !splt.is_empty()
+        {
+            png_set_sPLT(png_ptr, info_ptr, splt.as_ptr(), splt.len() as c_int);
+        }
+
+        let strings: Vec<[CString; 4]> = info.text().iter().map(|text| {
+            [c_string(&text.key), c_string(&text.text), c_string(&text.lang), c_string(&text.lang_key)]
+        }).collect();
+        let text: Vec<CText> = info.text().iter().zip(strings.iter()).map(|(text, strings)| CText {
+            compression: text.compression as c_int,
+            key: strings[0].as_ptr(),
+            text: strings[1].as_ptr(),
+            text_length: text.text.len(),
+            itxt_length: 0,
+            lang: strings[2].as_ptr(),
+            lang_key: strings[3].as_ptr(),
+        }).collect();
+        if !text.is_empty()
+        {
+            png_set_text(png_ptr, info_ptr, text.as_ptr(), text.len() as c_int);
+        }
+
+        png_write_info(png_ptr, info_ptr);
+        set_transforms(png_ptr);
+        png_write_image(png_ptr, row_pointers.as_ptr());
+        png_write_end(png_ptr, info_ptr);
+
+        png_destroy_write_struct(&mut png_ptr, &mut info_ptr);
+    }
+
+    output
+}
+
+extern "C" {
+    fn png_create_read_struct(user_png_ver: *const c_char, error_ptr: *mut c_void,
+                              error_fn: *mut c_void, warn_fn: *mut c_void) -> PngStructp;
+    fn png_destroy_read_struct(png_ptr_ptr: *mut PngStructp, info_ptr_ptr: *mut PngInfop,
+                               end_info_ptr_ptr: *mut PngInfop);
+    /* Declared as in pread.rs */
+    fn png_set_read_fn(png_ptr: CPtr, io_ptr: CPtr, read_data_fn: CPtr);
+    fn png_read_info(png_ptr: PngStructp, info_ptr: PngInfop);
+    fn png_set_interlace_handling(png_ptr: PngStructp) -> c_int;
+    fn png_read_update_info(png_ptr: CPtr, info_ptr: CPtr);
+    fn png_get_rowbytes(png_ptr: PngStructp, info_ptr: PngInfop) -> usize;
+    fn png_get_image_height(png_ptr: PngStructp, info_ptr: PngInfop) -> u32;
+    fn png_read_image(png_ptr: PngStructp, image: *mut *mut u8);
+    fn png_read_end(png_ptr: PngStructp, info_ptr: PngInfop);
+    fn png_set_read_rust_transform(png_ptr: PngStructp, transform: *mut c_void);
+    fn png_set_progressive_read_fn(png_ptr: PngStructp, progressive_ptr: *mut c_void, info_fn: *mut c_void,
+                                   row_fn: *mut c_void, end_fn: *mut c_void);
+    fn png_get_progressive_rust_ptr(png_ptr: PngStructp) -> *mut c_void;
+    fn png_process_data(png_ptr: PngStructp, info_ptr: PngInfop, buffer: *mut u8, buffer_size: usize);
+}
+
+extern "C" {
+    fn uncompress(dest: *mut u8, dest_len: *mut c_ulong, source: *const u8, source_len: c_ulong) -> c_int;
+}
+
+/* The data of a zlib stream inflated by zlib itself, None if zlib finds the
+ * stream invalid, its Adler-32 included, or larger than 'max_size'.
+ */
+pub fn zlib_uncompress(zdata: &[u8], max_size: usize) -> Option<Vec<u8>>
+{
+    let mut data = vec![0u8; max_size];
+    let mut size = max_size as c_ulong;
+
+    match unsafe { uncompress(data.as_mut_ptr(), &mut size, zdata.as_ptr(), zdata.len() as c_ulong) } {
+        0 => {
+            data.truncate(size as usize);
+            Some(data)
+        },
+        _ => None,
+    }
+}
+
+unsafe extern "C" fn read_data(png_ptr: PngStructp, data: *mut u8, length: usize)
+{
+    let input = &mut *(png_get_io_ptr(png_ptr) as *mut &[u8]);
+    let (head, tail) = input.split_at(length);
+
+    slice::from_raw_parts_mut(data, length).copy_from_slice(head);
+    *input = tail;
+}
+
+/* The rows of png_read_image, the interlace handling on */
+pub fn read_png(png: &[u8]) -> Vec<Vec<u8>>
+{
+    read_png_transformed(png, Vec::new())
+}
+
+/* read_png with 'transforms' given to png_set_read_rust_transform */
+pub fn read_png_transformed(png: &[u8], transforms: Vec<Box<dyn RowTransform>>) -> Vec<Vec<u8>>
+{
+    let mut input = png;
+
+    unsafe {
+        let mut png_ptr = png_create_read_struct(PNG_LIBPNG_VER_STRING.as_ptr() as *const c_char,
+                                                 ptr::null_mut(), ptr::null_mut(), ptr::null_mut());
+        assert!(!png_ptr.is_null());
+        let mut info_ptr = png_create_info_struct(png_ptr);
+
+        png_set_read_fn(png_ptr as CPtr, &mut input as *mut &[u8] as CPtr, read_data as PngRwPtr as CPtr);
+        png_read_info(png_ptr, info_ptr);
+        png_set_interlace_handling(png_ptr);
+        for transform in transforms {
+            png_set_read_rust_transform(png_ptr, Box::into_raw(Box::new(transform)) as *mut c_void);
+        }
+        png_read_update_info(png_ptr as CPtr, info_ptr as CPtr);
+
+        let rowbytes = png_get_rowbytes(png_ptr, info_ptr);
+        let mut rows = vec![vec![0u8; rowbytes]; png_get_image_height(png_ptr, info_ptr) as usize];
+        let mut row_pointers: Vec<*mut u8> = rows.iter_mut().map(|row| row.as_mut_ptr()).collect();
+
+        png_read_image(png_ptr, row_pointers.as_mut_ptr());
+        png_read_end(png_ptr, ptr::null_mut());
+
+        png_destroy_read_struct(&mut png_ptr, &mut info_ptr, ptr::null_mut());
+        rows
+    }
+}
+
+/* The events of the progressive reader of a png_struct given 'png' in
+ * pieces of 'size' bytes to png_process_data, taken after each piece.
+ */
+pub fn progressive_events(png: &[u8], size: usize) -> Vec<PngProgressiveEvent>
+{
+    let mut events = Vec::new();
+
+    unsafe {
+        let mut png_ptr = png_create_read_struct(PNG_LIBPNG_VER_STRING.as_ptr() as *const c_char,
+                                                 ptr::null_mut(), ptr::null_mut(), ptr::null_mut());
+        assert!(!png_ptr.is_null());
+        let mut info_ptr = png_create_info_struct(png_ptr);
+
+        png_set_progressive_read_fn(png_ptr, ptr::null_mut(), ptr::null_mut(), ptr::null_mut(), ptr::null_mut());
+        let rust_ptr = &mut *(png_get_progressive_rust_ptr(png_ptr) as *mut Png);
+        rust_ptr.set_progressive_events(true);
+
+        for piece in png.chunks(size) {
+            let mut piece = piece.to_vec();
+
+            png_process_data(png_ptr, info_ptr, piece.as_mut_ptr(), piece.len());
+            events.extend(rust_ptr.take_progressive_events());
+        }
+
+        png_destroy_read_struct(&mut png_ptr, &mut info_ptr, ptr::null_mut());
+    }
+
+    events
+}
+
+/* png_image of the simplified API */
+#[repr(C)]
+pub struct PngImage {
+    opaque: *mut c_void,
+    version: u32,
+    pub width: u32,
+    pub height: u32,
+    pub format: u32,
+    pub flags: u32,
+    pub colormap_entries: u32,
+    pub warning_or_error: u32,
+    pub message: [c_char; 64],
+}
+
+const PNG_IMAGE_VERSION: u32 = 1;
+
+extern "C" {
+    fn png_image_begin_read_from_memory(image: *mut PngImage, memory: *const u8, size: usize) -> c_int;
+    fn png_image_finish_read(image: *mut PngImage, background: *const PngPaletteColor,
+                             buffer: *mut u8, row_stride: i32, colormap: *mut u8) -> c_int;
+    fn png_image_free(image: *mut PngImage);
+    fn png_image_write_to_memory(image: *mut PngImage, memory: *mut u8, memory_bytes: *mut usize,
+                                 convert_to_8_bit: c_int, buffer: *const u8, row_stride: i32,
+                                 colormap: *const u8) -> c_int;
+}
+
+impl PngImage {
+    pub fn new(width: u32, height: u32, format: u32) -> PngImage
+    {
+        PngImage {
+            opaque: ptr::null_mut(),
+            version: PNG_IMAGE_VERSION,
+            width,
+            height,
+            format,
+            flags: 0,
+            colormap_entries: 0,
+            warning_or_error: 0,
+            message: [0; 64],
+        }
+    }
+
+    fn message(&self) -> String
+    {
+        let message: Vec<u8> = self.message.iter().take_while(|&&c| c != 0).map(|&c| c as u8).collect();
+
+        String::from_utf8_lossy(&message).into_owned()
+    }
+
+    /* png_image_begin_read_from_memory, None on error.  The image is boxed:
+     * the png_struct keeps its address.
+     */
+    pub fn begin_read_from_memory(png: &[u8]) -> Option<Box<PngImage>>
+    {
+        let mut image = Box::new(PngImage::new(0, 0, 0));
+
+        match unsafe { png_image_begin_read_from_memory(&mut *image, png.as_ptr(), png.len()) } {
+            0 => None,
+            _ => Some(image),
+        }
+    }
+
+    /* png_image_finish_read in 'buffer' and 'colormap', with the 'format'
+     * set by the caller.
+     */
+    pub fn finish_read(&mut self, background: Option<PngPaletteColor>, buffer: &mut [u8], row_stride: i32,
+                       colormap: Option<&mut [u8]>) -> Result<(), String>
+    {
+        let background = background.as_ref().map_or(ptr::null(), |color| color as *const PngPaletteColor);
+        let colormap = colormap.map_or(ptr::null_mut(), |colormap| colormap.as_mut_ptr());
+
+        match unsafe { png_image_finish_read(self, background, buffer.as_mut_ptr(), row_stride, colormap) } {
+            0 => Err(self.message()),
+            _ => Ok(()),
+        }
+    }
+
+    /* png_image_write_to_memory, the datastream written */
+    pub fn write_to_memory(&mut self, convert_to_8bit: bool, buffer: &[u8], row_stride: i32,
+                           colormap: Option<&[u8]>) -> Result<Vec<u8>, String>
+    {
+        let colormap = colormap.map_or(ptr::null(), |colormap| colormap.as_ptr());
+        let mut size = 0;
+
+        unsafe {
+            /* The size first, then the datastream */
+            if png_image_write_to_memory(self, ptr::null_mut(), &mut size, convert_to_8bit as c_int,
+                                         buffer.as_ptr(), row_stride, colormap) == 0
+            {
+                return Err(self.message());
+            }
+
+            let mut png = vec![0u8; size];
+            match png_image_write_to_memory(self, png.as_mut_ptr(), &mut size, convert_to_8bit as c_int,
+                                            buffer.as_ptr(), row_stride, colormap) {
+                0 => Err(self.message()),
+                _ => {
+                    png.truncate(size);
+                    Ok(png)
+                },
+            }
+        }
+    }
+}
+
+impl Drop for PngImage {
+    fn drop(&mut self)
+    {
+        unsafe { png_image_free(self); }
+    }
+}
@@ -0,0 +1,431 @@
+use crate::PngMng;
+use crate::png_info::{PngInfo, PngPaletteColor};
+use crate::decoder::{PngDecoder, read_chunk, get_u16, get_u32};
+
+/*******************************************************************************
+ *
+ *                               Png RUST
+ *
+ ******************************************************************************/
+
+/* Multiple-image Network Graphics (MNG): after the MHDR chunk a MNG datastream
+ * holds PNG datastreams without their signature, from IHDR to IEND, and the
+ * chunks that place and show them, up to MEND.  MngDecoder reads the MNG-VLC
+ * and MNG-LC profiles: the embedded images are returned in order with the
+ * position given by the DEFI chunk before them.  The frame chunks (FRAM,
+ * BACK, TERM) and the loops (LOOP, ENDL) are skipped, so a loop is read once.
+ * The JNG images are skipped too: libpng has no JPEG decoder.
+ */
+
+pub const MNG_SIGNATURE: [u8; 8] = [138, 77, 78, 71, 13, 10, 26, 10];
+pub const JNG_SIGNATURE: [u8; 8] = [139, 74, 78, 71, 13, 10, 26, 10];
+
+/* The bits of the simplicity profile of MHDR */
+const MNG_PROFILE_VALID: u32 = 0x01;
+const MNG_PROFILE_COMPLEX: u32 = 0x04;
+const MNG_PROFILE_DELTA_PNG: u32 = 0x20;
+
+/* The MNG header (MHDR) */
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct MngHeader {
+    pub frame_width: u32,
+    pub frame_height: u32,
+    pub ticks_per_second: u32,
+    pub layer_count: u32,       /* nominal counts, 0 if unknown */
+    pub frame_count: u32,
+    pub play_time: u32,         /* in ticks */
+    pub simplicity_profile: u32,
+}
+
+/* An image of a MNG datastream, at 'x_offset', 'y_offset' of the frame.  The
+ * rows are those PngDecoder::read_image returns, with the intrapixel
+ * differencing undone and the global palette used for an empty PLTE.
+ */
+pub struct MngImage {
+    pub object_id: u16,
+    pub visible: bool,
+    pub x_offset: i32,
+    pub y_offset: i32,
+    pub info: PngInfo,
+    pub data: Vec<u8>,
+}
+
+/* The object definition (DEFI) of the next image */
+#[derive(Clone, Copy, Default)]
+struct MngDefinition {
+    object_id: u16,
+    hidden: bool,
+    x_offset: i32,
+    y_offset: i32,
+}
+
+pub struct MngDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,         /* start of the next chunk */
+    header: Option<MngHeader>,
+    palette: Vec<PngPaletteColor>, /* global PLTE */
+    definition: MngDefinition,
+    ended: bool,        /* MEND read */
+}
+
+impl<'a> MngDecoder<'a> {
+    pub fn new(data: &'a [u8]) -> MngDecoder<'a>
+    {
+        MngDecoder {
+            data,
+            pos: 0,
+            header: None,
+            palette: Vec::new(),
+            definition: MngDefinition::default(),
+            ended: false,
+        }
+    }
+
+    /* Read the signature and the MHDR chunk */
+    pub fn read_header(&mut self) -> Result<MngHeader, &'static str>
+    {
+        if let Some(header) = self.header
+        {
+            return Ok(header);
+        }
+
+        if self.data.len() < 8 || self.data[..8] != MNG_SIGNATURE
+        {
+            return match self.data.len() >= 8 && self.data[..8] == JNG_SIGNATURE {
+                true => Err("JNG files are not supported"),
+                false => Err("Not a MNG file"),
+            };
+        }
+        self.pos = 8;
+
+        let (name, chunk) = read_chunk(self.data, &mut self.pos)?;
+        if &name != b"MHDR"
+        {
+            return Err("Missing MHDR");
+        }
+
+        if chunk.len() != 28
+        {
+            return Err("MHDR: invalid");
+        }
+
+        let header = MngHeader {
+            frame_width: get_u32(chunk),
+            frame_height: get_u32(&chunk[4..]),
+            ticks_per_second: get_u32(&chunk[8..]),
+            layer_count: get_u32(&chunk[12..]),
+            frame_count: get_u32(&chunk[16..]),
+            play_time: get_u32(&chunk[20..]),
+            simplicity_profile: get_u32(&chunk[24..]),
+        };
+
+        let profile = header.simplicity_profile;
+        if profile & MNG_PROFILE_VALID != 0 && profile & (MNG_PROFILE_COMPLEX | MNG_PROFILE_DELTA_PNG) != 0
+        {
+            return Err("MHDR: only the MNG-VLC and MNG-LC profiles are supported");
+        }
+
+        self.header = Some(header);
+        Ok(header)
+    }
+
+    /* Read the next embedded image, None after MEND */
+    pub fn next_image(&mut self) -> Result<Option<MngImage>, &'static str>
+    {
+        self.read_header()?;
+
+        while !self.ended {
+            let chunk_start = self.pos;
+            let (name, chunk) = read_chunk(self.data, &mut self.pos)?;
+
+            match &name {
+                b"IHDR" => {
+                    let end = self.skip_embedded(chunk_start)?;
+                    return self.read_embedded(&self.data[chunk_start..end]).map(Some);
+                },
+                b"JHDR" => {
+                    self.skip_embedded(chunk_start)?;
+                    self.definition = MngDefinition::default();
+                },
+                b"PLTE" => self.handle_plte(chunk)?,
+                b"DEFI" => self.handle_defi(chunk)?,
+                b"MEND" => self.ended = true,
+                b"MHDR" => return Err("MHDR: duplicate"),
+                b"IEND" => return Err("IEND: out of place"),
+                b"TERM" | b"BACK" | b"FRAM" | b"LOOP" | b"ENDL" | b"SAVE" | b"SEEK" => {},
+                _ => {
+                    if name[0] & 0x20 == 0
+                    {
+                        return Err("MNG: unsupported critical chunk");
+                    }
+                },
+            }
+        }
+
+        Ok(None)
+    }
+
+    /* Move past the IEND of the PNG or JNG datastream starting at 'start',
+     * return the end of the IEND chunk.
+     */
+    fn skip_embedded(&mut self, start: usize) -> Result<usize, &'static str>
+    {
+        self.pos = start;
+
+        loop {
+            let (name, _) = read_chunk(self.data, &mut self.pos)?;
+
+            if &name == b"IEND"
+            {
+                return Ok(self.pos);
+            }
+        }
+    }
+
+    fn read_embedded(&mut self, png: &[u8]) -> Result<MngImage, &'static str>
+    {
+        let mut decoder = PngDecoder::new_embedded(png, PngMng::EmptyPlte | PngMng::Filter64, &self.palette);
+        let data = decoder.read_image()?;
+        let definition = std::mem::take(&mut self.definition);
+
+        Ok(MngImage {
+            object_id: definition.object_id,
+            visible: !definition.hidden,
+            x_offset: definition.x_offset,
+            y_offset: definition.y_offset,
+            info: decoder.into_info(),
+            data,
+        })
+    }
+
+    /* The global palette, for the embedded images with an empty PLTE */
+    fn handle_plte(&mut self, chunk: &[u8]) -> Result<(), &'static str>
+    {
+        if !chunk.len().is_multiple_of(3) || chunk.len() / 3 > 256
+        {
+            return Err("PLTE: invalid");
+        }
+
+        self.palette = chunk.chunks(3)
+            .map(|c| PngPaletteColor { red: c[0], green: c[1], blue: c[2] })
+            .collect();
+        Ok(())
+    }
+
+    /* The object id, then optionally the do not show flag, the concrete flag,
+     * the location and the clipping boundaries.  The definition applies to
+     * the next image only.
+     */
+    fn handle_defi(&mut self, chunk: &[u8]) -> Result<(), &'static str>
+    {
+        if ![2, 3, 4, 12, 28].contains(&chunk.len())
+        {
+            return Err("DEFI: invalid");
+        }
+
+        let mut definition = MngDefinition {
+            object_id: get_u16(chunk),
+            ..Default::default()
+        };
+
+        if chunk.len() >= 3
+        {
+            definition.hidden = match chunk[2] {
+                0 => false,
+                1 => true,
+                _ => return Err("DEFI: invalid"),
+            };
+        }
+
+        if chunk.len() >= 12
+        {
+            definition.x_offset = get_u32(&chunk[4..]) as i32;
+            definition.y_offset = get_u32(&chunk[8..]) as i32;
+        }
+
+        self.definition = definition;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate::{zlib_compress, PngCompression};
+    use crate::{ChunkWriter, PngColor, PngFilterType};
+
+    const PALETTE: [[u8; 3]; 5] = [[0, 0, 0], [255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+
+    /* The RGB pixels of the image with intrapixel differencing */
+    const RGB: [[u8; 3]; 6] = [[10, 20, 30], [255, 0, 128], [0, 255, 1], [7, 7, 7], [200, 100, 50], [1, 2, 3]];
+
+    fn header(simplicity_profile: u32) -> Vec<u8>
+    {
+        [8, 6, 100, 3, 1, 0, simplicity_profile].iter().flat_map(|value: &u32| value.to_be_bytes()).collect()
+    }
+
+    fn ihdr(width: u32, height: u32, bit_depth: u8, color_type: u8, filter_method: u8) -> Vec<u8>
+    {
+        [&width.to_be_bytes()[..], &height.to_be_bytes(), &[bit_depth, color_type, 0, filter_method, 0]].concat()
+    }
+
+    /* The compressed rows, with a None filter byte */
+    fn idat(rows: &[Vec<u8>]) -> Vec<u8>
+    {
+        let filtered: Vec<u8> = rows.iter().flat_map(|row| [&[0][..], row].concat()).collect();
+
+        zlib_compress(&PngCompression::default(), &filtered)
+    }
+
+    /* The chunks of a MNG datastream: a global palette, an image of 2 by 2
+     * palette indexes at 2, -3 with an empty PLTE, a hidden RGB image with
+     * intrapixel differencing, a JNG image and a gray image without DEFI.
+     */
+    fn chunks() -> Vec<(&'static [u8; 4], Vec<u8>)>
+    {
+        let differenced: Vec<u8> = RGB.iter().flat_map(|&[red, green, blue]| {
+            vec![red.wrapping_sub(green), green, blue.wrapping_sub(green)]
+        }).collect();
+
+        vec![
+            (b"MHDR", header(1)),
+            (b"TERM", vec![0]),
+            (b"FRAM", vec![]),
+            (b"PLTE", PALETTE.concat()),
+            (b"DEFI", [&[0, 1, 0, 1][..], &2i32.to_be_bytes(), &(-3i32).to_be_bytes()].concat()),
+            (b"IHDR", ihdr(2, 2, 2, 3, 0)),
+            (b"PLTE", vec![]),
+            (b"IDAT", idat(&[vec![0b0001_0000], vec![0b1011_0000]])),
+            (b"IEND", vec![]),
+            (b"DEFI", vec![0, 2, 1]),
+            (b"IHDR", ihdr(3, 2, 8, 2, 64)),
+            (b"IDAT", idat(&[differenced[..9].to_vec(), differenced[9..].to_vec()])),
+            (b"IEND", vec![]),
+            (b"DEFI", vec![0, 3]),
+            (b"JHDR", vec![0; 16]),
+            (b"JDAT", vec![0xff, 0xd8]),
+            (b"IEND", vec![]),
+            (b"IHDR", ihdr(1, 1, 8, 0, 0)),
+            (b"IDAT", idat(&[vec![42]])),
+            (b"IEND", vec![]),
+            (b"MEND", vec![]),
+        ]
+    }
+
+    fn datastream(chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8>
+    {
+        let mut writer = ChunkWriter::new(MNG_SIGNATURE.to_vec());
+
+        for (name, data) in chunks {
+            writer.write_chunk(**name, data).unwrap();
+        }
+
+        writer.into_inner()
+    }
+
+    /* The images of 'mng', up to the first error */
+    fn images(mng: &[u8]) -> Result<Vec<MngImage>, &'static str>
+    {
+        let mut decoder = MngDecoder::new(mng);
+        let mut images = Vec::new();
+
+        while let Some(image) = decoder.next_image()? {
+            images.push(image);
+        }
+
+        Ok(images)
+    }
+
+    #[test]
+    fn embedded_images()
+    {
+        let mng = datastream(&chunks());
+        let mut decoder = MngDecoder::new(&mng);
+
+        assert_eq!(decoder.read_header(), Ok(MngHeader {
+            frame_width: 8,
+            frame_height: 6,
+            ticks_per_second: 100,
+            layer_count: 3,
+            frame_count: 1,
+            play_time: 0,
+            simplicity_profile: 1,
+        }));
+
+        let images = images(&mng).unwrap();
+        assert_eq!(images.len(), 3);
+
+        /* The global palette for the empty PLTE, reduced to 2 bits */
+        let image = &images[0];
+        assert_eq!((image.object_id, image.visible, image.x_offset, image.y_offset), (1, true, 2, -3));
+        assert_eq!(image.info.color_type, PngColor::TYPE_PALETTE);
+        let palette: Vec<[u8; 3]> = image.info.palette().iter().map(|color| [color.red, color.green, color.blue])
+            .collect();
+        assert_eq!(palette, PALETTE[..4]);
+        assert_eq!(image.data, [0b0001_0000, 0b1011_0000]);
+
+        /* The differencing undone */
+        let image = &images[1];
+        assert_eq!((image.object_id, image.visible, image.x_offset, image.y_offset), (2, false, 0, 0));
+        assert_eq!(image.info.filter_type, PngFilterType::Differencing);
+        assert_eq!(image.data, RGB.concat());
+
+        /* The JNG image is skipped with its DEFI */
+        let image = &images[2];
+        assert_eq!((image.object_id, image.visible, image.x_offset, image.y_offset), (0, true, 0, 0));
+        assert_eq!(image.data, [42]);
+
+        assert!(decoder.next_image().unwrap().is_some());
+        assert!(decoder.next_image().unwrap().is_some());
+        assert!(decoder.next_image().unwrap().is_some());
+        assert!(decoder.next_image().unwrap().is_none());
+        assert!(decoder.next_image().unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_datastreams()
+    {
+        let with_chunk = |index: usize, name: &'static [u8; 4], data: Vec<u8>| {
+            let mut chunks = chunks();
+            chunks[index] = (name, data);
+            images(&datastream(&chunks)).err()
+        };
+
+        let png = [&[137, 80, 78, 71, 13, 10, 26, 10][..], &datastream(&chunks())[8..]].concat();
+        assert_eq!(images(&png).err(), Some("Not a MNG file"));
+        let jng = [&JNG_SIGNATURE[..], &datastream(&chunks())[8..]].concat();
+        assert_eq!(images(&jng).err(), Some("JNG files are not supported"));
+
+        /* MHDR, first and once */
+        assert_eq!(images(&datastream(&chunks()[1..])).err(), Some("Missing MHDR"));
+        assert_eq!(with_chunk(0, b"MHDR", header(1)[..24].to_vec()), Some("MHDR: invalid"));
+        assert_eq!(with_chunk(0, b"MHDR", header(1 | MNG_PROFILE_COMPLEX)),
+                   Some("MHDR: only the MNG-VLC and MNG-LC profiles are supported"));
+        assert_eq!(with_chunk(0, b"MHDR", header(MNG_PROFILE_COMPLEX)), None);
+        assert_eq!(with_chunk(1, b"MHDR", header(1)), Some("MHDR: duplicate"));
+
+        /* An empty PLTE needs a global one */
+        assert_eq!(with_chunk(3, b"tEXt", b"Title\0no global palette".to_vec()),
+                   Some("PLTE: empty without a global palette"));
+        assert_eq!(with_chunk(3, b"PLTE", vec![0; 4]), Some("PLTE: invalid"));
+
+        /* Filter method 64 is for RGB images */
+        assert_eq!(with_chunk(10, b"IHDR", ihdr(3, 2, 8, 0, 64)), Some("Unknown filter method in IHDR"));
+        assert_eq!(with_chunk(4, b"DEFI", vec![0, 1, 2]), Some("DEFI: invalid"));
+        assert_eq!(with_chunk(4, b"DEFI", vec![0, 1, 0, 1, 0]), Some("DEFI: invalid"));
+        assert_eq!(with_chunk(13, b"BASI", vec![]), Some("MNG: unsupported critical chunk"));
+        assert_eq!(with_chunk(13, b"IEND", vec![]), Some("IEND: out of place"));
+
+        /* Outside of MNG, filter method 64 and an empty PLTE are invalid */
+        let png = |chunks: &[(&[u8; 4], Vec<u8>)]| {
+            let mut writer = ChunkWriter::new(Vec::new());
+            writer.write_sig(0).unwrap();
+            for (name, data) in chunks {
+                writer.write_chunk(**name, data).unwrap();
+            }
+            writer.into_inner()
+        };
+        assert_eq!(PngDecoder::new(&png(&chunks()[10..13])).read_image().err(), Some("Unknown filter method in IHDR"));
+        assert_eq!(PngDecoder::new(&png(&chunks()[5..9])).read_image().err(), Some("PLTE: invalid"));
+    }
+}
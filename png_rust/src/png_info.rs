@@ -3,17 +3,22 @@ use crate::PngColor;
 use crate::PngFilterType;
 use crate::PngCompressionType;
 use crate::PngInfoChunk;
+use crate::PngMode;
 use crate::CPtr;
+use crate::gamma::PNG_GAMMA_SRGB_INVERSE;
+use std::ffi::CStr;
 use std::fmt;
+use std::os::raw::c_char;
+use std::slice;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 #[repr(C)]
 pub struct PngColor16 {
-    index: u8,    /* used for palette files */
-    red: u16,   /* for use in red green blue files */
-    green: u16,
-    blue: u16,
-    gray: u16,  /* for use in grayscale files */
+    pub index: u8,    /* used for palette files */
+    pub red: u16,   /* for use in red green blue files */
+    pub green: u16,
+    pub blue: u16,
+    pub gray: u16,  /* for use in grayscale files */
 }
 
 impl fmt::Display for PngColor16 {
@@ -22,17 +27,227 @@ impl fmt::Display for PngColor16 {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 #[repr(C)]
 pub struct PngColor8 {
-    red: u8,   /* for use in red green blue files */
-    green: u8,
-    blue: u8,
-    gray: u8,  /* for use in grayscale files */
-    alpha: u8, /* for alpha channel files */
+    pub red: u8,   /* for use in red green blue files */
+    pub green: u8,
+    pub blue: u8,
+    pub gray: u8,  /* for use in grayscale files */
+    pub alpha: u8, /* for alpha channel files */
 }
 
 
+/* One entry of a PLTE chunk (png_color in C) */
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Default)]
+#[repr(C)]
+pub struct PngPaletteColor {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+/* The chromaticities of the red, green, blue and white points, in units of
+ * 1/100000 (png_xy in C)
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct PngXy {
+    pub red_x: i32,
+    pub red_y: i32,
+    pub green_x: i32,
+    pub green_y: i32,
+    pub blue_x: i32,
+    pub blue_y: i32,
+    pub white_x: i32,
+    pub white_y: i32,
+}
+
+/* Last modification time of the image (tIME), in UTC */
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct PngTime {
+    pub year: u16,   /* full year, as in, 1995 */
+    pub month: u8,   /* month of year, 1 - 12 */
+    pub day: u8,     /* day of month, 1 - 31 */
+    pub hour: u8,    /* hour of day, 0 - 23 */
+    pub minute: u8,  /* minute of hour, 0 - 59 */
+    pub second: u8,  /* second of minute, 0 - 60 (for leap seconds) */
+}
+
+/* The chunk a text is written in, with the values of the compression field of
+ * png_text in C.
+ */
+#[repr(i8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PngTextCompression {
+    None     = -1, /* tEXt */
+    Ztxt     = 0,  /* zTXt */
+    ItxtNone = 1,  /* uncompressed iTXt */
+    ItxtZtxt = 2,  /* compressed iTXt */
+}
+
+/* A tEXt, zTXt or iTXt chunk.  The language tag and the translated keyword
+ * are only written in iTXt chunks, and the text is UTF-8 in those.
+ */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PngText {
+    pub compression: PngTextCompression,
+    pub key: Vec<u8>,      /* keyword, 1-79 characters */
+    pub text: Vec<u8>,     /* the text, may be empty */
+    pub lang: Vec<u8>,     /* language code, may be empty */
+    pub lang_key: Vec<u8>, /* keyword translated in the language, may be empty */
+}
+
+/* One entry of a suggested palette (png_sPLT_entry in C) */
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct PngSpltEntry {
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
+    pub alpha: u16,
+    pub frequency: u16,
+}
+
+/* A suggested palette (sPLT).  With a depth of 8 only the low byte of the
+ * samples of the entries is written.
+ */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PngSplt {
+    pub name: Vec<u8>,
+    pub depth: u8,     /* 8 or 16 */
+    pub entries: Vec<PngSpltEntry>,
+}
+
+/* Calibration of the pixel values (pCAL) */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PngPcal {
+    pub purpose: Vec<u8>,     /* keyword */
+    pub x0: i32,              /* minimum value */
+    pub x1: i32,              /* maximum value */
+    pub equation_type: u8,    /* 0 (linear) to 3 (hyperbolic) */
+    pub units: Vec<u8>,       /* Latin-1 units name */
+    pub params: Vec<Vec<u8>>, /* ASCII floating point parameters */
+}
+
+/* Coding-independent code points (cICP), the values are those of ITU-T H.273.
+ * PNG samples are always RGB, so the matrix coefficients must be 0.
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PngCicp {
+    pub colour_primaries: u8,     /* 1 is BT.709 (sRGB), 9 is BT.2020 */
+    pub transfer_function: u8,    /* 13 is sRGB, 16 is PQ, 18 is HLG */
+    pub matrix_coefficients: u8,  /* 0 (identity) */
+    pub video_full_range: bool,   /* false for narrow range samples */
+}
+
+impl PngCicp {
+    /* The H.273 code points reserved for future use, and the ones that are
+     * not allowed in PNG.
+     */
+    pub(crate) fn check(&self) -> Result<(), &'static str>
+    {
+        if self.colour_primaries == 0 || self.colour_primaries == 3
+        {
+            return Err("cICP: reserved colour primaries");
+        }
+
+        if self.transfer_function == 0 || self.transfer_function == 3
+        {
+            return Err("cICP: reserved transfer function");
+        }
+
+        if self.matrix_coefficients != 0
+        {
+            return Err("cICP: matrix coefficients must be 0 for RGB samples");
+        }
+
+        Ok(())
+    }
+
+    /* The gamma of a transfer function that is a power law, in units of
+     * 1/100000.  The BT.709, BT.601 and BT.2020 functions are taken as the
+     * BT.1886 display gamma of 2.4.  PQ, HLG and the others have none.
+     */
+    pub fn gamma(&self) -> Option<u32>
+    {
+        match self.transfer_function {
+            1 | 6 | 14 | 15 => Some(41667),
+            4 | 13 => Some(PNG_GAMMA_SRGB_INVERSE),
+            5 => Some(35714),
+            8 => Some(100000),
+            _ => None,
+        }
+    }
+}
+
+/* Mastering display colour volume (mDCv), as in SMPTE ST 2086: the
+ * chromaticities are in units of 0.00002 and the luminances in units of
+ * 0.0001 cd/m².
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct PngMasteringDisplay {
+    pub red_x: u16,
+    pub red_y: u16,
+    pub green_x: u16,
+    pub green_y: u16,
+    pub blue_x: u16,
+    pub blue_y: u16,
+    pub white_x: u16,
+    pub white_y: u16,
+    pub max_luminance: u32,
+    pub min_luminance: u32,
+}
+
+impl PngMasteringDisplay {
+    pub(crate) fn check(&self) -> Result<(), &'static str>
+    {
+        let xy = [self.red_x, self.red_y, self.green_x, self.green_y,
+                  self.blue_x, self.blue_y, self.white_x, self.white_y];
+
+        /* 50000 is a chromaticity of 1 */
+        if xy.iter().any(|&v| v > 50000)
+        {
+            return Err("mDCv: invalid chromaticities");
+        }
+
+        if self.min_luminance >= self.max_luminance
+        {
+            return Err("mDCv: invalid luminance range");
+        }
+
+        Ok(())
+    }
+}
+
+/* Content light level (cLLi), in units of 0.0001 cd/m².  Zero means that the
+ * level is not known.
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct PngContentLightLevel {
+    pub max_cll: u32,   /* maximum content light level */
+    pub max_fall: u32,  /* maximum frame average light level */
+}
+
+impl PngContentLightLevel {
+    pub(crate) fn check(&self) -> Result<(), &'static str>
+    {
+        match self.max_cll != 0 && self.max_fall > self.max_cll {
+            true => Err("cLLi: frame average above the maximum light level"),
+            false => Ok(()),
+        }
+    }
+}
+
+/* A chunk which is not otherwise known to libpng.  The location is where the
+ * chunk is written: PngMode::HAVE_IHDR (before PLTE), PngMode::HAVE_PLTE
+ * (before IDAT) or PngMode::AFTER_IDAT.
+ */
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PngUnknownChunk {
+    pub name: [u8; 4],
+    pub data: Vec<u8>,
+    pub location: PngMode,
+}
+
 #[derive(Debug, PartialEq)]
 #[repr(u8)]
 enum PngResolution {
@@ -103,7 +318,7 @@ pub struct PngInfo {
      * the low-order bits is not specified.  Data is valid if
      * (valid & PNG_INFO_sBIT) is non-zero.
      */
-    sig_bit: PngColor8, /* significant bits in color channels */
+    pub sig_bit: PngColor8, /* significant bits in color channels */
 
     /* The tRNS chunk supplies transparency data for paletted images and
      * other image types that don't need a full alpha channel.  There are
@@ -156,6 +371,37 @@ pub struct PngInfo {
     scal_unit: u8,         /* unit of physical scale */
     scal_s_width: CPtr,     /* string containing height */
     scal_s_height: CPtr,    /* string containing width */
+
+    palette_buf: Vec<PngPaletteColor>, /* palette set from Rust, "palette"
+                                        * points to it */
+    trans_alpha_buf: Vec<u8>,  /* the same for "trans_alpha", */
+    iccp_name_buf: Vec<u8>,    /* "iccp_name" (NUL terminated), */
+    iccp_profile_buf: Vec<u8>, /* "iccp_profile", */
+    exif_data: Vec<u8>,        /* "exif" */
+    scal_s_buf: Vec<u8>,       /* and the two sCAL strings, "scal_s_width"
+                                * and "scal_s_height" are NUL terminated in
+                                * it */
+
+    /* Chunks only set from Rust, the C structure has its own copy of these */
+    gamma: Option<u32>,         /* gAMA, in units of 1/100000 */
+    chromaticities: Option<PngXy>, /* cHRM */
+    srgb_intent: Option<u8>,    /* sRGB rendering intent */
+    cicp: Option<PngCicp>,      /* cICP */
+    mastering_display: Option<PngMasteringDisplay>, /* mDCv */
+    content_light_level: Option<PngContentLightLevel>, /* cLLi */
+    hist: Vec<u16>,             /* hIST, one frequency per palette entry */
+    mod_time: Option<PngTime>,  /* tIME */
+    pcal: Option<PngPcal>,      /* pCAL */
+    splt_palettes: Vec<PngSplt>, /* sPLT */
+    text_chunks: Vec<PngText>,  /* tEXt, zTXt and iTXt */
+    unknown_chunks: Vec<PngUnknownChunk>,
+
+    /* Write state: text_chunks before text_written have been written, as
+     * has the eXIf when exif_written is set, so they are not written again at
+     * the end of the image.
+     */
+    pub(crate) text_written: usize,
+    pub(crate) exif_written: bool,
 }
 
 
@@ -164,66 +410,537 @@ pub extern fn png_info_rust_new(png_info_ptr: CPtr) -> *mut PngInfo
 {
     let obj = Box::new(PngInfo {
         png_info: png_info_ptr,
-        signature: [0; 8],
-        width: 0,
-        height: 0,
-        valid: PngInfoChunk::empty(),
-        rowbytes: 0,
-        palette: 0,
-        num_palette: 0,
-        num_trans: 0,
-        bit_depth: 0,
-        color_type: PngColor::empty(),
-        compression_type: PngCompressionType::Base,
-        filter_type: PngFilterType::Base,
-        interlace_type: PngInterlace::None,
-        channels: 0,
-        pixel_depth: 0,
-        spare_byte: 0,
-        iccp_name: 0,
-        iccp_profile: 0,
-        iccp_proflen: 0,
-        num_text: 0,
-        max_text: 0,
-        text: 0,
-        sig_bit: PngColor8 {
-            red: 0,
-            green: 0,
-            blue: 0,
-            gray: 0,
-            alpha: 0,
-        },
-        trans_alpha: 0,
-        trans_color: PngColor16 {
-            index: 0,
-            red: 0,
-            green: 0,
-            blue: 0,
-            gray: 0,
-        },
-        background: PngColor16 {
-            index: 0,
-            red: 0,
-            green: 0,
-            blue: 0,
-            gray: 0,
-        },
-        x_offset: 0,
-        y_offset: 0,
-        offset_unit_type: 0,
-        x_pixels_per_unit: 0,
-        y_pixels_per_unit: 0,
-        phys_unit_type: None,
-        num_exif: 0,
-        exif: 0,
-        eXIf_buf: 0,
-        scal_unit: 0,
-        scal_s_width: 0,
-        scal_s_height: 0,
+        ..PngInfo::new()
     });
     Box::into_raw(obj)
 }
 
+impl PngInfo {
+    pub fn new() -> PngInfo
+    {
+        PngInfo {
+            png_info: 0,
+            signature: [0; 8],
+            width: 0,
+            height: 0,
+            valid: PngInfoChunk::empty(),
+            rowbytes: 0,
+            palette: 0,
+            num_palette: 0,
+            num_trans: 0,
+            bit_depth: 0,
+            color_type: PngColor::empty(),
+            compression_type: PngCompressionType::Base,
+            filter_type: PngFilterType::Base,
+            interlace_type: PngInterlace::None,
+            channels: 0,
+            pixel_depth: 0,
+            spare_byte: 0,
+            iccp_name: 0,
+            iccp_profile: 0,
+            iccp_proflen: 0,
+            num_text: 0,
+            max_text: 0,
+            text: 0,
+            sig_bit: PngColor8 {
+                red: 0,
+                green: 0,
+                blue: 0,
+                gray: 0,
+                alpha: 0,
+            },
+            trans_alpha: 0,
+            trans_color: PngColor16 {
+                index: 0,
+                red: 0,
+                green: 0,
+                blue: 0,
+                gray: 0,
+            },
+            background: PngColor16 {
+                index: 0,
+                red: 0,
+                green: 0,
+                blue: 0,
+                gray: 0,
+            },
+            x_offset: 0,
+            y_offset: 0,
+            offset_unit_type: 0,
+            x_pixels_per_unit: 0,
+            y_pixels_per_unit: 0,
+            phys_unit_type: None,
+            num_exif: 0,
+            exif: 0,
+            eXIf_buf: 0,
+            scal_unit: 0,
+            scal_s_width: 0,
+            scal_s_height: 0,
+            palette_buf: Vec::new(),
+            trans_alpha_buf: Vec::new(),
+            iccp_name_buf: Vec::new(),
+            iccp_profile_buf: Vec::new(),
+            exif_data: Vec::new(),
+            scal_s_buf: Vec::new(),
+            gamma: None,
+            chromaticities: None,
+            srgb_intent: None,
+            cicp: None,
+            mastering_display: None,
+            content_light_level: None,
+            hist: Vec::new(),
+            mod_time: None,
+            pcal: None,
+            splt_palettes: Vec::new(),
+            text_chunks: Vec::new(),
+            unknown_chunks: Vec::new(),
+            text_written: 0,
+            exif_written: false,
+        }
+    }
+
+    pub fn is_valid(&self, chunk: PngInfoChunk) -> bool
+    {
+        self.valid.contains(chunk)
+    }
+
+    /* The palette of the image (PLTE), empty if there is none */
+    pub fn palette(&self) -> &[PngPaletteColor]
+    {
+        match self.palette {
+            0 => &[],
+            palette => unsafe {
+                slice::from_raw_parts(palette as *const PngPaletteColor, self.num_palette as usize)
+            },
+        }
+    }
+
+    /* Set the palette from Rust, the colors are kept by the info */
+    pub fn set_palette(&mut self, palette: &[PngPaletteColor])
+    {
+        self.palette_buf = palette.to_vec();
+        self.palette = self.palette_buf.as_ptr() as CPtr;
+        self.num_palette = self.palette_buf.len() as u16;
+        self.valid.insert(PngInfoChunk::PLTE);
+    }
+
+    /* The transparency (tRNS): the alpha values of the first palette entries
+     * for paletted images, the transparent color for the others.
+     */
+    pub fn trans(&self) -> Option<(&[u8], PngColor16)>
+    {
+        if !self.is_valid(PngInfoChunk::tRNS)
+        {
+            return None;
+        }
+
+        let trans_alpha = match self.trans_alpha {
+            0 => &[],
+            trans_alpha => unsafe {
+                slice::from_raw_parts(trans_alpha as *const u8, self.num_trans as usize)
+            },
+        };
+
+        Some((trans_alpha, self.trans_color))
+    }
+
+    pub fn set_trans(&mut self, trans_alpha: &[u8], trans_color: Option<PngColor16>)
+    {
+        self.trans_alpha_buf = trans_alpha[..trans_alpha.len().min(256)].to_vec();
+        self.trans_alpha = match self.trans_alpha_buf.is_empty() {
+            true => 0,
+            false => self.trans_alpha_buf.as_ptr() as CPtr,
+        };
+        self.num_trans = self.trans_alpha_buf.len() as u16;
+
+        if let Some(trans_color) = trans_color
+        {
+            self.trans_color = trans_color;
+            if self.num_trans == 0
+            {
+                self.num_trans = 1;
+            }
+        }
+
+        match self.num_trans {
+            0 => self.valid.remove(PngInfoChunk::tRNS),
+            _ => self.valid.insert(PngInfoChunk::tRNS),
+        }
+    }
+
+    /* The suggested background color (bKGD) */
+    pub fn background(&self) -> Option<PngColor16>
+    {
+        match self.is_valid(PngInfoChunk::bKGD) {
+            true => Some(self.background),
+            false => None,
+        }
+    }
+
+    pub fn set_background(&mut self, background: PngColor16)
+    {
+        self.background = background;
+        self.valid.insert(PngInfoChunk::bKGD);
+    }
+
+    /* The significant bits of the channels (sBIT) */
+    pub fn sig_bit(&self) -> Option<PngColor8>
+    {
+        match self.is_valid(PngInfoChunk::sBIT) {
+            true => Some(self.sig_bit),
+            false => None,
+        }
+    }
+
+    pub fn set_sig_bit(&mut self, sig_bit: PngColor8)
+    {
+        self.sig_bit = sig_bit;
+        self.valid.insert(PngInfoChunk::sBIT);
+    }
+
+    /* The gamma of the image (gAMA), in units of 1/100000 */
+    pub fn gamma(&self) -> Option<u32>
+    {
+        self.gamma.filter(|_| self.is_valid(PngInfoChunk::gAMA))
+    }
+
+    pub fn set_gamma(&mut self, gamma: u32)
+    {
+        self.gamma = Some(gamma);
+        self.valid.insert(PngInfoChunk::gAMA);
+    }
+
+    /* The chromaticities of the end points (cHRM) */
+    pub fn chromaticities(&self) -> Option<PngXy>
+    {
+        self.chromaticities.filter(|_| self.is_valid(PngInfoChunk::cHRM))
+    }
+
+    pub fn set_chromaticities(&mut self, xy: PngXy)
+    {
+        self.chromaticities = Some(xy);
+        self.valid.insert(PngInfoChunk::cHRM);
+    }
+
+    /* The rendering intent of an sRGB image (sRGB) */
+    pub fn srgb_intent(&self) -> Option<u8>
+    {
+        self.srgb_intent.filter(|_| self.is_valid(PngInfoChunk::sRGB))
+    }
+
+    pub fn set_srgb_intent(&mut self, intent: u8)
+    {
+        self.srgb_intent = Some(intent);
+        self.valid.insert(PngInfoChunk::sRGB);
+    }
+
+    /* The colour space of the image as H.273 code points (cICP) */
+    pub fn cicp(&self) -> Option<PngCicp>
+    {
+        self.cicp.filter(|_| self.is_valid(PngInfoChunk::cICP))
+    }
+
+    pub fn set_cicp(&mut self, cicp: PngCicp)
+    {
+        self.cicp = Some(cicp);
+        self.valid.insert(PngInfoChunk::cICP);
+    }
+
+    /* The colour volume of the mastering display (mDCv) */
+    pub fn mastering_display(&self) -> Option<PngMasteringDisplay>
+    {
+        self.mastering_display.filter(|_| self.is_valid(PngInfoChunk::mDCv))
+    }
+
+    pub fn set_mastering_display(&mut self, display: PngMasteringDisplay)
+    {
+        self.mastering_display = Some(display);
+        self.valid.insert(PngInfoChunk::mDCv);
+    }
+
+    /* The light levels of the content (cLLi) */
+    pub fn content_light_level(&self) -> Option<PngContentLightLevel>
+    {
+        self.content_light_level.filter(|_| self.is_valid(PngInfoChunk::cLLi))
+    }
+
+    pub fn set_content_light_level(&mut self, level: PngContentLightLevel)
+    {
+        self.content_light_level = Some(level);
+        self.valid.insert(PngInfoChunk::cLLi);
+    }
+
+    /* The gamma the samples are encoded with, in units of 1/100000.  The
+     * chunks take precedence as in the PNG specification: cICP, then iCCP,
+     * sRGB and gAMA.  libpng does not interpret ICC profiles, so with an
+     * iCCP chunk the sRGB or gAMA chunk is used as a fallback.  None if the
+     * chunk that applies gives no power law, like the PQ and HLG transfer
+     * functions of cICP.
+     */
+    pub fn file_gamma(&self) -> Option<u32>
+    {
+        match (self.cicp(), self.srgb_intent()) {
+            (Some(cicp), _) => cicp.gamma(),
+            (None, Some(_)) => Some(PNG_GAMMA_SRGB_INVERSE),
+            (None, None) => self.gamma(),
+        }
+    }
+
+    /* The name and the uncompressed data of the ICC profile (iCCP) */
+    pub fn iccp(&self) -> Option<(&[u8], &[u8])>
+    {
+        if !self.is_valid(PngInfoChunk::iCCP) || self.iccp_profile == 0
+        {
+            return None;
+        }
+
+        unsafe {
+            Some((c_string(self.iccp_name),
+                  slice::from_raw_parts(self.iccp_profile as *const u8, self.iccp_proflen as usize)))
+        }
+    }
+
+    pub fn set_iccp(&mut self, name: &[u8], profile: &[u8])
+    {
+        self.iccp_name_buf = name.to_vec();
+        self.iccp_name_buf.push(0);
+        self.iccp_profile_buf = profile.to_vec();
+        self.iccp_name = self.iccp_name_buf.as_ptr() as CPtr;
+        self.iccp_profile = self.iccp_profile_buf.as_ptr() as CPtr;
+        self.iccp_proflen = profile.len() as u32;
+        self.valid.insert(PngInfoChunk::iCCP);
+    }
+
+    /* The histogram of the palette (hIST) */
+    pub fn hist(&self) -> Option<&[u16]>
+    {
+        match self.is_valid(PngInfoChunk::hIST) {
+            true => Some(&self.hist),
+            false => None,
+        }
+    }
+
+    pub fn set_hist(&mut self, hist: &[u16])
+    {
+        self.hist = hist.to_vec();
+        self.valid.insert(PngInfoChunk::hIST);
+    }
+
+    /* The pixel density (pHYs): pixels per unit in x and y, and the unit */
+    pub fn phys(&self) -> Option<(u32, u32, u8)>
+    {
+        if !self.is_valid(PngInfoChunk::pHYs)
+        {
+            return None;
+        }
+
+        let unit_type = match self.phys_unit_type {
+            Some(PngResolution::Unknown) => 0,
+            Some(PngResolution::Meter) => 1,
+            _ => PngResolution::Last as u8,
+        };
+
+        Some((self.x_pixels_per_unit, self.y_pixels_per_unit, unit_type))
+    }
+
+    pub fn set_phys(&mut self, x_pixels_per_unit: u32, y_pixels_per_unit: u32, unit_type: u8)
+    {
+        self.x_pixels_per_unit = x_pixels_per_unit;
+        self.y_pixels_per_unit = y_pixels_per_unit;
+        self.phys_unit_type = match unit_type {
+            0 => Some(PngResolution::Unknown),
+            1 => Some(PngResolution::Meter),
+            _ => Some(PngResolution::Last),
+        };
+        self.valid.insert(PngInfoChunk::pHYs);
+    }
+
+    /* The position of the image on the page (oFFs): offsets and unit */
+    pub fn offsets(&self) -> Option<(i32, i32, u8)>
+    {
+        match self.is_valid(PngInfoChunk::oFFs) {
+            true => Some((self.x_offset, self.y_offset, self.offset_unit_type)),
+            false => None,
+        }
+    }
+
+    pub fn set_offsets(&mut self, x_offset: i32, y_offset: i32, unit_type: u8)
+    {
+        self.x_offset = x_offset;
+        self.y_offset = y_offset;
+        self.offset_unit_type = unit_type;
+        self.valid.insert(PngInfoChunk::oFFs);
+    }
+
+    /* The physical size of a pixel (sCAL): the unit, and the width and height
+     * as ASCII floating point numbers.
+     */
+    pub fn scal(&self) -> Option<(u8, &[u8], &[u8])>
+    {
+        if !self.is_valid(PngInfoChunk::sCAL) || self.scal_s_width == 0 || self.scal_s_height == 0
+        {
+            return None;
+        }
+
+        unsafe {
+            Some((self.scal_unit, c_string(self.scal_s_width), c_string(self.scal_s_height)))
+        }
+    }
+
+    pub fn set_scal(&mut self, unit: u8, width: &[u8], height: &[u8])
+    {
+        self.scal_s_buf = [width, &[0], height, &[0]].concat();
+        self.scal_unit = unit;
+        self.scal_s_width = self.scal_s_buf.as_ptr() as CPtr;
+        self.scal_s_height = self.scal_s_width + width.len() + 1;
+        self.valid.insert(PngInfoChunk::sCAL);
+    }
+
+    /* The calibration of the pixel values (pCAL) */
+    pub fn pcal(&self) -> Option<&PngPcal>
+    {
+        self.pcal.as_ref().filter(|_| self.is_valid(PngInfoChunk::pCAL))
+    }
+
+    pub fn set_pcal(&mut self, pcal: PngPcal)
+    {
+        self.pcal = Some(pcal);
+        self.valid.insert(PngInfoChunk::pCAL);
+    }
+
+    /* The last modification time (tIME) */
+    pub fn mod_time(&self) -> Option<PngTime>
+    {
+        self.mod_time.filter(|_| self.is_valid(PngInfoChunk::tIME))
+    }
+
+    pub fn set_mod_time(&mut self, mod_time: PngTime)
+    {
+        self.mod_time = Some(mod_time);
+        self.valid.insert(PngInfoChunk::tIME);
+    }
+
+    /* The Exif data (eXIf) */
+    pub fn exif(&self) -> Option<&[u8]>
+    {
+        match (self.is_valid(PngInfoChunk::eXIf), self.exif) {
+            (true, exif) if exif != 0 => unsafe {
+                Some(slice::from_raw_parts(exif as *const u8, self.num_exif as usize))
+            },
+            _ => None,
+        }
+    }
+
+    pub fn set_exif(&mut self, exif: &[u8])
+    {
+        self.exif_data = exif.to_vec();
+        self.exif = self.exif_data.as_ptr() as CPtr;
+        self.num_exif = exif.len() as i32;
+        self.exif_written = false;
+        self.valid.insert(PngInfoChunk::eXIf);
+    }
+
+    /* The text chunks, in the order they are written */
+    pub fn text(&self) -> &[PngText]
+    {
+        &self.text_chunks
+    }
+
+    /* Add text chunks after the ones already there.  The texts added after
+     * the info has been written go after the image data.
+     */
+    pub fn add_text(&mut self, text: &[PngText])
+    {
+        self.text_chunks.extend_from_slice(text);
+    }
+
+    /* The suggested palettes (sPLT) */
+    pub fn splt(&self) -> &[PngSplt]
+    {
+        match self.is_valid(PngInfoChunk::sPLT) {
+            true => &self.splt_palettes,
+            false => &[],
+        }
+    }
+
+    pub fn add_splt(&mut self, palettes: &[PngSplt])
+    {
+        self.splt_palettes.extend_from_slice(palettes);
+
+        if !self.splt_palettes.is_empty()
+        {
+            self.valid.insert(PngInfoChunk::sPLT);
+        }
+    }
+
+    /* The unknown chunks, each is written at its location */
+    pub fn unknown_chunks(&self) -> &[PngUnknownChunk]
+    {
+        &self.unknown_chunks
+    }
+
+    /* Add unknown chunks.  Their location is reduced to the latest of
+     * PngMode::HAVE_IHDR, PngMode::HAVE_PLTE and PngMode::AFTER_IDAT it
+     * contains, and must contain one of them.
+     */
+    pub fn add_unknown_chunks(&mut self, chunks: &[PngUnknownChunk]) -> Result<(), &'static str>
+    {
+        let mut checked = Vec::with_capacity(chunks.len());
+
+        for chunk in chunks {
+            let location = check_location(chunk.location)
+                .ok_or("invalid location in png_set_unknown_chunks")?;
+
+            checked.push(PngUnknownChunk {
+                location,
+                ..chunk.clone()
+            });
+        }
+
+        self.unknown_chunks.append(&mut checked);
+
+        Ok(())
+    }
+
+    /* Change the location of an unknown chunk.  A location without any of the
+     * valid ones falls back to the pre 1.6.0 behavior of libpng: after the
+     * image data if it has PngMode::HAVE_IDAT, otherwise before PLTE.
+     */
+    pub fn set_unknown_chunk_location(&mut self, chunk: usize, location: PngMode)
+    {
+        if let Some(chunk) = self.unknown_chunks.get_mut(chunk)
+        {
+            chunk.location = check_location(location).unwrap_or(
+                match location.contains(PngMode::HAVE_IDAT) {
+                    true => PngMode::AFTER_IDAT,
+                    false => PngMode::HAVE_IHDR,
+                });
+        }
+    }
+}
+
+/* Reduce the location of an unknown chunk to the top-most of the valid
+ * locations it contains, None if there is none.
+ */
+fn check_location(location: PngMode) -> Option<PngMode>
+{
+    let location = location & (PngMode::HAVE_IHDR | PngMode::HAVE_PLTE | PngMode::AFTER_IDAT);
+
+    match location.bits() {
+        0 => None,
+        bits => Some(PngMode::from_bits_truncate(1 << (31 - bits.leading_zeros()))),
+    }
+}
+
+/* The bytes of the NUL terminated string at 'ptr', without the NUL */
+unsafe fn c_string<'a>(ptr: CPtr) -> &'a [u8]
+{
+    match ptr {
+        0 => &[],
+        ptr => CStr::from_ptr(ptr as *const c_char).to_bytes(),
+    }
+}
+
 
 
 macro_rules! get_set_info {
@@ -432,3 +1149,136 @@ impl PngInterlace {
 pub unsafe extern fn png_info_rust_set_interlace_type(this: *mut PngInfo, value: u32) {
     this.as_mut().unwrap().interlace_type = PngInterlace::from_u32(value);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate::{zlib_compress, PngCompression};
+    use crate::{ChunkWriter, PngDecoder};
+
+    fn cicp(colour_primaries: u8, transfer_function: u8, matrix_coefficients: u8) -> PngCicp
+    {
+        PngCicp {
+            colour_primaries,
+            transfer_function,
+            matrix_coefficients,
+            video_full_range: true,
+        }
+    }
+
+    fn display(min_luminance: u32, max_luminance: u32) -> PngMasteringDisplay
+    {
+        PngMasteringDisplay {
+            red_x: 35400, red_y: 14600,
+            green_x: 8500, green_y: 39850,
+            blue_x: 6550, blue_y: 2300,
+            white_x: 15635, white_y: 16450,
+            max_luminance,
+            min_luminance,
+        }
+    }
+
+    /* The info PngDecoder reads from a 1 by 1 gray image with 'chunk' */
+    fn read_chunk(name: [u8; 4], data: &[u8]) -> PngInfo
+    {
+        let mut writer = ChunkWriter::new(Vec::new());
+        writer.write_sig(0).unwrap();
+        writer.write_chunk(*b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]).unwrap();
+        writer.write_chunk(name, data).unwrap();
+        writer.write_chunk(*b"IDAT", &zlib_compress(&PngCompression::default(), &[0, 0])).unwrap();
+        writer.write_chunk(*b"IEND", &[]).unwrap();
+        let png = writer.into_inner();
+
+        let mut decoder = PngDecoder::new(&png);
+        decoder.read_info().unwrap();
+        decoder.into_info()
+    }
+
+    #[test]
+    fn file_gamma_precedence()
+    {
+        let mut info = PngInfo::new();
+        assert_eq!(info.file_gamma(), None);
+
+        info.set_gamma(100000);
+        assert_eq!(info.file_gamma(), Some(100000));
+
+        /* No interpretation of the ICC profile: gAMA applies */
+        info.set_iccp(b"profile", &[0; 132]);
+        assert_eq!(info.file_gamma(), Some(100000));
+
+        info.set_srgb_intent(0);
+        assert_eq!(info.file_gamma(), Some(PNG_GAMMA_SRGB_INVERSE));
+
+        info.set_cicp(cicp(1, 8, 0));
+        assert_eq!(info.file_gamma(), Some(100000));
+
+        /* A cICP without a power law hides the other chunks */
+        info.set_cicp(cicp(9, 16, 0));
+        assert_eq!(info.file_gamma(), None);
+        assert_eq!(info.gamma(), Some(100000));
+        assert_eq!(info.srgb_intent(), Some(0));
+    }
+
+    #[test]
+    fn cicp_gamma()
+    {
+        let gammas: Vec<_> = [1, 4, 5, 8, 13, 16, 18].iter().map(|&transfer| cicp(1, transfer, 0).gamma()).collect();
+
+        assert_eq!(gammas, [Some(41667), Some(PNG_GAMMA_SRGB_INVERSE), Some(35714), Some(100000),
+                            Some(PNG_GAMMA_SRGB_INVERSE), None, None]);
+    }
+
+    #[test]
+    fn cicp_code_points()
+    {
+        assert_eq!(cicp(1, 13, 0).check(), Ok(()));
+        assert_eq!(cicp(0, 13, 0).check(), Err("cICP: reserved colour primaries"));
+        assert_eq!(cicp(3, 13, 0).check(), Err("cICP: reserved colour primaries"));
+        assert_eq!(cicp(1, 0, 0).check(), Err("cICP: reserved transfer function"));
+        assert_eq!(cicp(1, 3, 0).check(), Err("cICP: reserved transfer function"));
+        assert_eq!(cicp(9, 16, 1).check(), Err("cICP: matrix coefficients must be 0 for RGB samples"));
+
+        /* PngDecoder ignores the invalid chunks, the full range flag is 0 or 1 */
+        assert_eq!(read_chunk(*b"cICP", &[9, 16, 0, 1]).cicp(), Some(cicp(9, 16, 0)));
+        assert_eq!(read_chunk(*b"cICP", &[9, 16, 0, 0]).cicp(),
+                   Some(PngCicp { video_full_range: false, ..cicp(9, 16, 0) }));
+        assert_eq!(read_chunk(*b"cICP", &[9, 16, 0, 2]).cicp(), None);
+        assert_eq!(read_chunk(*b"cICP", &[9, 16, 9, 1]).cicp(), None);
+        assert_eq!(read_chunk(*b"cICP", &[3, 16, 0, 1]).cicp(), None);
+    }
+
+    #[test]
+    fn mastering_display_luminance()
+    {
+        assert_eq!(display(50, 10_000_000).check(), Ok(()));
+        assert_eq!(display(50, 50).check(), Err("mDCv: invalid luminance range"));
+        assert_eq!(display(51, 50).check(), Err("mDCv: invalid luminance range"));
+        assert_eq!(PngMasteringDisplay { white_x: 50001, ..display(0, 1) }.check(),
+                   Err("mDCv: invalid chromaticities"));
+
+        let chunk = |min_luminance: u32, max_luminance: u32| {
+            let display = display(min_luminance, max_luminance);
+            let xy = [display.red_x, display.red_y, display.green_x, display.green_y,
+                      display.blue_x, display.blue_y, display.white_x, display.white_y];
+            let mut chunk: Vec<u8> = xy.iter().flat_map(|v| v.to_be_bytes()).collect();
+
+            chunk.extend_from_slice(&max_luminance.to_be_bytes());
+            chunk.extend_from_slice(&min_luminance.to_be_bytes());
+            chunk
+        };
+        assert_eq!(read_chunk(*b"mDCv", &chunk(50, 10_000_000)).mastering_display(), Some(display(50, 10_000_000)));
+        assert_eq!(read_chunk(*b"mDCv", &chunk(50, 50)).mastering_display(), None);
+        assert_eq!(read_chunk(*b"mDCv", &chunk(10_000_000, 50)).mastering_display(), None);
+    }
+
+    #[test]
+    fn content_light_level()
+    {
+        let level = |max_cll, max_fall| PngContentLightLevel { max_cll, max_fall };
+
+        assert_eq!(level(1000, 400).check(), Ok(()));
+        assert_eq!(level(0, 400).check(), Ok(()));
+        assert_eq!(level(400, 1000).check(), Err("cLLi: frame average above the maximum light level"));
+    }
+}
@@ -9,6 +9,8 @@ use crate::PngChunkType;
 use crate::PngHandleChunk;
 use crate::PngPushMode;
 use crate::CPtr;
+use crate::interlace;
+use crate::interlace::{PNG_PASS_START, PNG_PASS_INC, PNG_PASS_YSTART, PNG_PASS_YINC};
 use std::slice;
 use std::mem;
 use std::cmp;
@@ -45,6 +47,7 @@ extern {
 
     fn png_push_have_end(png_ptr: CPtr, png_info_ptr: CPtr);
     fn png_push_have_info(png_ptr: CPtr, png_info_ptr: CPtr);
+    fn png_read_update_info(png_ptr: CPtr, png_info_ptr: CPtr);
 
     fn png_process_IDAT_data(png_ptr: CPtr, save_buffer_ptr: CPtr, min_size: usize);
 
@@ -60,6 +63,40 @@ extern {
     fn png_c_set_zstream_next_out(png_ptr: CPtr, row_buf: CPtr);
 }
 
+/* Events of the progressive reader, an alternative to the C callbacks
+ * (info_fn, row_fn and end_fn) for the Rust applications: while the events
+ * are enabled the callbacks are not called, and the rows are started with
+ * png_read_update_info at the first IDAT, so the transformations are set
+ * before.  png_get_progressive_rust_ptr gives the Png of a png_struct.
+ */
+#[derive(Debug, PartialEq, Clone)]
+pub enum PngProgressiveEvent {
+    /* The header is read, the image data starts */
+    Header {
+        width: u32,
+        height: u32,
+        bit_depth: u8,
+        color_type: PngColor,
+        interlaced: PngInterlace,
+    },
+
+    /* A row of the pass 'pass' (always 0 for non interlaced images): 'row'
+     * is the row in the image and 'data' the transformed pixels of the pass
+     * only, PNG_PASS_COLS(width, pass) of them.
+     */
+    Row {
+        pass: u8,
+        row: u32,
+        data: Vec<u8>,
+    },
+
+    /* All the rows of the pass have been read */
+    PassComplete(u8),
+
+    /* The IEND chunk is read */
+    End,
+}
+
 
 
 #[no_mangle]
@@ -107,6 +144,41 @@ pub unsafe extern fn png_read_push_finish_row(this: *mut Png)
 }
 
 
+/* Returns true when the events are enabled: the row is queued and the row
+ * callback is not called.
+ */
+#[no_mangle]
+pub unsafe extern fn png_rust_push_have_row(this: *mut Png, row: CPtr) -> bool
+{
+    match this.as_mut() {
+        None => {
+            false
+        },
+        Some(png_ptr) => {
+            if png_ptr.progressive_events.is_none()
+            {
+                return false;
+            }
+            if row == 0
+            {
+                return true;
+            }
+
+            /* Rows expanded by the interlace handling are full width */
+            let width = match png_ptr.interlaced != PngInterlace::None &&
+                png_ptr.transformations.contains(PngTransformations::INTERLACE) {
+                true => png_ptr.width,
+                false => png_ptr.iwidth,
+            };
+            let slice = slice::from_raw_parts(row as *const u8,
+                                              Png::compute_rowbytes(png_ptr.transformed_pixel_depth, width as usize));
+            png_ptr.push_have_row(slice);
+            true
+        }
+    }
+}
+
+
 #[no_mangle]
 pub unsafe extern fn png_rust_process_data_pause(this: *mut Png, save: bool) -> usize
 {
@@ -350,7 +422,10 @@ impl Png {
             self.handle_IEND(self.push_length);
 
             self.process_mode = PngPushMode::ReadDONE;
-            unsafe {png_push_have_end(self.png_ptr, info_ptr.png_info);}
+            match self.progressive_events.is_some() {
+                true => self.push_event(PngProgressiveEvent::End),
+                false => unsafe {png_push_have_end(self.png_ptr, info_ptr.png_info);},
+            }
 
         } else if keep != PngHandleChunk::AsDefault {
 
@@ -379,8 +454,24 @@ impl Png {
         {
             self.idat_size = self.push_length;
             self.process_mode = PngPushMode::ReadIDAT;
+            match self.progressive_events.is_some() {
+                true => {
+                    /* What the info callback does, which starts the rows */
+                    if !self.flags.contains(PngFlags::ROW_INIT)
+                    {
+                        unsafe {png_read_update_info(self.png_ptr, info_ptr.png_info);}
+                    }
+                    self.push_event(PngProgressiveEvent::Header {
+                        width: self.width,
+                        height: self.height,
+                        bit_depth: self.bit_depth,
+                        color_type: self.color_type,
+                        interlaced: self.interlaced,
+                    });
+                },
+                false => unsafe {png_push_have_info(self.png_ptr, info_ptr.png_info);},
+            }
             unsafe {
-                png_push_have_info(self.png_ptr, info_ptr.png_info);
                 png_c_set_zstream_avail_out(self.png_ptr, Png::compute_rowbytes(self.pixel_depth, self.iwidth as usize) + 1 as usize);
                 png_c_set_zstream_next_out(self.png_ptr, self.row_buf);
             }
@@ -694,29 +785,12 @@ impl Png {
 
     fn read_push_finish_row(&mut self)
     {
-        /* Arrays to facilitate easy interlacing - use pass (0 - 6) as index */
-
-        /* Start of interlace block */
-        static PNG_PASS_START: &'static [u8] = &[0, 4, 0, 2, 0, 1, 0];
-
-        /* Offset to next interlace block */
-        static PNG_PASS_INC: &'static [u8] = &[8, 8, 4, 4, 2, 2, 1];
-
-        /* Start of interlace block in the y direction */
-        static PNG_PASS_YSTART: &'static [u8] = &[0, 0, 4, 0, 2, 0, 1];
-
-        /* Offset to next interlace block in the y direction */
-        static PNG_PASS_YINC: &'static [u8] = &[8, 8, 8, 4, 4, 2, 2];
-
-        /* Height of interlace block.  This is not currently used - if you need it
-        static png_pass_height: &'static [u8] = &[8, 8, 4, 4, 2, 2, 1];
-         */
-
         self.row_number += 1;
         if self.row_number < self.num_rows
         {
             return;
         }
+        self.push_event(PngProgressiveEvent::PassComplete(self.pass));
 
         match self.interlaced {
             PngInterlace::None => (),
@@ -735,6 +809,8 @@ impl Png {
                          (self.pass == 3 && self.width < 3) ||
                          (self.pass == 5 && self.width < 2))
                     {
+                        /* The pass has no pixel: it is complete */
+                        self.push_event(PngProgressiveEvent::PassComplete(self.pass));
                         self.pass += 1;
                     }
 
@@ -760,6 +836,7 @@ impl Png {
                     {
                         break;
                     }
+                    self.push_event(PngProgressiveEvent::PassComplete(self.pass));
                 }
             }
         }
@@ -785,6 +862,65 @@ impl Png {
         self.buffer_size = self.current_buffer.len() + self.save_buffer.len();
     }
 
+    /* Queue the row for the events; rows which are not in the pass, passed
+     * by the interlace handling to the row callback, are not queued.
+     */
+    fn push_have_row(&mut self, row: &[u8])
+    {
+        let (y, data) = match self.interlaced {
+            PngInterlace::ADAM7 if self.transformations.contains(PngTransformations::INTERLACE) => {
+                if !interlace::row_in_interlace_pass(self.row_number, self.pass)
+                {
+                    return;
+                }
+                (self.row_number,
+                 interlace::pass_pixels(row, self.width, self.transformed_pixel_depth, self.pass,
+                                        self.transformations.contains(PngTransformations::PACKSWAP)))
+            },
+            PngInterlace::ADAM7 => (interlace::row_from_pass_row(self.row_number, self.pass), row.to_vec()),
+            PngInterlace::None => (self.row_number, row.to_vec()),
+        };
+
+        self.push_event(PngProgressiveEvent::Row { pass: self.pass, row: y, data });
+    }
+
+    fn push_event(&mut self, event: PngProgressiveEvent)
+    {
+        if let Some(events) = self.progressive_events.as_mut()
+        {
+            events.push_back(event);
+        }
+    }
+
+    /* Queue the events of the progressive reader, to be taken after each call
+     * to process_data.
+     */
+    pub fn set_progressive_events(&mut self, enable: bool)
+    {
+        self.progressive_events = match enable {
+            true => Some(self.progressive_events.take().unwrap_or_default()),
+            false => None,
+        };
+    }
+
+    pub fn take_progressive_events(&mut self) -> Vec<PngProgressiveEvent>
+    {
+        match self.progressive_events.as_mut() {
+            Some(events) => events.drain(..).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /* process_data returning the events of the data, which enables them */
+    pub fn process_data_events(&mut self, info_ptr: &mut Option<&mut PngInfo>, buffer: &[u8])
+                               -> Result<Vec<PngProgressiveEvent>, &'static str>
+    {
+        self.set_progressive_events(true);
+        self.process_data(info_ptr, buffer)?;
+
+        Ok(self.take_progressive_events())
+    }
+
     fn set_progressive_read_fn(&mut self, progressive_ptr: CPtr,
                                info_fn: CPtr, row_fn: CPtr, end_fn: CPtr)
     {
@@ -795,3 +931,247 @@ impl Png {
         unsafe {png_set_read_fn(self.png_ptr, progressive_ptr, png_push_fill_buffer_func_ptr());}
     }
 }
+
+/* A preview of an image read progressively, rendered from the events: each
+ * pixel of an interlaced pass is replicated over the block the later passes
+ * will fill in, the rows hold pixels of 'pixel_depth' bits in the order of
+ * the rows of the events, which 'packswap' (png_set_packswap) reverses.
+ */
+pub struct PngPreview {
+    width: u32,
+    height: u32,
+    pixel_depth: u8,
+    interlaced: PngInterlace,
+    packswap: bool,
+    rowbytes: usize,
+    data: Vec<u8>,
+    passes: u8,         /* number of complete passes */
+}
+
+impl PngPreview {
+    pub fn new(width: u32, height: u32, pixel_depth: u8, interlaced: PngInterlace, packswap: bool) -> PngPreview
+    {
+        let rowbytes = Png::compute_rowbytes(pixel_depth, width as usize);
+
+        PngPreview {
+            width,
+            height,
+            pixel_depth,
+            interlaced,
+            packswap,
+            rowbytes,
+            data: vec![0; rowbytes * height as usize],
+            passes: 0,
+        }
+    }
+
+    /* Update the preview with an event; returns true when a pass or the image
+     * is complete, a good time to display it.
+     */
+    pub fn update(&mut self, event: &PngProgressiveEvent) -> bool
+    {
+        match event {
+            PngProgressiveEvent::Row { pass, row, data } => {
+                self.update_row(*pass, *row, data);
+                false
+            },
+            PngProgressiveEvent::PassComplete(pass) => {
+                self.passes = pass + 1;
+                true
+            },
+            PngProgressiveEvent::End => true,
+            PngProgressiveEvent::Header { .. } => false,
+        }
+    }
+
+    fn update_row(&mut self, pass: u8, y: u32, data: &[u8])
+    {
+        if y >= self.height
+        {
+            return;
+        }
+
+        let pixel_depth = self.pixel_depth as usize;
+
+        let (block_width, block_height) = match self.interlaced {
+            PngInterlace::ADAM7 => (interlace::pass_block_width(pass),
+                                    cmp::min(interlace::pass_block_height(pass), self.height - y)),
+            PngInterlace::None => (1, 1),
+        };
+
+        let cols = match self.interlaced {
+            PngInterlace::ADAM7 => interlace::pass_cols(self.width, pass),
+            PngInterlace::None => self.width,
+        };
+
+        for i in 0..cols {
+            let x = match self.interlaced {
+                PngInterlace::ADAM7 => interlace::col_from_pass_col(i, pass),
+                PngInterlace::None => i,
+            };
+
+            for dy in 0..block_height {
+                let start = (y + dy) as usize * self.rowbytes;
+                let row = &mut self.data[start..start + self.rowbytes];
+
+                for dx in x..cmp::min(x + block_width, self.width) {
+                    interlace::move_pixel(row, dx as usize, data, i as usize, pixel_depth, self.packswap);
+                }
+            }
+        }
+    }
+
+    pub fn data(&self) -> &[u8]
+    {
+        &self.data
+    }
+
+    pub fn row(&self, y: u32) -> &[u8]
+    {
+        let start = y as usize * self.rowbytes;
+        &self.data[start..start + self.rowbytes]
+    }
+
+    pub fn rowbytes(&self) -> usize
+    {
+        self.rowbytes
+    }
+
+    /* Number of passes complete, 7 at the end of an interlaced image */
+    pub fn passes_complete(&self) -> u8
+    {
+        self.passes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preview_reads_packswapped_rows()
+    {
+        /* The pixel of the first pass covers the 8 pixels of the row */
+        for &(packswap, byte) in [(false, 0x80u8), (true, 0x01)].iter() {
+            let mut preview = PngPreview::new(8, 1, 1, PngInterlace::ADAM7, packswap);
+            preview.update(&PngProgressiveEvent::Row { pass: 0, row: 0, data: vec![byte] });
+
+            assert_eq!(preview.row(0), &[0xff], "packswap {}", packswap);
+        }
+
+        let mut preview = PngPreview::new(4, 1, 2, PngInterlace::None, true);
+        preview.update(&PngProgressiveEvent::Row { pass: 0, row: 0, data: vec![0x39] });
+        assert_eq!(preview.row(0), &[0x39]);
+    }
+
+    /* The passes completed after the last row of the first pass */
+    fn passes_after_first(width: u32, height: u32) -> Vec<PngProgressiveEvent>
+    {
+        let mut png_ptr = Png::new();
+        let mut prev_row = vec![0u8; 16];
+
+        png_ptr.width = width;
+        png_ptr.height = height;
+        png_ptr.interlaced = PngInterlace::ADAM7;
+        png_ptr.num_rows = interlace::pass_rows(height, 0);
+        png_ptr.row_number = png_ptr.num_rows - 1;
+        png_ptr.prev_row = prev_row.as_mut_ptr() as CPtr;
+        png_ptr.set_progressive_events(true);
+
+        png_ptr.read_push_finish_row();
+        png_ptr.prev_row = 0;
+
+        png_ptr.take_progressive_events()
+    }
+
+    #[test]
+    fn skipped_passes_are_complete()
+    {
+        /* Only the first pass has a pixel */
+        let expected: Vec<_> = (0..7).map(PngProgressiveEvent::PassComplete).collect();
+        assert_eq!(passes_after_first(1, 1), expected);
+
+        /* Passes 1 and 3 are skipped for their width, 2 and 4 have no row */
+        assert_eq!(passes_after_first(2, 1), expected[..5].to_vec());
+
+        /* Pass 1 is the next one */
+        assert_eq!(passes_after_first(8, 8), expected[..1].to_vec());
+    }
+
+    /* The image of the events: the rows of the passes put in place */
+    #[cfg(feature = "c-tests")]
+    fn events_image(events: &[PngProgressiveEvent], rowbytes: usize) -> Vec<Vec<u8>>
+    {
+        let (width, height, bit_depth, interlaced) = match events.first() {
+            Some(&PngProgressiveEvent::Header { width, height, bit_depth, interlaced, .. }) =>
+                (width, height, bit_depth, interlaced),
+            event => panic!("{:?} is not the header", event),
+        };
+        let pixel_depth = match bit_depth < 8 {
+            true => bit_depth as usize,
+            false => rowbytes / width as usize * 8,
+        };
+        let mut image = vec![vec![0u8; rowbytes]; height as usize];
+
+        for event in events {
+            if let PngProgressiveEvent::Row { pass, row, data } = event
+            {
+                for i in 0..data.len() * 8 / pixel_depth {
+                    let x = match interlaced {
+                        PngInterlace::ADAM7 => interlace::col_from_pass_col(i as u32, *pass),
+                        PngInterlace::None => i as u32,
+                    };
+                    if x < width
+                    {
+                        interlace::move_pixel(&mut image[*row as usize], x as usize, data, i, pixel_depth, false);
+                    }
+                }
+            }
+        }
+
+        image
+    }
+
+    /* The events of a png_struct without callbacks give the rows of
+     * png_read_image, and complete the 7 passes of the interlaced images.
+     */
+    #[cfg(feature = "c-tests")]
+    #[test]
+    fn events_give_the_rows_of_png_read_image()
+    {
+        use crate::libpng;
+        use std::fs;
+        use std::path::Path;
+
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../contrib/pngsuite");
+        let mut paths: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("png".as_ref()))
+            .collect();
+        paths.sort();
+        paths.push(dir.join("../../pngtest.png"));
+
+        for path in paths {
+            let png = fs::read(&path).unwrap();
+            let rows = libpng::read_png(&png);
+
+            for &size in [1, 7, png.len()].iter() {
+                let events = libpng::progressive_events(&png, size);
+                let interlaced = match events.first() {
+                    Some(&PngProgressiveEvent::Header { interlaced, .. }) => interlaced,
+                    _ => PngInterlace::None,
+                };
+                let passes: Vec<_> = events.iter().filter_map(|event| match event {
+                    PngProgressiveEvent::PassComplete(pass) => Some(*pass),
+                    _ => None,
+                }).collect();
+
+                assert_eq!(events_image(&events, rows[0].len()), rows, "{:?} in pieces of {}", path, size);
+                match interlaced {
+                    PngInterlace::ADAM7 => assert_eq!(passes, (0..7).collect::<Vec<u8>>(), "{:?}", path),
+                    PngInterlace::None => assert_eq!(passes, [0], "{:?}", path),
+                }
+                assert_eq!(events.last(), Some(&PngProgressiveEvent::End), "{:?}", path);
+            }
+        }
+    }
+}
@@ -0,0 +1,575 @@
+use crate::{Png, PngInterlace};
+use crate::png_info::PngInfo;
+use crate::decoder::{PngDecoder, check_chunk_header, read_chunk, get_u32, uses_chunk};
+use crate::filter::{PngFilterValue, unfilter_row};
+use crate::interlace::{pass_cols, pass_rows, row_from_pass_row, PNG_INTERLACE_ADAM7_PASSES};
+use crate::pread::PngProgressiveEvent;
+use crc::crc32;
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+
+/*******************************************************************************
+ *
+ *                               Png RUST
+ *
+ ******************************************************************************/
+
+/* A progressive decoder written in Rust only: the datastream is given in
+ * pieces of any size to push, which returns the events of the data as the
+ * progressive reader of libpng does: Header, the untransformed rows of each
+ * pass, PassComplete and End.  The chunks before the image data are read by
+ * PngDecoder, the chunks PngDecoder does not use are skipped as they arrive,
+ * and the image data is inflated and unfiltered a row at a time.  The decoder
+ * holds no other resource than its memory, it can be dropped at any point.
+ *
+ * The progressive reader of pread.rs is not used: it keeps its state in the C
+ * png_struct and calls into the C library for the chunk handlers of
+ * pngrutil.c, the CRC, the zstream and the transformations, so it cannot run
+ * without it.  What does not depend on C is shared instead: the chunks are
+ * parsed by PngDecoder, the rows unfiltered by unfilter_row and the passes
+ * counted by the interlace module, only the buffering of the pieces and the
+ * inflating of the image data are done here.
+ */
+pub struct PngPushDecoder {
+    decoder: PngDecoder<'static>,
+    buffer: Vec<u8>,    /* data pushed and not processed yet */
+    state: PushState,
+    header_read: bool,  /* the Header event is returned */
+
+    inflate: Box<InflateState>,
+    inflate_done: bool,
+    filtered: Vec<u8>,  /* inflated data, the start of a row */
+    idat_crc: u32,
+
+    pass: u8,
+    pass_row: u32,      /* row in the pass */
+    row: Vec<u8>,
+    prev_row: Vec<u8>,
+    image_done: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PushState {
+    Signature,
+    Chunk,              /* a chunk header, or a whole chunk, is awaited */
+    Idat(u32),          /* bytes of the current IDAT left, then its CRC */
+    IdatCrc,
+    Skip(u32),          /* bytes of an unused chunk left, with its CRC */
+    Done,               /* after IEND */
+}
+
+impl Default for PngPushDecoder {
+    fn default() -> PngPushDecoder
+    {
+        PngPushDecoder::new()
+    }
+}
+
+impl PngPushDecoder {
+    pub fn new() -> PngPushDecoder
+    {
+        PngPushDecoder {
+            decoder: PngDecoder::new(&[]),
+            buffer: Vec::new(),
+            state: PushState::Signature,
+            header_read: false,
+            inflate: InflateState::new_boxed(DataFormat::Zlib),
+            inflate_done: false,
+            filtered: Vec::new(),
+            idat_crc: 0,
+            pass: 0,
+            pass_row: 0,
+            row: Vec::new(),
+            prev_row: Vec::new(),
+            image_done: false,
+        }
+    }
+
+    /* The information of the chunks before the image data, once the Header
+     * event has been returned.
+     */
+    pub fn info(&self) -> Option<&PngInfo>
+    {
+        match self.header_read {
+            true => Some(self.decoder.info()),
+            false => None,
+        }
+    }
+
+    /* Whether the IEND chunk has been read */
+    pub fn is_done(&self) -> bool
+    {
+        self.state == PushState::Done
+    }
+
+    /* Process 'data', the next bytes of the datastream */
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<PngProgressiveEvent>, &'static str>
+    {
+        let mut events = Vec::new();
+        let mut buffer = std::mem::take(&mut self.buffer);
+        let mut pos = 0;
+
+        buffer.extend_from_slice(data);
+
+        loop {
+            let available = buffer.len() - pos;
+
+            match self.state {
+                PushState::Signature => {
+                    if available < 8
+                    {
+                        break;
+                    }
+
+                    self.decoder.check_signature(&buffer[pos..])?;
+                    pos += 8;
+                    self.state = PushState::Chunk;
+                },
+                PushState::Chunk => {
+                    if available < 8
+                    {
+                        break;
+                    }
+
+                    let mut name = [0u8; 4];
+                    name.copy_from_slice(&buffer[pos + 4..pos + 8]);
+                    let length = check_chunk_header(get_u32(&buffer[pos..]), &name)?;
+
+                    if &name == b"IDAT"
+                    {
+                        if !self.header_read
+                        {
+                            self.decoder.handle_info_chunk(&name, &[])?;
+                            self.start_image(&mut events);
+                        }
+
+                        self.idat_crc = crc32::update(0, &crc32::IEEE_TABLE, &name);
+                        self.state = PushState::Idat(length);
+                        pos += 8;
+                        continue;
+                    }
+
+                    if !uses_chunk(&name)
+                    {
+                        self.state = PushState::Skip(length + 4);
+                        pos += 8;
+                        continue;
+                    }
+
+                    if available < 12 + length as usize
+                    {
+                        break;
+                    }
+
+                    let (name, chunk) = read_chunk(&buffer, &mut pos)?;
+                    match self.header_read {
+                        false => { self.decoder.handle_info_chunk(&name, chunk)?; },
+                        true => self.handle_end_chunk(&name, &mut events)?,
+                    }
+                },
+                PushState::Idat(left) => {
+                    if left == 0
+                    {
+                        self.state = PushState::IdatCrc;
+                        continue;
+                    }
+
+                    if available == 0
+                    {
+                        break;
+                    }
+
+                    let size = available.min(left as usize);
+                    let start = pos;
+                    pos += size;
+
+                    self.idat_crc = crc32::update(self.idat_crc, &crc32::IEEE_TABLE, &buffer[start..pos]);
+                    self.process_idat(&buffer[start..pos], &mut events)?;
+                    self.state = PushState::Idat(left - size as u32);
+                },
+                PushState::IdatCrc => {
+                    if available < 4
+                    {
+                        break;
+                    }
+
+                    if get_u32(&buffer[pos..]) != self.idat_crc
+                    {
+                        return Err("CRC error");
+                    }
+
+                    pos += 4;
+                    self.state = PushState::Chunk;
+                },
+                PushState::Skip(left) => {
+                    let size = available.min(left as usize);
+
+                    pos += size;
+                    self.state = match left - size as u32 {
+                        0 => PushState::Chunk,
+                        left => PushState::Skip(left),
+                    };
+
+                    if size == 0
+                    {
+                        break;
+                    }
+                },
+                PushState::Done => {
+                    pos = buffer.len();
+                    break;
+                },
+            }
+        }
+
+        buffer.drain(..pos);
+        self.buffer = buffer;
+        Ok(events)
+    }
+
+    /* The chunks after the image data: only IEND matters */
+    fn handle_end_chunk(&mut self, name: &[u8; 4], events: &mut Vec<PngProgressiveEvent>)
+                        -> Result<(), &'static str>
+    {
+        if name == b"IEND"
+        {
+            if !self.image_done
+            {
+                return Err("Not enough image data");
+            }
+
+            events.push(PngProgressiveEvent::End);
+            self.state = PushState::Done;
+        }
+
+        Ok(())
+    }
+
+    /* The first IDAT: set up the first pass */
+    fn start_image(&mut self, events: &mut Vec<PngProgressiveEvent>)
+    {
+        let info = self.decoder.info();
+
+        events.push(PngProgressiveEvent::Header {
+            width: info.width,
+            height: info.height,
+            bit_depth: info.bit_depth,
+            color_type: info.color_type,
+            interlaced: info.interlace_type,
+        });
+
+        self.header_read = true;
+        self.pass = 0;
+        self.next_pass(false, events);
+    }
+
+    /* The number of rows of the current pass, and their size */
+    fn pass_size(&self) -> (u32, usize)
+    {
+        let info = self.decoder.info();
+
+        let (cols, rows) = match info.interlace_type {
+            PngInterlace::None => (info.width, info.height),
+            PngInterlace::ADAM7 => (pass_cols(info.width, self.pass), pass_rows(info.height, self.pass)),
+        };
+
+        match cols {
+            0 => (0, 0),
+            _ => (rows, Png::compute_rowbytes(info.pixel_depth, cols as usize)),
+        }
+    }
+
+    /* Move to the first non empty pass from the current one, or the one
+     * after it if 'skip_current'.  As in pread.rs, the empty passes skipped
+     * are complete at once: every pass has its PassComplete.
+     */
+    fn next_pass(&mut self, skip_current: bool, events: &mut Vec<PngProgressiveEvent>)
+    {
+        let passes = match self.decoder.info().interlace_type {
+            PngInterlace::None => 1,
+            PngInterlace::ADAM7 => PNG_INTERLACE_ADAM7_PASSES,
+        };
+
+        if skip_current
+        {
+            self.pass += 1;
+        }
+
+        while self.pass < passes {
+            let (rows, rowbytes) = self.pass_size();
+
+            if rows > 0 && rowbytes > 0
+            {
+                self.pass_row = 0;
+                self.row = vec![0; rowbytes];
+                self.prev_row.clear();
+                return;
+            }
+            events.push(PngProgressiveEvent::PassComplete(self.pass));
+            self.pass += 1;
+        }
+
+        self.row.clear();
+        self.prev_row.clear();
+        self.image_done = true;
+    }
+
+    /* Inflate 'zdata' and return the rows completed.  The data is inflated to
+     * the end of the stream, for its checksum, but the data past the end of
+     * the image is ignored.
+     */
+    fn process_idat(&mut self, mut zdata: &[u8], events: &mut Vec<PngProgressiveEvent>)
+                    -> Result<(), &'static str>
+    {
+        let mut out = [0u8; 8192];
+        let mut out_full = false;
+
+        while (!zdata.is_empty() || out_full) && !self.inflate_done {
+            let result = inflate(&mut self.inflate, zdata, &mut out, MZFlush::None);
+
+            zdata = &zdata[result.bytes_consumed..];
+            out_full = result.bytes_written == out.len();
+            if !self.image_done
+            {
+                self.filtered.extend_from_slice(&out[..result.bytes_written]);
+            }
+
+            match result.status {
+                Ok(MZStatus::StreamEnd) => self.inflate_done = true,
+                Ok(_) => {},
+                Err(MZError::Buf) => {},
+                Err(_) => return Err("IDAT: invalid data"),
+            }
+
+            self.process_rows(events)?;
+
+            if result.bytes_consumed == 0 && result.bytes_written == 0
+            {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /* Unfilter the complete rows of the inflated data */
+    fn process_rows(&mut self, events: &mut Vec<PngProgressiveEvent>) -> Result<(), &'static str>
+    {
+        let bpp = (self.decoder.info().pixel_depth as usize).div_ceil(8);
+        let mut start = 0;
+
+        while !self.image_done && self.filtered.len() - start > self.row.len() {
+            let rowbytes = self.row.len();
+            let filter = PngFilterValue::from_u8(self.filtered[start]).ok_or("bad adaptive filter value")?;
+
+            self.row.copy_from_slice(&self.filtered[start + 1..start + 1 + rowbytes]);
+            unfilter_row(filter, bpp, &mut self.row, &self.prev_row);
+            start += rowbytes + 1;
+
+            let y = match self.decoder.info().interlace_type {
+                PngInterlace::None => self.pass_row,
+                PngInterlace::ADAM7 => row_from_pass_row(self.pass_row, self.pass),
+            };
+            events.push(PngProgressiveEvent::Row { pass: self.pass, row: y, data: self.row.clone() });
+
+            std::mem::swap(&mut self.prev_row, &mut self.row);
+            self.row.resize(rowbytes, 0);
+            self.pass_row += 1;
+
+            if self.pass_row == self.pass_size().0
+            {
+                events.push(PngProgressiveEvent::PassComplete(self.pass));
+                self.next_pass(true, events);
+            }
+        }
+
+        self.filtered.drain(..start);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    /* The PNG files of contrib/pngsuite and of the crashers of
+     * contrib/testpngs.
+     */
+    fn test_pngs() -> Vec<(String, Vec<u8>)>
+    {
+        let contrib = Path::new(env!("CARGO_MANIFEST_DIR")).join("../contrib");
+        let mut pngs = Vec::new();
+
+        for dir in ["pngsuite", "pngsuite/interlaced", "testpngs/crashers"].iter() {
+            let mut paths: Vec<_> = fs::read_dir(contrib.join(dir)).unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension() == Some("png".as_ref()))
+                .collect();
+            paths.sort();
+
+            for path in paths {
+                pngs.push((path.display().to_string(), fs::read(&path).unwrap()));
+            }
+        }
+
+        pngs
+    }
+
+    /* The events of 'png' pushed in pieces of 'size' bytes, up to the first
+     * error.
+     */
+    fn push_pieces(png: &[u8], size: usize) -> Result<Vec<PngProgressiveEvent>, &'static str>
+    {
+        let mut decoder = PngPushDecoder::new();
+        let mut events = Vec::new();
+
+        for piece in png.chunks(size) {
+            events.append(&mut decoder.push(piece)?);
+        }
+
+        match decoder.is_done() {
+            true => Ok(events),
+            false => Err("Not enough image data"),
+        }
+    }
+
+    #[test]
+    fn pieces_of_any_size_give_the_same_events()
+    {
+        for (path, png) in test_pngs() {
+            let expected = push_pieces(&png, png.len().max(1));
+
+            for &size in [1, 7, 4096].iter() {
+                assert_eq!(push_pieces(&png, size), expected, "{} in pieces of {}", path, size);
+            }
+        }
+    }
+
+    #[test]
+    fn rows_are_those_of_png_decoder()
+    {
+        for (path, png) in test_pngs() {
+            let mut decoder = PngDecoder::new(&png);
+            let image = match decoder.read_image() {
+                Ok(image) => image,
+                Err(_) => continue,
+            };
+            if decoder.info().interlace_type != PngInterlace::None
+            {
+                continue;
+            }
+
+            let rows: Vec<u8> = push_pieces(&png, 7).unwrap().into_iter().filter_map(|event| match event {
+                PngProgressiveEvent::Row { data, .. } => Some(data),
+                _ => None,
+            }).flatten().collect();
+            assert_eq!(rows, image, "{}", path);
+        }
+    }
+
+    /* Check that 'events' are Header, the rows of each pass in order, then
+     * its PassComplete, even for the passes without pixels, and End.
+     */
+    fn check_passes(path: &str, events: &[PngProgressiveEvent])
+    {
+        let (width, height, interlaced) = match events.first() {
+            Some(&PngProgressiveEvent::Header { width, height, interlaced, .. }) => (width, height, interlaced),
+            event => panic!("{}: {:?} first", path, event),
+        };
+        assert_eq!(events.last(), Some(&PngProgressiveEvent::End), "{}", path);
+
+        let passes = match interlaced {
+            PngInterlace::ADAM7 => PNG_INTERLACE_ADAM7_PASSES,
+            PngInterlace::None => 1,
+        };
+        let mut rows = Vec::new();
+        for pass in 0..passes {
+            let pass_height = match (interlaced, pass_cols(width, pass)) {
+                (PngInterlace::ADAM7, 0) => 0,
+                (PngInterlace::ADAM7, _) => pass_rows(height, pass),
+                (PngInterlace::None, _) => height,
+            };
+            rows.extend((0..pass_height).map(|row| Ok((pass, match interlaced {
+                PngInterlace::ADAM7 => row_from_pass_row(row, pass),
+                PngInterlace::None => row,
+            }))));
+            rows.push(Err(pass));
+        }
+
+        let got: Vec<_> = events[1..events.len() - 1].iter().map(|event| match *event {
+            PngProgressiveEvent::Row { pass, row, .. } => Ok((pass, row)),
+            PngProgressiveEvent::PassComplete(pass) => Err(pass),
+            ref event => panic!("{}: {:?} among the rows", path, event),
+        }).collect();
+        assert_eq!(got, rows, "{}", path);
+    }
+
+    #[test]
+    fn events_follow_the_passes()
+    {
+        for (path, png) in test_pngs() {
+            if let Ok(events) = push_pieces(&png, 4096)
+            {
+                check_passes(&path, &events);
+            }
+        }
+    }
+
+    /* Images of 1 to 17 pixels in each direction have Adam7 passes without
+     * pixels: those of a single column or a single row above all.
+     */
+    #[test]
+    fn empty_passes_are_complete()
+    {
+        use crate::{ChunkWriter, PngColor};
+
+        for width in 1..=17 {
+            for height in 1..=17 {
+                let image: Vec<Vec<u8>> = (0..height).map(|y| (0..width).map(|x| (x * 37 + y * 101) as u8).collect())
+                    .collect();
+
+                let mut info = PngInfo::new();
+                info.width = width;
+                info.height = height;
+                info.bit_depth = 8;
+                info.color_type = PngColor::TYPE_GRAY;
+                info.interlace_type = PngInterlace::ADAM7;
+
+                let mut png_ptr = Png::new();
+                let mut writer = ChunkWriter::new(Vec::new());
+                png_ptr.write_info(&mut writer, &mut info).unwrap();
+                png_ptr.write_image(&mut writer, &image).unwrap();
+                png_ptr.write_end(&mut writer, Some(&mut info)).unwrap();
+                let png = writer.into_inner();
+
+                let case = format!("{}x{}", width, height);
+                let events = push_pieces(&png, 7).unwrap();
+                check_passes(&case, &events);
+
+                /* The pixels of each row are those of the pass */
+                for event in &events {
+                    if let PngProgressiveEvent::Row { pass, row, data } = event
+                    {
+                        let expected: Vec<u8> = (0..pass_cols(width, *pass))
+                            .map(|col| image[*row as usize][crate::interlace::col_from_pass_col(col, *pass) as usize])
+                            .collect();
+                        assert_eq!(data, &expected, "{} pass {} row {}", case, pass, row);
+                    }
+                }
+
+                #[cfg(feature = "c-tests")]
+                {
+                    let pread_passes: Vec<_> = crate::libpng::progressive_events(&png, 7).into_iter()
+                        .filter(|event| matches!(event, PngProgressiveEvent::PassComplete(_)))
+                        .collect();
+                    let passes: Vec<_> = events.into_iter()
+                        .filter(|event| matches!(event, PngProgressiveEvent::PassComplete(_)))
+                        .collect();
+                    assert_eq!(passes, pread_passes, "{}", case);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,860 @@
+use crate::Png;
+use crate::PngColor;
+use crate::PngTransformations;
+use crate::png_info::PngPaletteColor;
+use crate::trans::PngRowInfo;
+use crate::wutil::PNG_MAX_PALETTE_LENGTH;
+use crate::CPtr;
+use std::collections::HashMap;
+use std::slice;
+
+/* Precision of the RGB cube used to map a color to its palette entry */
+pub const PNG_QUANTIZE_RED_BITS: u32   = 5;
+pub const PNG_QUANTIZE_GREEN_BITS: u32 = 5;
+pub const PNG_QUANTIZE_BLUE_BITS: u32  = 5;
+
+/* Distances between two palette colors can't be larger than 3 * 255 */
+const PNG_MAX_COLOR_DIST: usize = 769;
+
+/*******************************************************************************
+ *
+ *                               Png C API
+ *
+ ******************************************************************************/
+
+#[no_mangle]
+pub unsafe extern fn png_c_set_quantize(this: *mut Png, palette: *mut PngPaletteColor,
+                                        num_palette: i32, maximum_colors: i32,
+                                        histogram: *const u16, full_quantize: i32)
+{
+    match this.as_mut() {
+        None => { return; },
+        Some(png_ptr) => {
+            if palette.is_null() || num_palette <= 0 || maximum_colors <= 0
+            {
+                return;
+            }
+
+            let palette = slice::from_raw_parts_mut(palette, num_palette as usize);
+            let histogram = match histogram.is_null() {
+                true => None,
+                false => Some(slice::from_raw_parts(histogram, num_palette as usize)),
+            };
+
+            /* A palette has at most 256 entries, more colors are as many */
+            let maximum_colors = (maximum_colors as usize).min(PNG_MAX_PALETTE_LENGTH);
+            /* An invalid palette leaves the transformation unset */
+            let _ = png_ptr.set_quantize(palette, maximum_colors, histogram, full_quantize != 0);
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_rust_has_palette_lookup(this: *const Png) -> bool
+{
+    match this.as_ref() {
+        None => false,
+        Some(png_ptr) => {
+            match &png_ptr.quantizer {
+                None => false,
+                Some(quantizer) => quantizer.palette_lookup.is_some(),
+            }
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_do_quantize(this: *mut Png, row_info: *mut PngRowInfo, row: CPtr)
+{
+    match this.as_mut() {
+        None => { return; },
+        Some(png_ptr) => {
+            match (row_info.as_mut(), png_ptr.quantizer.as_mut()) {
+                (Some(row_info), Some(quantizer)) => {
+                    let slice = slice::from_raw_parts_mut(row as *mut u8, row_info.rowbytes);
+                    quantizer.do_quantize(row_info, slice);
+                },
+                _ => { return; },
+            }
+        },
+    }
+}
+
+
+
+/*******************************************************************************
+ *
+ *                               Png RUST
+ *
+ ******************************************************************************/
+
+fn color_dist(c1: &PngPaletteColor, c2: &PngPaletteColor) -> usize
+{
+    ((c1.red as i32 - c2.red as i32).abs() +
+     (c1.green as i32 - c2.green as i32).abs() +
+     (c1.blue as i32 - c2.blue as i32).abs()) as usize
+}
+
+/* Index of a color in the RGB cube, with 5 bits per color we get:
+ *    (((r >> 3) & 0x1f) << 10) | (((g >> 3) & 0x1f) << 5) | ((b >> 3) & 0x1f)
+ */
+fn cube_index(red: u8, green: u8, blue: u8) -> usize
+{
+    let red   = (red   >> (8 - PNG_QUANTIZE_RED_BITS)) as usize;
+    let green = (green >> (8 - PNG_QUANTIZE_GREEN_BITS)) as usize;
+    let blue  = (blue  >> (8 - PNG_QUANTIZE_BLUE_BITS)) as usize;
+
+    (red << (PNG_QUANTIZE_GREEN_BITS + PNG_QUANTIZE_BLUE_BITS)) |
+        (green << PNG_QUANTIZE_BLUE_BITS) |
+        blue
+}
+
+/* Reduce an image to a palette of at most 256 colors.  A quantizer is either
+ * built from an existing palette (like png_set_quantize does) or from the
+ * pixels themselves with a median cut.  It then converts 8-bit RGB and RGBA
+ * rows into palette indexes, optionally with Floyd-Steinberg dithering, and
+ * translates palette rows when their palette has been reduced.
+ */
+#[derive(Debug, Clone)]
+pub struct Quantizer {
+    palette: Vec<PngPaletteColor>,   /* final palette, at most maximum_colors */
+    palette_lookup: Option<Vec<u8>>, /* lookup table for quantizing RGB rows */
+    quantize_index: Option<Vec<u8>>, /* index translation for palette rows */
+    dither: bool,                    /* apply Floyd-Steinberg dithering */
+    dither_errors: Vec<i32>,         /* errors carried to the next row (x16) */
+}
+
+impl Quantizer {
+    /* Dither file to 8-bit.  Supply a palette and the maximum number of
+     * colors allowed, and a histogram if possible (the hIST chunk of the
+     * file).  If the number of colors in the palette is greater than the
+     * maximum number, the palette will be modified to fit in the maximum
+     * number.  "full_quantize" indicates whether we need a quantizing cube set
+     * up for RGB images, or if we simply are reducing the number of colors in
+     * a paletted image.  The palette has 1 to 256 entries, and so does the
+     * reduced one.
+     */
+    pub fn new(palette: &[PngPaletteColor], maximum_colors: usize,
+               histogram: Option<&[u16]>, full_quantize: bool) -> Result<Quantizer, &'static str>
+    {
+        if maximum_colors == 0 || maximum_colors > PNG_MAX_PALETTE_LENGTH
+        {
+            return Err("Invalid maximum number of colors");
+        }
+
+        if palette.is_empty() || palette.len() > PNG_MAX_PALETTE_LENGTH
+        {
+            return Err("Invalid palette length");
+        }
+
+        let num_palette = palette.len();
+        let mut palette = palette.to_vec();
+        let mut quantize_index: Option<Vec<u8>> = match full_quantize {
+            true => None,
+            false => Some((0..num_palette).map(|i| i as u8).collect()),
+        };
+
+        /* A histogram is only useful if it covers the whole palette */
+        let histogram = histogram.filter(|histogram| histogram.len() >= num_palette);
+
+        if num_palette > maximum_colors
+        {
+            match histogram {
+                Some(histogram) => {
+                    Quantizer::reduce_with_histogram(&mut palette, &mut quantize_index,
+                                                     maximum_colors, histogram);
+                },
+                None => {
+                    Quantizer::reduce_closest_pairs(&mut palette, &mut quantize_index,
+                                                    maximum_colors);
+                },
+            }
+
+            palette.truncate(maximum_colors);
+        }
+
+        let palette_lookup = match full_quantize {
+            true => Some(Quantizer::build_palette_lookup(&palette)),
+            false => None,
+        };
+
+        Ok(Quantizer {
+            palette,
+            palette_lookup,
+            quantize_index,
+            dither: false,
+            dither_errors: Vec::new(),
+        })
+    }
+
+    /* Build a palette of at most maximum_colors entries for an 8-bit RGB or
+     * RGBA image with a median cut, and set up the quantizing cube for it.
+     * Alpha is ignored, as it is by the quantizing of rows.  There must be at
+     * least one pixel.
+     */
+    pub fn from_pixels(pixels: &[u8], color_type: PngColor, maximum_colors: usize)
+                       -> Result<Quantizer, &'static str>
+    {
+        let channels = match color_type {
+            PngColor::TYPE_RGB => 3,
+            PngColor::TYPE_RGB_ALPHA => 4,
+            _ => {
+                return Err("Only RGB and RGBA pixels are quantized");
+            }
+        };
+
+        let mut histogram: HashMap<PngPaletteColor, u32> = HashMap::new();
+        for pixel in pixels.chunks_exact(channels) {
+            let color = PngPaletteColor { red: pixel[0], green: pixel[1], blue: pixel[2] };
+            *histogram.entry(color).or_insert(0) += 1;
+        }
+
+        /* Most used colors first, the order only matters when every color
+         * fits in the palette.
+         */
+        let mut colors: Vec<(PngPaletteColor, u32)> = histogram.into_iter().collect();
+        colors.sort_by(|a, b| b.1.cmp(&a.1).then((a.0.red, a.0.green, a.0.blue).cmp(&(b.0.red, b.0.green, b.0.blue))));
+
+        if maximum_colors == 0 || maximum_colors > PNG_MAX_PALETTE_LENGTH
+        {
+            return Err("Invalid maximum number of colors");
+        }
+
+        let palette: Vec<PngPaletteColor> = match colors.len() <= maximum_colors {
+            true => colors.iter().map(|(color, _)| *color).collect(),
+            false => Quantizer::median_cut(colors, maximum_colors),
+        };
+
+        Quantizer::new(&palette, maximum_colors, None, true)
+    }
+
+    /* Enable or disable Floyd-Steinberg dithering of RGB rows.  The error of
+     * each row is diffused into the next one, so rows must be given in order;
+     * with interlaced images only the rows of a same pass are adjacent.
+     */
+    pub fn set_dither(&mut self, dither: bool)
+    {
+        self.dither = dither;
+        self.dither_errors.clear();
+    }
+
+    /* Forget the error carried from the previous row (new image or pass) */
+    pub fn reset_dither(&mut self)
+    {
+        self.dither_errors.clear();
+    }
+
+    pub fn palette(&self) -> &[PngPaletteColor]
+    {
+        &self.palette
+    }
+
+    /* For palette rows, the new index of each original palette entry */
+    pub fn quantize_index(&self) -> Option<&[u8]>
+    {
+        self.quantize_index.as_deref()
+    }
+
+    /* Quantize a whole 8-bit RGB, RGBA or palette image, returning one palette
+     * index per pixel.
+     */
+    pub fn quantize_image(&mut self, pixels: &[u8], width: u32, height: u32,
+                          color_type: PngColor) -> Vec<u8>
+    {
+        let row_info = PngRowInfo::new(width, color_type, 8);
+        let mut row = vec![0; row_info.rowbytes];
+        let mut indexes = Vec::with_capacity(width as usize * height as usize);
+
+        self.reset_dither();
+        for source in pixels.chunks_exact(row_info.rowbytes).take(height as usize) {
+            let mut row_info = row_info;
+            row.copy_from_slice(source);
+            self.do_quantize(&mut row_info, &mut row);
+            indexes.extend_from_slice(&row[..width as usize]);
+        }
+
+        indexes
+    }
+
+    /* Replace each pixel by its palette index (quantize_lookup for palette
+     * rows, the RGB cube for RGB and RGBA rows).  Only 8-bit rows are handled.
+     */
+    pub fn do_quantize(&mut self, row_info: &mut PngRowInfo, row: &mut [u8])
+    {
+        if row_info.bit_depth != 8
+        {
+            return;
+        }
+
+        let width = row_info.width as usize;
+
+        match row_info.color_type {
+            PngColor::TYPE_RGB | PngColor::TYPE_RGB_ALPHA => {
+                let channels = row_info.channels as usize;
+                assert!(width * channels <= row.len());
+
+                match (&self.palette_lookup, self.dither) {
+                    (None, _) => {
+                        return;
+                    },
+                    (Some(_), true) => {
+                        self.dither_row(width, channels, row);
+                    },
+                    (Some(palette_lookup), false) => {
+                        for i in 0..width {
+                            let pixel = i * channels;
+                            row[i] = palette_lookup[cube_index(row[pixel], row[pixel + 1], row[pixel + 2])];
+                        }
+                    },
+                }
+
+                row_info.color_type = PngColor::TYPE_PALETTE;
+                row_info.channels = 1;
+                row_info.pixel_depth = row_info.bit_depth;
+                row_info.rowbytes = Png::compute_rowbytes(row_info.pixel_depth, width);
+            },
+            PngColor::TYPE_PALETTE => {
+                if let Some(quantize_index) = &self.quantize_index
+                {
+                    for value in row[..width].iter_mut() {
+                        if let Some(index) = quantize_index.get(*value as usize)
+                        {
+                            *value = *index;
+                        }
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /* Floyd-Steinberg: 7/16 of the error goes to the right pixel, 3/16, 5/16
+     * and 1/16 to the bottom left, bottom and bottom right ones.  Errors are
+     * kept multiplied by 16 with one guard pixel on each side.
+     */
+    fn dither_row(&mut self, width: usize, channels: usize, row: &mut [u8])
+    {
+        let palette_lookup = match &self.palette_lookup {
+            None => { return; },
+            Some(palette_lookup) => palette_lookup,
+        };
+
+        let nb_errors = 3 * (width + 2);
+        if self.dither_errors.len() != nb_errors
+        {
+            self.dither_errors = vec![0; nb_errors];
+        }
+        let mut next_errors = vec![0; nb_errors];
+
+        for i in 0..width {
+            let pixel = i * channels;
+            let mut color = [0; 3];
+            for c in 0..3 {
+                let value = row[pixel + c] as i32 + (self.dither_errors[3 * (i + 1) + c] >> 4);
+                color[c] = value.clamp(0, 255);
+            }
+
+            let index = palette_lookup[cube_index(color[0] as u8, color[1] as u8, color[2] as u8)];
+            let chosen = self.palette[index as usize];
+            row[i] = index;
+
+            let chosen = [chosen.red as i32, chosen.green as i32, chosen.blue as i32];
+            for c in 0..3 {
+                let error = color[c] - chosen[c];
+                self.dither_errors[3 * (i + 2) + c] += error * 7;
+                next_errors[3 * i + c]       += error * 3;
+                next_errors[3 * (i + 1) + c] += error * 5;
+                next_errors[3 * (i + 2) + c] += error;
+            }
+        }
+
+        self.dither_errors = next_errors;
+    }
+
+    /* This is easy enough, just throw out the least used colors.
+     * Perhaps not the best solution, but good enough.
+     */
+    fn reduce_with_histogram(palette: &mut [PngPaletteColor], quantize_index: &mut Option<Vec<u8>>,
+                             maximum_colors: usize, histogram: &[u16])
+    {
+        let num_palette = palette.len();
+        let mut quantize_sort: Vec<u8> = (0..num_palette).map(|i| i as u8).collect();
+
+        /* Find the least used palette entries by starting a
+         * bubble sort, and running it until we have sorted
+         * out enough colors.  Note that we don't care about
+         * sorting all the colors, just finding which are
+         * least used.
+         */
+        for i in (maximum_colors..num_palette).rev() {
+            let mut done = true; /* To stop early if the list is pre-sorted */
+
+            for j in 0..i {
+                if histogram[quantize_sort[j] as usize] < histogram[quantize_sort[j + 1] as usize]
+                {
+                    quantize_sort.swap(j, j + 1);
+                    done = false;
+                }
+            }
+
+            if done
+            {
+                break;
+            }
+        }
+
+        /* Swap the palette around, and set up a table, if necessary */
+        let mut j = num_palette;
+        match quantize_index {
+            None => {
+                /* Put all the useful colors within the max, but don't
+                 * move the others.
+                 */
+                for i in 0..maximum_colors {
+                    if quantize_sort[i] as usize >= maximum_colors
+                    {
+                        j -= 1;
+                        while quantize_sort[j] as usize >= maximum_colors {
+                            j -= 1;
+                        }
+
+                        palette[i] = palette[j];
+                    }
+                }
+            },
+            Some(quantize_index) => {
+                /* Move all the used colors inside the max limit, and
+                 * develop a translation table.
+                 */
+                for i in 0..maximum_colors {
+                    /* Only move the colors we need to */
+                    if quantize_sort[i] as usize >= maximum_colors
+                    {
+                        j -= 1;
+                        while quantize_sort[j] as usize >= maximum_colors {
+                            j -= 1;
+                        }
+
+                        palette.swap(i, j);
+                        /* Indicate where the color went */
+                        quantize_index[j] = i as u8;
+                        quantize_index[i] = j as u8;
+                    }
+                }
+
+                /* Find closest color for those colors we are not using */
+                for index in quantize_index.iter_mut() {
+                    if *index as usize >= maximum_colors
+                    {
+                        /* Find the closest color to one we threw out */
+                        let d_index = *index as usize;
+                        let mut min_d = color_dist(&palette[d_index], &palette[0]);
+                        let mut min_k = 0;
+
+                        for k in 1..maximum_colors {
+                            let d = color_dist(&palette[d_index], &palette[k]);
+
+                            if d < min_d
+                            {
+                                min_d = d;
+                                min_k = k;
+                            }
+                        }
+
+                        /* Point to closest color */
+                        *index = min_k as u8;
+                    }
+                }
+            },
+        }
+    }
+
+    /* This is much harder to do simply (and quickly) without a histogram, so
+     * we will just find the closest two colors, and throw out one of them
+     * (chosen somewhat randomly), until the palette is small enough.
+     */
+    fn reduce_closest_pairs(palette: &mut [PngPaletteColor], quantize_index: &mut Option<Vec<u8>>,
+                            maximum_colors: usize)
+    {
+        let num_palette = palette.len();
+
+        /* Where the original index currently is in the palette, and which
+         * original index points to each palette color.
+         */
+        let mut index_to_palette: Vec<u8> = (0..num_palette).map(|i| i as u8).collect();
+        let mut palette_to_index: Vec<u8> = (0..num_palette).map(|i| i as u8).collect();
+
+        let mut num_new_palette = num_palette;
+
+        /* Initial wild guess at how far apart the farthest pixel
+         * pair we will be eliminating will be.  Larger
+         * numbers mean more areas will be allocated, Smaller
+         * numbers run the risk of not saving enough data, and
+         * having to do this all over again.
+         */
+        let mut max_d = 96;
+
+        while num_new_palette > maximum_colors
+        {
+            /* Pairs of colors sorted by distance */
+            let mut hash: Vec<Vec<(usize, usize)>> = vec![Vec::new(); PNG_MAX_COLOR_DIST];
+
+            /* The pairs are kept as original indexes: libpng stored the
+             * palette positions, which only match the original indexes in the
+             * first pass and could loop forever on later ones.
+             */
+            for i in 0..num_new_palette - 1 {
+                for j in i + 1..num_new_palette {
+                    let d = color_dist(&palette[i], &palette[j]);
+
+                    if d <= max_d
+                    {
+                        hash[d].push((palette_to_index[i] as usize, palette_to_index[j] as usize));
+                    }
+                }
+            }
+
+            'search: for pairs in hash.iter().take(max_d + 1) {
+                /* The most recent pair first, as libpng always did */
+                for &(left, right) in pairs.iter().rev() {
+                    if (index_to_palette[left] as usize) < num_new_palette &&
+                        (index_to_palette[right] as usize) < num_new_palette
+                    {
+                        let (j, next_j) = match num_new_palette & 0x01 {
+                            0 => (right, left),
+                            _ => (left, right),
+                        };
+
+                        num_new_palette -= 1;
+                        palette[index_to_palette[j] as usize] = palette[num_new_palette];
+
+                        if let Some(quantize_index) = quantize_index
+                        {
+                            for index in quantize_index.iter_mut() {
+                                if *index == index_to_palette[j]
+                                {
+                                    *index = index_to_palette[next_j];
+                                }
+
+                                if *index as usize == num_new_palette
+                                {
+                                    *index = index_to_palette[j];
+                                }
+                            }
+                        }
+
+                        index_to_palette[palette_to_index[num_new_palette] as usize] = index_to_palette[j];
+                        palette_to_index[index_to_palette[j] as usize] = palette_to_index[num_new_palette];
+                        index_to_palette[j] = num_new_palette as u8;
+                        palette_to_index[num_new_palette] = j as u8;
+                    }
+
+                    if num_new_palette <= maximum_colors
+                    {
+                        break 'search;
+                    }
+                }
+            }
+
+            max_d += 96;
+        }
+    }
+
+    /* For each cell of the RGB cube, the palette entry at the smallest
+     * distance.
+     */
+    fn build_palette_lookup(palette: &[PngPaletteColor]) -> Vec<u8>
+    {
+        let total_bits = PNG_QUANTIZE_RED_BITS + PNG_QUANTIZE_GREEN_BITS + PNG_QUANTIZE_BLUE_BITS;
+        let num_red   = 1 << PNG_QUANTIZE_RED_BITS;
+        let num_green = 1 << PNG_QUANTIZE_GREEN_BITS;
+        let num_blue  = 1 << PNG_QUANTIZE_BLUE_BITS;
+        let num_entries = 1 << total_bits;
+
+        let mut palette_lookup = vec![0; num_entries];
+        let mut distance = vec![0xff; num_entries];
+
+        for (i, color) in palette.iter().enumerate() {
+            let r = (color.red   >> (8 - PNG_QUANTIZE_RED_BITS)) as i32;
+            let g = (color.green >> (8 - PNG_QUANTIZE_GREEN_BITS)) as i32;
+            let b = (color.blue  >> (8 - PNG_QUANTIZE_BLUE_BITS)) as i32;
+
+            for ir in 0..num_red {
+                let dr = (ir - r).abs();
+                let index_r = (ir << (PNG_QUANTIZE_BLUE_BITS + PNG_QUANTIZE_GREEN_BITS)) as usize;
+
+                for ig in 0..num_green {
+                    let dg = (ig - g).abs();
+                    let dt = dr + dg;
+                    let dm = dr.max(dg);
+                    let index_g = index_r | (ig << PNG_QUANTIZE_BLUE_BITS) as usize;
+
+                    for ib in 0..num_blue {
+                        let d_index = index_g | ib as usize;
+                        let db = (ib - b).abs();
+                        let d = dm.max(db) + dt + db;
+
+                        if d < distance[d_index] as i32
+                        {
+                            distance[d_index] = d as u8;
+                            palette_lookup[d_index] = i as u8;
+                        }
+                    }
+                }
+            }
+        }
+
+        palette_lookup
+    }
+
+    /* Split the color space in boxes containing about the same number of
+     * pixels, each box giving one palette entry (the mean of its colors).
+     */
+    fn median_cut(colors: Vec<(PngPaletteColor, u32)>, maximum_colors: usize) -> Vec<PngPaletteColor>
+    {
+        fn component(color: &PngPaletteColor, axis: usize) -> u8
+        {
+            match axis {
+                0 => color.red,
+                1 => color.green,
+                _ => color.blue,
+            }
+        }
+
+        /* Longest axis of a box and its length */
+        fn longest_axis(colors: &[(PngPaletteColor, u32)]) -> (usize, u8)
+        {
+            (0..3).map(|axis| {
+                let min = colors.iter().map(|(color, _)| component(color, axis)).min().unwrap_or(0);
+                let max = colors.iter().map(|(color, _)| component(color, axis)).max().unwrap_or(0);
+                (axis, max - min)
+            }).max_by_key(|&(_, length)| length).unwrap_or((0, 0))
+        }
+
+        let mut boxes = vec![colors];
+
+        while boxes.len() < maximum_colors
+        {
+            /* Split the box with the longest side, weighted by its pixels */
+            let candidate = boxes.iter().enumerate()
+                .filter(|(_, colors)| colors.len() > 1)
+                .max_by_key(|(_, colors)| {
+                    let pixels: u64 = colors.iter().map(|(_, count)| *count as u64).sum();
+                    longest_axis(colors).1 as u64 * pixels
+                })
+                .map(|(i, _)| i);
+
+            let index = match candidate {
+                None => { break; },
+                Some(index) => index,
+            };
+
+            let mut colors = boxes.swap_remove(index);
+            let (axis, _) = longest_axis(&colors);
+            colors.sort_by_key(|(color, _)| component(color, axis));
+
+            /* Cut at the median pixel, keeping at least one color per box */
+            let total: u64 = colors.iter().map(|(_, count)| *count as u64).sum();
+            let mut cumulated = 0;
+            let mut cut = colors.len() - 1;
+            for (i, (_, count)) in colors.iter().enumerate() {
+                cumulated += *count as u64;
+                if 2 * cumulated >= total
+                {
+                    cut = i + 1;
+                    break;
+                }
+            }
+            let cut = cut.max(1).min(colors.len() - 1);
+
+            let upper = colors.split_off(cut);
+            boxes.push(colors);
+            boxes.push(upper);
+        }
+
+        boxes.iter().map(|colors| {
+            let mut sum = [0u64; 3];
+            let mut pixels = 0u64;
+            for (color, count) in colors.iter() {
+                sum[0] += color.red as u64 * *count as u64;
+                sum[1] += color.green as u64 * *count as u64;
+                sum[2] += color.blue as u64 * *count as u64;
+                pixels += *count as u64;
+            }
+            let pixels = pixels.max(1);
+            PngPaletteColor {
+                red:   ((sum[0] + pixels / 2) / pixels) as u8,
+                green: ((sum[1] + pixels / 2) / pixels) as u8,
+                blue:  ((sum[2] + pixels / 2) / pixels) as u8,
+            }
+        }).collect()
+    }
+}
+
+impl Png {
+    /* Set up the QUANTIZE read transformation.  As in libpng the palette of the
+     * application is modified in place to hold the reduced palette.
+     */
+    pub fn set_quantize(&mut self, palette: &mut [PngPaletteColor], maximum_colors: usize,
+                        histogram: Option<&[u16]>, full_quantize: bool) -> Result<(), &'static str>
+    {
+        let mut quantizer = Quantizer::new(palette, maximum_colors, histogram, full_quantize)?;
+        self.transformations.insert(PngTransformations::QUANTIZE);
+
+        let num_palette = quantizer.palette().len();
+        palette[..num_palette].copy_from_slice(quantizer.palette());
+
+        if self.palette == 0
+        {
+            self.palette = palette.as_ptr() as CPtr;
+        }
+        self.num_palette = num_palette as u16;
+
+        /* The dithering may be chosen before or after the palette */
+        quantizer.set_dither(self.quantize_dither);
+        self.quantizer = Some(quantizer);
+
+        Ok(())
+    }
+
+    /* Diffuse the quantizing error of RGB rows (Floyd-Steinberg) */
+    pub fn set_quantize_dither(&mut self, dither: bool)
+    {
+        self.quantize_dither = dither;
+
+        if let Some(quantizer) = self.quantizer.as_mut()
+        {
+            quantizer.set_dither(dither);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(red: u8, green: u8, blue: u8) -> PngPaletteColor
+    {
+        PngPaletteColor { red, green, blue }
+    }
+
+    /* Four clusters of two colors each, with their expected mean */
+    const CLUSTERS: [([u8; 3], [u8; 3], [u8; 3]); 4] = [
+        ([250, 0, 0], [254, 0, 0], [252, 0, 0]),
+        ([0, 250, 0], [0, 254, 0], [0, 252, 0]),
+        ([0, 0, 250], [0, 0, 254], [0, 0, 252]),
+        ([250, 250, 250], [254, 254, 254], [252, 252, 252]),
+    ];
+
+    #[test]
+    fn median_cut_finds_the_clusters()
+    {
+        let pixels: Vec<u8> = CLUSTERS.iter()
+            .flat_map(|(a, b, _)| a.iter().chain(b.iter()).copied().collect::<Vec<u8>>())
+            .collect();
+
+        let mut quantizer = Quantizer::from_pixels(&pixels, PngColor::TYPE_RGB, 4).unwrap();
+        let mut palette = quantizer.palette().to_vec();
+        palette.sort_by_key(|c| (c.red, c.green, c.blue));
+
+        let mut expected: Vec<PngPaletteColor> = CLUSTERS.iter().map(|(_, _, m)| color(m[0], m[1], m[2])).collect();
+        expected.sort_by_key(|c| (c.red, c.green, c.blue));
+        assert_eq!(palette, expected);
+
+        /* Both colors of a cluster map to its mean */
+        let indexes = quantizer.quantize_image(&pixels, 8, 1, PngColor::TYPE_RGB);
+        for (i, (_, _, mean)) in CLUSTERS.iter().enumerate() {
+            for &index in &indexes[2 * i..2 * i + 2] {
+                assert_eq!(quantizer.palette()[index as usize], color(mean[0], mean[1], mean[2]));
+            }
+        }
+    }
+
+    #[test]
+    fn median_cut_keeps_few_colors_as_they_are()
+    {
+        let pixels = [10, 20, 30, 10, 20, 30, 40, 50, 60];
+        let quantizer = Quantizer::from_pixels(&pixels, PngColor::TYPE_RGB, 16).unwrap();
+
+        assert_eq!(quantizer.palette(), &[color(10, 20, 30), color(40, 50, 60)]);
+    }
+
+    #[test]
+    fn floyd_steinberg_dithers_mid_gray()
+    {
+        let palette = [color(0, 0, 0), color(255, 255, 255)];
+        let pixels = vec![128u8; 4 * 4 * 3];
+
+        /* Without dithering every pixel takes the nearest color, white */
+        let mut quantizer = Quantizer::new(&palette, 2, None, true).unwrap();
+        let indexes = quantizer.quantize_image(&pixels, 4, 4, PngColor::TYPE_RGB);
+        assert!(indexes.iter().all(|&index| index == 1));
+
+        quantizer.set_dither(true);
+        let indexes = quantizer.quantize_image(&pixels, 4, 4, PngColor::TYPE_RGB);
+
+        /* 128 -> white (error -127), 128 - 889/16 = 72 -> black (error 72),
+         * 128 + 504/16 = 159 -> white (error -96), 128 - 672/16 = 86 -> black
+         */
+        assert_eq!(&indexes[..4], &[1, 0, 1, 0]);
+        let white = indexes.iter().filter(|&&index| index == 1).count();
+        assert_eq!(white, 8);
+    }
+
+    #[test]
+    fn dither_choice_survives_set_quantize()
+    {
+        let mut palette = [color(0, 0, 0), color(255, 255, 255)];
+        let mut png_ptr = Png::new();
+
+        png_ptr.set_quantize_dither(true);
+        png_ptr.set_quantize(&mut palette, 2, None, true).unwrap();
+        assert!(png_ptr.quantizer.as_ref().unwrap().dither);
+
+        png_ptr.set_quantize_dither(false);
+        assert!(!png_ptr.quantizer.as_ref().unwrap().dither);
+
+        png_ptr.set_quantize(&mut palette, 2, None, true).unwrap();
+        assert!(!png_ptr.quantizer.as_ref().unwrap().dither);
+    }
+
+    #[test]
+    fn maximum_colors_are_1_to_256()
+    {
+        let mut palette = [color(0, 0, 0), color(255, 255, 255)];
+        let pixels = [10, 20, 30, 40, 50, 60];
+
+        for &maximum_colors in [0, 257].iter() {
+            assert!(Quantizer::new(&palette, maximum_colors, None, true).is_err());
+            assert!(Quantizer::from_pixels(&pixels, PngColor::TYPE_RGB, maximum_colors).is_err());
+
+            let mut png_ptr = Png::new();
+            assert!(png_ptr.set_quantize(&mut palette, maximum_colors, None, true).is_err());
+            assert!(!png_ptr.transformations.contains(PngTransformations::QUANTIZE));
+        }
+
+        /* A single color is left, dithered or not */
+        for &dither in [false, true].iter() {
+            let mut quantizer = Quantizer::from_pixels(&pixels, PngColor::TYPE_RGB, 1).unwrap();
+            quantizer.set_dither(dither);
+
+            assert_eq!(quantizer.palette(), &[color(25, 35, 45)]);
+            assert_eq!(quantizer.quantize_image(&pixels, 2, 1, PngColor::TYPE_RGB), [0, 0]);
+        }
+
+        let mut quantizer = Quantizer::new(&palette, 1, None, false).unwrap();
+        assert_eq!(quantizer.palette().len(), 1);
+        assert_eq!(quantizer.quantize_index(), Some(&[0u8, 0][..]));
+        assert_eq!(quantizer.quantize_image(&[0, 1], 2, 1, PngColor::TYPE_PALETTE), [0, 0]);
+    }
+
+    #[test]
+    fn no_pixel_no_quantizer()
+    {
+        assert!(Quantizer::from_pixels(&[], PngColor::TYPE_RGB, 16).is_err());
+        assert!(Quantizer::from_pixels(&[], PngColor::TYPE_RGB_ALPHA, 16).is_err());
+        assert!(Quantizer::from_pixels(&[1, 2, 3], PngColor::TYPE_GRAY, 16).is_err());
+        assert!(Quantizer::from_pixels(&[1, 2, 3], PngColor::TYPE_PALETTE, 16).is_err());
+        assert!(Quantizer::new(&[], 16, None, true).is_err());
+    }
+}
@@ -0,0 +1,886 @@
+use crate::Png;
+use crate::PngColor;
+use crate::PngFlags;
+use crate::png_info::PngColor8;
+use crate::trans::PngRowInfo;
+use crate::CPtr;
+use std::cmp;
+use std::slice;
+
+/*******************************************************************************
+ *
+ *                               Png C API
+ *
+ ******************************************************************************/
+
+#[no_mangle]
+pub unsafe extern fn png_do_unpack(this: *mut PngRowInfo, row: CPtr) {
+    match this.as_mut() {
+        None => { return; },
+        Some(row_info) => {
+            /* The unpacked row is one byte per sample, it is always at least
+             * as big as the packed one.
+             */
+            let length = cmp::max(row_info.rowbytes,
+                                  row_info.width as usize * row_info.channels as usize);
+            let slice = slice::from_raw_parts_mut(row as *mut u8, length);
+            row_info.do_unpack(slice);
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_do_unshift(this: *const PngRowInfo, row: CPtr, sig_bits: *const PngColor8) {
+    match this.as_ref() {
+        None => { return; },
+        Some(row_info) => {
+            match sig_bits.as_ref() {
+                None => { return; },
+                Some(sig_bits) => {
+                    let slice = slice::from_raw_parts_mut(row as *mut u8, row_info.rowbytes);
+                    row_info.do_unshift(slice, sig_bits);
+                },
+            }
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_do_scale_16_to_8(this: *mut PngRowInfo, row: CPtr) {
+    match this.as_mut() {
+        None => { return; },
+        Some(row_info) => {
+            let slice = slice::from_raw_parts_mut(row as *mut u8, row_info.rowbytes);
+            row_info.do_scale_16_to_8(slice);
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_do_chop(this: *mut PngRowInfo, row: CPtr) {
+    match this.as_mut() {
+        None => { return; },
+        Some(row_info) => {
+            let slice = slice::from_raw_parts_mut(row as *mut u8, row_info.rowbytes);
+            row_info.do_chop(slice);
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_do_expand_16(this: *mut PngRowInfo, row: CPtr) {
+    match this.as_mut() {
+        None => { return; },
+        Some(row_info) => {
+            /* The row buffer is allocated for the maximum pixel depth, so
+             * it can hold the row once each sample doubled.
+             */
+            let slice = slice::from_raw_parts_mut(row as *mut u8, 2 * row_info.rowbytes);
+            row_info.do_expand_16(slice);
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_do_read_swap_alpha(this: *const PngRowInfo, row: CPtr) {
+    match this.as_ref() {
+        None => { return; },
+        Some(row_info) => {
+            let slice = slice::from_raw_parts_mut(row as *mut u8, row_info.rowbytes);
+            row_info.do_read_swap_alpha(slice);
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_do_read_invert_alpha(this: *const PngRowInfo, row: CPtr) {
+    match this.as_ref() {
+        None => { return; },
+        Some(row_info) => {
+            let slice = slice::from_raw_parts_mut(row as *mut u8, row_info.rowbytes);
+            row_info.do_read_invert_alpha(slice);
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_do_read_filler(this: *mut PngRowInfo, row: CPtr, filler: u32, flags: u32) {
+    match this.as_mut() {
+        None => { return; },
+        Some(row_info) => {
+            /* The row gains one channel */
+            let length = cmp::max(row_info.rowbytes,
+                                  row_info.width as usize * (row_info.channels as usize + 1) *
+                                  (row_info.bit_depth as usize >> 3));
+            let slice = slice::from_raw_parts_mut(row as *mut u8, length);
+            let flags = PngFlags::from_bits_truncate(flags);
+            row_info.do_read_filler(slice, filler as u16, flags.contains(PngFlags::FILLER_AFTER));
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_do_gray_to_rgb(this: *mut PngRowInfo, row: CPtr) {
+    match this.as_mut() {
+        None => { return; },
+        Some(row_info) => {
+            /* The row gains two channels */
+            let length = cmp::max(row_info.rowbytes,
+                                  row_info.width as usize * (row_info.channels as usize + 2) *
+                                  (row_info.bit_depth as usize >> 3));
+            let slice = slice::from_raw_parts_mut(row as *mut u8, length);
+            row_info.do_gray_to_rgb(slice);
+        },
+    }
+}
+
+
+
+/*******************************************************************************
+ *
+ *                               Png RUST
+ *
+ ******************************************************************************/
+
+impl PngRowInfo {
+    /* Unpack pixels of 1, 2, or 4 bits per pixel into 1 byte per pixel,
+     * without changing the actual values.  Thus, if you had a row with
+     * a bit depth of 1, you would end up with bytes that only contained
+     * the numbers 0 or 1.  If you would rather they contain 0 and 255, use
+     * do_shift() after this.
+     */
+    pub fn do_unpack(&mut self, row: &mut [u8])
+    {
+        if self.bit_depth >= 8
+        {
+            return;
+        }
+
+        let bit_depth = self.bit_depth as usize;
+        let nb_samples = self.width as usize * self.channels as usize;
+        let mask = (1 << bit_depth) - 1;
+        assert!(nb_samples <= row.len());
+
+        /* Work from the right so that the unpacked byte never overwrites a
+         * packed byte which has not been read yet.
+         */
+        for i in (0..nb_samples).rev() {
+            let bit = i * bit_depth;
+            let shift = 8 - bit_depth - (bit & 0x07);
+            row[i] = (row[bit >> 3] >> shift) & mask;
+        }
+
+        self.bit_depth = 8;
+        self.pixel_depth = 8 * self.channels;
+        self.rowbytes = nb_samples;
+    }
+
+    /* Reverse the effects of do_shift.  This routine merely shifts the
+     * pixels back to their significant bits values.  Thus, if you have
+     * a row of bit depth 8, but only 5 are significant, this will shift
+     * the values back to 0 through 31.
+     */
+    pub fn do_unshift(&self, row: &mut [u8], sig_bits: &PngColor8)
+    {
+        /* The palette case has already been handled in the _init routine. */
+        if self.color_type == PngColor::TYPE_PALETTE
+        {
+            return;
+        }
+
+        let bit_depth = self.bit_depth as i32;
+        let mut shift = Vec::with_capacity(4);
+
+        if self.color_type.contains(PngColor::MASK_COLOR)
+        {
+            shift.push(bit_depth - sig_bits.red as i32);
+            shift.push(bit_depth - sig_bits.green as i32);
+            shift.push(bit_depth - sig_bits.blue as i32);
+        }
+        else
+        {
+            shift.push(bit_depth - sig_bits.gray as i32);
+        }
+
+        if self.color_type.contains(PngColor::MASK_ALPHA)
+        {
+            shift.push(bit_depth - sig_bits.alpha as i32);
+        }
+
+        /* A shift of more than the bit depth is an error condition but it
+         * gets ignored here.
+         */
+        for value in shift.iter_mut() {
+            if *value <= 0 || *value >= bit_depth
+            {
+                *value = 0;
+            }
+        }
+
+        if shift.iter().all(|value| *value == 0)
+        {
+            return;
+        }
+
+        assert!(self.rowbytes <= row.len());
+        let row = &mut row[..self.rowbytes];
+
+        match self.bit_depth {
+            2 => {
+                /* Must be 2bpp gray, the only valid shift is 1 */
+                for value in row.iter_mut() {
+                    *value = (*value >> 1) & 0x55;
+                }
+            },
+            4 => {
+                /* Must be 4bpp gray */
+                let gray_shift = shift[0];
+                let mut mask = 0xf >> gray_shift;
+                mask |= mask << 4;
+
+                for value in row.iter_mut() {
+                    *value = (*value >> gray_shift) & mask;
+                }
+            },
+            8 => {
+                /* Single byte components, G, GA, RGB, RGBA */
+                for (value, channel_shift) in row.iter_mut().zip(shift.iter().cycle()) {
+                    *value >>= *channel_shift;
+                }
+            },
+            16 => {
+                /* Double byte components, G, GA, RGB, RGBA */
+                for (value, channel_shift) in row.chunks_exact_mut(2).zip(shift.iter().cycle()) {
+                    let sample = u16::from_be_bytes([value[0], value[1]]) >> *channel_shift;
+                    value.copy_from_slice(&sample.to_be_bytes());
+                }
+            },
+            _ => {
+                /* Must be 1bpp gray: should not be here! */
+            },
+        }
+    }
+
+    /* Scale rows of bit depth 16 down to 8 accurately */
+    pub fn do_scale_16_to_8(&mut self, row: &mut [u8])
+    {
+        if self.bit_depth != 16
+        {
+            return;
+        }
+
+        assert!(self.rowbytes <= row.len());
+        let nb_samples = self.rowbytes / 2;
+
+        for i in 0..nb_samples {
+            /* The input is an array of 16-bit components, these must be scaled to
+             * 8 bits each.  For a 16-bit value V the required value (from the PNG
+             * specification) is:
+             *
+             *    (V * 255) / 65535
+             *
+             * This reduces to round(V / 257), or floor((V + 128.5)/257)
+             *
+             * Represent V as the two byte value vhi.vlo.  Make a guess that the
+             * result is the top byte of V, vhi, then the correction to this value
+             * is:
+             *
+             *    error = floor(((V-vhi.vhi) + 128.5) / 257)
+             *          = floor(((vlo-vhi) + 128.5) / 257)
+             *
+             * The exact answer (correct for all 16-bit input values) is:
+             *
+             *    error = (vlo-vhi+128)*65535 >> 24;
+             */
+            let high = row[2 * i] as i32; /* must be signed! */
+            let low  = row[2 * i + 1] as i32;
+            row[i] = (high + (((low - high + 128) * 65535) >> 24)) as u8;
+        }
+
+        self.bit_depth = 8;
+        self.pixel_depth = 8 * self.channels;
+        self.rowbytes = self.width as usize * self.channels as usize;
+    }
+
+    /* Simply discard the low byte.  This was the default behavior prior
+     * to libpng-1.5.4.
+     */
+    pub fn do_chop(&mut self, row: &mut [u8])
+    {
+        if self.bit_depth != 16
+        {
+            return;
+        }
+
+        assert!(self.rowbytes <= row.len());
+        let nb_samples = self.rowbytes / 2;
+
+        for i in 0..nb_samples {
+            row[i] = row[2 * i]; /* skip low byte */
+        }
+
+        self.bit_depth = 8;
+        self.pixel_depth = 8 * self.channels;
+        self.rowbytes = self.width as usize * self.channels as usize;
+    }
+
+    /* If the bit depth is 8 and the color type is not a palette type expand the
+     * whole row to 16 bits.  Has no effect otherwise.
+     */
+    pub fn do_expand_16(&mut self, row: &mut [u8])
+    {
+        if self.bit_depth != 8 || self.color_type == PngColor::TYPE_PALETTE
+        {
+            return;
+        }
+
+        /* The row have a sequence of bytes containing [0..255] and we need
+         * to turn it into another row containing [0..65535], to do this we
+         * calculate:
+         *
+         *  (input / 255) * 65535
+         *
+         *  Which happens to be exactly input * 257 and this can be achieved
+         *  simply by byte replication in place (copying backwards).
+         */
+        assert!(2 * self.rowbytes <= row.len());
+
+        for i in (0..self.rowbytes).rev() {
+            row[2 * i] = row[i];
+            row[2 * i + 1] = row[i];
+        }
+
+        self.rowbytes *= 2;
+        self.bit_depth = 16;
+        self.pixel_depth = self.channels * 16;
+    }
+
+    /* Convert RGBA to ARGB and GA to AG (RRGGBBAA to AARRGGBB and GGAA to
+     * AAGG for 16-bit rows).
+     */
+    pub fn do_read_swap_alpha(&self, row: &mut [u8])
+    {
+        if self.color_type != PngColor::TYPE_RGB_ALPHA &&
+            self.color_type != PngColor::TYPE_GRAY_ALPHA
+        {
+            return;
+        }
+
+        let bytes = match self.bit_depth {
+            8 => 1,
+            16 => 2,
+            _ => { return; },
+        };
+
+        let pixel_bytes = self.channels as usize * bytes;
+        let row_length = self.width as usize * pixel_bytes;
+        assert!(row_length <= row.len());
+
+        for pixel in row[..row_length].chunks_exact_mut(pixel_bytes) {
+            pixel.rotate_right(bytes);
+        }
+    }
+
+    /* Invert the alpha channel of RGBA and GA rows (8 and 16-bit) */
+    pub fn do_read_invert_alpha(&self, row: &mut [u8])
+    {
+        if self.color_type != PngColor::TYPE_RGB_ALPHA &&
+            self.color_type != PngColor::TYPE_GRAY_ALPHA
+        {
+            return;
+        }
+
+        let bytes = match self.bit_depth {
+            8 => 1,
+            16 => 2,
+            _ => { return; },
+        };
+
+        let pixel_bytes = self.channels as usize * bytes;
+        let row_length = self.width as usize * pixel_bytes;
+        assert!(row_length <= row.len());
+
+        for pixel in row[..row_length].chunks_exact_mut(pixel_bytes) {
+            for value in pixel[pixel_bytes - bytes..].iter_mut() {
+                *value = 255 - *value;
+            }
+        }
+    }
+
+    /* Add filler channel if we have RGB color: G to GX or XG, RGB to RGBX
+     * or XRGB.  16-bit samples get the whole 16-bit filler, 8-bit ones its
+     * low byte.  The row is expanded in place, working from the right, so
+     * the slice must be large enough for the new channel.
+     */
+    pub fn do_read_filler(&mut self, row: &mut [u8], filler: u16, filler_after: bool)
+    {
+        if self.color_type != PngColor::TYPE_GRAY &&
+            self.color_type != PngColor::TYPE_RGB
+        {
+            return;
+        }
+
+        let filler = filler.to_be_bytes();
+        let filler = match self.bit_depth {
+            8 => &filler[1..],
+            16 => &filler[..],
+            _ => { return; },
+        };
+
+        let bytes = filler.len();
+        let width = self.width as usize;
+        let in_bytes = self.channels as usize * bytes;
+        let out_bytes = in_bytes + bytes;
+        assert!(width * out_bytes <= row.len());
+
+        for i in (0..width).rev() {
+            let sp = i * in_bytes;
+            let dp = i * out_bytes;
+
+            if filler_after
+            {
+                row.copy_within(sp..sp + in_bytes, dp);
+                row[dp + in_bytes..dp + out_bytes].copy_from_slice(filler);
+            }
+            else
+            {
+                row.copy_within(sp..sp + in_bytes, dp + bytes);
+                row[dp..dp + bytes].copy_from_slice(filler);
+            }
+        }
+
+        self.channels += 1;
+        self.pixel_depth = self.channels * self.bit_depth;
+        self.rowbytes = width * out_bytes;
+    }
+
+    /* The png_set_add_alpha variant of do_read_filler: the new channel is
+     * an alpha channel and the color type of the row says so.
+     */
+    pub fn do_read_add_alpha(&mut self, row: &mut [u8], alpha: u16, alpha_after: bool)
+    {
+        let channels = self.channels;
+
+        self.do_read_filler(row, alpha, alpha_after);
+
+        if self.channels != channels
+        {
+            self.color_type.insert(PngColor::MASK_ALPHA);
+        }
+    }
+
+    /* Undoes intrapixel differencing (filter method 64 of MNG): green is
+     * added back to red and blue.
+     */
+    pub fn do_read_intrapixel(&self, row: &mut [u8])
+    {
+        let pixel_samples = match self.color_type {
+            PngColor::TYPE_RGB => 3,
+            PngColor::TYPE_RGB_ALPHA => 4,
+            _ => { return; },
+        };
+
+        match self.bit_depth {
+            8 => {
+                for pixel in row[..pixel_samples * self.width as usize].chunks_mut(pixel_samples) {
+                    pixel[0] = pixel[0].wrapping_add(pixel[1]);
+                    pixel[2] = pixel[2].wrapping_add(pixel[1]);
+                }
+            },
+            16 => {
+                let pixel_bytes = 2 * pixel_samples;
+
+                for pixel in row[..pixel_bytes * self.width as usize].chunks_mut(pixel_bytes) {
+                    let s0 = u16::from_be_bytes([pixel[0], pixel[1]]);
+                    let s1 = u16::from_be_bytes([pixel[2], pixel[3]]);
+                    let s2 = u16::from_be_bytes([pixel[4], pixel[5]]);
+
+                    pixel[0..2].copy_from_slice(&s0.wrapping_add(s1).to_be_bytes());
+                    pixel[4..6].copy_from_slice(&s2.wrapping_add(s1).to_be_bytes());
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /* Expand grayscale rows to RGB, with or without alpha: G to RGB and GA
+     * to RGBA.  Like do_read_filler this works in place from the right.
+     */
+    pub fn do_gray_to_rgb(&mut self, row: &mut [u8])
+    {
+        if self.bit_depth < 8 || self.color_type.contains(PngColor::MASK_COLOR)
+        {
+            return;
+        }
+
+        let bytes = self.bit_depth as usize >> 3;
+        let width = self.width as usize;
+        let in_bytes = self.channels as usize * bytes;
+        let out_bytes = in_bytes + 2 * bytes;
+        assert!(width * out_bytes <= row.len());
+
+        for i in (0..width).rev() {
+            let sp = i * in_bytes;
+            let dp = i * out_bytes;
+
+            /* Move the alpha channel first, it goes the farthest */
+            if self.color_type.contains(PngColor::MASK_ALPHA)
+            {
+                row.copy_within(sp + bytes..sp + 2 * bytes, dp + 3 * bytes);
+            }
+
+            for channel in (0..3).rev() {
+                row.copy_within(sp..sp + bytes, dp + channel * bytes);
+            }
+        }
+
+        self.channels += 2;
+        self.color_type.insert(PngColor::MASK_COLOR);
+        self.pixel_depth = self.channels * self.bit_depth;
+        self.rowbytes = Png::compute_rowbytes(self.pixel_depth, width);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* A small deterministic generator for the test rows */
+    struct Lcg(u32);
+
+    impl Lcg {
+        fn next(&mut self) -> u8
+        {
+            self.0 = self.0.wrapping_mul(1103515245).wrapping_add(12345);
+            (self.0 >> 16) as u8
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8>
+        {
+            (0..len).map(|_| self.next()).collect()
+        }
+    }
+
+    const COLOR_TYPES: [PngColor; 4] = [PngColor::TYPE_GRAY, PngColor::TYPE_GRAY_ALPHA,
+                                        PngColor::TYPE_RGB, PngColor::TYPE_RGB_ALPHA];
+
+    #[test]
+    fn expand_16_then_chop_is_identity()
+    {
+        let mut lcg = Lcg(1);
+
+        for &color_type in COLOR_TYPES.iter() {
+            for width in 1..20 {
+                let mut row_info = PngRowInfo::new(width, color_type, 8);
+                let input = lcg.bytes(row_info.rowbytes);
+                let mut row = input.clone();
+                row.resize(2 * input.len(), 0);
+
+                row_info.do_expand_16(&mut row);
+                assert_eq!((row_info.bit_depth, row_info.rowbytes), (16, 2 * input.len()));
+
+                row_info.do_chop(&mut row);
+                assert_eq!((row_info.bit_depth, row_info.rowbytes), (8, input.len()));
+                assert_eq!(&row[..input.len()], &input[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn expand_16_replicates_bytes()
+    {
+        let mut row_info = PngRowInfo::new(256, PngColor::TYPE_GRAY, 8);
+        let mut row: Vec<u8> = (0..=255).collect();
+        row.resize(512, 0);
+
+        row_info.do_expand_16(&mut row);
+        for (v, sample) in row.chunks_exact(2).enumerate() {
+            assert_eq!(u16::from_be_bytes([sample[0], sample[1]]), v as u16 * 257);
+        }
+    }
+
+    #[test]
+    fn scale_16_to_8_inverts_expansion()
+    {
+        let mut row_info = PngRowInfo::new(256, PngColor::TYPE_GRAY, 16);
+        let mut row: Vec<u8> = (0..=255u16).flat_map(|v| (v * 257).to_be_bytes().to_vec()).collect();
+
+        row_info.do_scale_16_to_8(&mut row);
+        assert_eq!((row_info.bit_depth, row_info.rowbytes), (8, 256));
+        for (v, &sample) in row[..256].iter().enumerate() {
+            assert_eq!(sample, v as u8);
+        }
+    }
+
+    #[test]
+    fn scale_16_to_8_rounds_and_chop_truncates()
+    {
+        let mut scaled: Vec<u8> = (0..=65535u16).flat_map(|v| v.to_be_bytes().to_vec()).collect();
+        let mut chopped = scaled.clone();
+
+        PngRowInfo::new(65536, PngColor::TYPE_GRAY, 16).do_scale_16_to_8(&mut scaled);
+        PngRowInfo::new(65536, PngColor::TYPE_GRAY, 16).do_chop(&mut chopped);
+
+        for v in 0..=65535u32 {
+            assert_eq!(scaled[v as usize] as u32, (v * 255 + 32767) / 65535, "scale of {}", v);
+            assert_eq!(chopped[v as usize] as u32, v >> 8, "chop of {}", v);
+        }
+    }
+
+    #[test]
+    fn unpack_then_pack_is_identity()
+    {
+        let mut lcg = Lcg(2);
+
+        for &bit_depth in [1u8, 2, 4].iter() {
+            for width in 1..40 {
+                let mut row_info = PngRowInfo::new(width, PngColor::TYPE_GRAY, bit_depth);
+                let mut input = lcg.bytes(row_info.rowbytes);
+
+                /* The padding bits of the last byte are zero in a packed row */
+                let used = (width as usize * bit_depth as usize) & 7;
+                if used != 0
+                {
+                    *input.last_mut().unwrap() &= 0xffu8 << (8 - used);
+                }
+
+                let mut row = input.clone();
+                row.resize(width as usize, 0);
+
+                row_info.do_unpack(&mut row);
+                assert_eq!((row_info.bit_depth, row_info.rowbytes), (8, width as usize));
+                assert!(row.iter().all(|&v| v < 1 << bit_depth));
+
+                row_info.do_pack(&mut row, bit_depth);
+                assert_eq!((row_info.bit_depth, row_info.rowbytes), (bit_depth, input.len()));
+                assert_eq!(&row[..input.len()], &input[..], "{} bits, width {}", bit_depth, width);
+            }
+        }
+    }
+
+    #[test]
+    fn unshift_restores_shifted_samples()
+    {
+        let mut lcg = Lcg(3);
+        let cases = [
+            (PngColor::TYPE_GRAY, 2, PngColor8 { gray: 1, ..Default::default() }),
+            (PngColor::TYPE_GRAY, 4, PngColor8 { gray: 3, ..Default::default() }),
+            (PngColor::TYPE_GRAY, 8, PngColor8 { gray: 5, ..Default::default() }),
+            (PngColor::TYPE_GRAY_ALPHA, 8, PngColor8 { gray: 7, alpha: 4, ..Default::default() }),
+            (PngColor::TYPE_RGB, 8, PngColor8 { red: 5, green: 6, blue: 5, ..Default::default() }),
+            (PngColor::TYPE_RGB_ALPHA, 8, PngColor8 { red: 3, green: 3, blue: 2, gray: 0, alpha: 8 }),
+            (PngColor::TYPE_GRAY, 16, PngColor8 { gray: 12, ..Default::default() }),
+            (PngColor::TYPE_RGB_ALPHA, 16, PngColor8 { red: 10, green: 11, blue: 9, gray: 0, alpha: 1 }),
+        ];
+
+        for &(color_type, bit_depth, sig_bits) in cases.iter() {
+            let bits: Vec<u8> = match color_type {
+                PngColor::TYPE_GRAY => vec![sig_bits.gray],
+                PngColor::TYPE_GRAY_ALPHA => vec![sig_bits.gray, sig_bits.alpha],
+                PngColor::TYPE_RGB => vec![sig_bits.red, sig_bits.green, sig_bits.blue],
+                _ => vec![sig_bits.red, sig_bits.green, sig_bits.blue, sig_bits.alpha],
+            };
+
+            for width in 1..20 {
+                let row_info = PngRowInfo::new(width, color_type, bit_depth);
+                let samples = width as usize * row_info.channels as usize;
+
+                /* Samples of the significant bits only, packed as the row is */
+                let values: Vec<u16> = (0..samples).map(|i| {
+                    let value = u16::from_be_bytes([lcg.next(), lcg.next()]);
+                    value & ((1u32 << bits[i % bits.len()]) - 1) as u16
+                }).collect();
+
+                let mut input = vec![0u8; row_info.rowbytes];
+                for (i, &value) in values.iter().enumerate() {
+                    match bit_depth {
+                        16 => input[2 * i..2 * i + 2].copy_from_slice(&value.to_be_bytes()),
+                        8 => input[i] = value as u8,
+                        _ => {
+                            let bit = i * bit_depth as usize;
+                            input[bit >> 3] |= (value as u8) << (8 - bit_depth as usize - (bit & 7));
+                        },
+                    }
+                }
+
+                let mut row = input.clone();
+                row_info.do_shift(&mut row, &sig_bits);
+                if bits.iter().all(|&b| b < bit_depth) && values.iter().any(|&v| v != 0)
+                {
+                    assert_ne!(row, input);
+                }
+
+                row_info.do_unshift(&mut row, &sig_bits);
+                assert_eq!(row, input, "{:?} {} bits, width {}", color_type, bit_depth, width);
+            }
+        }
+    }
+    /* A row operation, the row given and the row expected, with the color
+     * type and the channels of the output.
+     */
+    struct Case {
+        color_type: PngColor,
+        bit_depth: u8,
+        width: u32,
+        input: &'static [u8],
+        output: &'static [u8],
+        out_color_type: PngColor,
+        out_channels: u8,
+    }
+
+    /* Run 'operation' on each case in a buffer sized for the output, as the
+     * read row buffer is, so the expanding operations work in place.
+     */
+    fn check_cases<F>(cases: &[Case], operation: F)
+        where F: Fn(&mut PngRowInfo, &mut [u8])
+    {
+        for (i, case) in cases.iter().enumerate() {
+            let mut row_info = PngRowInfo::new(case.width, case.color_type, case.bit_depth);
+            let mut row = case.input.to_vec();
+            row.resize(case.output.len().max(case.input.len()), 0xee);
+
+            operation(&mut row_info, &mut row);
+
+            assert_eq!(&row[..case.output.len()], case.output, "case {}", i);
+            assert_eq!(row_info.color_type, case.out_color_type, "case {}", i);
+            assert_eq!(row_info.channels, case.out_channels, "case {}", i);
+            assert_eq!(row_info.pixel_depth, case.out_channels * case.bit_depth, "case {}", i);
+            assert_eq!(row_info.rowbytes, case.output.len(), "case {}", i);
+        }
+    }
+
+    #[test]
+    fn filler_before_and_after()
+    {
+        const GRAY: PngColor = PngColor::TYPE_GRAY;
+        const RGB: PngColor = PngColor::TYPE_RGB;
+
+        let after = [
+            Case { color_type: GRAY, bit_depth: 8, width: 3, input: &[1, 2, 3],
+                   output: &[1, 0xcd, 2, 0xcd, 3, 0xcd], out_color_type: GRAY, out_channels: 2 },
+            Case { color_type: RGB, bit_depth: 8, width: 2, input: &[1, 2, 3, 4, 5, 6],
+                   output: &[1, 2, 3, 0xcd, 4, 5, 6, 0xcd], out_color_type: RGB, out_channels: 4 },
+            Case { color_type: GRAY, bit_depth: 16, width: 2, input: &[1, 2, 3, 4],
+                   output: &[1, 2, 0xab, 0xcd, 3, 4, 0xab, 0xcd], out_color_type: GRAY, out_channels: 2 },
+            Case { color_type: RGB, bit_depth: 16, width: 1, input: &[1, 2, 3, 4, 5, 6],
+                   output: &[1, 2, 3, 4, 5, 6, 0xab, 0xcd], out_color_type: RGB, out_channels: 4 },
+        ];
+        check_cases(&after, |row_info, row| row_info.do_read_filler(row, 0xabcd, true));
+
+        let before = [
+            Case { color_type: GRAY, bit_depth: 8, width: 3, input: &[1, 2, 3],
+                   output: &[0xcd, 1, 0xcd, 2, 0xcd, 3], out_color_type: GRAY, out_channels: 2 },
+            Case { color_type: RGB, bit_depth: 8, width: 2, input: &[1, 2, 3, 4, 5, 6],
+                   output: &[0xcd, 1, 2, 3, 0xcd, 4, 5, 6], out_color_type: RGB, out_channels: 4 },
+            Case { color_type: GRAY, bit_depth: 16, width: 2, input: &[1, 2, 3, 4],
+                   output: &[0xab, 0xcd, 1, 2, 0xab, 0xcd, 3, 4], out_color_type: GRAY, out_channels: 2 },
+            Case { color_type: RGB, bit_depth: 16, width: 1, input: &[1, 2, 3, 4, 5, 6],
+                   output: &[0xab, 0xcd, 1, 2, 3, 4, 5, 6], out_color_type: RGB, out_channels: 4 },
+        ];
+        check_cases(&before, |row_info, row| row_info.do_read_filler(row, 0xabcd, false));
+
+        /* No filler for rows which already have an alpha channel */
+        let unchanged = [
+            Case { color_type: PngColor::TYPE_GRAY_ALPHA, bit_depth: 8, width: 1, input: &[1, 2],
+                   output: &[1, 2], out_color_type: PngColor::TYPE_GRAY_ALPHA, out_channels: 2 },
+        ];
+        check_cases(&unchanged, |row_info, row| row_info.do_read_filler(row, 0xabcd, true));
+
+        /* The C entry point takes the side from PngFlags::FILLER_AFTER */
+        for &(flags, expected) in [(PngFlags::FILLER_AFTER, [1, 2, 3, 0xcd]), (PngFlags::empty(), [0xcd, 1, 2, 3])].iter() {
+            let mut row_info = PngRowInfo::new(1, RGB, 8);
+            let mut row: [u8; 4] = [1, 2, 3, 0xee];
+
+            unsafe {
+                png_do_read_filler(&mut row_info, row.as_mut_ptr() as CPtr, 0xabcd, flags.bits());
+            }
+            assert_eq!(row, expected);
+        }
+    }
+
+    #[test]
+    fn add_alpha_before_and_after()
+    {
+        const GA: PngColor = PngColor::TYPE_GRAY_ALPHA;
+        const RGBA: PngColor = PngColor::TYPE_RGB_ALPHA;
+
+        let after = [
+            Case { color_type: PngColor::TYPE_GRAY, bit_depth: 8, width: 2, input: &[1, 2],
+                   output: &[1, 0xff, 2, 0xff], out_color_type: GA, out_channels: 2 },
+            Case { color_type: PngColor::TYPE_RGB, bit_depth: 16, width: 1, input: &[1, 2, 3, 4, 5, 6],
+                   output: &[1, 2, 3, 4, 5, 6, 0xff, 0xff], out_color_type: RGBA, out_channels: 4 },
+        ];
+        check_cases(&after, |row_info, row| row_info.do_read_add_alpha(row, 0xffff, true));
+
+        let before = [
+            Case { color_type: PngColor::TYPE_GRAY, bit_depth: 16, width: 2, input: &[1, 2, 3, 4],
+                   output: &[0xff, 0xff, 1, 2, 0xff, 0xff, 3, 4], out_color_type: GA, out_channels: 2 },
+            Case { color_type: PngColor::TYPE_RGB, bit_depth: 8, width: 2, input: &[1, 2, 3, 4, 5, 6],
+                   output: &[0xff, 1, 2, 3, 0xff, 4, 5, 6], out_color_type: RGBA, out_channels: 4 },
+        ];
+        check_cases(&before, |row_info, row| row_info.do_read_add_alpha(row, 0xffff, false));
+    }
+
+    #[test]
+    fn gray_to_rgb_expands_in_place()
+    {
+        const RGB: PngColor = PngColor::TYPE_RGB;
+        const RGBA: PngColor = PngColor::TYPE_RGB_ALPHA;
+
+        let cases = [
+            Case { color_type: PngColor::TYPE_GRAY, bit_depth: 8, width: 3, input: &[1, 2, 3],
+                   output: &[1, 1, 1, 2, 2, 2, 3, 3, 3], out_color_type: RGB, out_channels: 3 },
+            Case { color_type: PngColor::TYPE_GRAY_ALPHA, bit_depth: 8, width: 2, input: &[1, 9, 2, 8],
+                   output: &[1, 1, 1, 9, 2, 2, 2, 8], out_color_type: RGBA, out_channels: 4 },
+            Case { color_type: PngColor::TYPE_GRAY, bit_depth: 16, width: 2, input: &[1, 2, 3, 4],
+                   output: &[1, 2, 1, 2, 1, 2, 3, 4, 3, 4, 3, 4], out_color_type: RGB, out_channels: 3 },
+            Case { color_type: PngColor::TYPE_GRAY_ALPHA, bit_depth: 16, width: 2, input: &[1, 2, 9, 8, 3, 4, 7, 6],
+                   output: &[1, 2, 1, 2, 1, 2, 9, 8, 3, 4, 3, 4, 3, 4, 7, 6], out_color_type: RGBA, out_channels: 4 },
+            Case { color_type: RGB, bit_depth: 8, width: 1, input: &[1, 2, 3],
+                   output: &[1, 2, 3], out_color_type: RGB, out_channels: 3 },
+        ];
+        check_cases(&cases, |row_info, row| row_info.do_gray_to_rgb(row));
+    }
+
+    #[test]
+    fn swap_alpha_moves_alpha_first()
+    {
+        const GA: PngColor = PngColor::TYPE_GRAY_ALPHA;
+        const RGBA: PngColor = PngColor::TYPE_RGB_ALPHA;
+
+        let cases = [
+            Case { color_type: GA, bit_depth: 8, width: 2, input: &[1, 9, 2, 8],
+                   output: &[9, 1, 8, 2], out_color_type: GA, out_channels: 2 },
+            Case { color_type: RGBA, bit_depth: 8, width: 2, input: &[1, 2, 3, 9, 4, 5, 6, 8],
+                   output: &[9, 1, 2, 3, 8, 4, 5, 6], out_color_type: RGBA, out_channels: 4 },
+            Case { color_type: GA, bit_depth: 16, width: 1, input: &[1, 2, 9, 8],
+                   output: &[9, 8, 1, 2], out_color_type: GA, out_channels: 2 },
+            Case { color_type: RGBA, bit_depth: 16, width: 1, input: &[1, 2, 3, 4, 5, 6, 9, 8],
+                   output: &[9, 8, 1, 2, 3, 4, 5, 6], out_color_type: RGBA, out_channels: 4 },
+            Case { color_type: PngColor::TYPE_RGB, bit_depth: 8, width: 1, input: &[1, 2, 3],
+                   output: &[1, 2, 3], out_color_type: PngColor::TYPE_RGB, out_channels: 3 },
+        ];
+        check_cases(&cases, |row_info, row| row_info.do_read_swap_alpha(row));
+    }
+
+    #[test]
+    fn invert_alpha_inverts_only_alpha()
+    {
+        const GA: PngColor = PngColor::TYPE_GRAY_ALPHA;
+        const RGBA: PngColor = PngColor::TYPE_RGB_ALPHA;
+
+        let cases = [
+            Case { color_type: GA, bit_depth: 8, width: 2, input: &[1, 0, 2, 0x0f],
+                   output: &[1, 0xff, 2, 0xf0], out_color_type: GA, out_channels: 2 },
+            Case { color_type: RGBA, bit_depth: 8, width: 1, input: &[1, 2, 3, 0xff],
+                   output: &[1, 2, 3, 0], out_color_type: RGBA, out_channels: 4 },
+            Case { color_type: GA, bit_depth: 16, width: 1, input: &[1, 2, 0x12, 0x34],
+                   output: &[1, 2, 0xed, 0xcb], out_color_type: GA, out_channels: 2 },
+            Case { color_type: RGBA, bit_depth: 16, width: 1, input: &[1, 2, 3, 4, 5, 6, 0, 1],
+                   output: &[1, 2, 3, 4, 5, 6, 0xff, 0xfe], out_color_type: RGBA, out_channels: 4 },
+            Case { color_type: PngColor::TYPE_GRAY, bit_depth: 8, width: 2, input: &[1, 2],
+                   output: &[1, 2], out_color_type: PngColor::TYPE_GRAY, out_channels: 1 },
+        ];
+        check_cases(&cases, |row_info, row| row_info.do_read_invert_alpha(row));
+    }
+}
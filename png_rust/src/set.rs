@@ -3,6 +3,7 @@ use crate::PngColor;
 use crate::PngInterlace;
 use crate::PngCompressionType;
 use crate::PngFilterType;
+use crate::PngHandleChunk;
 use crate::png_info::PngInfo;
 
 #[no_mangle]
@@ -78,4 +79,35 @@ impl Png {
 
         Ok(())
     }
+
+    /* Set how the chunks in 'chunks' are handled, or, when 'chunks' is empty,
+     * the default handling of the chunks not listed, as
+     * png_set_keep_unknown_chunks.  The last setting of a chunk is the one
+     * used; AsDefault removes it from the list.
+     */
+    pub fn set_keep_unknown_chunks(&mut self, keep: PngHandleChunk, chunks: &[[u8; 4]])
+    {
+        if chunks.is_empty()
+        {
+            self.unknown_default = keep;
+            return;
+        }
+
+        for name in chunks {
+            self.chunk_list.retain(|&(listed, _)| listed != *name);
+
+            if keep != PngHandleChunk::AsDefault
+            {
+                self.chunk_list.push((*name, keep));
+            }
+        }
+    }
+
+    /* The handling set for the chunk 'name', as png_handle_as_unknown */
+    pub fn handle_as_unknown(&self, name: [u8; 4]) -> PngHandleChunk
+    {
+        self.chunk_list.iter()
+            .find(|&&(listed, _)| listed == name)
+            .map_or(PngHandleChunk::AsDefault, |&(_, keep)| keep)
+    }
 }
@@ -0,0 +1,232 @@
+use crate::decoder::{PngDecoder, uses_chunk};
+use crate::wutil::PNG_SIGNATURE;
+use std::io;
+use std::io::{BufRead, Read, Seek, SeekFrom};
+
+/*******************************************************************************
+ *
+ *                               Png RUST
+ *
+ ******************************************************************************/
+
+/* The input sources of the Rust reader.  PngDecoder parses a datastream held
+ * in memory in place, so a memory-mapped file is read with no copy by giving
+ * the mapping to PngDecoder::new.  PngReader reads a datastream from a
+ * BufRead, or from a Read + Seek, keeping only the chunks PngDecoder uses: the
+ * other ancillary chunks (text, Exif, private chunks...) are consumed from a
+ * BufRead and seeked over with a Read + Seek, so they are never read.
+ */
+pub struct PngReader {
+    data: Vec<u8>, /* the signature and the chunks kept, up to IEND */
+}
+
+impl PngReader {
+    /* Read the datastream of 'reader', up to IEND */
+    pub fn from_buf_read<R: BufRead>(mut reader: R) -> io::Result<PngReader>
+    {
+        PngReader::read(&mut reader, |reader, mut count| {
+            while count > 0 {
+                let available = match reader.fill_buf()? {
+                    [] => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    buf => buf.len().min(count as usize),
+                };
+
+                reader.consume(available);
+                count -= available as u64;
+            }
+
+            Ok(())
+        })
+    }
+
+    /* Read the datastream of 'reader', up to IEND, from the current
+     * position
+     */
+    pub fn from_seek<R: Read + Seek>(mut reader: R) -> io::Result<PngReader>
+    {
+        PngReader::read(&mut reader, |reader, count| {
+            reader.seek(SeekFrom::Current(count as i64)).map(|_| ())
+        })
+    }
+
+    /* A decoder of the datastream read */
+    pub fn decoder(&self) -> PngDecoder<'_>
+    {
+        PngDecoder::new(&self.data)
+    }
+
+    /* The signature and the chunks kept, a PNG datastream */
+    pub fn data(&self) -> &[u8]
+    {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8>
+    {
+        self.data
+    }
+
+    /* Copy the signature and the chunks used by the decoder, up to IEND,
+     * 'skip' moves past the data of the others.  The reading stops at the
+     * end of the input, or at a chunk which is cut: the decoder then finds
+     * the datastream truncated.
+     */
+    fn read<R: Read, F>(reader: &mut R, mut skip: F) -> io::Result<PngReader>
+        where F: FnMut(&mut R, u64) -> io::Result<()>
+    {
+        let mut data = Vec::new();
+
+        /* Not a PNG datastream: the decoder reports it */
+        reader.by_ref().take(8).read_to_end(&mut data)?;
+        if data[..] != PNG_SIGNATURE
+        {
+            return Ok(PngReader { data });
+        }
+
+        loop {
+            let mut header = [0u8; 8];
+            match read_full(reader, &mut header)? {
+                true => {},
+                false => break,
+            }
+
+            let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+            if length > i32::MAX as u32
+            {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "PNG unsigned integer out of range"));
+            }
+
+            let mut name = [0u8; 4];
+            name.copy_from_slice(&header[4..]);
+
+            /* The CRC is skipped with the data */
+            if !uses_chunk(&name)
+            {
+                match skip(reader, length as u64 + 4) {
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                    result => result?,
+                }
+                continue;
+            }
+
+            /* The data and the CRC are not allocated from the length, which
+             * may be wrong
+             */
+            let start = data.len();
+            data.extend_from_slice(&header);
+            let count = reader.by_ref().take(length as u64 + 4).read_to_end(&mut data)?;
+            if count < length as usize + 4
+            {
+                data.truncate(start);
+                break;
+            }
+
+            if &name == b"IEND"
+            {
+                break;
+            }
+        }
+
+        Ok(PngReader { data })
+    }
+}
+
+/* Fill 'buf', false if the input ends before */
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool>
+{
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::ChunkReader;
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    /* Read 'png' through both paths, which must give the same datastream */
+    fn read_both(png: &[u8]) -> PngReader
+    {
+        let buf_read = PngReader::from_buf_read(png).unwrap();
+        let seek = PngReader::from_seek(Cursor::new(png)).unwrap();
+
+        assert_eq!(buf_read.data(), seek.data());
+        seek
+    }
+
+    /* A chunk with 'length' in its header and 'data' after it */
+    fn chunk(name: &[u8; 4], length: u32, data: &[u8]) -> Vec<u8>
+    {
+        let mut chunk = length.to_be_bytes().to_vec();
+        chunk.extend_from_slice(name);
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn pngtest() -> Vec<u8>
+    {
+        fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("../pngtest.png")).unwrap()
+    }
+
+    /* The names and the data of the chunks of 'png' */
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)>
+    {
+        ChunkReader::new(png).map(|chunk| {
+            let chunk = chunk.unwrap();
+            (chunk.name, chunk.data)
+        }).collect()
+    }
+
+    #[test]
+    fn chunks_kept_are_those_of_the_decoder()
+    {
+        let png = pngtest();
+        let reader = read_both(&png);
+
+        let used: Vec<_> = chunks(&png).into_iter().filter(|(name, _)| uses_chunk(name)).collect();
+        assert_eq!(chunks(reader.data()), used);
+        assert!(used.iter().any(|(name, _)| name == b"IDAT"));
+        assert!(used.len() < chunks(&png).len());
+
+        assert_eq!(reader.decoder().read_image().unwrap(), PngDecoder::new(&png).read_image().unwrap());
+    }
+
+    #[test]
+    fn cut_chunks_end_the_datastream()
+    {
+        let png = pngtest();
+        let idat = png.windows(4).position(|name| name == b"IDAT").unwrap() - 4;
+        let ztxt = png.windows(4).position(|name| name == b"zTXt").unwrap() - 4;
+
+        /* A chunk used, then a chunk skipped, cut in the header or the data */
+        for &end in [idat + 3, idat + 8, idat + 100, ztxt + 6, ztxt + 20].iter() {
+            let reader = read_both(&png[..end]);
+            let expected = match end > ztxt {
+                true => PngReader::from_buf_read(&png[..ztxt]).unwrap(),
+                false => PngReader::from_buf_read(&png[..idat]).unwrap(),
+            };
+
+            assert_eq!(reader.data(), expected.data(), "cut at {}", end);
+        }
+    }
+
+    #[test]
+    fn length_is_not_allocated_before_the_data()
+    {
+        let png = pngtest();
+        let idat = png.windows(4).position(|name| name == b"IDAT").unwrap() - 4;
+
+        /* The largest length allowed, with a few bytes of data only */
+        let mut cut = png[..idat].to_vec();
+        cut.extend_from_slice(&chunk(b"IDAT", i32::MAX as u32, &[0; 16]));
+
+        let reader = read_both(&cut);
+        assert_eq!(reader.data(), PngReader::from_buf_read(&png[..idat]).unwrap().data());
+        assert!(reader.into_data().capacity() < 1 << 20);
+    }
+}
@@ -5,6 +5,8 @@ use crate::PngMode;
 use crate::PngColor;
 use crate::PngFlags;
 use crate::CPtr;
+use crate::png_info::PngColor8;
+use crate::interlace;
 use std::slice;
 
 /* This is used for the transformation routines, as some of them
@@ -14,12 +16,149 @@ use std::slice;
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(C)]
 pub struct PngRowInfo {
-    width: u32,           /* width of row */
-    rowbytes: usize,      /* number of bytes in row */
-    color_type: PngColor, /* color type of row */
-    bit_depth: u8,        /* bit depth of row */
-    channels: u8,         /* number of channels (1, 2, 3, or 4) */
-    pixel_depth: u8,      /* bits per pixel (depth * channels) */
+    pub width: u32,           /* width of row */
+    pub rowbytes: usize,      /* number of bytes in row */
+    pub color_type: PngColor, /* color type of row */
+    pub bit_depth: u8,        /* bit depth of row */
+    pub channels: u8,         /* number of channels (1, 2, 3, or 4) */
+    pub pixel_depth: u8,      /* bits per pixel (depth * channels) */
+}
+
+/* A transformation supplied by the application, run at the end of the read
+ * pipeline or at the start of the write pipeline (where libpng calls the
+ * functions registered with png_set_read_user_transform_fn and
+ * png_set_write_user_transform_fn).
+ */
+pub trait RowTransform {
+    /* Bit depth of the rows produced, 0 if it is not changed.  Like the
+     * user_transform_depth of png_set_user_transform_info this is used to
+     * size the row buffer and the row info before the transform is called.
+     */
+    fn output_bit_depth(&self) -> u8 { 0 }
+
+    /* Number of channels of the rows produced, 0 if it is not changed */
+    fn output_channels(&self) -> u8 { 0 }
+
+    /* Largest pixel depth the transform needs in the row buffer for rows of
+     * 'bit_depth' and 'channels', by default that of the rows it produces.
+     */
+    fn max_pixel_depth(&self, bit_depth: u8, channels: u8) -> u8
+    {
+        let bit_depth = match self.output_bit_depth() {
+            0 => bit_depth,
+            output_bit_depth => output_bit_depth,
+        };
+        let channels = match self.output_channels() {
+            0 => channels,
+            output_channels => output_channels,
+        };
+
+        bit_depth * channels
+    }
+
+    /* Transform the row in place.  The slice holds the row and, on read, the
+     * room the transform declared; the color type of the row info is left to
+     * the transform to update.
+     */
+    fn transform(&mut self, row_info: &mut PngRowInfo, row: &mut [u8]);
+}
+
+/* Transforms applied one after the other, each one seeing the row info
+ * produced by the previous one.
+ */
+#[derive(Default)]
+pub struct RowTransformChain {
+    transforms: Vec<Box<dyn RowTransform>>,
+}
+
+impl RowTransformChain {
+    pub fn new() -> RowTransformChain
+    {
+        RowTransformChain { transforms: Vec::new() }
+    }
+
+    pub fn push(&mut self, transform: Box<dyn RowTransform>)
+    {
+        self.transforms.push(transform);
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.transforms.is_empty()
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.transforms.len()
+    }
+}
+
+impl RowTransform for RowTransformChain {
+    fn output_bit_depth(&self) -> u8
+    {
+        self.transforms.iter().map(|transform| transform.output_bit_depth())
+            .rfind(|bit_depth| *bit_depth != 0).unwrap_or(0)
+    }
+
+    fn output_channels(&self) -> u8
+    {
+        self.transforms.iter().map(|transform| transform.output_channels())
+            .rfind(|channels| *channels != 0).unwrap_or(0)
+    }
+
+    /* Each transform gets the depth and channels of the rows of the previous
+     * one, as png_set_user_transform_info sets them for the C transform.
+     */
+    fn max_pixel_depth(&self, bit_depth: u8, channels: u8) -> u8
+    {
+        let mut bit_depth = bit_depth;
+        let mut channels = channels;
+        let mut max_pixel_depth = bit_depth * channels;
+
+        for transform in self.transforms.iter() {
+            max_pixel_depth = max_pixel_depth.max(transform.max_pixel_depth(bit_depth, channels));
+
+            if transform.output_bit_depth() != 0
+            {
+                bit_depth = transform.output_bit_depth();
+            }
+
+            if transform.output_channels() != 0
+            {
+                channels = transform.output_channels();
+            }
+        }
+
+        max_pixel_depth
+    }
+
+    fn transform(&mut self, row_info: &mut PngRowInfo, row: &mut [u8])
+    {
+        for transform in self.transforms.iter_mut() {
+            transform.transform(row_info, row);
+
+            if transform.output_bit_depth() != 0
+            {
+                row_info.bit_depth = transform.output_bit_depth();
+            }
+
+            if transform.output_channels() != 0
+            {
+                row_info.channels = transform.output_channels();
+            }
+
+            row_info.pixel_depth = row_info.bit_depth * row_info.channels;
+            row_info.rowbytes = Png::compute_rowbytes(row_info.pixel_depth, row_info.width as usize);
+        }
+    }
+}
+
+/* Palette indexes found by the check for invalid indexes */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PngPaletteIndexes {
+    pub max_index: u8,                     /* largest index in the image */
+    pub first_invalid: Option<(u32, u32)>, /* row and column of the first index
+                                            * not in the palette */
 }
 
 /*******************************************************************************
@@ -37,12 +176,21 @@ pub unsafe extern fn png_c_set_filler(this: *mut Png, filler: u32, filler_loc: i
     }
 }
 
+#[no_mangle]
+pub unsafe extern fn png_c_set_add_alpha(this: *mut Png, filler: u32, filler_loc: i32)
+{
+    match this.as_mut() {
+        None => { return; },
+        Some(png_ptr) => { png_ptr.set_add_alpha(filler as u16, filler_loc); },
+    }
+}
+
 #[no_mangle]
 pub unsafe extern fn png_c_set_bgr(this: *mut Png)
 {
     match this.as_mut() {
         None => { return; },
-        Some(png_ptr) => { png_ptr.transformations.insert(PngTransformations::BGR); },
+        Some(png_ptr) => { png_ptr.set_bgr(); },
     }
 }
 
@@ -51,9 +199,7 @@ pub unsafe extern fn png_c_set_swap_alpha(this: *mut Png)
 {
     match this.as_mut() {
         None => { return; },
-        Some(png_ptr) => {
-            png_ptr.transformations.insert(PngTransformations::SWAP_ALPHA);
-        },
+        Some(png_ptr) => { png_ptr.set_swap_alpha(); },
     }
 }
 
@@ -62,9 +208,7 @@ pub unsafe extern fn png_c_set_invert_alpha(this: *mut Png)
 {
     match this.as_mut() {
         None => { return; },
-        Some(png_ptr) => {
-            png_ptr.transformations.insert(PngTransformations::INVERT_ALPHA);
-        },
+        Some(png_ptr) => { png_ptr.set_invert_alpha(); },
     }
 }
 
@@ -73,9 +217,7 @@ pub unsafe extern fn png_c_set_invert_mono(this: *mut Png)
 {
     match this.as_mut() {
         None => { return; },
-        Some(png_ptr) => {
-            png_ptr.transformations.insert(PngTransformations::INVERT_MONO);
-        },
+        Some(png_ptr) => { png_ptr.set_invert_mono(); },
     }
 }
 
@@ -84,11 +226,7 @@ pub unsafe extern fn png_c_set_swap(this: *mut Png)
 {
     match this.as_mut() {
         None => { return; },
-        Some(png_ptr) => {
-            if png_ptr.bit_depth == 16 {
-                png_ptr.transformations.insert(PngTransformations::SWAP_BYTES);
-            }
-        },
+        Some(png_ptr) => { png_ptr.set_swap(); },
     }
 }
 
@@ -97,12 +235,7 @@ pub unsafe extern fn png_c_set_packing(this: *mut Png)
 {
     match this.as_mut() {
         None => { return; },
-        Some(png_ptr) => {
-            if png_ptr.bit_depth < 8 {
-                png_ptr.transformations.insert(PngTransformations::PACK);
-                png_ptr.usr_bit_depth = 8;
-            }
-        },
+        Some(png_ptr) => { png_ptr.set_packing(); },
     }
 }
 
@@ -111,26 +244,102 @@ pub unsafe extern fn png_c_set_pack_swap(this: *mut Png)
 {
     match this.as_mut() {
         None => { return; },
-        Some(png_ptr) => {
-            if png_ptr.bit_depth < 8 {
-                png_ptr.transformations.insert(PngTransformations::PACKSWAP);
-            }
-        },
+        Some(png_ptr) => { png_ptr.set_packswap(); },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_c_set_shift(this: *mut Png, true_bits: *const PngColor8)
+{
+    if let (Some(png_ptr), Some(true_bits)) = (this.as_mut(), true_bits.as_ref())
+    {
+        png_ptr.set_shift(*true_bits);
     }
 }
 
+#[no_mangle]
+pub unsafe extern fn png_rust_ptr_shift(this: *mut Png) -> *mut PngColor8
+{
+    &mut this.as_mut().unwrap().shift
+}
+
 #[no_mangle]
 pub unsafe extern fn png_c_set_interlace_handling(this: *mut Png) -> i32
 {
     match this.as_mut() {
-        None => { return 1; },
-        Some(png_ptr) => {
-            if png_ptr.interlaced != PngInterlace::None {
-                png_ptr.transformations.insert(PngTransformations::INTERLACE);
-                return 7;
-            }
-            return 1;
+        None => 1,
+        Some(png_ptr) => png_ptr.set_interlace_handling() as i32,
+    }
+}
+
+
+
+/* Returns false when the rows of the transforms do not fit in the row buffer */
+#[no_mangle]
+pub unsafe extern fn png_rust_do_read_user_transform(this: *mut Png, row_info: *mut PngRowInfo, row: CPtr) -> bool
+{
+    match (this.as_mut(), row_info.as_mut()) {
+        (Some(png_ptr), Some(row_info)) => {
+            /* The row buffer is allocated for the maximum pixel depth, which
+             * includes the depth declared by the transforms.
+             */
+            let length = Png::compute_rowbytes(png_ptr.maximum_pixel_depth, row_info.width as usize);
+            let slice = slice::from_raw_parts_mut(row as *mut u8, length);
+            png_ptr.do_read_user_transform(row_info, slice).is_ok()
         },
+        _ => true,
+    }
+}
+
+/* The RowTransform of png_set_read_rust_transform and
+ * png_set_write_rust_transform: 'transform' is a Box<Box<dyn RowTransform>>
+ * given with Box::into_raw, the Png takes ownership of it.
+ */
+#[no_mangle]
+pub unsafe extern fn png_rust_add_read_transform(this: *mut Png, transform: *mut Box<dyn RowTransform>)
+{
+    if let (Some(png_ptr), false) = (this.as_mut(), transform.is_null())
+    {
+        png_ptr.add_read_transform(*Box::from_raw(transform));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_rust_add_write_transform(this: *mut Png, transform: *mut Box<dyn RowTransform>)
+{
+    if let (Some(png_ptr), false) = (this.as_mut(), transform.is_null())
+    {
+        png_ptr.add_write_transform(*Box::from_raw(transform));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_rust_get_user_transform_depth(this: *const Png) -> u8
+{
+    match this.as_ref() {
+        None => 0,
+        Some(png_ptr) => png_ptr.read_user_transform.output_bit_depth(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_rust_get_user_transform_channels(this: *const Png) -> u8
+{
+    match this.as_ref() {
+        None => 0,
+        Some(png_ptr) => png_ptr.read_user_transform.output_channels(),
+    }
+}
+
+/* The pixel depth the transforms need for rows of at most 'max_pixel_depth'
+ * bits, those of png_read_start_row.
+ */
+#[no_mangle]
+pub unsafe extern fn png_rust_get_user_transform_pixel_depth(this: *const Png, max_pixel_depth: u8) -> u8
+{
+    match this.as_ref() {
+        None => 0,
+        Some(png_ptr) => png_ptr.user_transform_pixel_depth(max_pixel_depth),
     }
 }
 
@@ -207,6 +416,14 @@ pub unsafe extern fn png_do_check_palette_indexes(this: *mut Png, row_info: *con
     }
 }
 
+#[no_mangle]
+pub unsafe extern fn png_rust_has_invalid_palette_index(this: *const Png) -> bool {
+    match this.as_ref() {
+        None => false,
+        Some(png_ptr) => png_ptr.first_invalid_index.is_some(),
+    }
+}
+
 #[no_mangle]
 pub unsafe extern fn png_do_swap(this: *const PngRowInfo, row: CPtr) {
     match this.as_ref() {
@@ -355,7 +572,7 @@ impl Png {
      * for 48-bit input data, as well as to avoid problems with some compilers
      * that don't like bytes as parameters.
      */
-    fn set_filler(&mut self, filler: u16, filler_loc: i32)
+    pub fn set_filler(&mut self, filler: u16, filler_loc: i32)
     {
         /* In libpng 1.6 it is possible to determine whether this is a read or write
          * operation and therefore to do more checking here for a valid call.
@@ -423,78 +640,275 @@ impl Png {
         }
     }
 
+    /* Append a transform to the read pipeline, after all libpng transforms.
+     * USER_TRANSFORM is set, the C reader runs the chain under that flag
+     * after the png_set_read_user_transform_fn function.  For a png_struct
+     * of the C API the transform is given to png_set_read_rust_transform.
+     */
+    pub fn add_read_transform(&mut self, transform: Box<dyn RowTransform>)
+    {
+        self.transformations.insert(PngTransformations::USER_TRANSFORM);
+        self.read_user_transform.push(transform);
+    }
+
+    /* Append a transform to the write pipeline, before all libpng transforms.
+     * The row buffer is sized for the rows of the application so these
+     * transforms must not make the row bigger.  For a png_struct of the C
+     * API the transform is given to png_set_write_rust_transform.
+     */
+    pub fn add_write_transform(&mut self, transform: Box<dyn RowTransform>)
+    {
+        self.transformations.insert(PngTransformations::USER_TRANSFORM);
+        self.write_user_transform.push(transform);
+    }
+
+    /* The rows reaching the transforms are not known before
+     * png_read_transform_info, which comes after the row buffer is allocated:
+     * the depth is the largest needed by the rows of a PNG pipeline of at most
+     * 'max_pixel_depth' bits (depths below 8 only with one channel).
+     */
+    pub(crate) fn user_transform_pixel_depth(&self, max_pixel_depth: u8) -> u8
+    {
+        let rows = [(1, 1), (2, 1), (4, 1), (8, 1), (8, 2), (8, 3), (8, 4), (16, 1), (16, 2), (16, 3), (16, 4)];
+
+        rows.iter()
+            .filter(|&&(bit_depth, channels)| bit_depth * channels <= max_pixel_depth)
+            .map(|&(bit_depth, channels)| self.read_user_transform.max_pixel_depth(bit_depth, channels))
+            .max().unwrap_or(0)
+    }
+
+    fn do_read_user_transform(&mut self, row_info: &mut PngRowInfo, row: &mut [u8]) -> Result<(), &'static str>
+    {
+        if self.read_user_transform.is_empty()
+        {
+            return Ok(());
+        }
+
+        let pixel_depth = self.read_user_transform.max_pixel_depth(row_info.bit_depth, row_info.channels);
+        if Png::compute_rowbytes(pixel_depth, row_info.width as usize) > row.len()
+        {
+            return Err("Row transform larger than the row buffer");
+        }
+
+        self.read_user_transform.transform(row_info, row);
+        Ok(())
+    }
+
+    pub(crate) fn do_write_user_transform(&mut self, row_info: &mut PngRowInfo, row: &mut [u8])
+    {
+        if !self.write_user_transform.is_empty()
+        {
+            self.write_user_transform.transform(row_info, row);
+            assert!(row_info.rowbytes <= row.len());
+        }
+    }
+
+    /* Added to libpng-1.2.7 */
+    pub fn set_add_alpha(&mut self, filler: u16, filler_loc: i32)
+    {
+        self.set_filler(filler, filler_loc);
+
+        /* The above may fail to do anything. */
+        if self.transformations.contains(PngTransformations::FILLER)
+        {
+            self.transformations.insert(PngTransformations::ADD_ALPHA);
+        }
+    }
+
+    /* Turn on BGR-to-RGB mapping */
+    pub fn set_bgr(&mut self)
+    {
+        self.transformations.insert(PngTransformations::BGR);
+    }
+
+    /* Swap the alpha channel: ARGB on read, from ARGB on write */
+    pub fn set_swap_alpha(&mut self)
+    {
+        self.transformations.insert(PngTransformations::SWAP_ALPHA);
+    }
+
+    /* Invert the alpha channel: 0 for opaque instead of transparent */
+    pub fn set_invert_alpha(&mut self)
+    {
+        self.transformations.insert(PngTransformations::INVERT_ALPHA);
+    }
+
+    /* Invert monochrome grayscale data */
+    pub fn set_invert_mono(&mut self)
+    {
+        self.transformations.insert(PngTransformations::INVERT_MONO);
+    }
+
+    /* Turn on 16-bit byte swapping */
+    pub fn set_swap(&mut self)
+    {
+        if self.bit_depth == 16
+        {
+            self.transformations.insert(PngTransformations::SWAP_BYTES);
+        }
+    }
+
+    /* Turn on pixel packing: rows of the application hold one pixel per byte
+     * for bit depths below 8.
+     */
+    pub fn set_packing(&mut self)
+    {
+        if self.bit_depth < 8
+        {
+            self.transformations.insert(PngTransformations::PACK);
+            self.usr_bit_depth = 8;
+        }
+    }
+
+    /* Turn on packed pixel swapping: the leftmost pixel is in the low bits */
+    pub fn set_packswap(&mut self)
+    {
+        if self.bit_depth < 8
+        {
+            self.transformations.insert(PngTransformations::PACKSWAP);
+        }
+    }
+
+    /* Turn on interlace handling: the application gives the full rows of the
+     * image once per pass.  The number of passes is returned.
+     */
+    pub fn set_interlace_handling(&mut self) -> u8
+    {
+        match self.interlaced {
+            PngInterlace::ADAM7 => {
+                self.transformations.insert(PngTransformations::INTERLACE);
+                interlace::PNG_INTERLACE_ADAM7_PASSES
+            },
+            PngInterlace::None => 1,
+        }
+    }
+
+    /* Shift the samples between the significant bits of true_bits and the
+     * bit depth of the image.
+     */
+    pub fn set_shift(&mut self, true_bits: PngColor8)
+    {
+        self.transformations.insert(PngTransformations::SHIFT);
+        self.shift = true_bits;
+    }
+
+    /* Check the palette indexes of the current row (in row_buf) against
+     * num_palette: keep the largest index found in num_palette_max and the
+     * position in the image of the first index beyond the palette.  With a
+     * palette of 1 << bit_depth entries no index is invalid, but the largest
+     * one is still reported.
+     */
     fn do_check_palette_indexes(&mut self, row_info: &PngRowInfo)
     {
-        if self.num_palette == 0 || self.num_palette >= (1 << row_info.bit_depth)
+        if self.num_palette == 0
         {
             /* num_palette can be 0 in MNG files */
             return;
         }
 
-        // This only works because row_info.pixel_depth is multiple of 2
-        let trailling_bits = (row_info.width % (8 / row_info.pixel_depth as u32)) * row_info.pixel_depth as u32;
-        let padding = 8 - trailling_bits;
-        let row_buf = unsafe { slice::from_raw_parts_mut(self.row_buf as *mut u8, self.rowbytes) };
+        /* The row starts after the filter byte */
+        let row = unsafe { slice::from_raw_parts((self.row_buf + 1) as *const u8, row_info.rowbytes) };
+        let (max_index, first_invalid) = row_info.check_palette_indexes(row, self.num_palette);
 
-        match row_info.bit_depth {
-            1 => {
-                /* in this case, all bytes must be 0 so we don't need
-                 * to unpack the pixels except for the rightmost one.
-                 */
-                self.num_palette_max = 0;
-                for byte_value in row_buf[..self.rowbytes - 1].iter() {
-                    if *byte_value != 0
-                    {
-                        self.num_palette_max = 1;
-                        break;
-                    }
-                }
+        if max_index as i32 > self.num_palette_max
+        {
+            self.num_palette_max = max_index as i32;
+        }
 
-                // Last byte is special (partial bits validity)
-                if self.num_palette_max != 1 && row_buf[self.rowbytes - 1] >> padding != 0
-                {
-                    self.num_palette_max = 1;
-                }
-            },
+        if let (None, Some(column)) = (self.first_invalid_index, first_invalid)
+        {
+            let pass = self.pass;
 
-            2 | 4 | 8 => {
-                let mask = (1 << row_info.bit_depth) - 1;
-                self.num_palette_max = 0;
+            self.first_invalid_index = match self.interlaced {
+                PngInterlace::ADAM7 if pass < interlace::PNG_INTERLACE_ADAM7_PASSES => {
+                    Some((interlace::row_from_pass_row(self.row_number, pass),
+                          interlace::col_from_pass_col(column, pass)))
+                },
+                _ => Some((self.row_number, column)),
+            };
+        }
+    }
 
-                // For each byte (except the latest)
-                for byte_value in row_buf[..self.rowbytes - 1].iter()  {
+    /* The result of the palette index check enabled by
+     * png_set_check_for_invalid_index, None if the check is disabled.
+     */
+    pub fn get_palette_indexes(&self) -> Option<PngPaletteIndexes>
+    {
+        if self.num_palette_max < 0
+        {
+            return None;
+        }
 
-                    // For each channel value inside on byte
-                    for j in num_iter::range_step(0, 8, row_info.bit_depth) {
-                        // Channel value
-                        let value = ((*byte_value >> j) & mask) as i32 ;
+        Some(PngPaletteIndexes {
+            max_index: self.num_palette_max as u8,
+            first_invalid: self.first_invalid_index,
+        })
+    }
+}
 
-                        if value > self.num_palette_max {
-                            self.num_palette_max = value;
+impl PngRowInfo {
+    /* Describe a row of 'width' pixels in the given format, as the row is
+     * stored in the file (before any transformation).
+     */
+    pub fn new(width: u32, color_type: PngColor, bit_depth: u8) -> PngRowInfo
+    {
+        let channels = match color_type {
+            PngColor::TYPE_RGB => 3,
+            PngColor::TYPE_GRAY_ALPHA => 2,
+            PngColor::TYPE_RGB_ALPHA => 4,
+            _ => 1,
+        };
+        let pixel_depth = bit_depth * channels;
 
-                            // Can't be bigger than mask
-                            if self.num_palette_max == mask as i32 {
-                                break;
-                            }
-                        }
-                    }
-                }
+        PngRowInfo {
+            width,
+            rowbytes: Png::compute_rowbytes(pixel_depth, width as usize),
+            color_type,
+            bit_depth,
+            channels,
+            pixel_depth,
+        }
+    }
 
-                // Last byte is special
-                if self.num_palette_max != mask as i32 {
-                }
-            },
+    /* Find the largest palette index of a palette row (1, 2, 4 or 8-bit)
+     * and the column of the first index which is not in a palette of
+     * num_palette entries.  Pixels are packed from the most significant bits
+     * and the padding bits at the end of the row are not looked at.
+     */
+    pub fn check_palette_indexes(&self, row: &[u8], num_palette: u16) -> (u8, Option<u32>)
+    {
+        let bit_depth = self.bit_depth as usize;
+        let mut max_index = 0;
+        let mut first_invalid = None;
 
-            _ => {
-                return;
+        if bit_depth == 0 || bit_depth > 8
+        {
+            return (max_index, first_invalid);
+        }
+
+        let mask = ((1u16 << bit_depth) - 1) as u8;
+        assert!(Png::compute_rowbytes(self.bit_depth, self.width as usize) <= row.len());
+
+        for column in 0..self.width {
+            let bit = column as usize * bit_depth;
+            let index = (row[bit >> 3] >> (8 - bit_depth - (bit & 0x07))) & mask;
+
+            if index > max_index
+            {
+                max_index = index;
             }
-        };
+
+            if first_invalid.is_none() && index as u16 >= num_palette
+            {
+                first_invalid = Some(column);
+            }
+        }
+
+        (max_index, first_invalid)
     }
-}
 
-impl PngRowInfo {
     /* Invert monochrome grayscale data */
-    fn do_invert(&self, row: &mut [u8])
+    pub(crate) fn do_invert(&self, row: &mut [u8])
     {
         assert!(self.rowbytes <= row.len());
 
@@ -516,11 +930,10 @@ impl PngRowInfo {
                         }
                     },
                     16 => {
-                        // Consider element of 16bits
-                        let row_16: &mut [u16] = unsafe { std::mem::transmute::<&mut [u8], &mut [u16]>(row) };
-                        let nb_element = self.rowbytes/2;
-                        for i in num_iter::range_step(0, nb_element, 2) {
-                            row_16[i] = ! row_16[i];
+                        // Only invert the two bytes of gray, not alpha
+                        for pixel in row[..self.rowbytes].chunks_mut(4) {
+                            pixel[0] = ! pixel[0];
+                            pixel[1] = ! pixel[1];
                         }
                     },
                     _ => {
@@ -535,25 +948,24 @@ impl PngRowInfo {
     }
 
     /* Swaps byte order on 16-bit depth images */
-    fn do_swap(&self, row: &mut [u8])
+    pub(crate) fn do_swap(&self, row: &mut [u8])
     {
         if self.bit_depth != 16
         {
             return;
         }
 
-        // Consider element of 16bits
-        let row_16: &mut [u16] = unsafe { std::mem::transmute::<&mut [u8], &mut [u16]>(row) };
+        // Consider element of 16bits, the row may not be aligned for u16
         let nb_u16 = (self.width * self.channels as u32) as usize;
-        assert!(nb_u16 <= row_16.len());
+        assert!(2 * nb_u16 <= row.len());
 
-        for value in row_16[..nb_u16].iter_mut() {
-            *value = (*value).swap_bytes();
+        for value in row[..2 * nb_u16].chunks_mut(2) {
+            value.swap(0, 1);
         }
     }
 
     /* Swaps pixel packing order within bytes */
-    fn do_packswap(&self, row: &mut [u8])
+    pub(crate) fn do_packswap(&self, row: &mut [u8])
     {
         assert!(self.rowbytes <= row.len());
 
@@ -572,7 +984,7 @@ impl PngRowInfo {
     }
 
     /* Swaps red and blue bytes within a pixel */
-    fn do_bgr(&self, row: &mut [u8])
+    pub(crate) fn do_bgr(&self, row: &mut [u8])
     {
         if ! self.color_type.contains(PngColor::MASK_COLOR)
         {
@@ -597,11 +1009,10 @@ impl PngRowInfo {
             },
 
             16 => {
-                // Consider element of 16bits
-                let row_16: &mut [u16] = unsafe { std::mem::transmute::<&mut [u8], &mut [u16]>(row) };
-
-                for i in num_iter::range_step(0, nb_element, nb_composant) {
-                    row_16.swap(i, i+2);
+                // Swap the two bytes of red with the two bytes of blue
+                for i in num_iter::range_step(0, 2 * nb_element, 2 * nb_composant) {
+                    row.swap(i, i+4);
+                    row.swap(i+1, i+5);
                 }
             },
             _ => {
@@ -619,7 +1030,7 @@ impl PngRowInfo {
      * The routine isn't general - the channel must be the channel at the start or
      * end (not in the middle) of each pixel.
      */
-    fn do_strip_channel(&mut self, row: &mut [u8], remove_first: bool)
+    pub(crate) fn do_strip_channel(&mut self, row: &mut [u8], remove_first: bool)
     {
         /* At the start sp will point to the first byte to copy and dp to where
          * it is copied to.  ep always points just beyond the end of the row, so
@@ -655,8 +1066,9 @@ impl PngRowInfo {
             8 => {
                 let nb_pixels = self.rowbytes / self.channels as usize;
 
-                let mut source_idx = 0;
-                let mut dest_idx   = match remove_first { true => 1, false => 0, };
+                /* Skip the channel removed from the first pixel */
+                let mut source_idx = match remove_first { true => 1, false => 0, };
+                let mut dest_idx   = 0;
                 for _i in 0..nb_pixels {
                     for _j in 0..self.channels - 1 {
                         row[dest_idx] = row[source_idx];
@@ -674,15 +1086,15 @@ impl PngRowInfo {
                 self.pixel_depth = self.bit_depth * self.channels;
             },
             16 => {
-                // Consider element of 16bits
-                let row_16: &mut [u16] = unsafe { std::mem::transmute::<&mut [u8], &mut [u16]>(row) };
+                // Consider element of 16bits, copied as two bytes
                 let nb_pixels = self.rowbytes / (2 * self.channels as usize);
 
-                let mut source_idx = 0;
-                let mut dest_idx   = match remove_first { true => 1, false => 0, };
+                /* Skip the channel removed from the first pixel */
+                let mut source_idx = match remove_first { true => 1, false => 0, };
+                let mut dest_idx   = 0;
                 for _i in 0..nb_pixels {
                     for _j in 0..self.channels - 1 {
-                        row_16[dest_idx] = row_16[source_idx];
+                        row.copy_within(2 * source_idx..2 * source_idx + 2, 2 * dest_idx);
                         source_idx += 1;
                         dest_idx   += 1;
                     }
@@ -702,3 +1114,286 @@ impl PngRowInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Gray to gray-alpha, opaque */
+    struct AddAlpha;
+
+    impl RowTransform for AddAlpha {
+        fn output_channels(&self) -> u8 { 2 }
+
+        fn transform(&mut self, row_info: &mut PngRowInfo, row: &mut [u8])
+        {
+            for i in (0..row_info.width as usize).rev() {
+                row[2 * i] = row[i];
+                row[2 * i + 1] = 0xff;
+            }
+            row_info.color_type = PngColor::TYPE_GRAY_ALPHA;
+        }
+    }
+
+    /* 8 to 16 bits, by replication */
+    struct Expand16;
+
+    impl RowTransform for Expand16 {
+        fn output_bit_depth(&self) -> u8 { 16 }
+
+        fn transform(&mut self, row_info: &mut PngRowInfo, row: &mut [u8])
+        {
+            for i in (0..row_info.rowbytes).rev() {
+                row[2 * i] = row[i];
+                row[2 * i + 1] = row[i];
+            }
+        }
+    }
+
+    #[test]
+    fn chain_declares_the_last_depth_and_channels()
+    {
+        let mut chain = RowTransformChain::new();
+        chain.push(Box::new(AddAlpha));
+        chain.push(Box::new(Expand16));
+
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain.output_channels(), 2);
+        assert_eq!(chain.output_bit_depth(), 16);
+        assert_eq!(chain.max_pixel_depth(8, 1), 32);
+
+        let mut row_info = PngRowInfo::new(3, PngColor::TYPE_GRAY, 8);
+        let mut row = vec![0u8; 12];
+        row[..3].copy_from_slice(&[1, 2, 3]);
+
+        chain.transform(&mut row_info, &mut row);
+        assert_eq!(row, [1, 1, 0xff, 0xff, 2, 2, 0xff, 0xff, 3, 3, 0xff, 0xff]);
+        assert_eq!((row_info.color_type, row_info.channels, row_info.bit_depth), (PngColor::TYPE_GRAY_ALPHA, 2, 16));
+        assert_eq!((row_info.pixel_depth, row_info.rowbytes), (32, 12));
+    }
+
+    /* Gray to RGBA, opaque */
+    struct GrayToRgba;
+
+    impl RowTransform for GrayToRgba {
+        fn output_channels(&self) -> u8 { 4 }
+
+        fn transform(&mut self, row_info: &mut PngRowInfo, row: &mut [u8])
+        {
+            for i in (0..row_info.width as usize).rev() {
+                let gray = row[i];
+                row[4 * i..4 * i + 4].copy_from_slice(&[gray, gray, gray, 0xff]);
+            }
+            row_info.color_type = PngColor::TYPE_RGB_ALPHA;
+        }
+    }
+
+    /* Gray 8 to RGBA 16: each transform widens the rows of the previous one */
+    #[test]
+    fn chain_walks_the_depth_and_channels()
+    {
+        let mut chain = RowTransformChain::new();
+        chain.push(Box::new(GrayToRgba));
+        chain.push(Box::new(Expand16));
+
+        assert_eq!(chain.max_pixel_depth(8, 1), 64);
+        assert_eq!(GrayToRgba.max_pixel_depth(8, 1), 32);
+        assert_eq!(Expand16.max_pixel_depth(8, 4), 64);
+
+        let mut png_ptr = Png::new();
+        png_ptr.add_read_transform(Box::new(GrayToRgba));
+        png_ptr.add_read_transform(Box::new(Expand16));
+
+        assert_eq!(png_ptr.user_transform_pixel_depth(8), 64);
+
+        /* The widest rows of at most 4 bits are gray 4, of at most 8 bits gray
+         * 8 and of at most 16 bits gray 16 (not gray-alpha 8).
+         */
+        let mut gray_to_rgba = Png::new();
+        gray_to_rgba.add_read_transform(Box::new(GrayToRgba));
+        assert_eq!(gray_to_rgba.user_transform_pixel_depth(4), 16);
+        assert_eq!(gray_to_rgba.user_transform_pixel_depth(8), 32);
+        assert_eq!(gray_to_rgba.user_transform_pixel_depth(16), 64);
+
+        for &(maximum_pixel_depth, fits) in [(64, true), (32, false)].iter() {
+            let mut row_info = PngRowInfo::new(3, PngColor::TYPE_GRAY, 8);
+            let mut row = vec![0u8; Png::compute_rowbytes(maximum_pixel_depth, 3)];
+            row[..3].copy_from_slice(&[1, 2, 3]);
+
+            png_ptr.maximum_pixel_depth = maximum_pixel_depth;
+            let done = unsafe {
+                png_rust_do_read_user_transform(&mut png_ptr, &mut row_info, row.as_mut_ptr() as CPtr)
+            };
+
+            assert_eq!(done, fits);
+            if fits
+            {
+                assert_eq!(row, [1, 1, 1, 1, 1, 1, 0xff, 0xff, 2, 2, 2, 2, 2, 2, 0xff, 0xff,
+                                 3, 3, 3, 3, 3, 3, 0xff, 0xff]);
+                assert_eq!((row_info.color_type, row_info.channels, row_info.bit_depth, row_info.rowbytes),
+                           (PngColor::TYPE_RGB_ALPHA, 4, 16, 24));
+            }
+            else
+            {
+                assert_eq!(row[..3], [1, 2, 3]);
+            }
+        }
+    }
+
+    /* The chain given to png_set_read_rust_transform sizes the row buffer of
+     * png_read_image.
+     */
+    #[cfg(feature = "c-tests")]
+    #[test]
+    fn png_read_image_runs_widening_transforms()
+    {
+        use crate::libpng;
+
+        let png = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/../contrib/pngsuite/basn0g08.png")).unwrap();
+        let rows = libpng::read_png(&png);
+        let transforms: Vec<Box<dyn RowTransform>> = vec![Box::new(GrayToRgba), Box::new(Expand16)];
+        let transformed = libpng::read_png_transformed(&png, transforms);
+
+        assert_eq!(transformed.len(), rows.len());
+        for (row, transformed) in rows.iter().zip(transformed.iter()) {
+            let expected: Vec<u8> = row.iter().flat_map(|&gray| vec![gray, gray, gray, gray, gray, gray, 0xff, 0xff])
+                .collect();
+            assert_eq!(transformed, &expected);
+        }
+    }
+
+    #[test]
+    fn c_entry_points_set_user_transform()
+    {
+        let mut png_ptr = Png::new();
+        let first: Box<dyn RowTransform> = Box::new(AddAlpha);
+        let second: Box<dyn RowTransform> = Box::new(Expand16);
+
+        unsafe {
+            png_rust_add_read_transform(&mut png_ptr, Box::into_raw(Box::new(first)));
+            png_rust_add_read_transform(&mut png_ptr, Box::into_raw(Box::new(second)));
+            png_rust_add_read_transform(&mut png_ptr, std::ptr::null_mut());
+
+            assert!(png_ptr.transformations.contains(PngTransformations::USER_TRANSFORM));
+            assert_eq!(png_ptr.read_user_transform.len(), 2);
+            assert_eq!(png_rust_get_user_transform_channels(&png_ptr), 2);
+            assert_eq!(png_rust_get_user_transform_depth(&png_ptr), 16);
+            assert_eq!(png_rust_get_user_transform_pixel_depth(&png_ptr, 8), 32);
+        }
+
+        let mut png_ptr = Png::new();
+        let transform: Box<dyn RowTransform> = Box::new(AddAlpha);
+        unsafe {
+            png_rust_add_write_transform(&mut png_ptr, Box::into_raw(Box::new(transform)));
+        }
+        assert!(png_ptr.transformations.contains(PngTransformations::USER_TRANSFORM));
+        assert_eq!(png_ptr.write_user_transform.len(), 1);
+        assert!(png_ptr.read_user_transform.is_empty());
+    }
+    /* Pack 'indexes' at 'bit_depth' with the padding bits of the last byte
+     * set, as an encoder may leave them.
+     */
+    fn pack(indexes: &[u8], bit_depth: usize) -> Vec<u8>
+    {
+        let mut row = vec![0u8; (indexes.len() * bit_depth).div_ceil(8)];
+
+        for (i, &index) in indexes.iter().enumerate() {
+            let bit = i * bit_depth;
+            row[bit >> 3] |= index << (8 - bit_depth - (bit & 7));
+        }
+
+        let used = (indexes.len() * bit_depth) & 7;
+        if used != 0
+        {
+            *row.last_mut().unwrap() |= 0xff >> used;
+        }
+
+        row
+    }
+
+    #[test]
+    fn palette_indexes_at_every_bit_depth()
+    {
+        let mut seed = 7u32;
+
+        for &bit_depth in [1u8, 2, 4, 8].iter() {
+            let mask = ((1u16 << bit_depth) - 1) as u8;
+
+            for width in 1..30u32 {
+                for &num_palette in [1u16, 2, 3, 5, 16, 100, 256].iter() {
+                    let indexes: Vec<u8> = (0..width).map(|_| {
+                        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                        (seed >> 16) as u8 & mask
+                    }).collect();
+
+                    let row_info = PngRowInfo::new(width, PngColor::TYPE_PALETTE, bit_depth);
+                    let row = pack(&indexes, bit_depth as usize);
+                    let expected_max = *indexes.iter().max().unwrap();
+                    let expected_invalid = indexes.iter().position(|&index| index as u16 >= num_palette);
+
+                    assert_eq!(row_info.check_palette_indexes(&row, num_palette),
+                               (expected_max, expected_invalid.map(|column| column as u32)),
+                               "{} bits, width {}, {} entries", bit_depth, width, num_palette);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn padding_bits_are_not_indexes()
+    {
+        /* Three 2-bit pixels of index 1, then two padding bits set */
+        let row_info = PngRowInfo::new(3, PngColor::TYPE_PALETTE, 2);
+        assert_eq!(row_info.check_palette_indexes(&[0b0101_0111], 2), (1, None));
+
+        /* One 1-bit pixel of index 0 and seven padding bits set */
+        let row_info = PngRowInfo::new(1, PngColor::TYPE_PALETTE, 1);
+        assert_eq!(row_info.check_palette_indexes(&[0b0111_1111], 1), (0, None));
+
+        /* One 4-bit pixel, the padding nibble is 0xf */
+        let row_info = PngRowInfo::new(1, PngColor::TYPE_PALETTE, 4);
+        assert_eq!(row_info.check_palette_indexes(&[0x3f], 4), (3, None));
+    }
+
+    /* Run the check of the Png on 'row' as the row number 'row_number' */
+    fn check_row(png_ptr: &mut Png, row: &[u8], width: u32, bit_depth: u8, row_number: u32)
+    {
+        let mut row_buf = vec![0u8];
+        row_buf.extend_from_slice(row);
+
+        png_ptr.row_buf = row_buf.as_ptr() as CPtr;
+        png_ptr.row_number = row_number;
+        png_ptr.do_check_palette_indexes(&PngRowInfo::new(width, PngColor::TYPE_PALETTE, bit_depth));
+        png_ptr.row_buf = 0;
+    }
+
+    #[test]
+    fn full_palette_reports_the_largest_index()
+    {
+        let mut png_ptr = Png::new();
+        png_ptr.num_palette = 16;
+
+        check_row(&mut png_ptr, &pack(&[3, 9, 2], 4), 3, 4, 0);
+        check_row(&mut png_ptr, &pack(&[12, 0, 1], 4), 3, 4, 1);
+
+        assert_eq!(png_ptr.get_palette_indexes(), Some(PngPaletteIndexes { max_index: 12, first_invalid: None }));
+    }
+
+    #[test]
+    fn first_invalid_index_position()
+    {
+        let mut png_ptr = Png::new();
+        png_ptr.num_palette = 3;
+
+        check_row(&mut png_ptr, &pack(&[0, 1, 2, 2], 2), 4, 2, 0);
+        check_row(&mut png_ptr, &pack(&[0, 1, 3, 0], 2), 4, 2, 1);
+        check_row(&mut png_ptr, &pack(&[3, 1, 0, 0], 2), 4, 2, 2);
+
+        assert_eq!(png_ptr.get_palette_indexes(), Some(PngPaletteIndexes { max_index: 3, first_invalid: Some((1, 2)) }));
+
+        /* A palette of no entry (MNG) is not checked */
+        let mut png_ptr = Png::new();
+        check_row(&mut png_ptr, &pack(&[3, 1, 0, 0], 2), 4, 2, 0);
+        assert_eq!(png_ptr.get_palette_indexes(), Some(PngPaletteIndexes { max_index: 0, first_invalid: None }));
+    }
+}
@@ -0,0 +1,570 @@
+use crate::Png;
+use crate::PngMode;
+use crate::PngFilter;
+use crate::PngInterlace;
+use crate::PngTransformations;
+use crate::deflate::{PngCompression, PngFlush, ZlibEncoder, adler32_combine};
+use crate::filter;
+use crate::filter::PngFilterHeuristic;
+use crate::interlace;
+use crate::trans::PngRowInfo;
+use crate::wutil::{ChunkWriter, PngRowEnd, invalid_input};
+use std::io;
+use std::io::Write;
+use std::ops::Range;
+use std::thread;
+
+/* The rows kept by the Rust writer while the image data is written: the row
+ * being written, the previous row of the pass for the filters which use it,
+ * and the rows the filters are tried in.  Unlike the buffers of libpng they
+ * have no room for the filter byte, which is compressed on its own.
+ */
+pub(crate) struct PngRowBuffers {
+    row_buf: Vec<u8>,
+    prev_row: Vec<u8>,
+    try_row: Vec<u8>,
+    tst_row: Vec<u8>,
+}
+
+impl PngRowBuffers {
+    /* Allocate the buffers needed for rows of 'row_bytes' filtered with
+     * 'filters', as png_write_start_row does.
+     */
+    fn new(row_bytes: usize, filters: PngFilter) -> PngRowBuffers
+    {
+        let trial_filters = filters & (PngFilter::SUB | PngFilter::UP | PngFilter::AVG | PngFilter::PAETH);
+        let prev_filters = filters & (PngFilter::UP | PngFilter::AVG | PngFilter::PAETH);
+
+        PngRowBuffers {
+            row_buf: vec![0; row_bytes],
+            prev_row: match prev_filters.is_empty() {
+                true => Vec::new(),
+                false => vec![0; row_bytes],
+            },
+            try_row: match trial_filters.is_empty() {
+                true => Vec::new(),
+                false => vec![0; row_bytes],
+            },
+            tst_row: match trial_filters.bits().count_ones() > 1 {
+                true => vec![0; row_bytes],
+                false => Vec::new(),
+            },
+        }
+    }
+}
+
+/* The rows of an image ready to be filtered, kept to be deflated in bands,
+ * and how they are filtered and deflated.
+ */
+struct PngImageRows {
+    rows: Vec<Vec<u8>>,
+    first_in_pass: Vec<bool>, /* the row has no previous row to filter with */
+    max_row_bytes: usize,
+    bpp: usize,
+    filters: PngFilter,
+    heuristic: PngFilterHeuristic,
+    params: PngCompression,
+    image_size: usize,        /* size of the filtered rows of the image */
+}
+
+/* A band of rows filtered and deflated on its own */
+struct PngBand {
+    data: Vec<u8>,    /* deflate data, ending at a sync flush or the end */
+    adler: u32,       /* Adler-32 of the filtered rows */
+    data_size: usize, /* size of the filtered rows */
+}
+
+/* Filter the rows 'band' of the image and deflate them from an empty
+ * dictionary.  The deflate data ends with a sync flush, at a byte boundary,
+ * or with the end of the stream for the last band, so that the bands can be
+ * joined into one stream.
+ */
+fn deflate_band(image: &PngImageRows, band: Range<usize>, last: bool) -> Result<PngBand, &'static str>
+{
+    let mut encoder = ZlibEncoder::new_raw(&image.params, Some(image.image_size));
+    let mut data = Vec::new();
+    let mut data_size = 0;
+    let prev_filters = image.filters & (PngFilter::UP | PngFilter::AVG | PngFilter::PAETH);
+    let zero_row = vec![0; image.max_row_bytes];
+    let mut try_row = vec![0; image.max_row_bytes];
+    let mut tst_row = vec![0; image.max_row_bytes];
+
+    for i in band {
+        let row = &image.rows[i][..];
+        let prev_row = match (prev_filters.is_empty(), image.first_in_pass[i]) {
+            (true, _) => &[][..],
+            (false, true) => &zero_row[..row.len()],
+            (false, false) => &image.rows[i - 1][..],
+        };
+
+        let (filter_value, best_row) = filter::find_filter(image.filters, image.heuristic, image.bpp,
+                                                           row, prev_row, &mut try_row, &mut tst_row);
+
+        encoder.compress(&[filter_value as u8], PngFlush::None, &mut data)?;
+        encoder.compress(best_row, PngFlush::None, &mut data)?;
+        data_size += 1 + row.len();
+    }
+
+    let flush = match last {
+        true => PngFlush::Finish,
+        false => PngFlush::Sync,
+    };
+    encoder.compress(&[], flush, &mut data)?;
+
+    Ok(PngBand {
+        data,
+        adler: encoder.adler32(),
+        data_size,
+    })
+}
+
+impl Png {
+    /* Initialize the row writing: the rows are then given to write_row */
+    fn write_start_row(&mut self) -> io::Result<()>
+    {
+        /* Make sure we wrote the header info */
+        if !self.mode.contains(PngMode::WROTE_INFO_BEFORE_PLTE)
+        {
+            return Err(invalid_input("write_info was never called before write_row"));
+        }
+
+        self.start_row();
+
+        let usr_pixel_depth = self.usr_channels * self.usr_bit_depth;
+        let buf_size = Png::compute_rowbytes(usr_pixel_depth, self.width as usize);
+
+        self.row_buffers = Some(PngRowBuffers::new(buf_size, self.do_filter));
+        self.flush_rows = 0;
+
+        Ok(())
+    }
+
+    /* The size of the next row write_row expects: a row of the pass, or of
+     * the image with interlace handling, in the format of the application.
+     * The row writing is set up if it wasn't, the transformations must not
+     * change after this.
+     */
+    pub fn write_row_bytes(&mut self) -> io::Result<usize>
+    {
+        if self.row_buffers.is_none() && !self.mode.contains(PngMode::AFTER_IDAT)
+        {
+            self.write_start_row()?;
+        }
+
+        Ok(self.to_usr_row_info().rowbytes)
+    }
+
+    /* Move to the next row once a row is written, and end the IDAT stream
+     * after the last row of the image.
+     */
+    fn write_finish_row<W: Write>(&mut self, writer: &mut ChunkWriter<W>) -> io::Result<()>
+    {
+        match self.finish_row() {
+            PngRowEnd::Row => Ok(()),
+            PngRowEnd::Pass => {
+                /* Reset the row above the image for the next pass */
+                if let Some(buffers) = self.row_buffers.as_mut()
+                {
+                    buffers.prev_row.iter_mut().for_each(|byte| *byte = 0);
+                }
+
+                Ok(())
+            },
+            PngRowEnd::Image => {
+                self.row_buffers = None;
+                self.compress_IDAT(writer, &[], PngFlush::Finish)
+            },
+        }
+    }
+
+    /* Write a row of image data.  Rows are in the format of the application,
+     * the write transformations turn them into rows of the image.  With an
+     * interlaced image, either the rows of each pass are given in turn, or,
+     * after set_interlace_handling, the full rows of the image are given once
+     * per pass and the pixels of the pass are picked out of them.  The IDAT
+     * stream is ended after the last row.
+     */
+    pub fn write_row<W: Write>(&mut self, writer: &mut ChunkWriter<W>, row: &[u8]) -> io::Result<()>
+    {
+        let row_info = match self.prepare_row(row)? {
+            Some(row_info) => row_info,
+            None => { return self.write_finish_row(writer); },
+        };
+
+        let mut buffers = match self.row_buffers.take() {
+            Some(buffers) => buffers,
+            None => { return Err(invalid_input("no row buffers")); },
+        };
+
+        /* Find a filter, filter the row and write it out */
+        let row_bytes = row_info.rowbytes;
+        let bpp = (row_info.pixel_depth as usize + 7) >> 3;
+        let prev_row = match buffers.prev_row.is_empty() {
+            true => &[][..],
+            false => &buffers.prev_row[..row_bytes],
+        };
+
+        let (filter_value, best_row) = filter::find_filter(self.do_filter, self.filter_heuristic, bpp,
+                                                           &buffers.row_buf[..row_bytes], prev_row,
+                                                           &mut buffers.try_row, &mut buffers.tst_row);
+
+        let result = self.compress_IDAT(writer, &[filter_value as u8], PngFlush::None)
+            .and_then(|_| self.compress_IDAT(writer, best_row, PngFlush::None));
+
+        /* Swap the current and previous rows */
+        if !buffers.prev_row.is_empty()
+        {
+            std::mem::swap(&mut buffers.row_buf, &mut buffers.prev_row);
+        }
+
+        self.row_buffers = Some(buffers);
+        result?;
+
+        /* Finish row - updates counters and flushes zlib if last row */
+        self.write_finish_row(writer)?;
+
+        self.flush_rows += 1;
+
+        if self.flush_dist > 0 && self.flush_rows >= self.flush_dist
+        {
+            self.write_flush(writer)?;
+        }
+
+        Ok(())
+    }
+
+    /* Copy a row of the application into the row buffer and transform it
+     * into a row of the image, described by the returned row info.  None if,
+     * with interlace handling, the row is not part of the pass: the row is
+     * then skipped.
+     */
+    fn prepare_row(&mut self, row: &[u8]) -> io::Result<Option<PngRowInfo>>
+    {
+        if self.mode.contains(PngMode::AFTER_IDAT)
+        {
+            return Err(invalid_input("too many rows written"));
+        }
+
+        /* Initialize transformations and other stuff if first time */
+        if self.row_buffers.is_none()
+        {
+            self.write_start_row()?;
+        }
+
+        let handle_interlace = self.interlaced == PngInterlace::ADAM7 &&
+            self.transformations.contains(PngTransformations::INTERLACE);
+
+        /* If interlaced and not interested in row, or the pass is empty
+         * because the image is too narrow, skip it.
+         */
+        if handle_interlace &&
+            (!interlace::row_in_interlace_pass(self.row_number, self.pass) ||
+             interlace::pass_cols(self.width, self.pass) == 0)
+        {
+            return Ok(None);
+        }
+
+        /* Set up row info for transformations */
+        let mut row_info = self.to_usr_row_info();
+
+        if row.len() < row_info.rowbytes
+        {
+            return Err(invalid_input("row is shorter than the rows of the application"));
+        }
+
+        let mut buffers = match self.row_buffers.take() {
+            Some(buffers) => buffers,
+            None => { return Err(invalid_input("no row buffers")); },
+        };
+
+        /* Copy user's row into buffer */
+        buffers.row_buf[..row_info.rowbytes].copy_from_slice(&row[..row_info.rowbytes]);
+
+        /* Handle interlacing */
+        if handle_interlace
+        {
+            row_info.do_write_interlace(&mut buffers.row_buf, self.pass,
+                                        self.transformations.contains(PngTransformations::PACKSWAP));
+        }
+
+        /* Handle other transformations */
+        let result = self.do_write_row_transformations(&mut row_info, &mut buffers.row_buf);
+
+        self.row_buffers = Some(buffers);
+        result.map_err(invalid_input)?;
+
+        Ok(Some(row_info))
+    }
+
+    /* Write a few rows of image data */
+    pub fn write_rows<W: Write, R: AsRef<[u8]>>(&mut self, writer: &mut ChunkWriter<W>, rows: &[R]) -> io::Result<()>
+    {
+        for row in rows {
+            self.write_row(writer, row.as_ref())?;
+        }
+
+        Ok(())
+    }
+
+    /* Write the image.  You only need to call this function once, even if
+     * you are writing an interlaced image: the interlace handling is turned
+     * on and the rows are given once per pass.
+     */
+    pub fn write_image<W: Write, R: AsRef<[u8]>>(&mut self, writer: &mut ChunkWriter<W>, image: &[R]) -> io::Result<()>
+    {
+        let height = self.height as usize;
+
+        if image.len() < height
+        {
+            return Err(invalid_input("image has fewer rows than its height"));
+        }
+
+        /* Initialize interlace handling.  If image is not interlaced, this
+         * will set pass to 1
+         */
+        let num_pass = self.set_interlace_handling();
+
+        if self.get_compression_threads() > 1 && self.row_buffers.is_none() &&
+            self.idat_encoder.is_none()
+        {
+            return self.write_image_parallel(writer, &image[..height], num_pass);
+        }
+
+        /* Loop through passes */
+        for _pass in 0..num_pass {
+            self.write_rows(writer, &image[..height])?;
+        }
+
+        Ok(())
+    }
+
+    /* Write the image deflating bands of rows on separate threads.  The rows
+     * are transformed in order, then each band is filtered and deflated on
+     * its own thread from an empty dictionary, and the bands are joined into
+     * one zlib stream, with the Adler-32 combined from the ones of the bands.
+     * The transformed image is kept in memory, and the stream is not flushed
+     * every set_flush rows.
+     */
+    fn write_image_parallel<W: Write, R: AsRef<[u8]>>(&mut self, writer: &mut ChunkWriter<W>,
+                                                      image: &[R], num_pass: u8) -> io::Result<()>
+    {
+        let mut image_rows = PngImageRows {
+            rows: Vec::new(),
+            first_in_pass: Vec::new(),
+            max_row_bytes: 0,
+            bpp: (self.pixel_depth as usize + 7) >> 3,
+            filters: self.do_filter,
+            heuristic: self.filter_heuristic,
+            params: self.get_compression(),
+            image_size: self.image_size(),
+        };
+        let mut pass_start = true;
+
+        for _pass in 0..num_pass {
+            for row in image {
+                if let Some(row_info) = self.prepare_row(row.as_ref())?
+                {
+                    if let Some(buffers) = self.row_buffers.as_ref()
+                    {
+                        image_rows.rows.push(buffers.row_buf[..row_info.rowbytes].to_vec());
+                        image_rows.first_in_pass.push(pass_start);
+                        image_rows.max_row_bytes = image_rows.max_row_bytes.max(row_info.rowbytes);
+                        pass_start = false;
+                    }
+                }
+
+                match self.finish_row() {
+                    PngRowEnd::Row => {},
+                    PngRowEnd::Pass => {
+                        pass_start = true;
+                    },
+                    PngRowEnd::Image => {
+                        self.row_buffers = None;
+                    },
+                }
+            }
+        }
+
+        let row_count = image_rows.rows.len();
+        let band_rows = row_count.div_ceil(self.get_compression_threads()).max(1);
+        let image_rows = &image_rows;
+
+        self.idat_buffer.clear();
+        self.idat_buffer.extend_from_slice(&ZlibEncoder::new(&image_rows.params, Some(image_rows.image_size)).header());
+
+        thread::scope(|scope| {
+            let bands: Vec<_> = (0..row_count).step_by(band_rows).map(|start| {
+                let end = (start + band_rows).min(row_count);
+
+                scope.spawn(move || deflate_band(image_rows, start..end, end == row_count))
+            }).collect();
+
+            /* Write the bands in order as they are done */
+            let mut adler = 1;
+
+            for band in bands {
+                let band = band.join().unwrap_or(Err("deflate thread panicked")).map_err(invalid_input)?;
+
+                adler = adler32_combine(adler, band.adler, band.data_size);
+                self.idat_buffer.extend_from_slice(&band.data);
+                self.write_IDAT_chunks(writer, false)?;
+            }
+
+            self.idat_buffer.extend_from_slice(&adler.to_be_bytes());
+            self.write_IDAT_chunks(writer, true)
+        })?;
+
+        self.mode.insert(PngMode::AFTER_IDAT);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PngColor;
+    use crate::png_info::PngInfo;
+    use crate::decoder::PngDecoder;
+    use crate::chunk::ChunkReader;
+    use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+    /* An interlaced image of 'width' by 'height' pixels written with
+     * write_image on 'threads' threads.
+     */
+    fn write_adam7(width: u32, height: u32, bit_depth: u8, color_type: PngColor, image: &[Vec<u8>],
+                   threads: usize) -> Vec<u8>
+    {
+        write_threads(width, height, bit_depth, color_type, PngInterlace::ADAM7, image, threads)
+    }
+
+    fn write_threads(width: u32, height: u32, bit_depth: u8, color_type: PngColor, interlace_type: PngInterlace,
+                     image: &[Vec<u8>], threads: usize) -> Vec<u8>
+    {
+        let mut info = PngInfo::new();
+        info.width = width;
+        info.height = height;
+        info.bit_depth = bit_depth;
+        info.color_type = color_type;
+        info.interlace_type = interlace_type;
+
+        let mut png = Png::new();
+        let mut writer = ChunkWriter::new(Vec::new());
+        png.set_compression_threads(threads);
+        png.write_info(&mut writer, &mut info).unwrap();
+        png.write_image(&mut writer, image).unwrap();
+        png.write_end(&mut writer, Some(&mut info)).unwrap();
+
+        writer.into_inner()
+    }
+
+    #[test]
+    fn adam7_images_read_back()
+    {
+        let formats = [
+            (PngColor::TYPE_GRAY, 1, 1),
+            (PngColor::TYPE_GRAY, 2, 1),
+            (PngColor::TYPE_GRAY, 4, 1),
+            (PngColor::TYPE_GRAY_ALPHA, 8, 2),
+            (PngColor::TYPE_RGB, 8, 3),
+            (PngColor::TYPE_RGB_ALPHA, 16, 4),
+        ];
+
+        for &(color_type, bit_depth, channels) in formats.iter() {
+            for width in 1..=17 {
+                for height in 1..=17 {
+                    let row_bytes = Png::compute_rowbytes(bit_depth * channels, width as usize);
+                    let image: Vec<Vec<u8>> = (0..height).map(|y| {
+                        let mut row: Vec<u8> = (0..row_bytes).map(|x| (x * 37 + y * 101 + 13) as u8).collect();
+
+                        /* The padding bits at the end of the row are read as 0 */
+                        let bits = (width as usize * (bit_depth * channels) as usize) % 8;
+                        if bits != 0
+                        {
+                            row[row_bytes - 1] &= 0xff << (8 - bits);
+                        }
+                        row
+                    }).collect();
+
+                    for &threads in [1, 4].iter() {
+                        let png = write_adam7(width, height as u32, bit_depth, color_type, &image, threads);
+                        let mut decoder = PngDecoder::new(&png);
+
+                        assert_eq!(decoder.read_info().unwrap().interlace_type, PngInterlace::ADAM7);
+                        assert_eq!(decoder.read_image().unwrap(), image.concat(),
+                                   "{:?} {} bits {}x{} on {} threads", color_type, bit_depth, width, height, threads);
+                    }
+                }
+            }
+        }
+    }
+
+    /* The IDAT data of a datastream */
+    fn idat_stream(png: &[u8]) -> Vec<u8>
+    {
+        ChunkReader::new(png).map(|chunk| chunk.unwrap())
+            .filter(|chunk| &chunk.name == b"IDAT")
+            .flat_map(|chunk| chunk.data)
+            .collect()
+    }
+
+    /* Adler-32 computed the slow way, byte by byte */
+    fn slow_adler32(data: &[u8]) -> u32
+    {
+        let (mut a, mut b) = (1u32, 0u32);
+
+        for &byte in data {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+
+        (b << 16) | a
+    }
+
+    #[test]
+    fn parallel_bands_join_into_one_stream()
+    {
+        let mut seed = 1u32;
+
+        for &(width, height, interlace_type) in [(21, 1, PngInterlace::None), (21, 3, PngInterlace::None),
+                                                 (40, 13, PngInterlace::None), (17, 37, PngInterlace::None),
+                                                 (64, 100, PngInterlace::None), (19, 23, PngInterlace::ADAM7)].iter() {
+            /* Smooth rows with some noise, so that the bands use several
+             * filters and refer to the data before them.
+             */
+            let image: Vec<Vec<u8>> = (0..height).map(|y| {
+                (0..3 * width as usize).map(|x| {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    ((x + 2 * y) as u32 + ((seed >> 16) & 7)) as u8
+                }).collect()
+            }).collect();
+
+            let single = write_threads(width, height as u32, 8, PngColor::TYPE_RGB, interlace_type, &image, 1);
+            let filtered = decompress_to_vec_zlib(&idat_stream(&single)).unwrap();
+
+            /* Bands of rows which do not divide the height evenly, and more
+             * threads than rows.
+             */
+            for &threads in [2, 3, 4, 7, 16].iter() {
+                let png = write_threads(width, height as u32, 8, PngColor::TYPE_RGB, interlace_type, &image, threads);
+                let zdata = idat_stream(&png);
+                let what = format!("{}x{} {:?} on {} threads", width, height, interlace_type, threads);
+
+                /* The rows filtered as the single threaded writer does, under
+                 * the Adler-32 of all of them.
+                 */
+                let inflated = decompress_to_vec_zlib(&zdata).unwrap();
+                assert_eq!(inflated, filtered, "{}", what);
+                assert_eq!(zdata[zdata.len() - 4..], slow_adler32(&inflated).to_be_bytes(), "{}", what);
+                assert_eq!(zdata[..2], idat_stream(&single)[..2], "{}", what);
+
+                #[cfg(feature = "c-tests")]
+                assert_eq!(crate::libpng::zlib_uncompress(&zdata, filtered.len()), Some(filtered.clone()), "{}", what);
+
+                let mut decoder = PngDecoder::new(&png);
+                decoder.read_info().unwrap();
+                assert_eq!(decoder.read_image().unwrap(), image.concat(), "{}", what);
+            }
+        }
+    }
+}
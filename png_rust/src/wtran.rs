@@ -0,0 +1,634 @@
+use crate::Png;
+use crate::PngColor;
+use crate::PngFlags;
+use crate::PngMng;
+use crate::PngFilterType;
+use crate::PngTransformations;
+use crate::png_info::PngColor8;
+use crate::trans::PngRowInfo;
+use std::slice;
+
+/*******************************************************************************
+ *
+ *                               Png C API
+ *
+ ******************************************************************************/
+
+/* Transform the row in png_ptr->row_buf, as png_do_write_transformations did
+ * after the user transform function of the application.
+ */
+#[no_mangle]
+pub unsafe extern fn png_rust_do_write_transformations(this: *mut Png, row_info: *mut PngRowInfo)
+{
+    if let (Some(png_ptr), Some(row_info)) = (this.as_mut(), row_info.as_mut())
+    {
+        let row = slice::from_raw_parts_mut((png_ptr.row_buf + 1) as *mut u8, row_info.rowbytes);
+        png_ptr.do_write_transformations(row_info, row);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_rust_do_write_intrapixel(this: *const Png, row_info: *const PngRowInfo)
+{
+    if let (Some(png_ptr), Some(row_info)) = (this.as_ref(), row_info.as_ref())
+    {
+        let row = slice::from_raw_parts_mut((png_ptr.row_buf + 1) as *mut u8, row_info.rowbytes);
+        png_ptr.do_write_intrapixel(row_info, row);
+    }
+}
+
+/*******************************************************************************
+ *
+ *                               Png RUST
+ *
+ ******************************************************************************/
+
+impl Png {
+    /* The row info of a row as the application gives it to the writer */
+    pub fn to_usr_row_info(&self) -> PngRowInfo
+    {
+        let pixel_depth = self.usr_bit_depth * self.usr_channels;
+
+        PngRowInfo {
+            width: self.usr_width,
+            color_type: self.color_type,
+            bit_depth: self.usr_bit_depth,
+            channels: self.usr_channels,
+            pixel_depth,
+            rowbytes: Png::compute_rowbytes(pixel_depth, self.usr_width as usize),
+        }
+    }
+
+    /* Transform the data according to the user's wishes.  The order of
+     * transformations is significant.
+     */
+    pub fn do_write_transformations(&mut self, row_info: &mut PngRowInfo, row: &mut [u8])
+    {
+        if self.transformations.contains(PngTransformations::USER_TRANSFORM)
+        {
+            self.do_write_user_transform(row_info, row);
+        }
+
+        if self.transformations.contains(PngTransformations::FILLER)
+        {
+            row_info.do_strip_channel(row, !self.flags.contains(PngFlags::FILLER_AFTER));
+        }
+
+        if self.transformations.contains(PngTransformations::PACKSWAP)
+        {
+            row_info.do_packswap(row);
+        }
+
+        if self.transformations.contains(PngTransformations::PACK)
+        {
+            row_info.do_pack(row, self.bit_depth);
+        }
+
+        if self.transformations.contains(PngTransformations::SWAP_BYTES)
+        {
+            row_info.do_swap(row);
+        }
+
+        if self.transformations.contains(PngTransformations::SHIFT)
+        {
+            row_info.do_shift(row, &self.shift);
+        }
+
+        if self.transformations.contains(PngTransformations::SWAP_ALPHA)
+        {
+            row_info.do_write_swap_alpha(row);
+        }
+
+        if self.transformations.contains(PngTransformations::INVERT_ALPHA)
+        {
+            row_info.do_write_invert_alpha(row);
+        }
+
+        if self.transformations.contains(PngTransformations::BGR)
+        {
+            row_info.do_bgr(row);
+        }
+
+        if self.transformations.contains(PngTransformations::INVERT_MONO)
+        {
+            row_info.do_invert(row);
+        }
+    }
+
+    /* Intrapixel differencing, only when filter method 64 is written: the
+     * Png is not writing a PNG signature (the method is only used in PNG
+     * datastreams embedded in MNG datastreams), the application permitted
+     * PngMng::Filter64 and the image is RGB or RGBA.
+     */
+    pub fn do_write_intrapixel(&self, row_info: &PngRowInfo, row: &mut [u8])
+    {
+        if self.mng_features_permitted.contains(PngMng::Filter64) &&
+            self.filter_type == PngFilterType::Differencing
+        {
+            row_info.do_write_intrapixel(row);
+        }
+    }
+
+    /* Transform, in place, a row given by the application, described by
+     * row_info (see to_usr_row_info), into a row of the image ready to be
+     * filtered: the write transformations, then intrapixel differencing.
+     */
+    pub fn do_write_row_transformations(&mut self, row_info: &mut PngRowInfo, row: &mut [u8]) -> Result<(), &'static str>
+    {
+        if row.len() < row_info.rowbytes
+        {
+            return Err("row is shorter than the rows of the application");
+        }
+
+        if !self.transformations.is_empty()
+        {
+            self.do_write_transformations(row_info, row);
+        }
+
+        /* At this point the row_info pixel depth must match the output depth */
+        if row_info.pixel_depth != self.pixel_depth
+        {
+            return Err("internal write transform logic error");
+        }
+
+        self.do_write_intrapixel(row_info, row);
+
+        Ok(())
+    }
+}
+
+/* Shift a sample of 'start' + 'dec' bits or less to the left by 'start' bits,
+ * replicating its high bits in the low ones as many times as they fit.
+ */
+fn shift_sample(value: u32, start: i32, dec: i32, mask: u32) -> u32
+{
+    let mut out = 0;
+    let mut j = start;
+
+    while j > -dec {
+        if j > 0
+        {
+            out |= value << j;
+        }
+        else
+        {
+            out |= (value >> -j) & mask;
+        }
+
+        j -= dec;
+    }
+
+    out
+}
+
+impl PngRowInfo {
+    /* Pack pixels into bytes.  Pass the true bit depth in bit_depth.  The
+     * row_info bit depth should be 8 (one pixel per byte).  The channels
+     * should be 1 (this only happens on grayscale and paletted images).
+     */
+    pub(crate) fn do_pack(&mut self, row: &mut [u8], bit_depth: u8)
+    {
+        if self.bit_depth != 8 || self.channels != 1
+        {
+            return;
+        }
+
+        if let 1 | 2 | 4 = bit_depth
+        {
+            let width = self.width as usize;
+            let per_byte = (8 / bit_depth) as usize;
+            let mask = (1u8 << bit_depth) - 1;
+
+            /* Each output byte only uses input bytes at or after it */
+            for dp in 0..width.div_ceil(per_byte) {
+                let mut v = 0u8;
+
+                for k in 0..per_byte {
+                    let sp = dp * per_byte + k;
+                    let value = match (sp < width, bit_depth) {
+                        (false, _) => 0,
+                        /* Any non-zero value is a one at depth 1 */
+                        (true, 1) => (row[sp] != 0) as u8,
+                        (true, _) => row[sp] & mask,
+                    };
+
+                    v |= value << (8 - bit_depth as usize * (k + 1));
+                }
+
+                row[dp] = v;
+            }
+        }
+
+        self.bit_depth = bit_depth;
+        self.pixel_depth = bit_depth * self.channels;
+        self.rowbytes = Png::compute_rowbytes(self.pixel_depth, self.width as usize);
+    }
+
+    /* Shift pixel values to take advantage of whole range.  Pass the
+     * true number of bits in bit_depth.  The row should be packed
+     * according to row_info->bit_depth.  Thus, if you had a row of
+     * bit depth 4, but the pixels only had values from 0 to 7, you
+     * would pass 3 as bit_depth, and this routine would translate the
+     * data to 0 to 15.
+     */
+    pub(crate) fn do_shift(&self, row: &mut [u8], bit_depth: &PngColor8)
+    {
+        if self.color_type == PngColor::TYPE_PALETTE
+        {
+            return;
+        }
+
+        let mut sig_bits = Vec::with_capacity(4);
+
+        if self.color_type.contains(PngColor::MASK_COLOR)
+        {
+            sig_bits.extend_from_slice(&[bit_depth.red, bit_depth.green, bit_depth.blue]);
+        }
+        else
+        {
+            sig_bits.push(bit_depth.gray);
+        }
+
+        if self.color_type.contains(PngColor::MASK_ALPHA)
+        {
+            sig_bits.push(bit_depth.alpha);
+        }
+
+        /* Nothing sensible can be done with a channel of no significant bits,
+         * or of more than the bit depth.
+         */
+        if sig_bits.iter().any(|&bits| bits == 0 || bits > self.bit_depth)
+        {
+            return;
+        }
+
+        let shift_start: Vec<i32> = sig_bits.iter().map(|&bits| self.bit_depth as i32 - bits as i32).collect();
+        let shift_dec: Vec<i32> = sig_bits.iter().map(|&bits| bits as i32).collect();
+        let channels = sig_bits.len();
+        let samples = channels * self.width as usize;
+
+        match self.bit_depth {
+            /* With low row depths, could only be grayscale, so one channel */
+            1 | 2 | 4 => {
+                let mask = match (self.bit_depth, bit_depth.gray) {
+                    (2, 1) => 0x55,
+                    (4, 3) => 0x11,
+                    _ => 0xff,
+                };
+
+                for byte in row[..self.rowbytes].iter_mut() {
+                    *byte = shift_sample(*byte as u32, shift_start[0], shift_dec[0], mask) as u8;
+                }
+            },
+            8 => {
+                for (i, byte) in row[..samples].iter_mut().enumerate() {
+                    let c = i % channels;
+                    *byte = shift_sample(*byte as u32, shift_start[c], shift_dec[c], 0xff) as u8;
+                }
+            },
+            _ => {
+                for (i, sample) in row[..2 * samples].chunks_mut(2).enumerate() {
+                    let c = i % channels;
+                    let value = u16::from_be_bytes([sample[0], sample[1]]) as u32;
+                    let value = shift_sample(value, shift_start[c], shift_dec[c], 0xffff) as u16;
+                    sample.copy_from_slice(&value.to_be_bytes());
+                }
+            },
+        }
+    }
+
+    /* Move the alpha channel from the start of the pixels (ARGB, AG) to their
+     * end (RGBA, GA).
+     */
+    fn do_write_swap_alpha(&self, row: &mut [u8])
+    {
+        if !self.color_type.contains(PngColor::MASK_ALPHA) ||
+            (self.bit_depth != 8 && self.bit_depth != 16)
+        {
+            return;
+        }
+
+        let sample_bytes = self.bit_depth as usize / 8;
+        let pixel_bytes = sample_bytes * self.channels as usize;
+
+        for pixel in row[..pixel_bytes * self.width as usize].chunks_mut(pixel_bytes) {
+            pixel.rotate_left(sample_bytes);
+        }
+    }
+
+    /* Invert the alpha channel of RGBA and GA pixels */
+    fn do_write_invert_alpha(&self, row: &mut [u8])
+    {
+        if !self.color_type.contains(PngColor::MASK_ALPHA) ||
+            (self.bit_depth != 8 && self.bit_depth != 16)
+        {
+            return;
+        }
+
+        let sample_bytes = self.bit_depth as usize / 8;
+        let pixel_bytes = sample_bytes * self.channels as usize;
+
+        for pixel in row[..pixel_bytes * self.width as usize].chunks_mut(pixel_bytes) {
+            for byte in pixel[pixel_bytes - sample_bytes..].iter_mut() {
+                *byte = 255 - *byte;
+            }
+        }
+    }
+
+    /* Performs intrapixel differencing: green is subtracted from red and
+     * blue.
+     */
+    fn do_write_intrapixel(&self, row: &mut [u8])
+    {
+        let pixel_samples = match self.color_type {
+            PngColor::TYPE_RGB => 3,
+            PngColor::TYPE_RGB_ALPHA => 4,
+            _ => { return; },
+        };
+
+        match self.bit_depth {
+            8 => {
+                for pixel in row[..pixel_samples * self.width as usize].chunks_mut(pixel_samples) {
+                    pixel[0] = pixel[0].wrapping_sub(pixel[1]);
+                    pixel[2] = pixel[2].wrapping_sub(pixel[1]);
+                }
+            },
+            16 => {
+                let pixel_bytes = 2 * pixel_samples;
+
+                for pixel in row[..pixel_bytes * self.width as usize].chunks_mut(pixel_bytes) {
+                    let s0 = u16::from_be_bytes([pixel[0], pixel[1]]);
+                    let s1 = u16::from_be_bytes([pixel[2], pixel[3]]);
+                    let s2 = u16::from_be_bytes([pixel[4], pixel[5]]);
+
+                    pixel[0..2].copy_from_slice(&s0.wrapping_sub(s1).to_be_bytes());
+                    pixel[4..6].copy_from_slice(&s2.wrapping_sub(s1).to_be_bytes());
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::PngDecoder;
+    use crate::png_info::PngInfo;
+    use crate::wutil::ChunkWriter;
+
+    const GRAY: PngColor = PngColor::TYPE_GRAY;
+    const GA: PngColor = PngColor::TYPE_GRAY_ALPHA;
+    const RGB: PngColor = PngColor::TYPE_RGB;
+    const RGBA: PngColor = PngColor::TYPE_RGB_ALPHA;
+
+    /* The write transformations, set once the info is written */
+    #[derive(Clone, Copy)]
+    enum Transform {
+        FillerBefore,
+        FillerAfter,
+        Packing,
+        Packswap,
+        Swap,
+        Shift(PngColor8),
+        SwapAlpha,
+        InvertAlpha,
+        Bgr,
+        InvertMono,
+    }
+
+    impl Transform {
+        fn set(self, png_ptr: &mut Png)
+        {
+            match self {
+                Transform::FillerBefore => png_ptr.set_filler(0, 0),
+                Transform::FillerAfter => png_ptr.set_filler(0, 1),
+                Transform::Packing => png_ptr.set_packing(),
+                Transform::Packswap => png_ptr.set_packswap(),
+                Transform::Swap => png_ptr.set_swap(),
+                Transform::Shift(true_bits) => png_ptr.set_shift(true_bits),
+                Transform::SwapAlpha => png_ptr.set_swap_alpha(),
+                Transform::InvertAlpha => png_ptr.set_invert_alpha(),
+                Transform::Bgr => png_ptr.set_bgr(),
+                Transform::InvertMono => png_ptr.set_invert_mono(),
+            }
+        }
+
+        #[cfg(feature = "c-tests")]
+        unsafe fn set_c(self, png_ptr: crate::libpng::PngStructp)
+        {
+            use crate::libpng::*;
+
+            match self {
+                Transform::FillerBefore => png_set_filler(png_ptr, 0, 0),
+                Transform::FillerAfter => png_set_filler(png_ptr, 0, 1),
+                Transform::Packing => png_set_packing(png_ptr),
+                Transform::Packswap => png_set_packswap(png_ptr),
+                Transform::Swap => png_set_swap(png_ptr),
+                Transform::Shift(true_bits) => png_set_shift(png_ptr, &true_bits),
+                Transform::SwapAlpha => png_set_swap_alpha(png_ptr),
+                Transform::InvertAlpha => png_set_invert_alpha(png_ptr),
+                Transform::Bgr => png_set_bgr(png_ptr),
+                Transform::InvertMono => png_set_invert_mono(png_ptr),
+            }
+        }
+    }
+
+    const fn sig_gray(gray: u8, alpha: u8) -> Transform
+    {
+        Transform::Shift(PngColor8 { red: 0, green: 0, blue: 0, gray, alpha })
+    }
+
+    const fn sig_rgb(red: u8, green: u8, blue: u8, alpha: u8) -> Transform
+    {
+        Transform::Shift(PngColor8 { red, green, blue, gray: 0, alpha })
+    }
+
+    /* A row of the application and the row of the image pngwtran.c wrote for
+     * it with 'transforms', the image being of 'color_type' and 'bit_depth'.
+     */
+    struct Case {
+        color_type: PngColor,
+        bit_depth: u8,
+        width: u32,
+        transforms: &'static [Transform],
+        input: &'static [u8],
+        output: &'static [u8],
+    }
+
+    const CASES: [Case; 30] = [
+        /* Filler stripped after or before the pixels */
+        Case { color_type: RGB, bit_depth: 8, width: 2, transforms: &[Transform::FillerAfter],
+               input: &[1, 2, 3, 0xff, 4, 5, 6, 0xff], output: &[1, 2, 3, 4, 5, 6] },
+        Case { color_type: RGB, bit_depth: 8, width: 2, transforms: &[Transform::FillerBefore],
+               input: &[0xff, 1, 2, 3, 0xff, 4, 5, 6], output: &[1, 2, 3, 4, 5, 6] },
+        Case { color_type: GRAY, bit_depth: 16, width: 2, transforms: &[Transform::FillerAfter],
+               input: &[1, 2, 0xff, 0xff, 3, 4, 0, 0], output: &[1, 2, 3, 4] },
+        Case { color_type: GRAY, bit_depth: 8, width: 3, transforms: &[Transform::FillerBefore],
+               input: &[9, 1, 9, 2, 9, 3], output: &[1, 2, 3] },
+        /* Packing: any non zero value is a one at depth 1, higher bits are
+         * dropped at depths 2 and 4.
+         */
+        Case { color_type: GRAY, bit_depth: 1, width: 10, transforms: &[Transform::Packing],
+               input: &[1, 0, 5, 1, 0, 0, 0, 1, 0xff, 0], output: &[0xb1, 0x80] },
+        Case { color_type: GRAY, bit_depth: 2, width: 5, transforms: &[Transform::Packing],
+               input: &[3, 0, 1, 0x12, 0x13], output: &[0xc6, 0xc0] },
+        Case { color_type: GRAY, bit_depth: 4, width: 3, transforms: &[Transform::Packing],
+               input: &[0xa, 0x5, 0xf], output: &[0xa5, 0xf0] },
+        /* Packswap comes before packing, when the rows still have one pixel
+         * per byte, so it only changes packed rows.
+         */
+        Case { color_type: GRAY, bit_depth: 2, width: 5, transforms: &[Transform::Packswap],
+               input: &[0x93, 0x03], output: &[0xc6, 0xc0] },
+        Case { color_type: GRAY, bit_depth: 1, width: 8, transforms: &[Transform::Packswap],
+               input: &[0x8d], output: &[0xb1] },
+        Case { color_type: GRAY, bit_depth: 2, width: 5, transforms: &[Transform::Packswap, Transform::Packing],
+               input: &[3, 0, 1, 2, 3], output: &[0xc6, 0xc0] },
+        /* Byte swapping of 16-bit samples */
+        Case { color_type: GRAY, bit_depth: 16, width: 2, transforms: &[Transform::Swap],
+               input: &[0x34, 0x12, 0x78, 0x56], output: &[0x12, 0x34, 0x56, 0x78] },
+        /* sBIT shift: the significant bits are replicated in the low ones */
+        Case { color_type: GRAY, bit_depth: 8, width: 3, transforms: &[sig_gray(5, 0)],
+               input: &[0x1f, 0x10, 0x01], output: &[0xff, 0x84, 0x08] },
+        Case { color_type: RGB, bit_depth: 8, width: 2, transforms: &[sig_rgb(3, 8, 1, 0)],
+               input: &[5, 0x42, 1, 2, 0x99, 0], output: &[0xb6, 0x42, 0xff, 0x49, 0x99, 0] },
+        Case { color_type: GRAY, bit_depth: 2, width: 4, transforms: &[sig_gray(1, 0)],
+               input: &[0x45], output: &[0xcf] },
+        Case { color_type: GRAY, bit_depth: 4, width: 2, transforms: &[sig_gray(3, 0)],
+               input: &[0x72], output: &[0xf4] },
+        Case { color_type: GRAY, bit_depth: 16, width: 1, transforms: &[sig_gray(12, 0)],
+               input: &[0x0a, 0xbc], output: &[0xab, 0xca] },
+        Case { color_type: GA, bit_depth: 8, width: 1, transforms: &[sig_gray(8, 4)],
+               input: &[0x42, 0x0c], output: &[0x42, 0xcc] },
+        /* A channel of no significant bits leaves the row alone */
+        Case { color_type: GA, bit_depth: 8, width: 1, transforms: &[sig_gray(4, 0)],
+               input: &[0x0c, 0x0c], output: &[0x0c, 0x0c] },
+        /* Alpha moved from the start of the pixels to their end */
+        Case { color_type: GA, bit_depth: 8, width: 2, transforms: &[Transform::SwapAlpha],
+               input: &[9, 1, 8, 2], output: &[1, 9, 2, 8] },
+        Case { color_type: RGBA, bit_depth: 8, width: 1, transforms: &[Transform::SwapAlpha],
+               input: &[9, 1, 2, 3], output: &[1, 2, 3, 9] },
+        Case { color_type: GA, bit_depth: 16, width: 1, transforms: &[Transform::SwapAlpha],
+               input: &[9, 8, 1, 2], output: &[1, 2, 9, 8] },
+        Case { color_type: RGBA, bit_depth: 16, width: 1, transforms: &[Transform::SwapAlpha],
+               input: &[9, 8, 1, 2, 3, 4, 5, 6], output: &[1, 2, 3, 4, 5, 6, 9, 8] },
+        /* Alpha inverted */
+        Case { color_type: RGBA, bit_depth: 8, width: 1, transforms: &[Transform::InvertAlpha],
+               input: &[1, 2, 3, 0x0f], output: &[1, 2, 3, 0xf0] },
+        Case { color_type: GA, bit_depth: 16, width: 1, transforms: &[Transform::InvertAlpha],
+               input: &[1, 2, 0x12, 0x34], output: &[1, 2, 0xed, 0xcb] },
+        /* Red and blue swapped, gray inverted */
+        Case { color_type: RGBA, bit_depth: 16, width: 1, transforms: &[Transform::Bgr],
+               input: &[5, 6, 3, 4, 1, 2, 7, 8], output: &[1, 2, 3, 4, 5, 6, 7, 8] },
+        Case { color_type: GRAY, bit_depth: 1, width: 8, transforms: &[Transform::InvertMono],
+               input: &[0xa5], output: &[0x5a] },
+        /* The transformations in the order of png_do_write_transformations */
+        Case { color_type: GRAY, bit_depth: 2, width: 4, transforms: &[sig_gray(1, 0), Transform::Packing],
+               input: &[1, 0, 1, 1], output: &[0xcf] },
+        Case { color_type: GRAY, bit_depth: 16, width: 1, transforms: &[sig_gray(12, 0), Transform::Swap],
+               input: &[0xbc, 0x0a], output: &[0xab, 0xca] },
+        Case { color_type: RGBA, bit_depth: 8, width: 1, transforms: &[Transform::InvertAlpha, Transform::SwapAlpha],
+               input: &[0x0f, 1, 2, 3], output: &[1, 2, 3, 0xf0] },
+        Case { color_type: RGB, bit_depth: 8, width: 1, transforms: &[Transform::Bgr, Transform::FillerAfter],
+               input: &[3, 2, 1, 0xff], output: &[1, 2, 3] },
+    ];
+
+    fn case_info(case: &Case) -> PngInfo
+    {
+        let mut info = PngInfo::new();
+        info.width = case.width;
+        info.height = 1;
+        info.bit_depth = case.bit_depth;
+        info.color_type = case.color_type;
+
+        info
+    }
+
+    /* The row of the image decoded from 'png' */
+    fn image_row(png: &[u8]) -> Vec<u8>
+    {
+        let mut decoder = PngDecoder::new(png);
+        decoder.read_info().unwrap();
+        decoder.read_image().unwrap()
+    }
+
+    #[test]
+    fn write_transformations()
+    {
+        for (i, case) in CASES.iter().enumerate() {
+            let mut info = case_info(case);
+            let mut png_ptr = Png::new();
+            let mut writer = ChunkWriter::new(Vec::new());
+
+            png_ptr.write_info(&mut writer, &mut info).unwrap();
+            for &transform in case.transforms {
+                transform.set(&mut png_ptr);
+            }
+            assert_eq!(png_ptr.to_usr_row_info().rowbytes, case.input.len(), "case {}", i);
+
+            png_ptr.write_row(&mut writer, case.input).unwrap();
+            png_ptr.write_end(&mut writer, Some(&mut info)).unwrap();
+
+            assert_eq!(image_row(&writer.into_inner()), case.output, "case {}", i);
+        }
+    }
+
+    /* The rows of the C writer, whose png_do_write_transformations runs the
+     * transformations of the Png.
+     */
+    #[cfg(feature = "c-tests")]
+    #[test]
+    fn png_do_write_transformations()
+    {
+        for (i, case) in CASES.iter().enumerate() {
+            let png = crate::libpng::write_png_transformed(&case_info(case), &[case.input.to_vec()], |png_ptr| {
+                for &transform in case.transforms {
+                    unsafe { transform.set_c(png_ptr) };
+                }
+            });
+
+            assert_eq!(image_row(&png), case.output, "case {}", i);
+        }
+    }
+
+    #[test]
+    fn intrapixel_differencing()
+    {
+        let cases = [
+            (RGB, 8, &[10u8, 5, 3, 0, 0, 0][..], &[5u8, 5, 254, 0, 0, 0][..]),
+            (RGBA, 8, &[10, 5, 3, 7], &[5, 5, 254, 7]),
+            (RGB, 16, &[0x12, 0x34, 0x01, 0x00, 0x00, 0x10], &[0x11, 0x34, 0x01, 0x00, 0xff, 0x10]),
+            (RGBA, 16, &[0x12, 0x34, 0x01, 0x00, 0x00, 0x10, 9, 9], &[0x11, 0x34, 0x01, 0x00, 0xff, 0x10, 9, 9]),
+            (GA, 8, &[10, 5], &[10, 5]),
+        ];
+
+        for &(color_type, bit_depth, input, output) in cases.iter() {
+            let channels = match color_type {
+                RGB => 3,
+                RGBA => 4,
+                _ => 2,
+            };
+            let width = (8 * input.len() / (bit_depth as usize * channels)) as u32;
+            let mut row = input.to_vec();
+
+            PngRowInfo::new(width, color_type, bit_depth).do_write_intrapixel(&mut row);
+            assert_eq!(row, output, "{:?} {} bits", color_type, bit_depth);
+        }
+
+        /* Only done for filter method 64 with the MNG feature permitted */
+        let row_info = PngRowInfo::new(1, RGB, 8);
+        let mut png_ptr = Png::new();
+        let mut row = [10, 5, 3];
+
+        png_ptr.do_write_intrapixel(&row_info, &mut row);
+        assert_eq!(row, [10, 5, 3]);
+
+        png_ptr.filter_type = PngFilterType::Differencing;
+        png_ptr.do_write_intrapixel(&row_info, &mut row);
+        assert_eq!(row, [10, 5, 3]);
+
+        png_ptr.mng_features_permitted = PngMng::Filter64;
+        png_ptr.do_write_intrapixel(&row_info, &mut row);
+        assert_eq!(row, [5, 5, 254]);
+    }
+}
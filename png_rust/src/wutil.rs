@@ -0,0 +1,1691 @@
+use crate::Png;
+use crate::png_info::{PngInfo, PngPaletteColor, PngColor8, PngColor16, PngXy, PngTime};
+use crate::png_info::{PngText, PngTextCompression, PngSplt, PngPcal};
+use crate::png_info::{PngCicp, PngMasteringDisplay, PngContentLightLevel};
+use crate::PngMode;
+use crate::PngMng;
+use crate::PngColor;
+use crate::PngFilter;
+use crate::PngInfoChunk;
+use crate::PngInterlace;
+use crate::PngCompressionType;
+use crate::PngFilterType;
+use crate::PngChunkType;
+use crate::PngHandleChunk;
+use crate::PngTransformations;
+use crate::deflate::{PngFlush, ZlibEncoder};
+use crate::interlace;
+use crc::crc32;
+use std::io;
+use std::io::Write;
+
+/* The PNG signature, the first 8 bytes of every PNG datastream */
+pub const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+/* Maximum length of the data of a chunk */
+pub const PNG_UINT_31_MAX: u32 = 0x7fff_ffff;
+
+/* Maximum number of entries in a palette */
+pub const PNG_MAX_PALETTE_LENGTH: usize = 256;
+
+/* Number of equation types of the pCAL chunk */
+const PNG_EQUATION_LAST: u8 = 4;
+
+/* What follows a row written: the next row of the pass, the first row of the
+ * next pass with pixels or the end of the image data.
+ */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(i32)]
+pub(crate) enum PngRowEnd {
+    Row   = 0,
+    Pass  = 1,
+    Image = 2,
+}
+
+pub(crate) fn invalid_input(msg: &'static str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/* Check a keyword for validity and return it cleaned up: leading, trailing
+ * and repeated spaces are removed, characters which are not printable
+ * Latin-1 are replaced by a space and it is truncated to 79 characters.
+ * None is returned when nothing is left of it.
+ */
+fn check_keyword(key: &[u8]) -> Option<Vec<u8>>
+{
+    let mut new_key = Vec::with_capacity(79);
+    let mut space = true;
+
+    for &ch in key {
+        if new_key.len() >= 79 || ch == 0
+        {
+            break;
+        }
+
+        if (ch > 32 && ch <= 126) || ch >= 161
+        {
+            new_key.push(ch);
+            space = false;
+        }
+        else if !space
+        {
+            /* A space or an invalid character when one wasn't seen
+             * immediately before; output just a space.
+             */
+            new_key.push(b' ');
+            space = true;
+        }
+    }
+
+    if space  /* trailing space */
+    {
+        new_key.pop();
+    }
+
+    match new_key.is_empty() {
+        true => None,
+        false => Some(new_key),
+    }
+}
+
+/*******************************************************************************
+ *
+ *                               Png C API
+ *
+ ******************************************************************************/
+
+#[no_mangle]
+pub unsafe extern fn png_rust_write_start_row(this: *mut Png)
+{
+    match this.as_mut() {
+        None => { return; },
+        Some(png_ptr) => { png_ptr.start_row(); },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn png_rust_write_finish_row(this: *mut Png) -> i32
+{
+    match this.as_mut() {
+        None => PngRowEnd::Image as i32,
+        Some(png_ptr) => png_ptr.finish_row() as i32,
+    }
+}
+
+/*******************************************************************************
+ *
+ *                               Png RUST
+ *
+ ******************************************************************************/
+
+/* Writes PNG chunks to a stream: the length, the chunk name, the data and the
+ * CRC of the name and the data, computed as the data is written.  A chunk is
+ * written all at once with write_chunk, or in pieces with write_chunk_header,
+ * write_chunk_data and write_chunk_end.
+ */
+pub struct ChunkWriter<W: Write> {
+    writer: W,
+    crc: u32,           /* CRC of the current chunk */
+    remaining: u32,     /* data of the current chunk still to write */
+    in_chunk: bool,     /* between write_chunk_header and write_chunk_end */
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn new(writer: W) -> ChunkWriter<W>
+    {
+        ChunkWriter {
+            writer,
+            crc: 0,
+            remaining: 0,
+            in_chunk: false,
+        }
+    }
+
+    /* Write the signature, less the 'sig_bytes' first bytes when they have
+     * already been written by the application.
+     */
+    pub fn write_sig(&mut self, sig_bytes: usize) -> io::Result<()>
+    {
+        let sig_bytes = sig_bytes.min(PNG_SIGNATURE.len());
+
+        self.writer.write_all(&PNG_SIGNATURE[sig_bytes..])
+    }
+
+    /* Start a chunk of 'length' bytes of data */
+    pub fn write_chunk_header(&mut self, chunk_name: [u8; 4], length: u32) -> io::Result<()>
+    {
+        if self.in_chunk
+        {
+            return Err(invalid_input("chunk header written inside a chunk"));
+        }
+
+        if length > PNG_UINT_31_MAX
+        {
+            return Err(invalid_input("length exceeds PNG maximum"));
+        }
+
+        let mut buf = [0u8; 8];
+        buf[..4].copy_from_slice(&length.to_be_bytes());
+        buf[4..].copy_from_slice(&chunk_name);
+        self.writer.write_all(&buf)?;
+
+        /* The CRC covers the chunk name but not the length */
+        self.crc = crc32::update(0, &crc32::IEEE_TABLE, &chunk_name);
+        self.remaining = length;
+        self.in_chunk = true;
+
+        Ok(())
+    }
+
+    /* Write data of the current chunk, the calls must add up to the length
+     * given to write_chunk_header.
+     */
+    pub fn write_chunk_data(&mut self, data: &[u8]) -> io::Result<()>
+    {
+        if !self.in_chunk
+        {
+            return Err(invalid_input("chunk data written outside a chunk"));
+        }
+
+        if data.len() > self.remaining as usize
+        {
+            return Err(invalid_input("chunk data exceeds the chunk length"));
+        }
+
+        self.writer.write_all(data)?;
+        self.crc = crc32::update(self.crc, &crc32::IEEE_TABLE, data);
+        self.remaining -= data.len() as u32;
+
+        Ok(())
+    }
+
+    /* Finish the current chunk by writing its CRC */
+    pub fn write_chunk_end(&mut self) -> io::Result<()>
+    {
+        if !self.in_chunk
+        {
+            return Err(invalid_input("chunk end written outside a chunk"));
+        }
+
+        if self.remaining != 0
+        {
+            return Err(invalid_input("chunk data is shorter than the chunk length"));
+        }
+
+        self.in_chunk = false;
+        self.writer.write_all(&self.crc.to_be_bytes())
+    }
+
+    /* Write a whole chunk */
+    pub fn write_chunk(&mut self, chunk_name: [u8; 4], data: &[u8]) -> io::Result<()>
+    {
+        if data.len() > PNG_UINT_31_MAX as usize
+        {
+            return Err(invalid_input("length exceeds PNG maximum"));
+        }
+
+        self.write_chunk_header(chunk_name, data.len() as u32)?;
+        self.write_chunk_data(data)?;
+        self.write_chunk_end()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()>
+    {
+        self.writer.flush()
+    }
+
+    pub fn get_ref(&self) -> &W
+    {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W
+    {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W
+    {
+        self.writer
+    }
+}
+
+impl PngChunkType {
+    pub(crate) fn to_bytes(self) -> [u8; 4]
+    {
+        (self as u32).to_be_bytes()
+    }
+}
+
+#[allow(non_snake_case)]
+impl Png {
+    /* Write the signature, or the rest of it if the application already wrote
+     * sig_bytes of it.
+     */
+    pub fn write_sig<W: Write>(&mut self, writer: &mut ChunkWriter<W>) -> io::Result<()>
+    {
+        writer.write_sig(self.sig_bytes)?;
+
+        if self.sig_bytes < 3
+        {
+            self.mode.insert(PngMode::HAVE_PNG_SIGNATURE);
+        }
+
+        Ok(())
+    }
+
+    /* Write the IHDR chunk, and update the Png with the necessary information.
+     * Note that the rest of the write code depends upon this information being
+     * correct.
+     */
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_IHDR<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                width: u32, height: u32, bit_depth: u8,
+                                color_type: PngColor,
+                                filter_type: PngFilterType,
+                                interlace_type: PngInterlace) -> io::Result<()>
+    {
+        /* Check that we have valid input data from the application info */
+        self.channels = match color_type {
+            PngColor::TYPE_GRAY => match bit_depth {
+                1 | 2 | 4 | 8 | 16 => 1,
+                _ => return Err(invalid_input("Invalid bit depth for grayscale image")),
+            },
+            PngColor::TYPE_RGB => match bit_depth {
+                8 | 16 => 3,
+                _ => return Err(invalid_input("Invalid bit depth for RGB image")),
+            },
+            PngColor::TYPE_PALETTE => match bit_depth {
+                1 | 2 | 4 | 8 => 1,
+                _ => return Err(invalid_input("Invalid bit depth for paletted image")),
+            },
+            PngColor::TYPE_GRAY_ALPHA => match bit_depth {
+                8 | 16 => 2,
+                _ => return Err(invalid_input("Invalid bit depth for grayscale+alpha image")),
+            },
+            PngColor::TYPE_RGB_ALPHA => match bit_depth {
+                8 | 16 => 4,
+                _ => return Err(invalid_input("Invalid bit depth for RGBA image")),
+            },
+            _ => return Err(invalid_input("Invalid image color type specified")),
+        };
+
+        /* Write filter_method 64 (intrapixel differencing) only if
+         * 1. Libpng did not write a PNG signature (this filter_method is only
+         *    used in PNG datastreams that are embedded in MNG datastreams) and
+         * 2. The application called permit_mng_features with a mask that
+         *    included Filter64 and
+         * 3. The color_type is RGB or RGBA
+         * otherwise the base filter method is written instead.
+         */
+        let filter_type = match filter_type {
+            PngFilterType::Differencing if self.mng_features_permitted.contains(PngMng::Filter64) &&
+                !self.mode.contains(PngMode::HAVE_PNG_SIGNATURE) &&
+                (color_type == PngColor::TYPE_RGB || color_type == PngColor::TYPE_RGB_ALPHA) => {
+                PngFilterType::Differencing
+            },
+            _ => PngFilterType::Base,
+        };
+
+        /* Save the relevant information */
+        self.bit_depth = bit_depth;
+        self.color_type = color_type;
+        self.interlaced = interlace_type;
+        self.filter_type = filter_type;
+        self.width = width;
+        self.height = height;
+
+        self.pixel_depth = bit_depth * self.channels;
+        self.rowbytes = Png::compute_rowbytes(self.pixel_depth, width as usize);
+        /* Set the usr info, so any transformations can modify it */
+        self.usr_width = self.width;
+        self.usr_bit_depth = self.bit_depth;
+        self.usr_channels = self.channels;
+
+        /* Pack the header information into the buffer */
+        let mut buf = [0u8; 13];
+        buf[0..4].copy_from_slice(&width.to_be_bytes());
+        buf[4..8].copy_from_slice(&height.to_be_bytes());
+        buf[8] = bit_depth;
+        buf[9] = color_type.bits();
+        buf[10] = PngCompressionType::Base as u8; /* the only method defined */
+        buf[11] = filter_type as u8;
+        buf[12] = interlace_type as u8;
+
+        /* Write the chunk */
+        writer.write_chunk(PngChunkType::IHDR.to_bytes(), &buf)?;
+
+        if self.do_filter.is_empty()
+        {
+            self.do_filter = match color_type == PngColor::TYPE_PALETTE || bit_depth < 8 {
+                true => PngFilter::NONE,
+                false => PngFilter::all(),
+            };
+        }
+
+        self.mode = PngMode::HAVE_IHDR; /* not READY_FOR_ZTXT */
+
+        Ok(())
+    }
+
+    /* Write the palette.  A palette which is empty or too large is an error
+     * for paletted images and is not written for the others.
+     */
+    pub fn write_PLTE<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                palette: &[PngPaletteColor]) -> io::Result<()>
+    {
+        let max_palette_length = match self.color_type == PngColor::TYPE_PALETTE {
+            true => 1 << self.bit_depth,
+            false => PNG_MAX_PALETTE_LENGTH,
+        };
+
+        if (!self.mng_features_permitted.contains(PngMng::EmptyPlte) && palette.is_empty()) ||
+            palette.len() > max_palette_length
+        {
+            if self.color_type == PngColor::TYPE_PALETTE
+            {
+                return Err(invalid_input("Invalid number of colors in palette"));
+            }
+
+            return Ok(());
+        }
+
+        /* Ignore the request to write a PLTE chunk in grayscale PNG */
+        if !self.color_type.contains(PngColor::MASK_COLOR)
+        {
+            return Ok(());
+        }
+
+        self.num_palette = palette.len() as u16;
+
+        writer.write_chunk_header(PngChunkType::PLTE.to_bytes(), (palette.len() * 3) as u32)?;
+        for color in palette {
+            writer.write_chunk_data(&[color.red, color.green, color.blue])?;
+        }
+        writer.write_chunk_end()?;
+
+        self.mode.insert(PngMode::HAVE_PLTE);
+
+        Ok(())
+    }
+
+    /* Write an IEND chunk */
+    pub fn write_IEND<W: Write>(&mut self, writer: &mut ChunkWriter<W>) -> io::Result<()>
+    {
+        writer.write_chunk(PngChunkType::IEND.to_bytes(), &[])?;
+        self.mode.insert(PngMode::HAVE_IEND);
+
+        Ok(())
+    }
+
+    /* Write a gAMA chunk, the gamma is in units of 1/100000 */
+    pub fn write_gAMA<W: Write>(&mut self, writer: &mut ChunkWriter<W>, gamma: u32) -> io::Result<()>
+    {
+        writer.write_chunk(PngChunkType::gAMA.to_bytes(), &gamma.to_be_bytes())
+    }
+
+    /* Write a sRGB chunk */
+    pub fn write_sRGB<W: Write>(&mut self, writer: &mut ChunkWriter<W>, srgb_intent: u8) -> io::Result<()>
+    {
+        writer.write_chunk(PngChunkType::sRGB.to_bytes(), &[srgb_intent])
+    }
+
+    /* Write an iCCP chunk, the profile is compressed here */
+    pub fn write_iCCP<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                name: &[u8], profile: &[u8]) -> io::Result<()>
+    {
+        if profile.len() < 132
+        {
+            return Err(invalid_input("ICC profile too short"));
+        }
+
+        let profile_len = u32::from_be_bytes([profile[0], profile[1], profile[2], profile[3]]);
+
+        if profile[8] > 3 && (profile_len & 0x03) != 0
+        {
+            return Err(invalid_input("ICC profile length invalid (not a multiple of 4)"));
+        }
+
+        if profile_len as usize != profile.len()
+        {
+            return Err(invalid_input("Profile length does not match profile"));
+        }
+
+        let mut data = check_keyword(name).ok_or_else(|| invalid_input("iCCP: invalid keyword"))?;
+        data.push(0);
+        data.push(PngCompressionType::Base as u8);
+        data.extend_from_slice(&self.compress_text(profile));
+
+        writer.write_chunk(PngChunkType::iCCP.to_bytes(), &data)
+    }
+
+    /* Write a sPLT chunk */
+    pub fn write_sPLT<W: Write>(&mut self, writer: &mut ChunkWriter<W>, spalette: &PngSplt) -> io::Result<()>
+    {
+        let mut data = check_keyword(&spalette.name).ok_or_else(|| invalid_input("sPLT: invalid keyword"))?;
+        data.push(0);
+        data.push(spalette.depth);
+
+        /* Loop through each palette entry, writing appropriately */
+        for entry in &spalette.entries {
+            if spalette.depth == 8
+            {
+                data.extend_from_slice(&[entry.red as u8, entry.green as u8,
+                                         entry.blue as u8, entry.alpha as u8]);
+            }
+            else
+            {
+                for sample in &[entry.red, entry.green, entry.blue, entry.alpha] {
+                    data.extend_from_slice(&sample.to_be_bytes());
+                }
+            }
+            data.extend_from_slice(&entry.frequency.to_be_bytes());
+        }
+
+        writer.write_chunk(PngChunkType::sPLT.to_bytes(), &data)
+    }
+
+    /* Write the sBIT chunk */
+    pub fn write_sBIT<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                sbit: &PngColor8, color_type: PngColor) -> io::Result<()>
+    {
+        let mut buf = Vec::with_capacity(4);
+
+        if color_type.contains(PngColor::MASK_COLOR)
+        {
+            let maxbits = match color_type == PngColor::TYPE_PALETTE {
+                true => 8,
+                false => self.usr_bit_depth,
+            };
+
+            if [sbit.red, sbit.green, sbit.blue].iter().any(|&bits| bits == 0 || bits > maxbits)
+            {
+                return Err(invalid_input("Invalid sBIT depth specified"));
+            }
+
+            buf.extend_from_slice(&[sbit.red, sbit.green, sbit.blue]);
+        }
+        else
+        {
+            if sbit.gray == 0 || sbit.gray > self.usr_bit_depth
+            {
+                return Err(invalid_input("Invalid sBIT depth specified"));
+            }
+
+            buf.push(sbit.gray);
+        }
+
+        if color_type.contains(PngColor::MASK_ALPHA)
+        {
+            if sbit.alpha == 0 || sbit.alpha > self.usr_bit_depth
+            {
+                return Err(invalid_input("Invalid sBIT depth specified"));
+            }
+
+            buf.push(sbit.alpha);
+        }
+
+        writer.write_chunk(PngChunkType::sBIT.to_bytes(), &buf)
+    }
+
+    /* Write the cHRM chunk, each value is in units of 1/100000 */
+    pub fn write_cHRM<W: Write>(&mut self, writer: &mut ChunkWriter<W>, xy: &PngXy) -> io::Result<()>
+    {
+        let mut buf = [0u8; 32];
+        let values = [xy.white_x, xy.white_y, xy.red_x, xy.red_y,
+                      xy.green_x, xy.green_y, xy.blue_x, xy.blue_y];
+
+        for (out, value) in buf.chunks_mut(4).zip(values.iter()) {
+            out.copy_from_slice(&value.to_be_bytes());
+        }
+
+        writer.write_chunk(PngChunkType::cHRM.to_bytes(), &buf)
+    }
+
+    /* Write a cICP chunk */
+    pub fn write_cICP<W: Write>(&mut self, writer: &mut ChunkWriter<W>, cicp: &PngCicp) -> io::Result<()>
+    {
+        cicp.check().map_err(invalid_input)?;
+
+        let buf = [cicp.colour_primaries, cicp.transfer_function, cicp.matrix_coefficients,
+                   cicp.video_full_range as u8];
+
+        writer.write_chunk(PngChunkType::cICP.to_bytes(), &buf)
+    }
+
+    /* Write a mDCv chunk */
+    pub fn write_mDCv<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                display: &PngMasteringDisplay) -> io::Result<()>
+    {
+        display.check().map_err(invalid_input)?;
+
+        let mut buf = [0u8; 24];
+        let xy = [display.red_x, display.red_y, display.green_x, display.green_y,
+                  display.blue_x, display.blue_y, display.white_x, display.white_y];
+
+        for (out, value) in buf.chunks_mut(2).zip(xy.iter()) {
+            out.copy_from_slice(&value.to_be_bytes());
+        }
+        buf[16..20].copy_from_slice(&display.max_luminance.to_be_bytes());
+        buf[20..].copy_from_slice(&display.min_luminance.to_be_bytes());
+
+        writer.write_chunk(PngChunkType::mDCv.to_bytes(), &buf)
+    }
+
+    /* Write a cLLi chunk */
+    pub fn write_cLLi<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                level: &PngContentLightLevel) -> io::Result<()>
+    {
+        level.check().map_err(invalid_input)?;
+
+        let mut buf = [0u8; 8];
+        buf[..4].copy_from_slice(&level.max_cll.to_be_bytes());
+        buf[4..].copy_from_slice(&level.max_fall.to_be_bytes());
+
+        writer.write_chunk(PngChunkType::cLLi.to_bytes(), &buf)
+    }
+
+    /* Write the tRNS chunk: 'trans_alpha' for paletted images, 'tran' for the
+     * others.  The alpha values are inverted when the alpha channel is.
+     */
+    pub fn write_tRNS<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                trans_alpha: &[u8], tran: &PngColor16,
+                                color_type: PngColor) -> io::Result<()>
+    {
+        match color_type {
+            PngColor::TYPE_PALETTE => {
+                if trans_alpha.is_empty() || trans_alpha.len() > self.num_palette as usize
+                {
+                    return Err(invalid_input("Invalid number of transparent colors specified"));
+                }
+
+                match self.transformations.contains(PngTransformations::INVERT_ALPHA) {
+                    true => {
+                        let inverted: Vec<u8> = trans_alpha.iter().map(|&alpha| 255 - alpha).collect();
+                        writer.write_chunk(PngChunkType::tRNS.to_bytes(), &inverted)
+                    },
+                    false => writer.write_chunk(PngChunkType::tRNS.to_bytes(), trans_alpha),
+                }
+            },
+            PngColor::TYPE_GRAY => {
+                /* One 16-bit value */
+                if tran.gray as u32 >= 1 << self.bit_depth
+                {
+                    return Err(invalid_input("Ignoring attempt to write tRNS chunk out-of-range for bit_depth"));
+                }
+
+                writer.write_chunk(PngChunkType::tRNS.to_bytes(), &tran.gray.to_be_bytes())
+            },
+            PngColor::TYPE_RGB => {
+                /* Three 16-bit values */
+                let buf = rgb16_bytes(tran);
+
+                if self.bit_depth == 8 && (buf[0] | buf[2] | buf[4]) != 0
+                {
+                    return Err(invalid_input("Ignoring attempt to write 16-bit tRNS chunk when bit_depth is 8"));
+                }
+
+                writer.write_chunk(PngChunkType::tRNS.to_bytes(), &buf)
+            },
+            _ => Err(invalid_input("Can't write tRNS with an alpha channel")),
+        }
+    }
+
+    /* Write the background chunk */
+    pub fn write_bKGD<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                back: &PngColor16, color_type: PngColor) -> io::Result<()>
+    {
+        if color_type == PngColor::TYPE_PALETTE
+        {
+            if (self.num_palette != 0 || !self.mng_features_permitted.contains(PngMng::EmptyPlte)) &&
+                back.index as u16 >= self.num_palette
+            {
+                return Err(invalid_input("Invalid background palette index"));
+            }
+
+            writer.write_chunk(PngChunkType::bKGD.to_bytes(), &[back.index])
+        }
+        else if color_type.contains(PngColor::MASK_COLOR)
+        {
+            let buf = rgb16_bytes(back);
+
+            if self.bit_depth == 8 && (buf[0] | buf[2] | buf[4]) != 0
+            {
+                return Err(invalid_input("Ignoring attempt to write 16-bit bKGD chunk when bit_depth is 8"));
+            }
+
+            writer.write_chunk(PngChunkType::bKGD.to_bytes(), &buf)
+        }
+        else
+        {
+            if back.gray as u32 >= 1 << self.bit_depth
+            {
+                return Err(invalid_input("Ignoring attempt to write bKGD chunk out-of-range for bit_depth"));
+            }
+
+            writer.write_chunk(PngChunkType::bKGD.to_bytes(), &back.gray.to_be_bytes())
+        }
+    }
+
+    /* Write the Exif data */
+    pub fn write_eXIf<W: Write>(&mut self, writer: &mut ChunkWriter<W>, exif: &[u8]) -> io::Result<()>
+    {
+        writer.write_chunk(PngChunkType::eXIf.to_bytes(), exif)
+    }
+
+    /* Write the histogram */
+    pub fn write_hIST<W: Write>(&mut self, writer: &mut ChunkWriter<W>, hist: &[u16]) -> io::Result<()>
+    {
+        if hist.len() > self.num_palette as usize
+        {
+            return Err(invalid_input("Invalid number of histogram entries specified"));
+        }
+
+        let data: Vec<u8> = hist.iter().flat_map(|freq| freq.to_be_bytes()).collect();
+
+        writer.write_chunk(PngChunkType::hIST.to_bytes(), &data)
+    }
+
+    /* Write a tEXt chunk */
+    pub fn write_tEXt<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                key: &[u8], text: &[u8]) -> io::Result<()>
+    {
+        let mut data = check_keyword(key).ok_or_else(|| invalid_input("tEXt: invalid keyword"))?;
+
+        /* Make sure we include the 0 after the key */
+        data.push(0);
+        data.extend_from_slice(text);
+
+        writer.write_chunk(PngChunkType::tEXt.to_bytes(), &data)
+    }
+
+    /* Write a compressed text chunk */
+    pub fn write_zTXt<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                key: &[u8], text: &[u8]) -> io::Result<()>
+    {
+        let mut data = check_keyword(key).ok_or_else(|| invalid_input("zTXt: invalid keyword"))?;
+
+        /* Add the keyword separator and the compression method */
+        data.push(0);
+        data.push(PngCompressionType::Base as u8);
+        data.extend_from_slice(&self.compress_text(text));
+
+        writer.write_chunk(PngChunkType::zTXt.to_bytes(), &data)
+    }
+
+    /* Write an iTXt chunk */
+    pub fn write_iTXt<W: Write>(&mut self, writer: &mut ChunkWriter<W>, compression: PngTextCompression,
+                                key: &[u8], lang: &[u8], lang_key: &[u8], text: &[u8]) -> io::Result<()>
+    {
+        let mut data = check_keyword(key).ok_or_else(|| invalid_input("iTXt: invalid keyword"))?;
+
+        /* Set the compression flag */
+        let compressed = match compression {
+            PngTextCompression::None | PngTextCompression::ItxtNone => false,
+            PngTextCompression::Ztxt | PngTextCompression::ItxtZtxt => true,
+        };
+
+        data.push(0);
+        data.push(compressed as u8);
+        data.push(PngCompressionType::Base as u8);
+        data.extend_from_slice(lang);
+        data.push(0);
+        data.extend_from_slice(lang_key);
+        data.push(0);
+
+        match compressed {
+            true => data.extend_from_slice(&self.compress_text(text)),
+            false => data.extend_from_slice(text),
+        }
+
+        writer.write_chunk(PngChunkType::iTXt.to_bytes(), &data)
+    }
+
+    /* Write a text chunk in the chunk its compression asks for */
+    fn write_text<W: Write>(&mut self, writer: &mut ChunkWriter<W>, text: &PngText) -> io::Result<()>
+    {
+        match text.compression {
+            PngTextCompression::None => self.write_tEXt(writer, &text.key, &text.text),
+            PngTextCompression::Ztxt => self.write_zTXt(writer, &text.key, &text.text),
+            PngTextCompression::ItxtNone | PngTextCompression::ItxtZtxt => {
+                self.write_iTXt(writer, text.compression, &text.key, &text.lang,
+                                &text.lang_key, &text.text)
+            },
+        }
+    }
+
+    /* Write the oFFs chunk */
+    pub fn write_oFFs<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                x_offset: i32, y_offset: i32, unit_type: u8) -> io::Result<()>
+    {
+        let mut buf = [0u8; 9];
+        buf[0..4].copy_from_slice(&x_offset.to_be_bytes());
+        buf[4..8].copy_from_slice(&y_offset.to_be_bytes());
+        buf[8] = unit_type;
+
+        writer.write_chunk(PngChunkType::oFFs.to_bytes(), &buf)
+    }
+
+    /* Write the pCAL chunk (described in the PNG extensions document) */
+    pub fn write_pCAL<W: Write>(&mut self, writer: &mut ChunkWriter<W>, pcal: &PngPcal) -> io::Result<()>
+    {
+        if pcal.equation_type >= PNG_EQUATION_LAST
+        {
+            return Err(invalid_input("Unrecognized equation type for pCAL chunk"));
+        }
+
+        if pcal.params.len() > 255
+        {
+            return Err(invalid_input("Too many parameters for pCAL chunk"));
+        }
+
+        let mut data = check_keyword(&pcal.purpose).ok_or_else(|| invalid_input("pCAL: invalid keyword"))?;
+        data.push(0);
+        data.extend_from_slice(&pcal.x0.to_be_bytes());
+        data.extend_from_slice(&pcal.x1.to_be_bytes());
+        data.push(pcal.equation_type);
+        data.push(pcal.params.len() as u8);
+        data.extend_from_slice(&pcal.units);
+
+        /* The units and the parameters are separated by a null, there is no
+         * terminator after the last one.
+         */
+        for param in &pcal.params {
+            data.push(0);
+            data.extend_from_slice(param);
+        }
+
+        writer.write_chunk(PngChunkType::pCAL.to_bytes(), &data)
+    }
+
+    /* Write the sCAL chunk */
+    pub fn write_sCAL_s<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                  unit: u8, width: &[u8], height: &[u8]) -> io::Result<()>
+    {
+        if width.len() + height.len() + 2 > 64
+        {
+            return Err(invalid_input("Can't write sCAL (buffer too small)"));
+        }
+
+        /* The width is NUL terminated, the height is not */
+        let data = [&[unit], width, &[0], height].concat();
+
+        writer.write_chunk(PngChunkType::sCAL.to_bytes(), &data)
+    }
+
+    /* Write the pHYs chunk */
+    pub fn write_pHYs<W: Write>(&mut self, writer: &mut ChunkWriter<W>, x_pixels_per_unit: u32,
+                                y_pixels_per_unit: u32, unit_type: u8) -> io::Result<()>
+    {
+        let mut buf = [0u8; 9];
+        buf[0..4].copy_from_slice(&x_pixels_per_unit.to_be_bytes());
+        buf[4..8].copy_from_slice(&y_pixels_per_unit.to_be_bytes());
+        buf[8] = unit_type;
+
+        writer.write_chunk(PngChunkType::pHYs.to_bytes(), &buf)
+    }
+
+    /* Write the tIME chunk */
+    pub fn write_tIME<W: Write>(&mut self, writer: &mut ChunkWriter<W>, mod_time: &PngTime) -> io::Result<()>
+    {
+        if mod_time.month > 12 || mod_time.month < 1 ||
+            mod_time.day > 31 || mod_time.day < 1 ||
+            mod_time.hour > 23 || mod_time.second > 60
+        {
+            return Err(invalid_input("Invalid time specified for tIME chunk"));
+        }
+
+        let mut buf = [0u8; 7];
+        buf[0..2].copy_from_slice(&mod_time.year.to_be_bytes());
+        buf[2] = mod_time.month;
+        buf[3] = mod_time.day;
+        buf[4] = mod_time.hour;
+        buf[5] = mod_time.minute;
+        buf[6] = mod_time.second;
+
+        writer.write_chunk(PngChunkType::tIME.to_bytes(), &buf)
+    }
+
+    /* Write the unknown chunks of the info at 'location' which are to be
+     * kept, see set_keep_unknown_chunks.
+     */
+    fn write_unknown_chunks<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                      info_ptr: &PngInfo, location: PngMode) -> io::Result<()>
+    {
+        for chunk in info_ptr.unknown_chunks() {
+            if !chunk.location.intersects(location)
+            {
+                continue;
+            }
+
+            /* As in pngwrite.c, unlike the reader, the chunks safe to copy are
+             * written unless never kept, the others only when always kept,
+             * by name or by default.
+             */
+            let keep = self.handle_as_unknown(chunk.name);
+            let write = keep != PngHandleChunk::Never &&
+                (chunk.name[3] & 0x20 != 0 || keep == PngHandleChunk::Always ||
+                 (keep == PngHandleChunk::AsDefault && self.unknown_default == PngHandleChunk::Always));
+
+            if write
+            {
+                writer.write_chunk(chunk.name, &chunk.data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /* The size of the filtered image data, filter bytes included, used to pick
+     * a smaller window for small images.  Only sizes up to the maximum of a
+     * u32 are returned.
+     */
+    pub fn image_size(&self) -> usize
+    {
+        let h = self.height;
+
+        if self.rowbytes >= 32768 || h >= 32768
+        {
+            return 0xffff_ffff;
+        }
+
+        match self.interlaced {
+            PngInterlace::ADAM7 => {
+                /* Interlacing makes the image larger because of the replication
+                 * of both the filter byte and the padding to a byte boundary.
+                 */
+                (0..interlace::PNG_INTERLACE_ADAM7_PASSES).map(|pass| {
+                    match interlace::pass_cols(self.width, pass) {
+                        0 => 0,
+                        pw => (Png::compute_rowbytes(self.pixel_depth, pw as usize) + 1) *
+                              interlace::pass_rows(h, pass) as usize,
+                    }
+                }).sum()
+            },
+            PngInterlace::None => (self.rowbytes + 1) * h as usize,
+        }
+    }
+
+    /* Set up the writing of the rows: the filters which can be used with the
+     * size of the image, and the width and number of rows of the first pass.
+     * With the INTERLACE transformation the application gives all the rows
+     * of the image for each pass, otherwise only the rows of the pass.
+     */
+    pub(crate) fn start_row(&mut self)
+    {
+        let mut filters = self.do_filter;
+
+        /* No previous row with a single row, no previous pixel with a single
+         * column.
+         */
+        if self.height == 1
+        {
+            filters.remove(PngFilter::UP | PngFilter::AVG | PngFilter::PAETH);
+        }
+
+        if self.width == 1
+        {
+            filters.remove(PngFilter::SUB | PngFilter::AVG | PngFilter::PAETH);
+        }
+
+        if filters.is_empty()
+        {
+            filters = PngFilter::NONE;
+        }
+
+        self.do_filter = filters;
+
+        match self.interlaced {
+            PngInterlace::ADAM7 if !self.transformations.contains(PngTransformations::INTERLACE) => {
+                self.num_rows = interlace::pass_rows(self.height, 0);
+                self.usr_width = interlace::pass_cols(self.width, 0);
+            },
+            _ => {
+                self.num_rows = self.height;
+                self.usr_width = self.width;
+            },
+        }
+    }
+
+    /* Called when finished processing a row of data: move to the next row, or
+     * to the first row of the next pass.  Passes without pixels are skipped
+     * when the application gives the rows of each pass (the 'width < 5' and
+     * 'height < 5' cases of the first passes).  With the INTERLACE
+     * transformation every pass takes all the rows of the image, the rows
+     * without pixels in the pass are skipped by write_row instead.
+     */
+    pub(crate) fn finish_row(&mut self) -> PngRowEnd
+    {
+        /* Next row */
+        self.row_number += 1;
+
+        /* See if we are done */
+        if self.row_number < self.num_rows
+        {
+            return PngRowEnd::Row;
+        }
+
+        /* If interlaced, go to next pass */
+        if self.interlaced == PngInterlace::ADAM7
+        {
+            self.row_number = 0;
+
+            if self.transformations.contains(PngTransformations::INTERLACE)
+            {
+                self.pass += 1;
+            }
+            else
+            {
+                /* Loop until we find a non-zero width or height pass */
+                loop {
+                    self.pass += 1;
+
+                    if self.pass >= interlace::PNG_INTERLACE_ADAM7_PASSES
+                    {
+                        break;
+                    }
+
+                    self.usr_width = interlace::pass_cols(self.width, self.pass);
+                    self.num_rows = interlace::pass_rows(self.height, self.pass);
+
+                    if self.usr_width != 0 && self.num_rows != 0
+                    {
+                        break;
+                    }
+                }
+            }
+
+            if self.pass < interlace::PNG_INTERLACE_ADAM7_PASSES
+            {
+                return PngRowEnd::Pass;
+            }
+        }
+
+        PngRowEnd::Image
+    }
+
+    /* Compress image data (filtered rows) into the IDAT stream, writing an IDAT
+     * chunk each time a buffer full of compressed data is available.  Unlike
+     * libpng, a Sync or Full flush also writes out what has been compressed so
+     * far, so that a reader of the stream gets all the rows written up to that
+     * point.  PngFlush::Finish ends the stream and writes the last IDAT.
+     *
+     * The input is compressed a buffer size at a time, so that no more than
+     * about twice the buffer size of compressed data is ever kept, however
+     * much data is given at once.
+     */
+    pub fn compress_IDAT<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                   input: &[u8], flush: PngFlush) -> io::Result<()>
+    {
+        if self.mode.contains(PngMode::AFTER_IDAT)
+        {
+            return Err(invalid_input("image data written after the end of the IDAT stream"));
+        }
+
+        if self.idat_encoder.is_none()
+        {
+            let params = self.get_compression();
+            self.idat_encoder = Some(ZlibEncoder::new(&params, Some(self.image_size())));
+            self.idat_buffer.clear();
+        }
+
+        let mut pieces = input.chunks(self.zbuffer_size).peekable();
+
+        loop {
+            let piece = pieces.next().unwrap_or(&[]);
+            let last = pieces.peek().is_none();
+            let piece_flush = match last {
+                true => flush,
+                false => PngFlush::None,
+            };
+
+            if let Some(encoder) = self.idat_encoder.as_mut()
+            {
+                encoder.compress(piece, piece_flush, &mut self.idat_buffer).map_err(invalid_input)?;
+            }
+
+            self.write_IDAT_chunks(writer, piece_flush != PngFlush::None)?;
+
+            if last
+            {
+                break;
+            }
+        }
+
+        if flush == PngFlush::Finish
+        {
+            self.idat_encoder = None;
+            self.mode.insert(PngMode::AFTER_IDAT);
+        }
+
+        Ok(())
+    }
+
+    /* Write the complete IDAT chunks of the compressed data, then, if 'all',
+     * the rest of it.
+     */
+    pub(crate) fn write_IDAT_chunks<W: Write>(&mut self, writer: &mut ChunkWriter<W>, all: bool) -> io::Result<()>
+    {
+        let mut written = 0;
+        while self.idat_buffer.len() - written >= self.zbuffer_size {
+            writer.write_chunk(PngChunkType::IDAT.to_bytes(),
+                               &self.idat_buffer[written..written + self.zbuffer_size])?;
+            self.mode.insert(PngMode::HAVE_IDAT);
+            written += self.zbuffer_size;
+        }
+
+        /* Then any pending output if the stream was flushed */
+        if all && written < self.idat_buffer.len()
+        {
+            writer.write_chunk(PngChunkType::IDAT.to_bytes(), &self.idat_buffer[written..])?;
+            self.mode.insert(PngMode::HAVE_IDAT);
+            written = self.idat_buffer.len();
+        }
+
+        self.idat_buffer.drain(..written);
+
+        Ok(())
+    }
+
+    /* Flush the current output buffers now */
+    pub fn write_flush<W: Write>(&mut self, writer: &mut ChunkWriter<W>) -> io::Result<()>
+    {
+        /* Nothing to flush before the image data or after its end */
+        if self.idat_encoder.is_none()
+        {
+            return Ok(());
+        }
+
+        self.compress_IDAT(writer, &[], PngFlush::Sync)?;
+        self.flush_rows = 0;
+        writer.flush()
+    }
+
+    /* Write the signature and the chunks that go before the PLTE */
+    pub fn write_info_before_PLTE<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                            info_ptr: &PngInfo) -> io::Result<()>
+    {
+        if self.mode.contains(PngMode::WROTE_INFO_BEFORE_PLTE)
+        {
+            return Ok(());
+        }
+
+        self.write_sig(writer)?;
+
+        /* MNG features are not allowed in a PNG datastream */
+        if self.mode.contains(PngMode::HAVE_PNG_SIGNATURE)
+        {
+            self.mng_features_permitted = PngMng::empty();
+        }
+
+        self.write_IHDR(writer, info_ptr.width, info_ptr.height, info_ptr.bit_depth,
+                        info_ptr.color_type, info_ptr.filter_type,
+                        info_ptr.interlace_type)?;
+
+        if let Some(gamma) = info_ptr.gamma()
+        {
+            self.write_gAMA(writer, gamma)?;
+        }
+
+        /* Write only one of sRGB or an ICC profile */
+        if let Some((name, profile)) = info_ptr.iccp()
+        {
+            self.write_iCCP(writer, name, profile)?;
+        }
+        else if let Some(srgb_intent) = info_ptr.srgb_intent()
+        {
+            self.write_sRGB(writer, srgb_intent)?;
+        }
+
+        if let Some(sig_bit) = info_ptr.sig_bit()
+        {
+            self.write_sBIT(writer, &sig_bit, info_ptr.color_type)?;
+        }
+
+        if let Some(xy) = info_ptr.chromaticities()
+        {
+            self.write_cHRM(writer, &xy)?;
+        }
+
+        /* The HDR chunks, cICP takes precedence over the chunks above for the
+         * decoders that know it, they are kept for the others.
+         */
+        if let Some(cicp) = info_ptr.cicp()
+        {
+            self.write_cICP(writer, &cicp)?;
+        }
+
+        if let Some(display) = info_ptr.mastering_display()
+        {
+            self.write_mDCv(writer, &display)?;
+        }
+
+        if let Some(level) = info_ptr.content_light_level()
+        {
+            self.write_cLLi(writer, &level)?;
+        }
+
+        self.write_unknown_chunks(writer, info_ptr, PngMode::HAVE_IHDR)?;
+
+        self.mode.insert(PngMode::WROTE_INFO_BEFORE_PLTE);
+
+        Ok(())
+    }
+
+    /* Write the chunks that go before the image data.  The text chunks and
+     * the Exif data are marked as written in the info, so that write_end does
+     * not write them again.
+     */
+    pub fn write_info<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                                info_ptr: &mut PngInfo) -> io::Result<()>
+    {
+        self.write_info_before_PLTE(writer, info_ptr)?;
+
+        if info_ptr.is_valid(PngInfoChunk::PLTE)
+        {
+            self.write_PLTE(writer, info_ptr.palette())?;
+        }
+        else if info_ptr.color_type == PngColor::TYPE_PALETTE
+        {
+            return Err(invalid_input("Valid palette required for paletted images"));
+        }
+
+        if let Some((trans_alpha, trans_color)) = info_ptr.trans()
+        {
+            self.write_tRNS(writer, trans_alpha, &trans_color, info_ptr.color_type)?;
+        }
+
+        if let Some(background) = info_ptr.background()
+        {
+            self.write_bKGD(writer, &background, info_ptr.color_type)?;
+        }
+
+        if let Some(exif) = info_ptr.exif()
+        {
+            self.write_eXIf(writer, exif)?;
+            info_ptr.exif_written = true;
+        }
+
+        if let Some(hist) = info_ptr.hist()
+        {
+            self.write_hIST(writer, hist)?;
+        }
+
+        if let Some((x_offset, y_offset, unit_type)) = info_ptr.offsets()
+        {
+            self.write_oFFs(writer, x_offset, y_offset, unit_type)?;
+        }
+
+        if let Some(pcal) = info_ptr.pcal()
+        {
+            self.write_pCAL(writer, pcal)?;
+        }
+
+        if let Some((unit, width, height)) = info_ptr.scal()
+        {
+            self.write_sCAL_s(writer, unit, width, height)?;
+        }
+
+        if let Some((x_pixels_per_unit, y_pixels_per_unit, unit_type)) = info_ptr.phys()
+        {
+            self.write_pHYs(writer, x_pixels_per_unit, y_pixels_per_unit, unit_type)?;
+        }
+
+        if let Some(mod_time) = info_ptr.mod_time()
+        {
+            self.write_tIME(writer, &mod_time)?;
+            self.mode.insert(PngMode::WROTE_T_IME);
+        }
+
+        for spalette in info_ptr.splt() {
+            self.write_sPLT(writer, spalette)?;
+        }
+
+        for text in info_ptr.text() {
+            self.write_text(writer, text)?;
+        }
+        info_ptr.text_written = info_ptr.text().len();
+
+        self.write_unknown_chunks(writer, info_ptr, PngMode::HAVE_PLTE)
+    }
+
+    /* Write the end of the PNG file: the chunks of the info which go after the
+     * image data, and IEND.  The info may be the one given to write_info, then
+     * only the texts added since are written, or None.
+     */
+    pub fn write_end<W: Write>(&mut self, writer: &mut ChunkWriter<W>,
+                               info_ptr: Option<&mut PngInfo>) -> io::Result<()>
+    {
+        if !self.mode.contains(PngMode::HAVE_IDAT)
+        {
+            return Err(invalid_input("No IDATs written into file"));
+        }
+
+        /* See if user wants us to write information chunks */
+        if let Some(info_ptr) = info_ptr
+        {
+            /* Check to see if user has supplied a time chunk */
+            if let Some(mod_time) = info_ptr.mod_time()
+            {
+                if !self.mode.contains(PngMode::WROTE_T_IME)
+                {
+                    self.write_tIME(writer, &mod_time)?;
+                    self.mode.insert(PngMode::WROTE_T_IME);
+                }
+            }
+
+            for text in &info_ptr.text()[info_ptr.text_written..] {
+                self.write_text(writer, text)?;
+            }
+            info_ptr.text_written = info_ptr.text().len();
+
+            /* Only one eXIf is allowed, unlike libpng the one already written
+             * before the image data is not written again.
+             */
+            if let Some(exif) = info_ptr.exif()
+            {
+                if !info_ptr.exif_written
+                {
+                    self.write_eXIf(writer, exif)?;
+                    info_ptr.exif_written = true;
+                }
+            }
+
+            self.write_unknown_chunks(writer, info_ptr, PngMode::AFTER_IDAT)?;
+        }
+
+        self.mode.insert(PngMode::AFTER_IDAT);
+
+        /* Write end of PNG file */
+        self.write_IEND(writer)?;
+        writer.flush()
+    }
+}
+
+/* The red, green and blue samples of a color as 16-bit values */
+fn rgb16_bytes(color: &PngColor16) -> [u8; 6]
+{
+    let mut buf = [0u8; 6];
+    buf[0..2].copy_from_slice(&color.red.to_be_bytes());
+    buf[2..4].copy_from_slice(&color.green.to_be_bytes());
+    buf[4..6].copy_from_slice(&color.blue.to_be_bytes());
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png_info::PngUnknownChunk;
+    use crate::{ChunkReader, PngDecoder, StreamEncoder};
+    use miniz_oxide::inflate::decompress_to_vec_zlib;
+    use std::fs;
+    use std::path::Path;
+
+    /* The chunks of a datastream with their bytes, from the length to the
+     * CRC.
+     */
+    fn chunk_bytes(png: &[u8]) -> Vec<([u8; 4], &[u8])>
+    {
+        crate::ChunkReader::new(png).map(|chunk| {
+            let chunk = chunk.unwrap();
+            let start = chunk.offset as usize;
+
+            assert!(chunk.crc_ok);
+            (chunk.name, &png[start..start + 12 + chunk.data.len()])
+        }).collect()
+    }
+
+    /* The text of a tEXt, zTXt or iTXt chunk, inflated if compressed */
+    fn decode_text(name: &[u8; 4], data: &[u8]) -> PngText
+    {
+        let mut fields = data.splitn(2, |&byte| byte == 0);
+        let key = fields.next().unwrap().to_vec();
+        let data = fields.next().unwrap();
+
+        match name {
+            b"tEXt" => PngText {
+                compression: PngTextCompression::None,
+                key,
+                text: data.to_vec(),
+                lang: Vec::new(),
+                lang_key: Vec::new(),
+            },
+            b"zTXt" => PngText {
+                compression: PngTextCompression::Ztxt,
+                key,
+                text: decompress_to_vec_zlib(&data[1..]).unwrap(),
+                lang: Vec::new(),
+                lang_key: Vec::new(),
+            },
+            _ => {
+                /* The compression flag and method, then the language tag and
+                 * the translated keyword.
+                 */
+                let mut fields = data[2..].splitn(3, |&byte| byte == 0);
+                let lang = fields.next().unwrap().to_vec();
+                let lang_key = fields.next().unwrap().to_vec();
+                let text = fields.next().unwrap();
+
+                match data[0] {
+                    0 => PngText {
+                        compression: PngTextCompression::ItxtNone,
+                        key,
+                        text: text.to_vec(),
+                        lang,
+                        lang_key,
+                    },
+                    _ => PngText {
+                        compression: PngTextCompression::ItxtZtxt,
+                        key,
+                        text: decompress_to_vec_zlib(text).unwrap(),
+                        lang,
+                        lang_key,
+                    },
+                }
+            },
+        }
+    }
+
+    /* The chunks of chunk_bytes but the IDAT ones, with the decoded text of
+     * the text chunks.  The bytes of the compressed texts are left out: zlib
+     * and miniz_oxide do not compress the same way.
+     */
+    fn chunks_but_idat(png: &[u8]) -> Vec<([u8; 4], &[u8], Option<PngText>)>
+    {
+        chunk_bytes(png).into_iter().filter(|(name, _)| name != b"IDAT").map(|(name, bytes)| {
+            match &name {
+                b"tEXt" | b"zTXt" | b"iTXt" => {
+                    let text = decode_text(&name, &bytes[8..bytes.len() - 4]);
+
+                    match text.compression {
+                        PngTextCompression::Ztxt | PngTextCompression::ItxtZtxt => (name, &[][..], Some(text)),
+                        _ => (name, bytes, Some(text)),
+                    }
+                },
+                _ => (name, bytes, None),
+            }
+        }).collect()
+    }
+
+    #[cfg(feature = "c-tests")]
+    #[test]
+    fn emitters_write_the_chunks_of_png_write_info()
+    {
+        let palette: Vec<PngPaletteColor> = (0..256).map(|i| PngPaletteColor {
+            red: i as u8,
+            green: (i * 7) as u8,
+            blue: 255 - i as u8,
+        }).collect();
+
+        let cases = [
+            (PngColor::TYPE_PALETTE, 1, 2, PngInterlace::None),
+            (PngColor::TYPE_PALETTE, 2, 3, PngInterlace::ADAM7),
+            (PngColor::TYPE_PALETTE, 4, 16, PngInterlace::None),
+            (PngColor::TYPE_PALETTE, 8, 256, PngInterlace::ADAM7),
+            (PngColor::TYPE_PALETTE, 8, 1, PngInterlace::None),
+            (PngColor::TYPE_GRAY, 1, 0, PngInterlace::None),
+            (PngColor::TYPE_GRAY, 16, 0, PngInterlace::ADAM7),
+            (PngColor::TYPE_GRAY_ALPHA, 8, 0, PngInterlace::None),
+            (PngColor::TYPE_RGB, 8, 0, PngInterlace::None),
+            (PngColor::TYPE_RGB, 16, 100, PngInterlace::ADAM7),
+            (PngColor::TYPE_RGB_ALPHA, 8, 256, PngInterlace::None),
+            (PngColor::TYPE_RGB_ALPHA, 16, 0, PngInterlace::None),
+        ];
+
+        for &(color_type, bit_depth, num_palette, interlace_type) in cases.iter() {
+            let (width, height) = (13, 7);
+            let channels = match color_type {
+                PngColor::TYPE_GRAY_ALPHA => 2,
+                PngColor::TYPE_RGB => 3,
+                PngColor::TYPE_RGB_ALPHA => 4,
+                _ => 1,
+            };
+            let rows = vec![vec![0u8; Png::compute_rowbytes(bit_depth * channels, width as usize)]; height];
+            let palette = &palette[..num_palette];
+
+            let c_png = crate::libpng::write_png(width, height as u32, bit_depth, color_type, interlace_type,
+                                                 palette, &rows);
+
+            let mut png_ptr = Png::new();
+            let mut writer = ChunkWriter::new(Vec::new());
+            png_ptr.write_sig(&mut writer).unwrap();
+            png_ptr.write_IHDR(&mut writer, width, height as u32, bit_depth, color_type,
+                               PngFilterType::Base, interlace_type).unwrap();
+            if num_palette != 0
+            {
+                png_ptr.write_PLTE(&mut writer, palette).unwrap();
+            }
+            png_ptr.write_IEND(&mut writer).unwrap();
+            let rust_png = writer.into_inner();
+
+            let case = format!("{:?} {} bits, {} colors, {:?}", color_type, bit_depth, num_palette, interlace_type);
+            assert_eq!(rust_png[..8], c_png[..8], "{}", case);
+
+            /* The C datastream has the IDAT chunks in addition */
+            let c_chunks: Vec<_> = chunk_bytes(&c_png).into_iter().filter(|(name, _)| name != b"IDAT").collect();
+            let rust_chunks = chunk_bytes(&rust_png);
+            assert_eq!(rust_chunks.len(), if num_palette != 0 { 3 } else { 2 }, "{}", case);
+            assert_eq!(rust_chunks, c_chunks, "{}", case);
+        }
+    }
+
+    /* An 8-bit paletted image with all the chunks of png_write_info set but
+     * the ones before PLTE, tRNS and bKGD, checked above.
+     */
+    #[cfg(feature = "c-tests")]
+    fn ancillary_info() -> PngInfo
+    {
+        use crate::png_info::PngSpltEntry;
+
+        let mut info = PngInfo::new();
+        info.width = 5;
+        info.height = 3;
+        info.bit_depth = 8;
+        info.color_type = PngColor::TYPE_PALETTE;
+        info.set_palette(&[
+            PngPaletteColor { red: 0, green: 0, blue: 0 },
+            PngPaletteColor { red: 255, green: 128, blue: 1 },
+            PngPaletteColor { red: 9, green: 99, blue: 199 },
+        ]);
+
+        info.set_exif(b"MM\0\x2a\0\0\0\x08\0\0");
+        info.set_hist(&[7, 0, 65535]);
+        info.set_offsets(-300, 70000, 1);
+        info.set_pcal(PngPcal {
+            purpose: b"Temperature".to_vec(),
+            x0: -2,
+            x1: 40000,
+            equation_type: 2,
+            units: b"K".to_vec(),
+            params: vec![b"1.5".to_vec(), b"-2e3".to_vec(), b"10".to_vec()],
+        });
+        info.set_scal(1, b"0.25", b"3.5e-2");
+        info.set_phys(2835, 3780, 1);
+        info.set_mod_time(PngTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 60 });
+
+        let entry = |red: u16, frequency: u16| PngSpltEntry {
+            red,
+            green: red / 2,
+            blue: 255 - red,
+            alpha: 255,
+            frequency,
+        };
+        info.add_splt(&[
+            PngSplt { name: b"eight bits".to_vec(), depth: 8, entries: vec![entry(3, 1), entry(250, 900)] },
+            PngSplt { name: b"sixteen".to_vec(), depth: 16, entries: vec![entry(255, 65535)] },
+        ]);
+
+        let text = |compression, key: &[u8], text: &[u8], lang: &[u8], lang_key: &[u8]| PngText {
+            compression,
+            key: key.to_vec(),
+            text: text.to_vec(),
+            lang: lang.to_vec(),
+            lang_key: lang_key.to_vec(),
+        };
+        info.add_text(&[
+            text(PngTextCompression::None, b"Title", b"Plain text", b"", b""),
+            text(PngTextCompression::None, b"Empty", b"", b"", b""),
+            text(PngTextCompression::Ztxt, b"Comment", &b"compressed ".repeat(20), b"", b""),
+            text(PngTextCompression::ItxtNone, b"Author", "Zo\u{eb}".as_bytes(), b"fr", "Auteur".as_bytes()),
+            text(PngTextCompression::ItxtZtxt, b"Description", &"\u{e9}t\u{e9} ".repeat(30).into_bytes(),
+                 b"fr-CA", b"Description"),
+            text(PngTextCompression::ItxtNone, b"Source", b"", b"", b""),
+        ]);
+
+        info
+    }
+
+    #[cfg(feature = "c-tests")]
+    #[test]
+    fn ancillary_emitters_write_the_chunks_of_png_write_info()
+    {
+        let rows = vec![vec![0u8, 1, 2, 1, 0]; 3];
+        let c_png = crate::libpng::write_png_info(&ancillary_info(), &rows);
+
+        let mut encoder = StreamEncoder::new(Vec::new(), ancillary_info()).unwrap();
+        encoder.write_rows(&rows.concat()).unwrap();
+        let rust_png = encoder.finish().unwrap();
+
+        /* libpng writes the eXIf chunk again after the image data, write_end
+         * does not.
+         */
+        let mut c_chunks = chunks_but_idat(&c_png);
+        let rust_chunks = chunks_but_idat(&rust_png);
+        assert_eq!(c_chunks.iter().filter(|(name, _, _)| name == b"eXIf").count(), 2);
+        let last_exif = c_chunks.iter().rposition(|(name, _, _)| name == b"eXIf").unwrap();
+        c_chunks.remove(last_exif);
+
+        let names: Vec<[u8; 4]> = rust_chunks.iter().map(|(name, _, _)| *name).collect();
+        assert_eq!(names, [*b"IHDR", *b"PLTE", *b"eXIf", *b"hIST", *b"oFFs", *b"pCAL", *b"sCAL", *b"pHYs",
+                           *b"tIME", *b"sPLT", *b"sPLT", *b"tEXt", *b"tEXt", *b"zTXt", *b"iTXt", *b"iTXt",
+                           *b"iTXt", *b"IEND"]);
+        assert_eq!(rust_chunks, c_chunks);
+
+        let texts: Vec<PngText> = rust_chunks.into_iter().filter_map(|(_, _, text)| text).collect();
+        assert_eq!(texts, ancillary_info().text());
+    }
+
+    /* The chunks read by PngDecoder, and the text chunks decoded by
+     * rewrite.
+     */
+    const DECODED: [&[u8; 4]; 17] = [
+        b"IHDR", b"PLTE", b"IDAT", b"IEND", b"tRNS", b"gAMA", b"sRGB",
+        b"cHRM", b"iCCP", b"bKGD", b"sBIT", b"cICP", b"mDCv", b"cLLi",
+        b"tEXt", b"zTXt", b"iTXt",
+    ];
+
+    /* Read 'png' with PngDecoder and write it again with 'png_ptr'.  The
+     * texts are added to the info, those after the image data once the rows
+     * are written, so that they are written by write_info and write_end.
+     * The other chunks PngDecoder does not read are copied as unknown chunks
+     * at their location, followed by 'unknown_chunks'.  The chunks after
+     * tRNS or bKGD go after PLTE, where these are written, even without a
+     * PLTE.
+     */
+    fn rewrite(png: &[u8], png_ptr: Png, unknown_chunks: &[PngUnknownChunk]) -> Vec<u8>
+    {
+        let mut decoder = PngDecoder::new(png);
+        decoder.read_info().unwrap();
+        let image = decoder.read_image().unwrap();
+        let mut info = decoder.into_info();
+
+        let mut location = PngMode::HAVE_IHDR;
+        let mut chunks = Vec::new();
+        let mut texts_after_idat = Vec::new();
+        for chunk in ChunkReader::new(png) {
+            let chunk = chunk.unwrap();
+
+            match &chunk.name {
+                b"PLTE" | b"tRNS" | b"bKGD" => location = PngMode::HAVE_PLTE,
+                b"IDAT" => location = PngMode::AFTER_IDAT,
+                name @ b"tEXt" | name @ b"zTXt" | name @ b"iTXt" => {
+                    let text = decode_text(name, &chunk.data);
+
+                    match location {
+                        PngMode::AFTER_IDAT => texts_after_idat.push(text),
+                        _ => info.add_text(&[text]),
+                    }
+                },
+                name if !DECODED.contains(&name) => chunks.push(PngUnknownChunk {
+                    name: chunk.name,
+                    data: chunk.data,
+                    location,
+                }),
+                _ => (),
+            }
+        }
+        chunks.extend_from_slice(unknown_chunks);
+        info.add_unknown_chunks(&chunks).unwrap();
+
+        let mut encoder = StreamEncoder::with_png(Vec::new(), png_ptr, info).unwrap();
+        encoder.write_rows(&image).unwrap();
+        encoder.info_mut().add_text(&texts_after_idat);
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn testpngs_round_trip()
+    {
+        let testpngs = Path::new(env!("CARGO_MANIFEST_DIR")).join("../contrib/testpngs");
+        let mut paths: Vec<_> = fs::read_dir(testpngs).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("png".as_ref()))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+
+        for path in paths {
+            let png = fs::read(&path).unwrap();
+            let written = rewrite(&png, Png::new(), &[]);
+
+            /* The same chunks but the IDAT and the compressed texts,
+             * compressed again.
+             */
+            assert!(chunks_but_idat(&png).iter().any(|(_, _, text)| text.is_some()), "{}", path.display());
+            assert_eq!(chunks_but_idat(&written), chunks_but_idat(&png), "{}", path.display());
+
+            let mut decoder = PngDecoder::new(&png);
+            let mut written_decoder = PngDecoder::new(&written);
+            assert_eq!(written_decoder.read_image().unwrap(), decoder.read_image().unwrap(), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn unknown_chunks_are_kept_as_in_pngwrite()
+    {
+        let png = fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("../contrib/testpngs/gray-8.png")).unwrap();
+        let unknown_chunks = [
+            PngUnknownChunk { name: *b"saFe", data: vec![1], location: PngMode::HAVE_IHDR },
+            PngUnknownChunk { name: *b"unSF", data: vec![2], location: PngMode::HAVE_PLTE },
+            PngUnknownChunk { name: *b"laST", data: vec![3], location: PngMode::AFTER_IDAT },
+        ];
+        let written = |keep: &[(PngHandleChunk, &[[u8; 4]])]| {
+            let mut png_ptr = Png::new();
+            for &(keep, chunks) in keep.iter() {
+                png_ptr.set_keep_unknown_chunks(keep, chunks);
+            }
+
+            chunk_bytes(&rewrite(&png, png_ptr, &unknown_chunks)).into_iter()
+                .map(|(name, _)| name)
+                .filter(|name| unknown_chunks.iter().any(|chunk| chunk.name == *name))
+                .collect::<Vec<_>>()
+        };
+
+        /* A chunk safe to copy is written by default, the others when kept */
+        assert_eq!(written(&[]), [*b"saFe"]);
+        assert_eq!(written(&[(PngHandleChunk::Always, &[])]), [*b"saFe", *b"unSF", *b"laST"]);
+        assert_eq!(written(&[(PngHandleChunk::Always, &[*b"laST"])]), [*b"saFe", *b"laST"]);
+        assert_eq!(written(&[(PngHandleChunk::IfSafe, &[*b"unSF"])]), [*b"saFe"]);
+        assert_eq!(written(&[(PngHandleChunk::Never, &[*b"saFe"])]), Vec::<[u8; 4]>::new());
+        assert_eq!(written(&[(PngHandleChunk::Always, &[]), (PngHandleChunk::Never, &[*b"unSF"])]),
+                   [*b"saFe", *b"laST"]);
+
+        /* The last setting of a chunk is used, AsDefault removes it */
+        assert_eq!(written(&[(PngHandleChunk::Never, &[*b"saFe"]), (PngHandleChunk::AsDefault, &[*b"saFe"])]),
+                   [*b"saFe"]);
+        assert_eq!(written(&[(PngHandleChunk::Never, &[*b"laST"]), (PngHandleChunk::Always, &[*b"laST"])]),
+                   [*b"saFe", *b"laST"]);
+    }
+}
@@ -419,6 +419,9 @@ png_push_have_end(png_structrp png_ptr, png_inforp info_ptr)
 void /* PRIVATE */
 png_push_have_row(png_structrp png_ptr, png_bytep row)
 {
+   if (png_rust_push_have_row(png_ptr->rust_ptr, row))
+      return;
+
    if (png_rust_get_row_fn(png_ptr->rust_ptr) != NULL)
       (*(png_rust_get_row_fn(png_ptr->rust_ptr)))(png_ptr, row, png_rust_get_row_number(png_ptr->rust_ptr),
           (int)png_rust_get_pass(png_ptr->rust_ptr));
@@ -459,6 +462,15 @@ png_get_progressive_ptr(png_const_structrp png_ptr)
    return png_rust_get_io_ptr(png_ptr->rust_ptr);
 }
 
+png_voidp PNGAPI
+png_get_progressive_rust_ptr(png_const_structrp png_ptr)
+{
+   if (png_ptr == NULL)
+      return (NULL);
+
+   return png_ptr->rust_ptr;
+}
+
 void /* PRIVATE */
 png_push_fill_buffer(png_structp png_ptr, png_bytep buffer, size_t length)
 {
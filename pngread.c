@@ -784,7 +784,7 @@ png_read_end(png_structrp png_ptr, png_inforp info_ptr)
 #ifdef PNG_READ_CHECK_FOR_INVALID_INDEX_SUPPORTED
    /* Report invalid palette index; added at libng-1.5.10 */
    if (png_rust_is_color_type(png_ptr->rust_ptr, PNG_COLOR_TYPE_PALETTE) &&
-       png_rust_get_num_palette_max(png_ptr->rust_ptr) > png_rust_get_num_palette(png_ptr->rust_ptr))
+       png_rust_has_invalid_palette_index(png_ptr->rust_ptr))
       png_benign_error(png_ptr, "Read palette index exceeding num_palette");
 #endif
 
@@ -951,13 +951,6 @@ png_read_destroy(png_structrp png_ptr)
    png_free(png_ptr, png_rust_get_read_buffer(png_ptr->rust_ptr));
    png_rust_set_read_buffer(png_ptr->rust_ptr, NULL);
 
-#ifdef PNG_READ_QUANTIZE_SUPPORTED
-   png_free(png_ptr, png_ptr->palette_lookup);
-   png_ptr->palette_lookup = NULL;
-   png_free(png_ptr, png_ptr->quantize_index);
-   png_ptr->quantize_index = NULL;
-#endif
-
    if ((png_ptr->free_me & PNG_FREE_PLTE) != 0)
    {
       png_zfree(png_ptr, png_rust_get_palette(png_ptr->rust_ptr));
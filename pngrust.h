@@ -23,8 +23,8 @@ uint8_t png_rust_get_pass(PngRust* pngrust);
 void png_rust_incr_pass(PngRust* pngrust);
 void png_rust_decr_pass(PngRust* pngrust);
 
-int32_t png_rust_get_interlace(PngRust* pngrust);
-void    png_rust_set_interlace(PngRust* pngrust, int32_t value);
+uint8_t png_rust_get_interlace(PngRust* pngrust);
+void    png_rust_set_interlace(PngRust* pngrust, uint8_t value);
 
 uint32_t png_rust_get_flags(PngRust* pngrust);
 void png_rust_set_flags(PngRust* pngrust, uint32_t flags);
@@ -71,7 +71,7 @@ void png_rust_set_bit_depth(PngRust* pngrust, uint8_t bit_depth);
 uint8_t png_rust_get_usr_bit_depth(PngRust* pngrust);
 void png_rust_set_usr_bit_depth(PngRust* pngrust, uint8_t bit_depth);
 
-uint32_t png_rust_get_do_filter(PngRust* pngrust);
+uint8_t png_rust_get_do_filter(PngRust* pngrust);
 void png_rust_set_do_filter(PngRust* pngrust, uint32_t flags);
 bool png_rust_is_do_filter(PngRust* pngrust, uint32_t flags);
 
@@ -94,6 +94,7 @@ uint32_t png_rust_get_crc(PngRust* pngrust);
 png_color *png_rust_get_palette(PngRust* pngrust);
 uint16_t png_rust_get_num_palette(PngRust* pngrust);
 int32_t  png_rust_get_num_palette_max(PngRust* pngrust);
+bool png_rust_has_invalid_palette_index(PngRust* pngrust);
 uint8_t  png_rust_get_usr_channels(PngRust* pngrust);
 uint8_t  png_rust_get_sig_bytes(PngRust* pngrust);
 uint8_t  png_rust_get_maximum_pixel_depth(PngRust* pngrust);
@@ -178,8 +179,34 @@ size_t png_rust_get_save_buffer_size(PngRust* pngrust);
 uint32_t png_rust_decr_user_chunk_cache_max(PngRust* pngrust);
 
 void png_c_set_strip_error_numbers(PngRust* pngrust, uint32_t ustrip_mode);
+void png_c_set_add_alpha(PngRust* pngrust, uint32_t filler, int filler_loc);
+void png_c_set_quantize(PngRust* pngrust, png_color *palette, int num_palette,
+                        int maximum_colors, const uint16_t *histogram,
+                        int full_quantize);
+bool png_rust_has_palette_lookup(PngRust* pngrust);
+bool png_rust_do_read_user_transform(PngRust* pngrust, png_row_info *row_info, uint8_t *row);
+void png_rust_add_read_transform(PngRust* pngrust, void* transform);
+void png_rust_add_write_transform(PngRust* pngrust, void* transform);
+void png_rust_do_write_transformations(PngRust* pngrust, png_row_info *row_info);
+void png_rust_do_write_intrapixel(PngRust* pngrust, png_row_info *row_info);
+void png_c_set_shift(PngRust* pngrust, png_const_color_8p true_bits);
+png_color_8p png_rust_ptr_shift(PngRust* pngrust);
+uint8_t png_rust_get_user_transform_depth(PngRust* pngrust);
+uint8_t png_rust_get_user_transform_channels(PngRust* pngrust);
+uint8_t png_rust_get_user_transform_pixel_depth(PngRust* pngrust, uint8_t max_pixel_depth);
+void png_rust_combine_row(PngRust* pngrust, uint8_t *dp, int display);
 void png_rust_process_data(PngRust* pngrust, PngInfoRust* rust_ptr, void* buffer, size_t buffer_size);
 size_t png_rust_process_data_pause(PngRust* pngrust, bool save);
+bool png_rust_push_have_row(PngRust* pngrust, uint8_t *row);
+uint8_t *png_rust_find_filter(PngRust* pngrust, png_row_info *row_info);
+
+/* What follows a row written, see png_rust_write_finish_row */
+#define PNG_ROW_END_ROW   0 /* the next row of the pass */
+#define PNG_ROW_END_PASS  1 /* the first row of the next pass */
+#define PNG_ROW_END_IMAGE 2 /* the image is complete */
+
+void png_rust_write_start_row(PngRust* pngrust);
+int png_rust_write_finish_row(PngRust* pngrust);
 
 int32_t png_rust_get_IHDR(PngRust* pngrust, PngInfoRust* rust_ptr,
                           uint32_t* width, uint32_t* height, int32_t* bit_depth,
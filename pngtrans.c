@@ -65,8 +65,7 @@ png_set_shift(png_structrp png_ptr, png_const_color_8p true_bits)
    if (png_ptr == NULL)
       return;
 
-   png_rust_add_transformations(png_ptr->rust_ptr, PNG_SHIFT);
-   png_ptr->shift = *true_bits;
+   png_c_set_shift(png_ptr->rust_ptr, true_bits);
 }
 #endif
 
@@ -102,10 +101,7 @@ png_set_add_alpha(png_structrp png_ptr, png_uint_32 filler, int filler_loc)
    if (png_ptr == NULL)
       return;
 
-   png_set_filler(png_ptr, filler, filler_loc);
-   /* The above may fail to do anything. */
-   if (png_rust_has_transformations(png_ptr->rust_ptr, PNG_FILLER))
-      png_rust_add_transformations(png_ptr->rust_ptr, PNG_ADD_ALPHA);
+   png_c_set_add_alpha(png_ptr->rust_ptr, filler, filler_loc);
 }
 
 #endif
@@ -365,7 +365,7 @@ png_write_end(png_structrp png_ptr, png_inforp info_ptr)
       png_error(png_ptr, "No IDATs written into file");
 
 #ifdef PNG_WRITE_CHECK_FOR_INVALID_INDEX_SUPPORTED
-   if (png_rust_get_num_palette_max(png_ptr->rust_ptr) > png_rust_get_num_palette(png_ptr->rust_ptr))
+   if (png_rust_has_invalid_palette_index(png_ptr->rust_ptr))
       png_benign_error(png_ptr, "Wrote palette index exceeding num_palette");
 #endif
 
@@ -627,71 +627,6 @@ png_write_image(png_structrp png_ptr, png_bytepp image)
    }
 }
 
-#ifdef PNG_MNG_FEATURES_SUPPORTED
-/* Performs intrapixel differencing  */
-static void
-png_do_write_intrapixel(png_row_infop row_info, png_bytep row)
-{
-   png_debug(1, "in png_do_write_intrapixel");
-
-   if ((row_info->color_type & PNG_COLOR_MASK_COLOR) != 0)
-   {
-      int bytes_per_pixel;
-      png_uint_32 row_width = row_info->width;
-      if (row_info->bit_depth == 8)
-      {
-         png_bytep rp;
-         png_uint_32 i;
-
-         if (row_info->color_type == PNG_COLOR_TYPE_RGB)
-            bytes_per_pixel = 3;
-
-         else if (row_info->color_type == PNG_COLOR_TYPE_RGB_ALPHA)
-            bytes_per_pixel = 4;
-
-         else
-            return;
-
-         for (i = 0, rp = row; i < row_width; i++, rp += bytes_per_pixel)
-         {
-            *(rp)     = (png_byte)(*rp       - *(rp + 1));
-            *(rp + 2) = (png_byte)(*(rp + 2) - *(rp + 1));
-         }
-      }
-
-#ifdef PNG_WRITE_16BIT_SUPPORTED
-      else if (row_info->bit_depth == 16)
-      {
-         png_bytep rp;
-         png_uint_32 i;
-
-         if (row_info->color_type == PNG_COLOR_TYPE_RGB)
-            bytes_per_pixel = 6;
-
-         else if (row_info->color_type == PNG_COLOR_TYPE_RGB_ALPHA)
-            bytes_per_pixel = 8;
-
-         else
-            return;
-
-         for (i = 0, rp = row; i < row_width; i++, rp += bytes_per_pixel)
-         {
-            png_uint_32 s0   = (png_uint_32)(*(rp    ) << 8) | *(rp + 1);
-            png_uint_32 s1   = (png_uint_32)(*(rp + 2) << 8) | *(rp + 3);
-            png_uint_32 s2   = (png_uint_32)(*(rp + 4) << 8) | *(rp + 5);
-            png_uint_32 red  = (png_uint_32)((s0 - s1) & 0xffffL);
-            png_uint_32 blue = (png_uint_32)((s2 - s1) & 0xffffL);
-            *(rp    ) = (png_byte)(red >> 8);
-            *(rp + 1) = (png_byte)red;
-            *(rp + 4) = (png_byte)(blue >> 8);
-            *(rp + 5) = (png_byte)blue;
-         }
-      }
-#endif /* WRITE_16BIT */
-   }
-}
-#endif /* MNG_FEATURES */
-
 /* Called by user to write a row of image data */
 void PNGAPI
 png_write_row(png_structrp png_ptr, png_const_bytep row)
@@ -845,7 +780,8 @@ png_write_row(png_structrp png_ptr, png_const_bytep row)
    if (png_rust_get_interlace(png_ptr->rust_ptr) && png_rust_get_pass(png_ptr->rust_ptr) < 6 &&
        png_rust_has_transformations(png_ptr->rust_ptr, PNG_INTERLACE))
    {
-      png_do_write_interlace(&row_info, png_rust_get_row_buf(png_ptr->rust_ptr) + 1, png_rust_get_pass(png_ptr->rust_ptr));
+      png_do_write_interlace(&row_info, png_rust_get_row_buf(png_ptr->rust_ptr) + 1, png_rust_get_pass(png_ptr->rust_ptr),
+          png_rust_get_transformations(png_ptr->rust_ptr));
       /* This should always get caught above, but still ... */
       if (row_info.width == 0)
       {
@@ -878,12 +814,7 @@ png_write_row(png_structrp png_ptr, png_const_bytep row)
     * 4. The filter_method is 64 and
     * 5. The color_type is RGB or RGBA
     */
-   if ((png_rust_get_mng_features_permitted(png_ptr->rust_ptr) & PNG_FLAG_MNG_FILTER_64) != 0 &&
-       (png_rust_get_filter_type(png_ptr->rust_ptr) == PNG_INTRAPIXEL_DIFFERENCING))
-   {
-      /* Intrapixel differencing */
-      png_do_write_intrapixel(&row_info, png_rust_get_row_buf(png_ptr->rust_ptr) + 1);
-   }
+   png_rust_do_write_intrapixel(png_ptr->rust_ptr, &row_info);
 #endif
 
 /* Added at libpng-1.5.10 */
@@ -891,7 +822,7 @@ png_write_row(png_structrp png_ptr, png_const_bytep row)
    /* Check for out-of-range palette index */
    if (row_info.color_type == PNG_COLOR_TYPE_PALETTE &&
        png_rust_get_num_palette_max(png_ptr->rust_ptr) >= 0)
-      png_do_check_palette_indexes(png_ptr, &row_info);
+      png_do_check_palette_indexes(png_ptr->rust_ptr, &row_info);
 #endif
 
    /* Find a filter if necessary, filter the row and write it out. */
@@ -1334,6 +1265,17 @@ png_set_write_user_transform_fn(png_structrp png_ptr, png_user_transform_ptr
    png_rust_add_transformations(png_ptr->rust_ptr, PNG_USER_TRANSFORM);
    png_ptr->write_user_transform_fn = write_user_transform_fn;
 }
+
+void PNGAPI
+png_set_write_rust_transform(png_structrp png_ptr, png_voidp transform)
+{
+   png_debug(1, "in png_set_write_rust_transform");
+
+   if (png_ptr == NULL || transform == NULL)
+      return;
+
+   png_rust_add_write_transform(png_ptr->rust_ptr, transform);
+}
 #endif
 
 
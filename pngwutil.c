@@ -1888,22 +1888,6 @@ png_write_tIME(png_structrp png_ptr, png_const_timep mod_time)
 void /* PRIVATE */
 png_write_start_row(png_structrp png_ptr)
 {
-#ifdef PNG_WRITE_INTERLACING_SUPPORTED
-   /* Arrays to facilitate easy interlacing - use pass (0 - 6) as index */
-
-   /* Start of interlace block */
-   static const png_byte png_pass_start[7] = {0, 4, 0, 2, 0, 1, 0};
-
-   /* Offset to next interlace block */
-   static const png_byte png_pass_inc[7] = {8, 8, 4, 4, 2, 2, 1};
-
-   /* Start of interlace block in the y direction */
-   static const png_byte png_pass_ystart[7] = {0, 0, 4, 0, 2, 0, 1};
-
-   /* Offset to next interlace block in the y direction */
-   static const png_byte png_pass_yinc[7] = {8, 8, 8, 4, 4, 2, 2};
-#endif
-
    png_alloc_size_t buf_size;
    int usr_pixel_depth;
 
@@ -1920,6 +1904,11 @@ png_write_start_row(png_structrp png_ptr)
    png_rust_set_transformed_pixel_depth(png_ptr->rust_ptr, png_rust_get_pixel_depth(png_ptr->rust_ptr));
    png_rust_set_maximum_pixel_depth(png_ptr->rust_ptr, (png_byte)usr_pixel_depth);
 
+   /* The filters usable with the size of the image, and the width and the
+    * number of rows of the first pass, are set up on the Rust side.
+    */
+   png_rust_write_start_row(png_ptr->rust_ptr);
+
    /* Set up row buffer */
    png_rust_set_row_buf(png_ptr->rust_ptr, png_voidcast(png_bytep, png_malloc(png_ptr, buf_size)));
 
@@ -1928,17 +1917,6 @@ png_write_start_row(png_structrp png_ptr)
 #ifdef PNG_WRITE_FILTER_SUPPORTED
    filters = png_rust_get_do_filter(png_ptr->rust_ptr);
 
-   if (png_rust_get_height(png_ptr->rust_ptr) == 1)
-      filters &= 0xff & ~(PNG_FILTER_UP|PNG_FILTER_AVG|PNG_FILTER_PAETH);
-
-   if (png_rust_get_width(png_ptr->rust_ptr) == 1)
-      filters &= 0xff & ~(PNG_FILTER_SUB|PNG_FILTER_AVG|PNG_FILTER_PAETH);
-
-   if (filters == 0)
-      filters = PNG_FILTER_NONE;
-
-   png_rust_set_do_filter(png_ptr->rust_ptr, filters);
-
    if (((filters & (PNG_FILTER_SUB | PNG_FILTER_UP | PNG_FILTER_AVG |
        PNG_FILTER_PAETH)) != 0) && png_rust_get_try_row(png_ptr->rust_ptr) == NULL)
    {
@@ -1970,301 +1948,38 @@ png_write_start_row(png_structrp png_ptr)
       png_rust_set_prev_row(png_ptr->rust_ptr, png_voidcast(png_bytep,
           png_calloc(png_ptr, buf_size)));
 #endif /* WRITE_FILTER */
-
-#ifdef PNG_WRITE_INTERLACING_SUPPORTED
-   /* If interlaced, we need to set up width and height of pass */
-   if (png_rust_get_interlace(png_ptr->rust_ptr) != 0)
-   {
-      if ( ! png_rust_has_transformations(png_ptr->rust_ptr, PNG_INTERLACE))
-      {
-         png_rust_set_num_rows(png_ptr->rust_ptr, (png_rust_get_height(png_ptr->rust_ptr) + png_pass_yinc[0] - 1 -
-             png_pass_ystart[0]) / png_pass_yinc[0]);
-
-         png_rust_set_usr_width(png_ptr->rust_ptr, (png_rust_get_width(png_ptr->rust_ptr) + png_pass_inc[0] - 1 -
-             png_pass_start[0]) / png_pass_inc[0]);
-      }
-
-      else
-      {
-         png_rust_set_num_rows(png_ptr->rust_ptr, png_rust_get_height(png_ptr->rust_ptr));
-         png_rust_set_usr_width(png_ptr->rust_ptr, png_rust_get_width(png_ptr->rust_ptr));
-      }
-   }
-
-   else
-#endif
-   {
-      png_rust_set_num_rows(png_ptr->rust_ptr, png_rust_get_height(png_ptr->rust_ptr));
-      png_rust_set_usr_width(png_ptr->rust_ptr, png_rust_get_width(png_ptr->rust_ptr));
-   }
 }
 
 /* Internal use only.  Called when finished processing a row of data. */
 void /* PRIVATE */
 png_write_finish_row(png_structrp png_ptr)
 {
-#ifdef PNG_WRITE_INTERLACING_SUPPORTED
-   /* Arrays to facilitate easy interlacing - use pass (0 - 6) as index */
-
-   /* Start of interlace block */
-   static const png_byte png_pass_start[7] = {0, 4, 0, 2, 0, 1, 0};
-
-   /* Offset to next interlace block */
-   static const png_byte png_pass_inc[7] = {8, 8, 4, 4, 2, 2, 1};
-
-   /* Start of interlace block in the y direction */
-   static const png_byte png_pass_ystart[7] = {0, 0, 4, 0, 2, 0, 1};
-
-   /* Offset to next interlace block in the y direction */
-   static const png_byte png_pass_yinc[7] = {8, 8, 8, 4, 4, 2, 2};
-#endif
-
    png_debug(1, "in png_write_finish_row");
 
-   /* Next row */
-   png_rust_incr_row_number(png_ptr->rust_ptr);
-
-   /* See if we are done */
-   if (png_rust_get_row_number(png_ptr->rust_ptr) < png_rust_get_num_rows(png_ptr->rust_ptr))
-      return;
-
-#ifdef PNG_WRITE_INTERLACING_SUPPORTED
-   /* If interlaced, go to next pass */
-   if (png_rust_get_interlace(png_ptr->rust_ptr) != 0)
+   /* Next row, or the first row of the next pass with pixels */
+   switch (png_rust_write_finish_row(png_ptr->rust_ptr))
    {
-      png_rust_set_row_number(png_ptr->rust_ptr, 0);
-      if (png_rust_has_transformations(png_ptr->rust_ptr, PNG_INTERLACE) )
-      {
-         png_rust_incr_pass(png_ptr->rust_ptr);
-      }
-
-      else
-      {
-         /* Loop until we find a non-zero width or height pass */
-         do
-         {
-            png_rust_incr_pass(png_ptr->rust_ptr);
-
-            if ( ! png_rust_pass_is_valid(png_ptr->rust_ptr) )
-               break;
-
-            png_rust_set_usr_width(png_ptr->rust_ptr, (png_rust_get_width(png_ptr->rust_ptr) +
-                png_pass_inc[png_rust_get_pass(png_ptr->rust_ptr)] - 1 -
-                png_pass_start[png_rust_get_pass(png_ptr->rust_ptr)]) /
-                png_pass_inc[png_rust_get_pass(png_ptr->rust_ptr)]);
-
-            png_rust_set_num_rows(png_ptr->rust_ptr, (png_rust_get_height(png_ptr->rust_ptr) +
-                png_pass_yinc[png_rust_get_pass(png_ptr->rust_ptr)] - 1 -
-                png_pass_ystart[png_rust_get_pass(png_ptr->rust_ptr)]) /
-                png_pass_yinc[png_rust_get_pass(png_ptr->rust_ptr)]);
-
-            if (png_rust_has_transformations(png_ptr->rust_ptr, PNG_INTERLACE))
-               break;
-
-         } while (png_rust_get_usr_width(png_ptr->rust_ptr) == 0 || png_rust_get_num_rows(png_ptr->rust_ptr) == 0);
-
-      }
+      case PNG_ROW_END_ROW:
+         return;
 
-      /* Reset the row above the image for the next pass */
-      if (png_rust_pass_is_valid(png_ptr->rust_ptr))
-      {
+      case PNG_ROW_END_PASS:
+         /* Reset the row above the image for the next pass */
          if (png_rust_get_prev_row(png_ptr->rust_ptr) != NULL)
             memset(png_rust_get_prev_row(png_ptr->rust_ptr), 0,
                 PNG_ROWBYTES(png_rust_get_usr_channels(png_ptr->rust_ptr) *
                 png_rust_get_usr_bit_depth(png_ptr->rust_ptr), png_rust_get_width(png_ptr->rust_ptr)) + 1);
 
          return;
-      }
+
+      default:
+         break;
    }
-#endif
 
    /* If we get here, we've just written the last row, so we need
       to flush the compressor */
    png_compress_IDAT(png_ptr, NULL, 0, Z_FINISH);
 }
 
-#ifdef PNG_WRITE_INTERLACING_SUPPORTED
-/* Pick out the correct pixels for the interlace pass.
- * The basic idea here is to go through the row with a source
- * pointer and a destination pointer (sp and dp), and copy the
- * correct pixels for the pass.  As the row gets compacted,
- * sp will always be >= dp, so we should never overwrite anything.
- * See the default: case for the easiest code to understand.
- */
-void /* PRIVATE */
-png_do_write_interlace(png_row_infop row_info, png_bytep row, int pass)
-{
-   /* Arrays to facilitate easy interlacing - use pass (0 - 6) as index */
-
-   /* Start of interlace block */
-   static const png_byte png_pass_start[7] = {0, 4, 0, 2, 0, 1, 0};
-
-   /* Offset to next interlace block */
-   static const png_byte png_pass_inc[7] = {8, 8, 4, 4, 2, 2, 1};
-
-   png_debug(1, "in png_do_write_interlace");
-
-   /* We don't have to do anything on the last pass (6) */
-   if (pass < 6)
-   {
-      /* Each pixel depth is handled separately */
-      switch (row_info->pixel_depth)
-      {
-         case 1:
-         {
-            png_bytep sp;
-            png_bytep dp;
-            unsigned int shift;
-            int d;
-            int value;
-            png_uint_32 i;
-            png_uint_32 row_width = row_info->width;
-
-            dp = row;
-            d = 0;
-            shift = 7;
-
-            for (i = png_pass_start[pass]; i < row_width;
-               i += png_pass_inc[pass])
-            {
-               sp = row + (size_t)(i >> 3);
-               value = (int)(*sp >> (7 - (int)(i & 0x07))) & 0x01;
-               d |= (value << shift);
-
-               if (shift == 0)
-               {
-                  shift = 7;
-                  *dp++ = (png_byte)d;
-                  d = 0;
-               }
-
-               else
-                  shift--;
-
-            }
-            if (shift != 7)
-               *dp = (png_byte)d;
-
-            break;
-         }
-
-         case 2:
-         {
-            png_bytep sp;
-            png_bytep dp;
-            unsigned int shift;
-            int d;
-            int value;
-            png_uint_32 i;
-            png_uint_32 row_width = row_info->width;
-
-            dp = row;
-            shift = 6;
-            d = 0;
-
-            for (i = png_pass_start[pass]; i < row_width;
-               i += png_pass_inc[pass])
-            {
-               sp = row + (size_t)(i >> 2);
-               value = (*sp >> ((3 - (int)(i & 0x03)) << 1)) & 0x03;
-               d |= (value << shift);
-
-               if (shift == 0)
-               {
-                  shift = 6;
-                  *dp++ = (png_byte)d;
-                  d = 0;
-               }
-
-               else
-                  shift -= 2;
-            }
-            if (shift != 6)
-               *dp = (png_byte)d;
-
-            break;
-         }
-
-         case 4:
-         {
-            png_bytep sp;
-            png_bytep dp;
-            unsigned int shift;
-            int d;
-            int value;
-            png_uint_32 i;
-            png_uint_32 row_width = row_info->width;
-
-            dp = row;
-            shift = 4;
-            d = 0;
-            for (i = png_pass_start[pass]; i < row_width;
-                i += png_pass_inc[pass])
-            {
-               sp = row + (size_t)(i >> 1);
-               value = (*sp >> ((1 - (int)(i & 0x01)) << 2)) & 0x0f;
-               d |= (value << shift);
-
-               if (shift == 0)
-               {
-                  shift = 4;
-                  *dp++ = (png_byte)d;
-                  d = 0;
-               }
-
-               else
-                  shift -= 4;
-            }
-            if (shift != 4)
-               *dp = (png_byte)d;
-
-            break;
-         }
-
-         default:
-         {
-            png_bytep sp;
-            png_bytep dp;
-            png_uint_32 i;
-            png_uint_32 row_width = row_info->width;
-            size_t pixel_bytes;
-
-            /* Start at the beginning */
-            dp = row;
-
-            /* Find out how many bytes each pixel takes up */
-            pixel_bytes = (row_info->pixel_depth >> 3);
-
-            /* Loop through the row, only looking at the pixels that matter */
-            for (i = png_pass_start[pass]; i < row_width;
-               i += png_pass_inc[pass])
-            {
-               /* Find out where the original pixel is */
-               sp = row + (size_t)i * pixel_bytes;
-
-               /* Move the pixel */
-               if (dp != sp)
-                  memcpy(dp, sp, pixel_bytes);
-
-               /* Next pixel */
-               dp += pixel_bytes;
-            }
-            break;
-         }
-      }
-      /* Set new row width */
-      row_info->width = (row_info->width +
-          png_pass_inc[pass] - 1 -
-          png_pass_start[pass]) /
-          png_pass_inc[pass];
-
-      row_info->rowbytes = PNG_ROWBYTES(row_info->pixel_depth,
-          row_info->width);
-   }
-}
-#endif
-
-
 /* This filters the row, chooses which filter to use, if it has not already
  * been specified by the application, and then writes the row out with the
  * chosen filter.
@@ -2273,466 +1988,18 @@ static void /* PRIVATE */
 png_write_filtered_row(png_structrp png_ptr, png_bytep filtered_row,
     size_t row_bytes);
 
-#ifdef PNG_WRITE_FILTER_SUPPORTED
-static size_t /* PRIVATE */
-png_setup_sub_row(png_structrp png_ptr, png_uint_32 bpp,
-    size_t row_bytes, size_t lmins)
-{
-   png_bytep rp, dp, lp;
-   size_t i;
-   size_t sum = 0;
-   unsigned int v;
-
-   png_rust_get_try_row(png_ptr->rust_ptr)[0] = PNG_FILTER_VALUE_SUB;
-
-   for (i = 0, rp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1, dp = png_rust_get_try_row(png_ptr->rust_ptr) + 1; i < bpp;
-        i++, rp++, dp++)
-   {
-      v = *dp = *rp;
-#ifdef PNG_USE_ABS
-      sum += 128 - abs((int)v - 128);
-#else
-      sum += (v < 128) ? v : 256 - v;
-#endif
-   }
-
-   for (lp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1; i < row_bytes;
-      i++, rp++, lp++, dp++)
-   {
-      v = *dp = (png_byte)(((int)*rp - (int)*lp) & 0xff);
-#ifdef PNG_USE_ABS
-      sum += 128 - abs((int)v - 128);
-#else
-      sum += (v < 128) ? v : 256 - v;
-#endif
-
-      if (sum > lmins)  /* We are already worse, don't continue. */
-        break;
-   }
-
-   return (sum);
-}
-
-static void /* PRIVATE */
-png_setup_sub_row_only(png_structrp png_ptr, png_uint_32 bpp,
-    size_t row_bytes)
-{
-   png_bytep rp, dp, lp;
-   size_t i;
-
-   png_rust_get_try_row(png_ptr->rust_ptr)[0] = PNG_FILTER_VALUE_SUB;
-
-   for (i = 0, rp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1, dp = png_rust_get_try_row(png_ptr->rust_ptr) + 1; i < bpp;
-        i++, rp++, dp++)
-   {
-      *dp = *rp;
-   }
-
-   for (lp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1; i < row_bytes;
-      i++, rp++, lp++, dp++)
-   {
-      *dp = (png_byte)(((int)*rp - (int)*lp) & 0xff);
-   }
-}
-
-static size_t /* PRIVATE */
-png_setup_up_row(png_structrp png_ptr, size_t row_bytes, size_t lmins)
-{
-   png_bytep rp, dp, pp;
-   size_t i;
-   size_t sum = 0;
-   unsigned int v;
-
-   png_rust_get_try_row(png_ptr->rust_ptr)[0] = PNG_FILTER_VALUE_UP;
-
-   for (i = 0, rp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1, dp = png_rust_get_try_row(png_ptr->rust_ptr) + 1,
-       pp = png_rust_get_prev_row(png_ptr->rust_ptr) + 1; i < row_bytes;
-       i++, rp++, pp++, dp++)
-   {
-      v = *dp = (png_byte)(((int)*rp - (int)*pp) & 0xff);
-#ifdef PNG_USE_ABS
-      sum += 128 - abs((int)v - 128);
-#else
-      sum += (v < 128) ? v : 256 - v;
-#endif
-
-      if (sum > lmins)  /* We are already worse, don't continue. */
-        break;
-   }
-
-   return (sum);
-}
-static void /* PRIVATE */
-png_setup_up_row_only(png_structrp png_ptr, size_t row_bytes)
-{
-   png_bytep rp, dp, pp;
-   size_t i;
-
-   png_rust_get_try_row(png_ptr->rust_ptr)[0] = PNG_FILTER_VALUE_UP;
-
-   for (i = 0, rp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1, dp = png_rust_get_try_row(png_ptr->rust_ptr) + 1,
-       pp = png_rust_get_prev_row(png_ptr->rust_ptr) + 1; i < row_bytes;
-       i++, rp++, pp++, dp++)
-   {
-      *dp = (png_byte)(((int)*rp - (int)*pp) & 0xff);
-   }
-}
-
-static size_t /* PRIVATE */
-png_setup_avg_row(png_structrp png_ptr, png_uint_32 bpp,
-    size_t row_bytes, size_t lmins)
-{
-   png_bytep rp, dp, pp, lp;
-   png_uint_32 i;
-   size_t sum = 0;
-   unsigned int v;
-
-   png_rust_get_try_row(png_ptr->rust_ptr)[0] = PNG_FILTER_VALUE_AVG;
-
-   for (i = 0, rp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1, dp = png_rust_get_try_row(png_ptr->rust_ptr) + 1,
-       pp = png_rust_get_prev_row(png_ptr->rust_ptr) + 1; i < bpp; i++)
-   {
-      v = *dp++ = (png_byte)(((int)*rp++ - ((int)*pp++ / 2)) & 0xff);
-
-#ifdef PNG_USE_ABS
-      sum += 128 - abs((int)v - 128);
-#else
-      sum += (v < 128) ? v : 256 - v;
-#endif
-   }
-
-   for (lp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1; i < row_bytes; i++)
-   {
-      v = *dp++ = (png_byte)(((int)*rp++ - (((int)*pp++ + (int)*lp++) / 2))
-          & 0xff);
-
-#ifdef PNG_USE_ABS
-      sum += 128 - abs((int)v - 128);
-#else
-      sum += (v < 128) ? v : 256 - v;
-#endif
-
-      if (sum > lmins)  /* We are already worse, don't continue. */
-        break;
-   }
-
-   return (sum);
-}
-static void /* PRIVATE */
-png_setup_avg_row_only(png_structrp png_ptr, png_uint_32 bpp,
-    size_t row_bytes)
-{
-   png_bytep rp, dp, pp, lp;
-   png_uint_32 i;
-
-   png_rust_get_try_row(png_ptr->rust_ptr)[0] = PNG_FILTER_VALUE_AVG;
-
-   for (i = 0, rp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1, dp = png_rust_get_try_row(png_ptr->rust_ptr) + 1,
-       pp = png_rust_get_prev_row(png_ptr->rust_ptr) + 1; i < bpp; i++)
-   {
-      *dp++ = (png_byte)(((int)*rp++ - ((int)*pp++ / 2)) & 0xff);
-   }
-
-   for (lp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1; i < row_bytes; i++)
-   {
-      *dp++ = (png_byte)(((int)*rp++ - (((int)*pp++ + (int)*lp++) / 2))
-          & 0xff);
-   }
-}
-
-static size_t /* PRIVATE */
-png_setup_paeth_row(png_structrp png_ptr, png_uint_32 bpp,
-    size_t row_bytes, size_t lmins)
-{
-   png_bytep rp, dp, pp, cp, lp;
-   size_t i;
-   size_t sum = 0;
-   unsigned int v;
-
-   png_rust_get_try_row(png_ptr->rust_ptr)[0] = PNG_FILTER_VALUE_PAETH;
-
-   for (i = 0, rp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1, dp = png_rust_get_try_row(png_ptr->rust_ptr) + 1,
-       pp = png_rust_get_prev_row(png_ptr->rust_ptr) + 1; i < bpp; i++)
-   {
-      v = *dp++ = (png_byte)(((int)*rp++ - (int)*pp++) & 0xff);
-
-#ifdef PNG_USE_ABS
-      sum += 128 - abs((int)v - 128);
-#else
-      sum += (v < 128) ? v : 256 - v;
-#endif
-   }
-
-   for (lp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1, cp = png_rust_get_prev_row(png_ptr->rust_ptr) + 1; i < row_bytes;
-        i++)
-   {
-      int a, b, c, pa, pb, pc, p;
-
-      b = *pp++;
-      c = *cp++;
-      a = *lp++;
-
-      p = b - c;
-      pc = a - c;
-
-#ifdef PNG_USE_ABS
-      pa = abs(p);
-      pb = abs(pc);
-      pc = abs(p + pc);
-#else
-      pa = p < 0 ? -p : p;
-      pb = pc < 0 ? -pc : pc;
-      pc = (p + pc) < 0 ? -(p + pc) : p + pc;
-#endif
-
-      p = (pa <= pb && pa <=pc) ? a : (pb <= pc) ? b : c;
-
-      v = *dp++ = (png_byte)(((int)*rp++ - p) & 0xff);
-
-#ifdef PNG_USE_ABS
-      sum += 128 - abs((int)v - 128);
-#else
-      sum += (v < 128) ? v : 256 - v;
-#endif
-
-      if (sum > lmins)  /* We are already worse, don't continue. */
-        break;
-   }
-
-   return (sum);
-}
-static void /* PRIVATE */
-png_setup_paeth_row_only(png_structrp png_ptr, png_uint_32 bpp,
-    size_t row_bytes)
-{
-   png_bytep rp, dp, pp, cp, lp;
-   size_t i;
-
-   png_rust_get_try_row(png_ptr->rust_ptr)[0] = PNG_FILTER_VALUE_PAETH;
-
-   for (i = 0, rp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1, dp = png_rust_get_try_row(png_ptr->rust_ptr) + 1,
-       pp = png_rust_get_prev_row(png_ptr->rust_ptr) + 1; i < bpp; i++)
-   {
-      *dp++ = (png_byte)(((int)*rp++ - (int)*pp++) & 0xff);
-   }
-
-   for (lp = png_rust_get_row_buf(png_ptr->rust_ptr) + 1, cp = png_rust_get_prev_row(png_ptr->rust_ptr) + 1; i < row_bytes;
-        i++)
-   {
-      int a, b, c, pa, pb, pc, p;
-
-      b = *pp++;
-      c = *cp++;
-      a = *lp++;
-
-      p = b - c;
-      pc = a - c;
-
-#ifdef PNG_USE_ABS
-      pa = abs(p);
-      pb = abs(pc);
-      pc = abs(p + pc);
-#else
-      pa = p < 0 ? -p : p;
-      pb = pc < 0 ? -pc : pc;
-      pc = (p + pc) < 0 ? -(p + pc) : p + pc;
-#endif
-
-      p = (pa <= pb && pa <=pc) ? a : (pb <= pc) ? b : c;
-
-      *dp++ = (png_byte)(((int)*rp++ - p) & 0xff);
-   }
-}
-#endif /* WRITE_FILTER */
-
 void /* PRIVATE */
 png_write_find_filter(png_structrp png_ptr, png_row_infop row_info)
 {
 #ifndef PNG_WRITE_FILTER_SUPPORTED
    png_write_filtered_row(png_ptr, png_rust_get_row_buf(png_ptr->rust_ptr), row_info->rowbytes+1);
 #else
-   unsigned int filter_to_do = png_rust_get_do_filter(png_ptr->rust_ptr);
-   png_bytep row_buf;
    png_bytep best_row;
-   png_uint_32 bpp;
-   size_t mins;
-   size_t row_bytes = row_info->rowbytes;
 
    png_debug(1, "in png_write_find_filter");
 
-   /* Find out how many bytes offset each pixel is */
-   bpp = (row_info->pixel_depth + 7) >> 3;
-
-   row_buf = png_rust_get_row_buf(png_ptr->rust_ptr);
-   mins = PNG_SIZE_MAX - 256/* so we can detect potential overflow of the
-                               running sum */;
-
-   /* The prediction method we use is to find which method provides the
-    * smallest value when summing the absolute values of the distances
-    * from zero, using anything >= 128 as negative numbers.  This is known
-    * as the "minimum sum of absolute differences" heuristic.  Other
-    * heuristics are the "weighted minimum sum of absolute differences"
-    * (experimental and can in theory improve compression), and the "zlib
-    * predictive" method (not implemented yet), which does test compressions
-    * of lines using different filter methods, and then chooses the
-    * (series of) filter(s) that give minimum compressed data size (VERY
-    * computationally expensive).
-    *
-    * GRR 980525:  consider also
-    *
-    *   (1) minimum sum of absolute differences from running average (i.e.,
-    *       keep running sum of non-absolute differences & count of bytes)
-    *       [track dispersion, too?  restart average if dispersion too large?]
-    *
-    *  (1b) minimum sum of absolute differences from sliding average, probably
-    *       with window size <= deflate window (usually 32K)
-    *
-    *   (2) minimum sum of squared differences from zero or running average
-    *       (i.e., ~ root-mean-square approach)
-    */
-
-
-   /* We don't need to test the 'no filter' case if this is the only filter
-    * that has been chosen, as it doesn't actually do anything to the data.
-    */
-   best_row = png_rust_get_row_buf(png_ptr->rust_ptr);
-
-   if (PNG_SIZE_MAX/128 <= row_bytes)
-   {
-      /* Overflow can occur in the calculation, just select the lowest set
-       * filter.
-       */
-      filter_to_do &= 0U-filter_to_do;
-   }
-   else if ((filter_to_do & PNG_FILTER_NONE) != 0 &&
-         filter_to_do != PNG_FILTER_NONE)
-   {
-      /* Overflow not possible and multiple filters in the list, including the
-       * 'none' filter.
-       */
-      png_bytep rp;
-      size_t sum = 0;
-      size_t i;
-      unsigned int v;
-
-      {
-         for (i = 0, rp = row_buf + 1; i < row_bytes; i++, rp++)
-         {
-            v = *rp;
-#ifdef PNG_USE_ABS
-            sum += 128 - abs((int)v - 128);
-#else
-            sum += (v < 128) ? v : 256 - v;
-#endif
-         }
-      }
-
-      mins = sum;
-   }
-
-   /* Sub filter */
-   if (filter_to_do == PNG_FILTER_SUB)
-   /* It's the only filter so no testing is needed */
-   {
-      png_setup_sub_row_only(png_ptr, bpp, row_bytes);
-      best_row = png_rust_get_try_row(png_ptr->rust_ptr);
-   }
-
-   else if ((filter_to_do & PNG_FILTER_SUB) != 0)
-   {
-      size_t sum;
-      size_t lmins = mins;
-
-      sum = png_setup_sub_row(png_ptr, bpp, row_bytes, lmins);
-
-      if (sum < mins)
-      {
-         mins = sum;
-         best_row = png_rust_get_try_row(png_ptr->rust_ptr);
-         if (png_rust_get_tst_row(png_ptr->rust_ptr) != NULL)
-         {
-            png_rust_set_try_row(png_ptr->rust_ptr, png_rust_get_tst_row(png_ptr->rust_ptr));
-            png_rust_set_tst_row(png_ptr->rust_ptr, best_row);
-         }
-      }
-   }
-
-   /* Up filter */
-   if (filter_to_do == PNG_FILTER_UP)
-   {
-      png_setup_up_row_only(png_ptr, row_bytes);
-      best_row = png_rust_get_try_row(png_ptr->rust_ptr);
-   }
-
-   else if ((filter_to_do & PNG_FILTER_UP) != 0)
-   {
-      size_t sum;
-      size_t lmins = mins;
-
-      sum = png_setup_up_row(png_ptr, row_bytes, lmins);
-
-      if (sum < mins)
-      {
-         mins = sum;
-         best_row = png_rust_get_try_row(png_ptr->rust_ptr);
-         if (png_rust_get_tst_row(png_ptr->rust_ptr) != NULL)
-         {
-            png_rust_set_try_row(png_ptr->rust_ptr, png_rust_get_tst_row(png_ptr->rust_ptr));
-            png_rust_set_tst_row(png_ptr->rust_ptr, best_row);
-         }
-      }
-   }
-
-   /* Avg filter */
-   if (filter_to_do == PNG_FILTER_AVG)
-   {
-      png_setup_avg_row_only(png_ptr, bpp, row_bytes);
-      best_row = png_rust_get_try_row(png_ptr->rust_ptr);
-   }
-
-   else if ((filter_to_do & PNG_FILTER_AVG) != 0)
-   {
-      size_t sum;
-      size_t lmins = mins;
-
-      sum= png_setup_avg_row(png_ptr, bpp, row_bytes, lmins);
-
-      if (sum < mins)
-      {
-         mins = sum;
-         best_row = png_rust_get_try_row(png_ptr->rust_ptr);
-         if (png_rust_get_tst_row(png_ptr->rust_ptr) != NULL)
-         {
-            png_rust_set_try_row(png_ptr->rust_ptr, png_rust_get_tst_row(png_ptr->rust_ptr));
-            png_rust_set_tst_row(png_ptr->rust_ptr, best_row);
-         }
-      }
-   }
-
-   /* Paeth filter */
-   if (filter_to_do == PNG_FILTER_PAETH)
-   {
-      png_setup_paeth_row_only(png_ptr, bpp, row_bytes);
-      best_row = png_rust_get_try_row(png_ptr->rust_ptr);
-   }
-
-   else if ((filter_to_do & PNG_FILTER_PAETH) != 0)
-   {
-      size_t sum;
-      size_t lmins = mins;
-
-      sum = png_setup_paeth_row(png_ptr, bpp, row_bytes, lmins);
-
-      if (sum < mins)
-      {
-         best_row = png_rust_get_try_row(png_ptr->rust_ptr);
-         if (png_rust_get_tst_row(png_ptr->rust_ptr) != NULL)
-         {
-            png_rust_set_try_row(png_ptr->rust_ptr, png_rust_get_tst_row(png_ptr->rust_ptr));
-            png_rust_set_tst_row(png_ptr->rust_ptr, best_row);
-         }
-      }
-   }
+   /* The filter is chosen, and the row filtered, by the Rust filter engine */
+   best_row = png_rust_find_filter(png_ptr->rust_ptr, row_info);
 
    /* Do the actual writing of the filtered row data from the chosen filter. */
    png_write_filtered_row(png_ptr, best_row, row_info->rowbytes+1);